@@ -0,0 +1,146 @@
+/* Load generator: floods a service with method calls and reports throughput and latency.
+
+   Usage: loadgen [--dest NAME --path PATH --iface IFACE --method METHOD]
+                  [--threads N] [--calls N] [--rate N] [--size BYTES]
+
+   Every call takes one string argument of --size bytes. Without --dest, an echo
+   server is started in-process and used as the target. --rate limits the calls per
+   second of each thread; 0 means as fast as possible. */
+
+extern crate dbus;
+extern crate libc;
+
+use dbus::{Connection, BusType, NameFlag, ConnectionItem, Message, MessageItem};
+use dbus::objpath::{ObjectPath, Interface, Method};
+use std::io::timer;
+use std::time::Duration;
+use std::sync::mpsc::channel;
+use std::thread::Thread;
+
+#[repr(C)]
+struct Timespec { tv_sec: libc::time_t, tv_nsec: libc::c_long }
+
+extern { fn clock_gettime(clk: libc::c_int, ts: *mut Timespec) -> libc::c_int; }
+
+const CLOCK_MONOTONIC: libc::c_int = 1;
+
+fn now_us() -> u64 {
+    let mut ts = Timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { clock_gettime(CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1000000 + ts.tv_nsec as u64 / 1000
+}
+
+struct Options {
+    dest: Option<String>,
+    path: String,
+    iface: String,
+    method: String,
+    threads: uint,
+    calls: uint,
+    rate: uint,
+    size: uint,
+}
+
+fn parse_args() -> Options {
+    let mut o = Options { dest: None, path: "/loadgen".to_string(), iface: "com.example.LoadGen".to_string(),
+        method: "Echo".to_string(), threads: 4, calls: 10000, rate: 0, size: 16 };
+    let args = std::os::args();
+    let mut it = args.iter().skip(1);
+    while let Some(a) = it.next() {
+        let v = it.next().unwrap_or_else(|| panic!("Missing value for {}", a)).clone();
+        let num = || v.as_slice().parse::<uint>().unwrap_or_else(|| panic!("Invalid number for {}", a));
+        match a.as_slice() {
+            "--dest" => o.dest = Some(v.clone()),
+            "--path" => o.path = v.clone(),
+            "--iface" => o.iface = v.clone(),
+            "--method" => o.method = v.clone(),
+            "--threads" => o.threads = num(),
+            "--calls" => o.calls = num(),
+            "--rate" => o.rate = num(),
+            "--size" => o.size = num(),
+            _ => panic!("Unknown option {}", a),
+        }
+    }
+    o
+}
+
+fn start_echo_server(path: String, iface: String, method: String) -> String {
+    let name = format!("com.example.loadgen{}", std::rand::random::<u32>());
+    let (tx, rx) = channel();
+    let n2 = name.clone();
+    Thread::spawn(move || {
+        let c = Connection::get_private(BusType::Session).unwrap();
+        c.register_name(n2.as_slice(), NameFlag::ReplaceExisting as u32).unwrap();
+        let mut i = Interface::new();
+        i.add_method(method.as_slice(), Method::new_typed(&["request"], &["reply"],
+            |&: (s,): (String,)| Ok((s,))));
+        let mut o = ObjectPath::new(&c, path.as_slice(), false);
+        o.insert_interface(iface, i);
+        o.set_registered(true).unwrap();
+        tx.send(()).unwrap();
+        for n in c.iter(1000) {
+            if let ConnectionItem::MethodCall(mut m) = n { o.handle_message(&mut m); }
+        }
+    }).detach();
+    rx.recv().unwrap();
+    name
+}
+
+fn percentile(sorted: &[u64], p: uint) -> u64 {
+    if sorted.len() == 0 { return 0 }
+    sorted[std::cmp::min(sorted.len() * p / 100, sorted.len() - 1)]
+}
+
+fn main() {
+    let o = parse_args();
+    let dest = match o.dest {
+        Some(ref d) => d.clone(),
+        None => start_echo_server(o.path.clone(), o.iface.clone(), o.method.clone()),
+    };
+    let payload = String::from_char(o.size, 'x');
+
+    let (tx, rx) = channel();
+    let start = now_us();
+    for _ in range(0, o.threads) {
+        let tx = tx.clone();
+        let (dest, path, iface, method, payload) =
+            (dest.clone(), o.path.clone(), o.iface.clone(), o.method.clone(), payload.clone());
+        let (calls, rate) = (o.calls, o.rate);
+        Thread::spawn(move || {
+            let c = Connection::get_private(BusType::Session).unwrap();
+            let interval_us = if rate > 0 { 1000000 / rate as u64 } else { 0 };
+            let mut latencies = Vec::with_capacity(calls);
+            let mut errors = 0u;
+            let mut next = now_us();
+            for _ in range(0, calls) {
+                let mut m = Message::new_method_call(dest.as_slice(), path.as_slice(),
+                    iface.as_slice(), method.as_slice()).unwrap();
+                m.append_items(&[MessageItem::Str(payload.clone())]);
+                let t = now_us();
+                if c.send_with_reply_and_block(m, 25000).is_err() { errors += 1; }
+                latencies.push(now_us() - t);
+                if interval_us > 0 {
+                    next += interval_us;
+                    let n = now_us();
+                    if next > n { timer::sleep(Duration::microseconds((next - n) as i64)); }
+                }
+            }
+            tx.send((latencies, errors)).unwrap();
+        }).detach();
+    }
+    drop(tx);
+
+    let mut all = vec!();
+    let mut errors = 0;
+    for (l, e) in rx.iter() {
+        all.extend(l.into_iter());
+        errors += e;
+    }
+    let elapsed = now_us() - start;
+    all.sort();
+
+    println!("{} calls ({} errors) in {} ms, {} calls/s", all.len(), errors, elapsed / 1000,
+        all.len() as u64 * 1000000 / std::cmp::max(elapsed, 1));
+    println!("latency us: p50 {} p90 {} p99 {} max {}", percentile(all.as_slice(), 50),
+        percentile(all.as_slice(), 90), percentile(all.as_slice(), 99), all.last().map(|x| *x).unwrap_or(0));
+}
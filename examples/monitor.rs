@@ -0,0 +1,18 @@
+extern crate dbus;
+
+use dbus::{Connection, BusType, ConnectionItem};
+use dbus::pretty::Pretty;
+
+fn main() {
+    let c = Connection::get_private(BusType::Session).unwrap();
+    // Also see messages sent to other connections, where the bus allows it
+    for r in ["type='signal',eavesdrop=true", "type='method_call',eavesdrop=true"].iter() {
+        c.add_match(*r).unwrap();
+    }
+    for n in c.iter(1000) {
+        match n {
+            ConnectionItem::MethodCall(m) | ConnectionItem::Signal(m) => println!("{}", Pretty(&m)),
+            ConnectionItem::Nothing => (),
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use super::{Connection, Message, MessageItem, Error};
+use std::collections::HashMap;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// A set of bus names allowed to make method calls, see `ObjectPath::set_allowed_senders`.
+///
+/// Method calls always come from unique names, so the owners of the well-known names
+/// are looked up when the list is created, and then kept up to date by watching
+/// NameOwnerChanged on the connection (which happens when the connection is iterated).
+/// The list can be cloned to share it between objects.
+#[deriving(Clone)]
+pub struct SenderAllowList {
+    unique_names: Vec<String>,
+    // Well-known name -> unique name of its current owner
+    owners: Rc<RefCell<HashMap<String, String>>>,
+}
+
+fn get_name_owner(c: &Connection, name: &str) -> Result<Option<String>, Error> {
+    let mut m = try!(Message::new_method_call("org.freedesktop.DBus", "/org/freedesktop/DBus",
+        "org.freedesktop.DBus", "GetNameOwner"));
+    try!(m.try_append_items(&[MessageItem::Str(name.to_string())]));
+    match c.send_with_reply_and_block(m, 25000) {
+        Ok(mut r) => Ok(match try!(r.get_items()).into_iter().next() { Some(MessageItem::Str(s)) => Some(s), _ => None }),
+        Err(ref e) if e.name() == Some("org.freedesktop.DBus.Error.NameHasNoOwner") => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl SenderAllowList {
+    pub fn new(c: &Connection, names: &[&str]) -> Result<SenderAllowList, Error> {
+        let mut owners = HashMap::new();
+        let mut unique_names = vec!();
+        for n in names.iter() {
+            if n.starts_with(":") { unique_names.push(n.to_string()); continue; }
+            try!(c.add_match(format!("type='signal',sender='org.freedesktop.DBus',interface='org.freedesktop.DBus',\
+                member='NameOwnerChanged',arg0='{}'", n).as_slice()));
+            // Names without an owner are kept with an empty one, so that they are tracked
+            // when they get an owner later.
+            owners.insert(n.to_string(), try!(get_name_owner(c, *n)).unwrap_or("".to_string()));
+        }
+
+        let owners = Rc::new(RefCell::new(owners));
+        let o2 = owners.clone();
+        c.add_signal_predicate("org.freedesktop.DBus", "NameOwnerChanged", move |&: items: &[MessageItem]| {
+            if let (Some(&MessageItem::Str(ref name)), Some(&MessageItem::Str(ref new_owner))) = (items.get(0), items.get(2)) {
+                let mut o = o2.borrow_mut();
+                if o.contains_key(name) { o.insert(name.clone(), new_owner.clone()); }
+            }
+            // This predicate only listens, it does not filter.
+            true
+        });
+        Ok(SenderAllowList { unique_names: unique_names, owners: owners })
+    }
+
+    /// True if `sender` is one of the unique names, or owns one of the well-known names.
+    pub fn allows(&self, sender: &str) -> bool {
+        if sender.len() == 0 { return false; }
+        self.unique_names.iter().any(|n| n.as_slice() == sender) ||
+            self.owners.borrow().values().any(|o| o.as_slice() == sender)
+    }
+}
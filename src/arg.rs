@@ -0,0 +1,307 @@
+use super::{MessageItem, Error, Path};
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Options controlling how lenient reading into Rust types is.
+///
+/// The default is to require an exact match.
+#[deriving(Copy, Clone, Show, PartialEq, Default)]
+pub struct ReadOptions {
+    /// Transparently unwrap variants, e g read a "v" containing an "s" as a String.
+    pub unwrap_variants: bool,
+    /// Read smaller integer types into larger ones, e g a "u" into a u64 or a "y" into
+    /// an i32, as long as no value can be lost.
+    pub widen_integers: bool,
+    /// Disable all loose conversions (such as `unwrap_variants`) regardless of their setting.
+    pub strict: bool,
+}
+
+impl ReadOptions {
+    pub fn strict() -> ReadOptions { ReadOptions { strict: true, ..ReadOptions::default() } }
+}
+
+fn unwrap_variant<'a>(i: &'a MessageItem, o: &ReadOptions) -> &'a MessageItem {
+    if !o.unwrap_variants || o.strict { return i };
+    match i {
+        &MessageItem::Variant(ref v) => unwrap_variant(&**v, o),
+        _ => i,
+    }
+}
+
+/// An item did not have the requested type, see `MessageItem::get`.
+#[deriving(Clone, Show, PartialEq)]
+pub struct TypeMismatch {
+    /// The signature of the requested type.
+    pub expected: String,
+    /// The signature of the item.
+    pub found: String,
+}
+
+impl TypeMismatch {
+    /// An InvalidArgs error describing the mismatch, e g for replying to a method call.
+    pub fn to_error(&self) -> Error {
+        let f = format!("Expected an argument of type '{}', got '{}'", self.expected, self.found);
+        Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs", f.as_slice())
+    }
+}
+
+/// A Rust type that maps to a single D-Bus type.
+pub trait Arg {
+    /// The D-Bus signature of this type, e g "u" or "as".
+    fn signature() -> String;
+    fn read(i: &MessageItem, o: &ReadOptions) -> Option<Self>;
+    fn to_item(self) -> MessageItem;
+
+    fn from_item(i: &MessageItem) -> Option<Self> {
+        <Self as Arg>::read(i, &ReadOptions::default())
+    }
+}
+
+/// A list of arguments, implemented for tuples of up to four `Arg` types.
+pub trait ArgList {
+    fn signatures() -> Vec<String>;
+    fn read(i: &[MessageItem], o: &ReadOptions) -> Option<Self>;
+    fn to_items(self) -> Vec<MessageItem>;
+
+    fn from_items(i: &[MessageItem]) -> Option<Self> {
+        <Self as ArgList>::read(i, &ReadOptions::default())
+    }
+}
+
+macro_rules! basic_arg {
+    ($t:ty, $variant:ident, $sig:expr $(, $from:ident)*) => {
+        impl Arg for $t {
+            fn signature() -> String { $sig.to_string() }
+            fn read(i: &MessageItem, o: &ReadOptions) -> Option<$t> {
+                let widen = o.widen_integers && !o.strict;
+                match unwrap_variant(i, o) {
+                    &MessageItem::$variant(ref v) => Some(v.clone()),
+                    $(&MessageItem::$from(v) if widen => Some(v as $t),)*
+                    _ => None,
+                }
+            }
+            fn to_item(self) -> MessageItem { MessageItem::$variant(self) }
+        }
+    }
+}
+
+basic_arg!(bool, Bool, "b");
+basic_arg!(u8, Byte, "y");
+basic_arg!(i16, Int16, "n", Byte);
+basic_arg!(i32, Int32, "i", Byte, Int16, UInt16);
+basic_arg!(i64, Int64, "x", Byte, Int16, UInt16, Int32, UInt32);
+basic_arg!(u16, UInt16, "q", Byte);
+basic_arg!(u32, UInt32, "u", Byte, UInt16);
+basic_arg!(u64, UInt64, "t", Byte, UInt16, UInt32);
+basic_arg!(f64, Double, "d");
+basic_arg!(String, Str, "s");
+basic_arg!(Path, ObjectPath, "o");
+
+impl<T: Arg> Arg for Vec<T> {
+    fn signature() -> String { format!("a{}", <T as Arg>::signature()) }
+    fn read(i: &MessageItem, o: &ReadOptions) -> Option<Vec<T>> {
+        if let &MessageItem::Array(ref a, _) = unwrap_variant(i, o) {
+            let mut v = Vec::with_capacity(a.len());
+            for item in a.iter() {
+                match <T as Arg>::read(item, o) {
+                    Some(x) => v.push(x),
+                    None => return None,
+                }
+            }
+            Some(v)
+        } else { None }
+    }
+    fn to_item(self) -> MessageItem {
+        MessageItem::Array(self.into_iter().map(|x| x.to_item()).collect(), <T as Arg>::signature())
+    }
+}
+
+/// Maps to a dictionary, e g `HashMap<String, MessageItem>` is "a{sv}".
+impl<K: Arg + Eq + Hash, V: Arg> Arg for HashMap<K, V> {
+    fn signature() -> String { format!("a{{{}{}}}", <K as Arg>::signature(), <V as Arg>::signature()) }
+    fn read(i: &MessageItem, o: &ReadOptions) -> Option<HashMap<K, V>> {
+        let mut m = HashMap::new();
+        match unwrap_variant(i, o) {
+            &MessageItem::Dict(ref a, _, _) => for &(ref k, ref v) in a.iter() {
+                match (<K as Arg>::read(k, o), <V as Arg>::read(v, o)) {
+                    (Some(k), Some(v)) => { m.insert(k, v); }
+                    _ => return None,
+                }
+            },
+            // Dictionaries built by hand
+            &MessageItem::Array(ref a, _) => for e in a.iter() {
+                match e {
+                    &MessageItem::DictEntry(ref k, ref v) => match (<K as Arg>::read(&**k, o), <V as Arg>::read(&**v, o)) {
+                        (Some(k), Some(v)) => { m.insert(k, v); }
+                        _ => return None,
+                    },
+                    _ => return None,
+                }
+            },
+            _ => return None,
+        }
+        Some(m)
+    }
+    fn to_item(self) -> MessageItem {
+        MessageItem::Dict(self.into_iter().map(|(k, v)| (k.to_item(), v.to_item())).collect(),
+            <K as Arg>::signature(), <V as Arg>::signature())
+    }
+}
+
+/// Maps to a variant, so that values of any type can be used, e g in "a{sv}".
+impl Arg for MessageItem {
+    fn signature() -> String { "v".to_string() }
+    fn read(i: &MessageItem, _: &ReadOptions) -> Option<MessageItem> {
+        match i {
+            &MessageItem::Variant(ref v) => Some((**v).clone()),
+            _ => None,
+        }
+    }
+    fn to_item(self) -> MessageItem { MessageItem::Variant(box self) }
+}
+
+/// Converts Rust values into `MessageItem`s, so that argument lists can be written as
+/// `&["hello".into_item(), 5u32.into_item()]`.
+///
+/// This is `Arg::to_item`, plus string slices and items themselves, which are not `Arg`s.
+pub trait IntoItem {
+    fn into_item(self) -> MessageItem;
+}
+
+macro_rules! into_item_via_arg {
+    ($($t:ty),+) => { $(impl IntoItem for $t { fn into_item(self) -> MessageItem { self.to_item() } })+ }
+}
+
+into_item_via_arg!(bool, u8, i16, i32, i64, u16, u32, u64, f64, String, Path);
+
+impl<'a> IntoItem for &'a str {
+    fn into_item(self) -> MessageItem { MessageItem::Str(self.to_string()) }
+}
+
+impl<T: Arg> IntoItem for Vec<T> {
+    fn into_item(self) -> MessageItem { self.to_item() }
+}
+
+impl<K: Arg + Eq + Hash, V: Arg> IntoItem for HashMap<K, V> {
+    fn into_item(self) -> MessageItem { self.to_item() }
+}
+
+/// Items are used as they are. Use `Arg::to_item` to wrap one in a variant.
+impl IntoItem for MessageItem {
+    fn into_item(self) -> MessageItem { self }
+}
+
+/// Collects arguments before they are appended to a message, see `Message::append_transaction`.
+pub struct ArgBuilder {
+    items: Vec<MessageItem>,
+}
+
+impl ArgBuilder {
+    pub fn new() -> ArgBuilder { ArgBuilder { items: vec!() } }
+
+    pub fn item(&mut self, i: MessageItem) -> &mut ArgBuilder {
+        self.items.push(i);
+        self
+    }
+
+    pub fn arg<A: Arg>(&mut self, a: A) -> &mut ArgBuilder {
+        self.items.push(a.to_item());
+        self
+    }
+
+    pub fn items(&self) -> &[MessageItem] { self.items.as_slice() }
+
+    pub fn into_items(self) -> Vec<MessageItem> { self.items }
+}
+
+impl ArgList for () {
+    fn signatures() -> Vec<String> { vec!() }
+    fn read(i: &[MessageItem], _: &ReadOptions) -> Option<()> { if i.len() == 0 { Some(()) } else { None } }
+    fn to_items(self) -> Vec<MessageItem> { vec!() }
+}
+
+macro_rules! tuple_arglist {
+    ($len:expr, $($t:ident $n:ident $idx:expr),+) => {
+        impl<$($t: Arg),+> ArgList for ($($t,)+) {
+            fn signatures() -> Vec<String> { vec!($(<$t as Arg>::signature()),+) }
+            fn read(i: &[MessageItem], o: &ReadOptions) -> Option<($($t,)+)> {
+                if i.len() != $len { return None; }
+                $(let $n = match <$t as Arg>::read(&i[$idx], o) { Some(x) => x, None => return None };)+
+                Some(($($n,)+))
+            }
+            fn to_items(self) -> Vec<MessageItem> {
+                let ($($n,)+) = self;
+                vec!($($n.to_item()),+)
+            }
+        }
+    }
+}
+
+tuple_arglist!(1, A a 0);
+tuple_arglist!(2, A a 0, B b 1);
+tuple_arglist!(3, A a 0, B b 1, C c 2);
+tuple_arglist!(4, A a 0, B b 1, C c 2, D d 3);
+
+#[test]
+fn arglist_roundtrip() {
+    assert_eq!(<(u32, String, Vec<i16>) as ArgList>::signatures(),
+        vec!("u".to_string(), "s".to_string(), "an".to_string()));
+    let items = (5u32, "Hello".to_string(), vec!(1i16, 2)).to_items();
+    let (a, b, c): (u32, String, Vec<i16>) = ArgList::from_items(items.as_slice()).unwrap();
+    assert_eq!(a, 5);
+    assert_eq!(b.as_slice(), "Hello");
+    assert_eq!(c, vec!(1i16, 2));
+    assert!(<(u32,) as ArgList>::from_items(items.as_slice()).is_none());
+}
+
+#[test]
+fn unwrap_variants() {
+    let i = MessageItem::Variant(box MessageItem::Variant(box MessageItem::UInt32(7)));
+    assert_eq!(<u32 as Arg>::from_item(&i), None);
+    let o = ReadOptions { unwrap_variants: true, strict: false, widen_integers: false };
+    assert_eq!(<u32 as Arg>::read(&i, &o), Some(7u32));
+    assert_eq!(<String as Arg>::read(&i, &o), None);
+    let o = ReadOptions { unwrap_variants: true, strict: true, widen_integers: false };
+    assert_eq!(<u32 as Arg>::read(&i, &o), None);
+}
+
+#[test]
+fn widen_integers() {
+    let o = ReadOptions { widen_integers: true, ..ReadOptions::default() };
+    assert_eq!(<u64 as Arg>::read(&MessageItem::UInt32(7), &o), Some(7u64));
+    assert_eq!(<i32 as Arg>::read(&MessageItem::Byte(200), &o), Some(200i32));
+    assert_eq!(<u32 as Arg>::read(&MessageItem::Int16(-1), &o), None);
+    assert_eq!(<u32 as Arg>::read(&MessageItem::UInt64(1), &o), None);
+    assert_eq!(<u64 as Arg>::from_item(&MessageItem::UInt32(7)), None);
+}
+
+#[test]
+fn dicts() {
+    let mut h = HashMap::new();
+    h.insert("a".to_string(), MessageItem::UInt32(1));
+    assert_eq!(<HashMap<String, MessageItem> as Arg>::signature().as_slice(), "a{sv}");
+    let i = h.clone().to_item();
+    assert_eq!(i, MessageItem::Dict(vec!(("a".to_string().to_item(), MessageItem::Variant(box MessageItem::UInt32(1)))),
+        "s".to_string(), "v".to_string()));
+    assert_eq!(<HashMap<String, MessageItem> as Arg>::from_item(&i), Some(h));
+    let e = HashMap::<u32, String>::new().to_item();
+    assert_eq!(e, MessageItem::Dict(vec!(), "u".to_string(), "s".to_string()));
+}
+
+#[test]
+fn into_items() {
+    assert_eq!(["hello".into_item(), 5u32.into_item()].as_slice(),
+        [MessageItem::Str("hello".to_string()), MessageItem::UInt32(5)].as_slice());
+    assert_eq!(vec!(1u8).into_item(), MessageItem::Array(vec!(MessageItem::Byte(1)), "y".to_string()));
+    assert_eq!(MessageItem::Bool(true).into_item(), MessageItem::Bool(true));
+}
+
+#[test]
+fn get_typed() {
+    let i = MessageItem::UInt32(5);
+    assert_eq!(i.get::<u32>(), Ok(5));
+    assert_eq!(i.get::<String>(), Err(TypeMismatch { expected: "s".to_string(), found: "u".to_string() }));
+    assert_eq!(i.as_u32(), Some(5));
+    assert_eq!(i.as_str(), None);
+    assert_eq!(MessageItem::Str("a".to_string()).as_str(), Some("a"));
+}
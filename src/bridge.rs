@@ -0,0 +1,262 @@
+use super::{Connection, Message, MessageItem, MessageType, Error};
+use super::allowlist::SenderAllowList;
+
+/// How much a client of a filtering bridge may do with a bus name, like in xdg-dbus-proxy.
+/// Each level includes the ones before it.
+#[deriving(Show, PartialEq, PartialOrd, Copy, Clone)]
+pub enum PolicyLevel {
+    None,
+    /// The name may be looked up, e g with GetNameOwner.
+    See,
+    /// Method calls may be made to the name, and signals from it are forwarded.
+    Talk,
+    /// The name may be requested.
+    Own,
+}
+
+struct PolicyRule {
+    name: String,
+    level: PolicyLevel,
+    path: Option<String>,
+    interface: Option<String>,
+}
+
+/// A set of rules deciding which traffic a `Bridge` forwards.
+///
+/// Names ending in ".*" match all names below that prefix, and paths ending in "/*"
+/// match the whole subtree.
+pub struct Policy {
+    rules: Vec<PolicyRule>,
+}
+
+fn name_matches(pattern: &str, name: &str) -> bool {
+    if pattern.ends_with(".*") { name.starts_with(pattern.slice_to(pattern.len() - 1)) }
+    else { pattern == name }
+}
+
+fn path_matches(pattern: &str, path: &str) -> bool {
+    if pattern.ends_with("/*") {
+        let prefix = pattern.slice_to(pattern.len() - 2);
+        path == prefix || path.starts_with(pattern.slice_to(pattern.len() - 1))
+    }
+    else { pattern == path }
+}
+
+impl Policy {
+    pub fn new() -> Policy { Policy { rules: vec!() } }
+
+    /// Allows everything up to `level` for the name.
+    pub fn allow(&mut self, name: &str, level: PolicyLevel) -> &mut Policy {
+        self.rules.push(PolicyRule { name: name.to_string(), level: level, path: None, interface: None });
+        self
+    }
+
+    /// Allows method calls to the name only on the given path and/or interface.
+    pub fn allow_call(&mut self, name: &str, path: Option<&str>, iface: Option<&str>) -> &mut Policy {
+        self.rules.push(PolicyRule { name: name.to_string(), level: PolicyLevel::Talk,
+            path: path.map(|p| p.to_string()), interface: iface.map(|i| i.to_string()) });
+        self
+    }
+
+    /// The highest level granted to the name by a rule without path or interface restrictions.
+    pub fn level(&self, name: &str) -> PolicyLevel {
+        self.rules.iter().filter(|r| r.path.is_none() && r.interface.is_none() && name_matches(r.name.as_slice(), name))
+            .fold(PolicyLevel::None, |l, r| if r.level > l { r.level } else { l })
+    }
+
+    pub fn allows_call(&self, name: &str, path: &str, iface: &str) -> bool {
+        self.rules.iter().any(|r| r.level >= PolicyLevel::Talk && name_matches(r.name.as_slice(), name) &&
+            r.path.as_ref().map_or(true, |p| path_matches(p.as_slice(), path)) &&
+            r.interface.as_ref().map_or(true, |i| i.as_slice() == iface))
+    }
+
+    fn talk_names(&self) -> Vec<&str> {
+        self.rules.iter().filter(|r| r.level >= PolicyLevel::Talk && !r.name.ends_with(".*"))
+            .map(|r| r.name.as_slice()).collect()
+    }
+}
+
+/// Relays method calls and signals between two connections, e g the session bus and a
+/// peer connection to a sandboxed process.
+///
+/// Method calls received on `from` are forwarded to `destination` on `to`, and the reply
+/// (or error) is sent back to the original caller. Signals received on `to` are emitted
+/// again on `from`. The caller decides which messages to pass to the bridge.
+///
+/// If `destination` is empty, calls are forwarded to their original destination instead.
+/// With a `Policy` set, traffic the policy does not allow is rejected with AccessDenied
+/// (for method calls) or dropped (for signals). Of the methods of the bus itself, only
+/// the ones needed by ordinary clients are allowed.
+///
+/// # Example
+///
+/// ```ignore
+/// let b = Bridge::new(&inner, &outer, "org.freedesktop.Notifications", 10000);
+/// for n in inner.iter(100) {
+///     if let ConnectionItem::MethodCall(mut m) = n { b.forward_method_call(&mut m).unwrap(); }
+///     for n in outer.iter(0) {
+///         if let ConnectionItem::Signal(mut s) = n { b.forward_signal(&mut s).unwrap(); }
+///     }
+/// }
+/// ```
+pub struct Bridge<'a> {
+    from: &'a Connection,
+    to: &'a Connection,
+    destination: String,
+    timeout_ms: int,
+    policy: Option<Policy>,
+    // The current owners of the policy's Talk names on `to`
+    talk_owners: Option<SenderAllowList>,
+}
+
+fn copy_items(src: &mut Message, dest: &mut Message) -> Result<(), Error> {
+    let items: Vec<MessageItem> = try!(src.get_items());
+    dest.try_append_items(items.as_slice())
+}
+
+// Calls to the bus itself. Everything not listed here, e g BecomeMonitor or
+// UpdateActivationEnvironment, is denied.
+fn bus_call_allowed(p: &Policy, iface: &str, member: &str, arg0: &str) -> bool {
+    match (iface, member) {
+        ("org.freedesktop.DBus.Peer", "Ping") | ("org.freedesktop.DBus.Introspectable", "Introspect") => true,
+        ("org.freedesktop.DBus", "Hello") | ("org.freedesktop.DBus", "GetId") | ("org.freedesktop.DBus", "RemoveMatch") => true,
+        // Eavesdropping would give access to traffic for names outside the policy.
+        ("org.freedesktop.DBus", "AddMatch") => {
+            let r = arg0.replace(" ", "");
+            !r.contains("eavesdrop=true") && !r.contains("eavesdrop='true'")
+        }
+        ("org.freedesktop.DBus", "RequestName") | ("org.freedesktop.DBus", "ReleaseName") =>
+            p.level(arg0) >= PolicyLevel::Own,
+        ("org.freedesktop.DBus", "GetNameOwner") | ("org.freedesktop.DBus", "NameHasOwner") |
+        ("org.freedesktop.DBus", "StartServiceByName") | ("org.freedesktop.DBus", "GetConnectionUnixUser") |
+        ("org.freedesktop.DBus", "GetConnectionUnixProcessID") | ("org.freedesktop.DBus", "GetConnectionCredentials") =>
+            p.level(arg0) >= PolicyLevel::See,
+        _ => false,
+    }
+}
+
+impl<'a> Bridge<'a> {
+    pub fn new(from: &'a Connection, to: &'a Connection, destination: &str, timeout_ms: int) -> Bridge<'a> {
+        Bridge { from: from, to: to, destination: destination.to_string(), timeout_ms: timeout_ms,
+            policy: None, talk_owners: None }
+    }
+
+    /// Signals come from unique names, so the owners of the names the policy allows to
+    /// talk are looked up on `to`, and kept up to date while `to` is iterated.
+    pub fn set_policy(&mut self, policy: Option<Policy>) -> Result<(), Error> {
+        self.talk_owners = match policy {
+            Some(ref p) => Some(try!(SenderAllowList::new(self.to, p.talk_names().as_slice()))),
+            None => None,
+        };
+        self.policy = policy;
+        Ok(())
+    }
+
+    fn call_allowed(&self, m: &mut Message, dest: &str, path: &str, iface: &str, member: &str) -> bool {
+        let p = match self.policy { Some(ref p) => p, None => return true };
+        if dest != "org.freedesktop.DBus" { return p.allows_call(dest, path, iface) }
+
+        let arg0 = match m.get_items().map(|i| i.into_iter().next()) { Ok(Some(MessageItem::Str(s))) => s, _ => "".to_string() };
+        bus_call_allowed(p, iface, member, arg0.as_slice())
+    }
+
+    fn signal_allowed(&self, sender: &str) -> bool {
+        let p = match self.policy { Some(ref p) => p, None => return true };
+        p.level(sender) >= PolicyLevel::Talk || self.talk_owners.as_ref().map_or(false, |o| o.allows(sender))
+    }
+
+    /// Forwards a method call received on `from`, and relays the reply back to its sender.
+    /// Fails if `m` is not a method call.
+    pub fn forward_method_call(&self, m: &mut Message) -> Result<(), ()> {
+        let (t, path, iface, member) = m.headers();
+        if t != MessageType::MethodCall { return Err(()) }
+        let (path, iface, member) = match (path, iface, member) {
+            (Some(p), Some(i), Some(mb)) => (p, i, mb),
+            // Calls without an interface are allowed by the spec, but can't be forwarded
+            // unambiguously, so they are rejected.
+            _ => return self.from.send(Message::new_error(m, "org.freedesktop.DBus.Error.UnknownMethod",
+                "Method calls without interface cannot be forwarded").unwrap()),
+        };
+
+        let dest = if self.destination.len() > 0 { self.destination.clone() }
+            else { m.destination().unwrap_or("".to_string()) };
+        if !self.call_allowed(m, dest.as_slice(), path.as_slice(), iface.as_slice(), member.as_slice()) {
+            return self.from.send(Message::new_error(m, "org.freedesktop.DBus.Error.AccessDenied",
+                "Method call not allowed by policy").unwrap());
+        }
+
+        let mut call = Message::new_method_call(dest.as_slice(), path.as_slice(),
+            iface.as_slice(), member.as_slice()).unwrap();
+        if let Err(e) = copy_items(m, &mut call) {
+            return self.from.send(Message::new_error(m, "org.freedesktop.DBus.Error.InvalidArgs",
+                e.message().unwrap_or("")).unwrap());
+        }
+
+        if m.get_no_reply() { return self.to.send(call); }
+
+        let reply = self.to.send_with_reply_and_block(call, self.timeout_ms).and_then(|mut r| {
+            let mut z = Message::new_method_return(m).unwrap();
+            try!(copy_items(&mut r, &mut z));
+            Ok(z)
+        });
+        let reply = match reply {
+            Ok(z) => z,
+            Err(e) => Message::new_error(m, e.name().unwrap_or("org.freedesktop.DBus.Error.Failed"),
+                e.message().unwrap_or("")).unwrap(),
+        };
+        self.from.send(reply)
+    }
+
+    /// Emits a signal received on `to` again on `from`. Fails if `m` is not a signal.
+    pub fn forward_signal(&self, m: &mut Message) -> Result<(), ()> {
+        let (t, path, iface, member) = m.headers();
+        let (path, iface, member) = match (t, path, iface, member) {
+            (MessageType::Signal, Some(p), Some(i), Some(mb)) => (p, i, mb),
+            _ => return Err(()),
+        };
+        if !self.signal_allowed(m.sender().unwrap_or("".to_string()).as_slice()) { return Ok(()) };
+        let mut s = try!(Message::new_signal(path.as_slice(), iface.as_slice(), member.as_slice()).map_err(|_| ()));
+        // Signals with arguments that cannot be read are dropped, like the ones not allowed.
+        if copy_items(m, &mut s).is_err() { return Ok(()) };
+        self.from.send(s)
+    }
+}
+
+#[test]
+fn policy_levels() {
+    let mut p = Policy::new();
+    p.allow("org.example.*", PolicyLevel::See)
+     .allow("org.example.Foo", PolicyLevel::Own)
+     .allow_call("org.example.Bar", Some("/org/example/Bar/*"), Some("org.example.Bar"));
+    assert_eq!(p.level("org.example.Foo"), PolicyLevel::Own);
+    assert_eq!(p.level("org.example.Bar"), PolicyLevel::See);
+    assert_eq!(p.level("org.other"), PolicyLevel::None);
+    assert!(p.allows_call("org.example.Foo", "/", "org.example.Anything"));
+    assert!(p.allows_call("org.example.Bar", "/org/example/Bar/1", "org.example.Bar"));
+    assert!(!p.allows_call("org.example.Bar", "/org/example/Barn", "org.example.Bar"));
+    assert!(!p.allows_call("org.example.Bar", "/org/example/Bar", "org.example.Other"));
+}
+
+#[test]
+fn bus_calls() {
+    let mut p = Policy::new();
+    p.allow("org.example.Foo", PolicyLevel::Own).allow("org.example.Bar", PolicyLevel::See);
+    assert!(bus_call_allowed(&p, "org.freedesktop.DBus", "Hello", ""));
+    assert!(bus_call_allowed(&p, "org.freedesktop.DBus", "RequestName", "org.example.Foo"));
+    assert!(!bus_call_allowed(&p, "org.freedesktop.DBus", "RequestName", "org.example.Bar"));
+    assert!(bus_call_allowed(&p, "org.freedesktop.DBus", "GetNameOwner", "org.example.Bar"));
+    assert!(bus_call_allowed(&p, "org.freedesktop.DBus", "AddMatch", "type='signal'"));
+    assert!(!bus_call_allowed(&p, "org.freedesktop.DBus", "AddMatch", "type='signal',eavesdrop='true'"));
+    assert!(!bus_call_allowed(&p, "org.freedesktop.DBus.Monitoring", "BecomeMonitor", ""));
+    assert!(!bus_call_allowed(&p, "org.freedesktop.DBus", "UpdateActivationEnvironment", ""));
+}
+
+#[test]
+fn bridge_wrong_type() {
+    let c = Connection::get_private(super::BusType::Session).unwrap();
+    let b = Bridge::new(&c, &c, "", 1000);
+    let mut s = Message::new_signal("/", "com.example.Foo", "Changed").unwrap();
+    assert!(b.forward_method_call(&mut s).is_err());
+    let mut m = Message::new_method_call("com.example.Foo", "/", "com.example.Foo", "Bar").unwrap();
+    assert!(b.forward_signal(&mut m).is_err());
+}
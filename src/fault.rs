@@ -0,0 +1,65 @@
+use super::Message;
+
+/// A fault to inject into method calls made with `Connection::send_with_reply_and_block`.
+#[deriving(Show, Clone, PartialEq)]
+pub enum Fault {
+    /// The reply arrives this many milliseconds late. If that is longer than the
+    /// call's timeout, the call fails with NoReply once the timeout has passed.
+    Delay(uint),
+    /// The call fails with NoReply right away, as if it had timed out.
+    NoReply,
+    /// The call is not sent; an error with this name and message is returned instead.
+    Error(String, String),
+    /// The connection is closed before the call is sent.
+    Disconnect,
+}
+
+struct FaultRule {
+    interface: Option<String>,
+    member: Option<String>,
+    fault: Fault,
+    remaining: Option<uint>,
+}
+
+/// A list of faults to inject, used for testing how an application copes with
+/// slow, failing or disappearing services. See `Connection::inject_fault`.
+pub struct FaultPlan {
+    rules: Vec<FaultRule>,
+}
+
+impl FaultPlan {
+    pub fn new() -> FaultPlan { FaultPlan { rules: vec!() } }
+
+    /// Adds a fault for calls to the given interface and member (None matches any).
+    /// The fault applies to the next `times` matching calls, or to all of them if None.
+    pub fn add(&mut self, iface: Option<&str>, member: Option<&str>, fault: Fault, times: Option<uint>) {
+        self.rules.push(FaultRule { interface: iface.map(|s| s.to_string()), member: member.map(|s| s.to_string()),
+            fault: fault, remaining: times });
+    }
+
+    pub fn clear(&mut self) { self.rules.clear(); }
+
+    /// Finds the first rule matching the call, and uses it up once.
+    pub fn take(&mut self, m: &Message) -> Option<Fault> {
+        let (i, mb) = (m.interface(), m.member());
+        let r = self.rules.iter_mut().find(|r| r.remaining != Some(0) &&
+            r.interface.as_ref().map_or(true, |x| Some(x.as_slice()) == i) &&
+            r.member.as_ref().map_or(true, |x| Some(x.as_slice()) == mb));
+        r.map(|r| {
+            if let Some(ref mut n) = r.remaining { *n -= 1; }
+            r.fault.clone()
+        })
+    }
+}
+
+#[test]
+fn fault_plan() {
+    let mut p = FaultPlan::new();
+    p.add(Some("com.example.Foo"), None, Fault::NoReply, Some(1));
+    p.add(None, Some("Bar"), Fault::Delay(10), None);
+    let m = Message::new_method_call("com.example", "/", "com.example.Foo", "Bar").unwrap();
+    assert_eq!(p.take(&m), Some(Fault::NoReply));
+    assert_eq!(p.take(&m), Some(Fault::Delay(10)));
+    let m = Message::new_method_call("com.example", "/", "com.example.Foo", "Baz").unwrap();
+    assert_eq!(p.take(&m), None);
+}
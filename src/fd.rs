@@ -0,0 +1,83 @@
+//! Owned unix file descriptors, for passing them in messages (the "h" type).
+
+use std::fmt;
+use std::cmp::Ordering;
+use libc;
+
+extern "C" {
+    fn dup(fd: libc::c_int) -> libc::c_int;
+    fn close(fd: libc::c_int) -> libc::c_int;
+}
+
+/// A unix file descriptor that is closed when dropped.
+///
+/// Appending one to a message sends a duplicate of it, so it stays open. Every time one is
+/// read from a message, libdbus hands out a new duplicate, which is then owned by the
+/// returned `OwnedFd`. Cloning duplicates the file descriptor too.
+pub struct OwnedFd {
+    fd: libc::c_int,
+}
+
+impl OwnedFd {
+    /// Takes ownership of `fd`, which must be open and not owned by anything else.
+    pub unsafe fn new(fd: libc::c_int) -> OwnedFd { OwnedFd { fd: fd } }
+
+    /// Releases ownership; the caller is then responsible for closing the fd.
+    pub fn into_fd(self) -> libc::c_int {
+        let fd = self.fd;
+        unsafe { ::std::mem::forget(self) };
+        fd
+    }
+
+    pub fn as_raw_fd(&self) -> libc::c_int { self.fd }
+}
+
+impl Drop for OwnedFd {
+    fn drop(&mut self) {
+        unsafe { close(self.fd) };
+    }
+}
+
+impl Clone for OwnedFd {
+    fn clone(&self) -> OwnedFd {
+        let fd = unsafe { dup(self.fd) };
+        if fd < 0 { panic!("Failed to duplicate file descriptor {}", self.fd) }
+        OwnedFd { fd: fd }
+    }
+}
+
+// Descriptors are compared by number, which is only meaningful within one process.
+impl PartialEq for OwnedFd {
+    fn eq(&self, other: &OwnedFd) -> bool { self.fd == other.fd }
+}
+
+impl PartialOrd for OwnedFd {
+    fn partial_cmp(&self, other: &OwnedFd) -> Option<Ordering> { self.fd.partial_cmp(&other.fd) }
+}
+
+impl fmt::Show for OwnedFd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "OwnedFd({})", self.fd) }
+}
+
+#[test]
+fn owned_fd() {
+    let a = unsafe { OwnedFd::new(dup(0)) };
+    let b = a.clone();
+    assert!(a.as_raw_fd() >= 0);
+    assert!(a != b);
+    let fd = b.into_fd();
+    assert_eq!(unsafe { close(fd) }, 0);
+}
+
+#[test]
+fn message_fds() {
+    let a = unsafe { OwnedFd::new(dup(0)) };
+    let mut m = super::Message::new_signal("/", "com.example.Foo", "Bar").unwrap();
+    m.append_items(&[super::MessageItem::UnixFd(a.clone())]);
+    m.append1(a.clone()).unwrap();
+    assert_eq!(m.unix_fd_count(), 2);
+    // Reading gives a new descriptor each time
+    let b: OwnedFd = m.read1().unwrap();
+    assert!(b != a);
+    assert!(m.read1::<OwnedFd>().unwrap() != b);
+}
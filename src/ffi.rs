@@ -1,9 +1,11 @@
 #![allow(dead_code)]
 
-use libc::{c_void, c_char, c_uint, c_int};
+use libc::{c_void, c_char, c_uint, c_int, c_long, c_ulong, c_short, time_t};
 
 pub type DBusConnection = c_void;
 pub type DBusMessage = c_void;
+pub type DBusServer = c_void;
+pub type DBusWatch = c_void;
 pub type DBusCallback = extern "C" fn(*mut c_void, *mut c_void, *mut c_void) -> DBusHandlerResult;
 
 #[repr(C)]
@@ -19,7 +21,9 @@ pub const DBUS_TYPE_VARIANT: c_int = 'v' as c_int;
 pub const DBUS_TYPE_BOOLEAN: c_int = 'b' as c_int;
 pub const DBUS_TYPE_INVALID: c_int = 0;
 pub const DBUS_TYPE_STRING: c_int = 's' as c_int;
+pub const DBUS_TYPE_OBJECT_PATH: c_int = 'o' as c_int;
 pub const DBUS_TYPE_DICT_ENTRY: c_int = 'e' as c_int;
+pub const DBUS_TYPE_STRUCT: c_int = 'r' as c_int;
 pub const DBUS_TYPE_BYTE: c_int = 'y' as c_int;
 pub const DBUS_TYPE_INT16: c_int = 'n' as c_int;
 pub const DBUS_TYPE_UINT16: c_int = 'q' as c_int;
@@ -27,6 +31,9 @@ pub const DBUS_TYPE_INT32: c_int = 'i' as c_int;
 pub const DBUS_TYPE_UINT32: c_int = 'u' as c_int;
 pub const DBUS_TYPE_INT64: c_int = 'x' as c_int;
 pub const DBUS_TYPE_UINT64: c_int = 't' as c_int;
+pub const DBUS_TYPE_UNIX_FD: c_int = 'h' as c_int;
+pub const DBUS_TYPE_DOUBLE: c_int = 'd' as c_int;
+pub const DBUS_TYPE_SIGNATURE: c_int = 'g' as c_int;
 
 #[repr(C)]
 #[deriving(Show, PartialEq, Copy)]
@@ -62,7 +69,7 @@ pub enum DBusHandlerResult {
 }
 
 #[repr(C)]
-#[deriving(Show, PartialEq, Copy)]
+#[deriving(Show, PartialEq, Copy, Clone)]
 pub enum DBusMessageType {
     Invalid = 0i,
     MethodCall = 1i,
@@ -108,6 +115,17 @@ pub struct DBusMessageIter {
 pub type DBusHandleMessageFunction = Option<
         extern fn(conn: *mut DBusConnection, msg: *mut DBusMessage, user_data: *mut c_void) -> DBusHandlerResult>;
 
+pub type DBusNewConnectionFunction = Option<
+        extern fn(server: *mut DBusServer, conn: *mut DBusConnection, user_data: *mut c_void)>;
+pub type DBusAddWatchFunction = Option<extern fn(watch: *mut DBusWatch, user_data: *mut c_void) -> u32>;
+pub type DBusWatchToggledFunction = Option<extern fn(watch: *mut DBusWatch, user_data: *mut c_void)>;
+pub type DBusRemoveWatchFunction = Option<extern fn(watch: *mut DBusWatch, user_data: *mut c_void)>;
+
+pub const DBUS_WATCH_READABLE: c_uint = 1;
+pub const DBUS_WATCH_WRITABLE: c_uint = 2;
+pub const DBUS_WATCH_ERROR: c_uint = 4;
+pub const DBUS_WATCH_HANGUP: c_uint = 8;
+
 #[repr(C)]
 pub struct DBusObjectPathVTable {
     pub unregister_function: Option<extern fn(conn: *mut DBusConnection, user_data: *mut c_void)>,
@@ -120,7 +138,9 @@ pub struct DBusObjectPathVTable {
 
 #[link(name = "dbus-1")]
 extern "C" {
+    pub fn dbus_bus_get(t: DBusBusType, error: *mut DBusError) -> *mut DBusConnection;
     pub fn dbus_bus_get_private(t: DBusBusType, error: *mut DBusError) -> *mut DBusConnection;
+    pub fn dbus_bus_register(conn: *mut DBusConnection, error: *mut DBusError) -> u32;
     pub fn dbus_bus_get_unique_name(conn: *mut DBusConnection) -> *const c_char;
     pub fn dbus_bus_request_name(conn: *mut DBusConnection, name: *const c_char,
         flags: c_uint, error: *mut DBusError) -> c_int;
@@ -131,20 +151,44 @@ extern "C" {
     pub fn dbus_bus_remove_match(conn: *mut DBusConnection, rule: *const c_char,
         error: *mut DBusError);
 
+    pub fn dbus_connection_open_private(address: *const c_char, error: *mut DBusError) -> *mut DBusConnection;
     pub fn dbus_connection_close(conn: *mut DBusConnection);
     pub fn dbus_connection_dispatch(conn: *mut DBusConnection) -> DBusDispatchStatus;
     pub fn dbus_connection_flush(conn: *mut DBusConnection);
+    pub fn dbus_connection_get_is_connected(conn: *mut DBusConnection) -> u32;
+    pub fn dbus_connection_get_is_authenticated(conn: *mut DBusConnection) -> u32;
+    pub fn dbus_connection_get_is_anonymous(conn: *mut DBusConnection) -> u32;
+    pub fn dbus_connection_get_server_id(conn: *mut DBusConnection) -> *mut c_char;
+    pub fn dbus_connection_get_unix_user(conn: *mut DBusConnection, uid: *mut c_ulong) -> u32;
+    pub fn dbus_connection_get_unix_process_id(conn: *mut DBusConnection, pid: *mut c_ulong) -> u32;
+    pub fn dbus_connection_ref(conn: *mut DBusConnection) -> *mut DBusConnection;
     pub fn dbus_connection_unref(conn: *mut DBusConnection);
     pub fn dbus_connection_set_exit_on_disconnect(conn: *mut DBusConnection, enable: u32);
+    pub fn dbus_connection_set_max_message_size(conn: *mut DBusConnection, size: c_long);
+    pub fn dbus_connection_get_max_message_size(conn: *mut DBusConnection) -> c_long;
+    pub fn dbus_connection_set_max_received_size(conn: *mut DBusConnection, size: c_long);
+    pub fn dbus_connection_get_max_received_size(conn: *mut DBusConnection) -> c_long;
+    pub fn dbus_connection_set_max_message_unix_fds(conn: *mut DBusConnection, n: c_long);
+    pub fn dbus_connection_get_max_message_unix_fds(conn: *mut DBusConnection) -> c_long;
+    pub fn dbus_connection_set_max_received_unix_fds(conn: *mut DBusConnection, n: c_long);
+    pub fn dbus_connection_get_max_received_unix_fds(conn: *mut DBusConnection) -> c_long;
     pub fn dbus_connection_send_with_reply_and_block(conn: *mut DBusConnection,
         message: *mut DBusMessage, timeout_milliseconds: c_int, error: *mut DBusError) -> *mut DBusMessage;
     pub fn dbus_connection_send(conn: *mut DBusConnection,
         message: *mut DBusMessage, serial: *mut u32) -> u32;
     pub fn dbus_connection_read_write_dispatch(conn: *mut DBusConnection,
         timeout_milliseconds: c_int) -> u32;
+    pub fn dbus_connection_read_write(conn: *mut DBusConnection, timeout_milliseconds: c_int) -> u32;
+    pub fn dbus_connection_get_dispatch_status(conn: *mut DBusConnection) -> DBusDispatchStatus;
+    pub fn dbus_connection_can_send_type(connection: *mut DBusConnection, t: c_int) -> u32;
+    pub fn dbus_connection_pop_message(connection: *mut DBusConnection) -> *mut DBusMessage;
+    pub fn dbus_connection_get_unix_fd(conn: *mut DBusConnection, fd: *mut c_int) -> u32;
     pub fn dbus_connection_try_register_object_path(conn: *mut DBusConnection,
         path: *const c_char, vtable: *const DBusObjectPathVTable, user_data: *mut c_void,
         error: *mut DBusError) -> u32;
+    pub fn dbus_connection_try_register_fallback(conn: *mut DBusConnection,
+        path: *const c_char, vtable: *const DBusObjectPathVTable, user_data: *mut c_void,
+        error: *mut DBusError) -> u32;
     pub fn dbus_connection_unregister_object_path(conn: *mut DBusConnection,
         path: *const c_char) -> u32;
     pub fn dbus_connection_add_filter(conn: *mut DBusConnection, function: DBusHandleMessageFunction,
@@ -166,6 +210,7 @@ extern "C" {
         iface: *const c_char, name: *const c_char) -> *mut DBusMessage;
     pub fn dbus_message_ref(message: *mut DBusMessage) -> *mut DBusMessage;
     pub fn dbus_message_unref(message: *mut DBusMessage);
+    pub fn dbus_message_copy(message: *const DBusMessage) -> *mut DBusMessage;
     pub fn dbus_message_get_type(message: *mut DBusMessage) -> c_int;
     pub fn dbus_message_is_method_call(message: *mut DBusMessage, iface: *const c_char, method: *const c_char) -> u32;
     pub fn dbus_message_is_signal(message: *mut DBusMessage, iface: *const c_char, signal_name: *const c_char) -> u32;
@@ -173,17 +218,83 @@ extern "C" {
     pub fn dbus_message_get_interface(message: *mut DBusMessage) -> *const c_char;
     pub fn dbus_message_get_member(message: *mut DBusMessage) -> *const c_char;
     pub fn dbus_message_get_sender(message: *mut DBusMessage) -> *const c_char;
+    pub fn dbus_message_get_destination(message: *mut DBusMessage) -> *const c_char;
+    pub fn dbus_message_get_no_reply(message: *mut DBusMessage) -> u32;
+    pub fn dbus_message_set_no_reply(message: *mut DBusMessage, no_reply: u32);
+    pub fn dbus_message_get_auto_start(message: *mut DBusMessage) -> u32;
+    pub fn dbus_message_set_auto_start(message: *mut DBusMessage, auto_start: u32);
+    pub fn dbus_message_get_serial(message: *mut DBusMessage) -> u32;
+    pub fn dbus_message_set_serial(message: *mut DBusMessage, serial: u32);
+    pub fn dbus_message_marshal(msg: *mut DBusMessage, marshalled_data_p: *mut *mut c_char, len_p: *mut c_int) -> u32;
+    pub fn dbus_message_demarshal(s: *const c_char, len: c_int, error: *mut DBusError) -> *mut DBusMessage;
+    pub fn dbus_message_get_reply_serial(message: *mut DBusMessage) -> u32;
+    pub fn dbus_message_get_error_name(message: *mut DBusMessage) -> *const c_char;
+    pub fn dbus_message_contains_unix_fds(message: *mut DBusMessage) -> u32;
+    pub fn dbus_message_get_signature(message: *mut DBusMessage) -> *const c_char;
 
     pub fn dbus_message_iter_append_basic(iter: *mut DBusMessageIter, t: c_int, value: *const c_void) -> u32;
+    pub fn dbus_message_iter_append_fixed_array(iter: *mut DBusMessageIter, element_type: c_int, value: *const c_void, n_elements: c_int) -> u32;
     pub fn dbus_message_iter_init(message: *mut DBusMessage, iter: *mut DBusMessageIter) -> u32;
     pub fn dbus_message_iter_init_append(message: *mut DBusMessage, iter: *mut DBusMessageIter);
     pub fn dbus_message_iter_get_arg_type(iter: *mut DBusMessageIter) -> c_int;
     pub fn dbus_message_iter_get_basic(iter: *mut DBusMessageIter, value: *mut c_void);
     pub fn dbus_message_iter_next(iter: *mut DBusMessageIter) -> u32;
+    pub fn dbus_message_iter_get_signature(iter: *mut DBusMessageIter) -> *mut c_char;
+    pub fn dbus_message_iter_get_element_type(iter: *mut DBusMessageIter) -> c_int;
+    pub fn dbus_message_iter_get_fixed_array(iter: *mut DBusMessageIter, value: *mut c_void, n_elements: *mut c_int);
     pub fn dbus_message_iter_recurse(iter: *mut DBusMessageIter, subiter: *mut DBusMessageIter);
     pub fn dbus_message_iter_open_container(iter: *mut DBusMessageIter, _type: c_int,
         contained_signature: *const c_char, sub: *mut DBusMessageIter) -> u32;
     pub fn dbus_message_iter_close_container(iter: *mut DBusMessageIter, sub: *mut DBusMessageIter) -> u32;
+    pub fn dbus_message_iter_abandon_container(iter: *mut DBusMessageIter, sub: *mut DBusMessageIter);
 
+    pub fn dbus_server_listen(address: *const c_char, error: *mut DBusError) -> *mut DBusServer;
+    pub fn dbus_server_disconnect(server: *mut DBusServer);
+    pub fn dbus_server_unref(server: *mut DBusServer);
+    pub fn dbus_server_get_is_connected(server: *mut DBusServer) -> u32;
+    pub fn dbus_server_get_address(server: *mut DBusServer) -> *mut c_char;
+    pub fn dbus_server_get_id(server: *mut DBusServer) -> *mut c_char;
+    pub fn dbus_server_set_new_connection_function(server: *mut DBusServer, function: DBusNewConnectionFunction,
+        data: *mut c_void, free_data_function: Option<extern fn(memory: *mut c_void)>);
+    pub fn dbus_server_set_watch_functions(server: *mut DBusServer, add_function: DBusAddWatchFunction,
+        remove_function: DBusRemoveWatchFunction, toggled_function: DBusWatchToggledFunction,
+        data: *mut c_void, free_data_function: Option<extern fn(memory: *mut c_void)>) -> u32;
+
+    pub fn dbus_watch_get_unix_fd(watch: *mut DBusWatch) -> c_int;
+    pub fn dbus_watch_get_flags(watch: *mut DBusWatch) -> c_uint;
+    pub fn dbus_watch_get_enabled(watch: *mut DBusWatch) -> u32;
+    pub fn dbus_watch_handle(watch: *mut DBusWatch, flags: c_uint) -> u32;
+
+    pub fn dbus_signature_validate(signature: *const c_char, error: *mut DBusError) -> u32;
+
+    pub fn dbus_free(memory: *mut c_void);
+    pub fn dbus_get_local_machine_id() -> *mut c_char;
     pub fn dbus_threads_init_default() -> c_int;
 }
+
+// From libc, for waiting on connections without running a main loop.
+
+#[repr(C)]
+pub struct PollFd {
+    pub fd: c_int,
+    pub events: c_short,
+    pub revents: c_short,
+}
+
+pub const POLLIN: c_short = 1;
+pub const POLLOUT: c_short = 4;
+pub const POLLERR: c_short = 8;
+pub const POLLHUP: c_short = 16;
+
+#[repr(C)]
+pub struct Timespec {
+    pub tv_sec: time_t,
+    pub tv_nsec: c_long,
+}
+
+pub const CLOCK_MONOTONIC: c_int = 1;
+
+extern "C" {
+    pub fn poll(fds: *mut PollFd, nfds: c_ulong, timeout: c_int) -> c_int;
+    pub fn clock_gettime(clock: c_int, t: *mut Timespec) -> c_int;
+}
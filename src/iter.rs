@@ -0,0 +1,872 @@
+//! Reading and appending arguments directly as Rust types, without building
+//! `MessageItem`s first.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let mut m = Message::new_method_call("org.freedesktop.DBus", "/", "org.freedesktop.DBus", "RequestName").unwrap();
+//! m.append2("com.example.Foo", 4u32).unwrap();
+//! let mut r = c.send_with_reply_and_block(m, 2000).unwrap();
+//! let reply: u32 = r.read1().unwrap();
+//! ```
+
+use super::{ffi, Error, Message, MessageItem, OwnedFd, Path, ParseLimits, ReadState, Utf8Policy};
+use super::arg::TypeMismatch;
+use super::newdbus;
+use std::any::{Any, AnyRefExt};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt::Show;
+use std::hash::Hash;
+use std::io;
+use std::io::{BufReader, IoError, IoResult};
+use std::num::ToPrimitive;
+use std::kinds::marker::ContravariantLifetime;
+use std::rc::Rc;
+use std::{ptr, mem};
+use libc;
+
+/// Reads the arguments of a message, one at a time.
+pub struct Iter<'a> {
+    i: ffi::DBusMessageIter,
+    limits: ParseLimits,
+    // Bytes read so far through this iterator and the ones recursed from it
+    allocated: Rc<Cell<uint>>,
+    marker: ContravariantLifetime<'a>,
+}
+
+/// Appends arguments to a message, one at a time.
+pub struct IterAppend<'a> {
+    i: ffi::DBusMessageIter,
+    marker: ContravariantLifetime<'a>,
+}
+
+/// Writes into an "ay" being appended, see `IterAppend::append_byte_stream`.
+pub struct ByteWriter<'b> {
+    i: &'b mut ffi::DBusMessageIter,
+}
+
+impl<'b> Writer for ByteWriter<'b> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        if buf.len() == 0 { return Ok(()) }
+        let p = buf.as_ptr();
+        let r = unsafe { ffi::dbus_message_iter_append_fixed_array(self.i, ffi::DBUS_TYPE_BYTE, mem::transmute(&p), buf.len() as libc::c_int) };
+        if r != 0 { return Ok(()) }
+        Err(IoError { kind: io::OtherIoError, desc: "Out of memory", detail: None })
+    }
+}
+
+/// A Rust type that can be appended to a message as a single complete type.
+pub trait Append {
+    /// The D-Bus signature of this type, e g "u" or "as".
+    fn signature() -> String;
+    fn append(self, i: &mut IterAppend) -> Result<(), Error>;
+}
+
+/// A Rust type that can be read from a message as a single complete type.
+pub trait Get {
+    /// Reads the argument the iterator points to, without advancing the iterator.
+    /// Returns None if the argument is not of this type, or if reading it would exceed
+    /// the iterator's `ParseLimits`.
+    fn get(i: &mut Iter) -> Option<Self>;
+    /// The signature, for error messages.
+    fn signature() -> String;
+}
+
+/// A type whose arrays libdbus stores as plain C arrays, so they can be borrowed
+/// directly from the message. Booleans are not included, since D-Bus stores them as 32 bits.
+pub trait FixedArray: Copy {
+    /// The type code of the elements, e g `ffi::DBUS_TYPE_BYTE`.
+    fn type_code() -> libc::c_int;
+}
+
+macro_rules! fixed_array {
+    ($t:ty, $code:expr) => {
+        impl FixedArray for $t { fn type_code() -> libc::c_int { $code } }
+    }
+}
+
+fixed_array!(u8, ffi::DBUS_TYPE_BYTE);
+fixed_array!(i16, ffi::DBUS_TYPE_INT16);
+fixed_array!(u16, ffi::DBUS_TYPE_UINT16);
+fixed_array!(i32, ffi::DBUS_TYPE_INT32);
+fixed_array!(u32, ffi::DBUS_TYPE_UINT32);
+fixed_array!(i64, ffi::DBUS_TYPE_INT64);
+fixed_array!(u64, ffi::DBUS_TYPE_UINT64);
+fixed_array!(f64, ffi::DBUS_TYPE_DOUBLE);
+
+/// Wraps a value in a variant, e g `Variant(5u32)` is appended as a "v" containing a "u".
+#[deriving(Clone, Show, PartialEq)]
+pub struct Variant<T>(pub T);
+
+unsafe fn init_iter<'a>(msg: *mut ffi::DBusMessage, limits: ParseLimits) -> Iter<'a> {
+    let mut i = super::new_dbus_message_iter();
+    // This returns false for messages without arguments, but the iterator is still
+    // usable, and points at DBUS_TYPE_INVALID.
+    ffi::dbus_message_iter_init(msg, &mut i);
+    Iter { i: i, limits: limits, allocated: Rc::new(Cell::new(0)), marker: ContravariantLifetime }
+}
+
+unsafe fn init_append<'a>(msg: *mut ffi::DBusMessage) -> IterAppend<'a> {
+    let mut i = super::new_dbus_message_iter();
+    ffi::dbus_message_iter_init_append(msg, &mut i);
+    IterAppend { i: i, marker: ContravariantLifetime }
+}
+
+impl<'a> Iter<'a> {
+    pub fn new(m: &'a Message) -> Iter<'a> { unsafe { init_iter(m.msg, m.limits) } }
+
+    /// Like `new`, but for the message types in `newdbus`.
+    pub fn from_message<M: newdbus::Message>(m: &'a M) -> Iter<'a> {
+        unsafe { init_iter(m.msg_ptr(), ::std::default::Default::default()) }
+    }
+
+    /// The type code of the current argument, or 0 after the last one.
+    pub fn arg_type(&mut self) -> libc::c_int {
+        unsafe { ffi::dbus_message_iter_get_arg_type(&mut self.i) }
+    }
+
+    /// The signature of the current argument, or an empty string after the last one.
+    pub fn signature(&mut self) -> String {
+        if self.arg_type() == ffi::DBUS_TYPE_INVALID { return "".to_string() }
+        super::iter_signature(&mut self.i).unwrap_or("".to_string())
+    }
+
+    /// Moves to the next argument. Returns false if there is none.
+    pub fn advance(&mut self) -> bool {
+        if self.arg_type() == ffi::DBUS_TYPE_INVALID { return false }
+        unsafe { ffi::dbus_message_iter_next(&mut self.i) != 0 }
+    }
+
+    /// Reads the current argument without advancing.
+    pub fn get<T: Get>(&mut self) -> Option<T> { Get::get(self) }
+
+    /// Reads the current argument and moves to the next one.
+    pub fn read<T: Get>(&mut self) -> Result<T, TypeMismatch> {
+        match Get::get(self) {
+            Some(v) => { self.advance(); Ok(v) }
+            None => Err(TypeMismatch { expected: <T as Get>::signature(), found: self.signature() }),
+        }
+    }
+
+    /// Borrows the current argument if it is an array of `T`, e g an "ay" as a `&[u8]`.
+    ///
+    /// Unlike reading a `Vec<u8>`, this does not copy anything, which matters for large
+    /// blobs such as image data. Does not advance.
+    pub fn get_fixed_array<T: FixedArray>(&mut self) -> Option<&'a [T]> {
+        if self.arg_type() != ffi::DBUS_TYPE_ARRAY { return None }
+        if unsafe { ffi::dbus_message_iter_get_element_type(&mut self.i) } != <T as FixedArray>::type_code() { return None }
+        let mut sub = match self.recurse(ffi::DBUS_TYPE_ARRAY) { Some(s) => s, None => return None };
+        let mut p: *const T = ptr::null();
+        let mut n: libc::c_int = 0;
+        unsafe {
+            ffi::dbus_message_iter_get_fixed_array(&mut sub.i, mem::transmute(&mut p), &mut n);
+            if n <= 0 || p.is_null() {
+                let e: &'a [T] = &[];
+                return Some(e)
+            }
+            // The data lives in the message, which outlives 'a.
+            Some(mem::transmute(::std::raw::Slice { data: p, len: n as uint }))
+        }
+    }
+
+    /// An iterator over the members of the current argument, if it is a struct.
+    pub fn recurse_struct(&mut self) -> Option<Iter<'a>> { self.recurse(ffi::DBUS_TYPE_STRUCT) }
+
+    /// A reader over the current argument if it is an "ay", borrowing the bytes from
+    /// the message rather than copying them. Does not advance.
+    pub fn get_byte_reader(&mut self) -> Option<BufReader<'a>> {
+        self.get_fixed_array::<u8>().map(|b| BufReader::new(b))
+    }
+
+    /// An iterator over the contents of the current argument, if it is a container of
+    /// type `t`: an array (including dictionaries), a dictionary entry, a struct or a variant.
+    pub fn recurse(&mut self, t: libc::c_int) -> Option<Iter<'a>> {
+        match t {
+            ffi::DBUS_TYPE_ARRAY | ffi::DBUS_TYPE_DICT_ENTRY | ffi::DBUS_TYPE_STRUCT | ffi::DBUS_TYPE_VARIANT => {},
+            _ => return None,
+        }
+        if self.arg_type() != t { return None }
+        let mut sub = super::new_dbus_message_iter();
+        unsafe { ffi::dbus_message_iter_recurse(&mut self.i, &mut sub) };
+        Some(Iter { i: sub, limits: self.limits, allocated: self.allocated.clone(), marker: ContravariantLifetime })
+    }
+
+    /* Accounts for reading `n` more bytes; false if that exceeds the `max_allocation` limit. */
+    fn alloc(&mut self, n: uint) -> bool {
+        let a = self.allocated.get() + n;
+        self.allocated.set(a);
+        a <= self.limits.max_allocation
+    }
+
+    /* Reads a string argument of type `t`, within the `max_string_len` limit. */
+    fn get_str(&mut self, t: libc::c_int) -> Option<String> {
+        if self.arg_type() != t { return None }
+        let mut c: *const libc::c_char = ptr::null();
+        unsafe { ffi::dbus_message_iter_get_basic(&mut self.i, mem::transmute(&mut c)) };
+        let s = match super::c_str_to_slice(&c) { Some(s) => s, None => return None };
+        if s.len() > self.limits.max_string_len || !self.alloc(s.len()) { return None }
+        Some(s.to_string())
+    }
+
+    fn get_basic(&mut self, t: libc::c_int) -> Option<i64> {
+        if self.arg_type() != t { return None }
+        Some(super::iter_get_basic(&mut self.i))
+    }
+}
+
+/// Reads all remaining arguments as `MessageItem`s, without collecting them first.
+impl<'a> Iterator<MessageItem> for Iter<'a> {
+    fn next(&mut self) -> Option<MessageItem> {
+        let r = self.get::<MessageItem>();
+        if r.is_some() { self.advance(); }
+        r
+    }
+}
+
+impl<'a> IterAppend<'a> {
+    pub fn new(m: &'a mut Message) -> IterAppend<'a> { unsafe { init_append(m.msg) } }
+
+    /// Like `new`, but for the message types in `newdbus`.
+    pub fn from_message<M: newdbus::Message>(m: &'a mut M) -> IterAppend<'a> { unsafe { init_append(m.msg_ptr()) } }
+
+    pub fn append<T: Append>(&mut self, v: T) -> Result<(), Error> { v.append(self) }
+
+    /// Appends an array of `T` in one go, e g an "ay" from a `&[u8]`, without going
+    /// through the elements one by one.
+    pub fn append_fixed_array<T: FixedArray>(&mut self, a: &[T]) -> Result<(), Error> {
+        let t = <T as FixedArray>::type_code();
+        let mut sig = String::new();
+        sig.push(t as u8 as char);
+        super::iter_append_container(&mut self.i, ffi::DBUS_TYPE_ARRAY, Some(sig.as_slice()), |sub| {
+            let p = a.as_ptr();
+            let r = unsafe { ffi::dbus_message_iter_append_fixed_array(sub, t, mem::transmute(&p), a.len() as libc::c_int) };
+            if r == 0 { Err(super::no_memory()) } else { Ok(()) }
+        })
+    }
+
+    /// Appends an "ay".
+    pub fn append_bytes(&mut self, a: &[u8]) -> Result<(), Error> { self.append_fixed_array(a) }
+
+    /// Appends an "ay" whose contents are written by `f`, a chunk at a time. If `f` fails,
+    /// the message is left unusable and must be discarded.
+    pub fn append_byte_stream<F>(&mut self, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut ByteWriter) -> IoResult<()>
+    {
+        super::iter_append_container(&mut self.i, ffi::DBUS_TYPE_ARRAY, Some("y"), |sub| {
+            f(&mut ByteWriter { i: sub }).map_err(|e| {
+                let f = format!("{}", e);
+                Error::new_custom("org.freedesktop.DBus.Error.IOError", f.as_slice())
+            })
+        })
+    }
+
+    /// Appends an "ay" with everything `r` reads until the end of the file. If reading
+    /// fails, the message must be discarded, like for `append_byte_stream`.
+    pub fn append_from_reader<R: Reader>(&mut self, r: &mut R) -> Result<(), Error> {
+        self.append_byte_stream(|w| {
+            let mut buf = Vec::from_elem(64 * 1024, 0u8);
+            loop {
+                match r.read(buf.as_mut_slice()) {
+                    Ok(n) => try!(w.write(buf.slice_to(n))),
+                    Err(ref e) if e.kind == io::EndOfFile => return Ok(()),
+                    Err(e) => return Err(e),
+                }
+            }
+        })
+    }
+
+    /// Appends an array with elements of signature `elem_sig`, which are appended by `f`.
+    pub fn append_array<F>(&mut self, elem_sig: &str, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut IterAppend<'a>) -> Result<(), Error>
+    {
+        self.append_container(ffi::DBUS_TYPE_ARRAY, Some(elem_sig), f)
+    }
+
+    /// Appends a dictionary entry, whose key and value are appended by `f`.
+    /// Only valid directly inside an array with elements like "{sv}".
+    pub fn append_dict_entry<F>(&mut self, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut IterAppend<'a>) -> Result<(), Error>
+    {
+        self.append_container(ffi::DBUS_TYPE_DICT_ENTRY, None, f)
+    }
+
+    /// Appends a variant containing a single complete type of signature `sig`, which is appended by `f`.
+    pub fn append_variant<F>(&mut self, sig: &str, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut IterAppend<'a>) -> Result<(), Error>
+    {
+        self.append_container(ffi::DBUS_TYPE_VARIANT, Some(sig), f)
+    }
+
+    /// Appends a struct, whose members are appended by `f`.
+    pub fn append_struct<F>(&mut self, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut IterAppend<'a>) -> Result<(), Error>
+    {
+        self.append_container(ffi::DBUS_TYPE_STRUCT, None, f)
+    }
+
+    fn append_basic(&mut self, t: libc::c_int, v: i64) -> Result<(), Error> {
+        let r = unsafe { ffi::dbus_message_iter_append_basic(&mut self.i, t, mem::transmute(&v)) };
+        if r == 0 { Err(super::no_memory()) } else { Ok(()) }
+    }
+
+    fn append_container<F>(&mut self, t: libc::c_int, sig: Option<&str>, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut IterAppend<'a>) -> Result<(), Error>
+    {
+        super::iter_append_container(&mut self.i, t, sig, |sub| {
+            let mut s = IterAppend { i: mem::replace(sub, super::new_dbus_message_iter()), marker: ContravariantLifetime };
+            let r = f(&mut s);
+            *sub = s.i;
+            r
+        })
+    }
+}
+
+macro_rules! basic_append_get {
+    ($t:ty, $code:expr, $sig:expr, $to:expr, $from:expr) => {
+        impl Append for $t {
+            fn signature() -> String { $sig.to_string() }
+            fn append(self, i: &mut IterAppend) -> Result<(), Error> { i.append_basic($code, $to(self)) }
+        }
+        impl Get for $t {
+            fn signature() -> String { $sig.to_string() }
+            fn get(i: &mut Iter) -> Option<$t> { i.get_basic($code).map($from) }
+        }
+    }
+}
+
+basic_append_get!(bool, ffi::DBUS_TYPE_BOOLEAN, "b", |v: bool| v as i64, |v: i64| v as u32 != 0);
+basic_append_get!(u8, ffi::DBUS_TYPE_BYTE, "y", |v: u8| v as i64, |v: i64| v as u8);
+basic_append_get!(i16, ffi::DBUS_TYPE_INT16, "n", |v: i16| v as i64, |v: i64| v as i16);
+basic_append_get!(u16, ffi::DBUS_TYPE_UINT16, "q", |v: u16| v as i64, |v: i64| v as u16);
+basic_append_get!(i32, ffi::DBUS_TYPE_INT32, "i", |v: i32| v as i64, |v: i64| v as i32);
+basic_append_get!(u32, ffi::DBUS_TYPE_UINT32, "u", |v: u32| v as i64, |v: i64| v as u32);
+basic_append_get!(i64, ffi::DBUS_TYPE_INT64, "x", |v: i64| v, |v: i64| v);
+basic_append_get!(u64, ffi::DBUS_TYPE_UINT64, "t", |v: u64| v as i64, |v: i64| v as u64);
+basic_append_get!(f64, ffi::DBUS_TYPE_DOUBLE, "d", |v: f64| unsafe { mem::transmute::<f64, i64>(v) },
+    |v: i64| unsafe { mem::transmute::<i64, f64>(v) });
+
+/// Sends a duplicate, see `OwnedFd`.
+impl Append for OwnedFd {
+    fn signature() -> String { "h".to_string() }
+    fn append(self, i: &mut IterAppend) -> Result<(), Error> { i.append_basic(ffi::DBUS_TYPE_UNIX_FD, self.as_raw_fd() as i64) }
+}
+
+impl Get for OwnedFd {
+    fn signature() -> String { "h".to_string() }
+    fn get(i: &mut Iter) -> Option<OwnedFd> {
+        i.get_basic(ffi::DBUS_TYPE_UNIX_FD).map(|fd| unsafe { OwnedFd::new(fd as libc::c_int) })
+    }
+}
+
+impl<'b> Append for &'b str {
+    fn signature() -> String { "s".to_string() }
+    fn append(self, i: &mut IterAppend) -> Result<(), Error> { super::iter_append_str(&mut i.i, ffi::DBUS_TYPE_STRING, self) }
+}
+
+impl Append for String {
+    fn signature() -> String { "s".to_string() }
+    fn append(self, i: &mut IterAppend) -> Result<(), Error> { self.as_slice().append(i) }
+}
+
+impl Get for String {
+    fn signature() -> String { "s".to_string() }
+    fn get(i: &mut Iter) -> Option<String> {
+        i.get_str(ffi::DBUS_TYPE_STRING)
+    }
+}
+
+impl Append for Path {
+    fn signature() -> String { "o".to_string() }
+    fn append(self, i: &mut IterAppend) -> Result<(), Error> { super::iter_append_str(&mut i.i, ffi::DBUS_TYPE_OBJECT_PATH, self.as_str()) }
+}
+
+impl Get for Path {
+    fn signature() -> String { "o".to_string() }
+    fn get(i: &mut Iter) -> Option<Path> {
+        i.get_str(ffi::DBUS_TYPE_OBJECT_PATH).and_then(|s| Path::new(s.as_slice()).ok())
+    }
+}
+
+impl<T: Append> Append for Vec<T> {
+    fn signature() -> String { format!("a{}", <T as Append>::signature()) }
+    fn append(self, i: &mut IterAppend) -> Result<(), Error> {
+        let s = <T as Append>::signature();
+        i.append_container(ffi::DBUS_TYPE_ARRAY, Some(s.as_slice()), |sub| {
+            for x in self.into_iter() { try!(x.append(sub)); }
+            Ok(())
+        })
+    }
+}
+
+/// Appended with `append_fixed_array`.
+impl<'b, T: FixedArray + Append> Append for &'b [T] {
+    fn signature() -> String { format!("a{}", <T as Append>::signature()) }
+    fn append(self, i: &mut IterAppend) -> Result<(), Error> { i.append_fixed_array(self) }
+}
+
+impl<T: Get> Get for Vec<T> {
+    fn signature() -> String { format!("a{}", <T as Get>::signature()) }
+    fn get(i: &mut Iter) -> Option<Vec<T>> {
+        let mut sub = match i.recurse(ffi::DBUS_TYPE_ARRAY) { Some(s) => s, None => return None };
+        let mut v = vec!();
+        while sub.arg_type() != ffi::DBUS_TYPE_INVALID {
+            if v.len() == sub.limits.max_array_len || !sub.alloc(mem::size_of::<T>()) { return None }
+            match sub.read() { Ok(x) => v.push(x), Err(_) => return None }
+        }
+        Some(v)
+    }
+}
+
+impl<K: Append + Eq + Hash, V: Append> Append for HashMap<K, V> {
+    fn signature() -> String { format!("a{{{}{}}}", <K as Append>::signature(), <V as Append>::signature()) }
+    fn append(self, i: &mut IterAppend) -> Result<(), Error> {
+        let s = format!("{{{}{}}}", <K as Append>::signature(), <V as Append>::signature());
+        i.append_container(ffi::DBUS_TYPE_ARRAY, Some(s.as_slice()), |sub| {
+            for (k, v) in self.into_iter() {
+                try!(sub.append_container(ffi::DBUS_TYPE_DICT_ENTRY, None, |e| {
+                    try!(k.append(e));
+                    v.append(e)
+                }));
+            }
+            Ok(())
+        })
+    }
+}
+
+impl<K: Get + Eq + Hash, V: Get> Get for HashMap<K, V> {
+    fn signature() -> String { format!("a{{{}{}}}", <K as Get>::signature(), <V as Get>::signature()) }
+    fn get(i: &mut Iter) -> Option<HashMap<K, V>> {
+        let mut sub = match i.recurse(ffi::DBUS_TYPE_ARRAY) { Some(s) => s, None => return None };
+        let (mut m, mut n) = (HashMap::new(), 0u);
+        while sub.arg_type() != ffi::DBUS_TYPE_INVALID {
+            // Counted separately, since duplicate keys do not grow the map
+            if n == sub.limits.max_array_len || !sub.alloc(mem::size_of::<(K, V)>()) { return None }
+            let mut e = match sub.recurse(ffi::DBUS_TYPE_DICT_ENTRY) { Some(e) => e, None => return None };
+            let k = match e.read() { Ok(k) => k, Err(_) => return None };
+            let v = match e.read() { Ok(v) => v, Err(_) => return None };
+            m.insert(k, v);
+            n += 1;
+            sub.advance();
+        }
+        Some(m)
+    }
+}
+
+impl<T: Append> Append for Variant<T> {
+    fn signature() -> String { "v".to_string() }
+    fn append(self, i: &mut IterAppend) -> Result<(), Error> {
+        let s = <T as Append>::signature();
+        let Variant(v) = self;
+        i.append_container(ffi::DBUS_TYPE_VARIANT, Some(s.as_slice()), |sub| v.append(sub))
+    }
+}
+
+impl<T: Get> Get for Variant<T> {
+    fn signature() -> String { "v".to_string() }
+    fn get(i: &mut Iter) -> Option<Variant<T>> {
+        i.recurse(ffi::DBUS_TYPE_VARIANT).and_then(|mut sub| sub.get()).map(|v| Variant(v))
+    }
+}
+
+/// Reads any argument, for when the type is not known in advance.
+impl Get for MessageItem {
+    fn signature() -> String { "v".to_string() }
+    fn get(i: &mut Iter) -> Option<MessageItem> {
+        let t = i.arg_type();
+        if t == ffi::DBUS_TYPE_INVALID { return None }
+        MessageItem::from_iter_single(&mut i.i, &mut ReadState::new(Utf8Policy::Strict, i.limits), t).ok()
+    }
+}
+
+/// Reads an optional trailing argument: None if there are no more arguments.
+impl<T: Get> Get for Option<T> {
+    fn signature() -> String { <T as Get>::signature() }
+    fn get(i: &mut Iter) -> Option<Option<T>> {
+        if i.arg_type() == ffi::DBUS_TYPE_INVALID { return Some(None) }
+        i.get().map(|v| Some(v))
+    }
+}
+
+/// A type-erased argument, for handling values of different types the same way,
+/// e g the values of an "a{sv}" read as `HashMap<String, Variant<Box<RefArg>>>`.
+///
+/// When read, basic types and strings become their Rust types, variants become
+/// `Variant<Box<RefArg>>`, and everything else (containers, object paths and signatures)
+/// becomes a `MessageItem`.
+pub trait RefArg: Show {
+    /// The type code, e g `ffi::DBUS_TYPE_UINT32`.
+    fn arg_type(&self) -> libc::c_int;
+    fn signature(&self) -> String;
+    fn append(&self, i: &mut IterAppend) -> Result<(), Error>;
+    /// For downcasting, see also `cast`.
+    fn as_any(&self) -> &Any;
+    fn box_clone(&self) -> Box<RefArg>;
+
+    /// Any integer, if it fits in an i64.
+    fn as_i64(&self) -> Option<i64> { None }
+    /// Any integer, if it fits in a u64.
+    fn as_u64(&self) -> Option<u64> { None }
+    /// A double, or any integer converted to one.
+    fn as_f64(&self) -> Option<f64> { None }
+    /// A string, object path or signature.
+    fn as_str(&self) -> Option<&str> { None }
+}
+
+/// Downcasts a `RefArg` to its concrete type, e g `cast::<u32>(&*v)`.
+pub fn cast<'a, T: 'static>(a: &'a RefArg) -> Option<&'a T> { a.as_any().downcast_ref() }
+
+macro_rules! int_ref_arg {
+    ($t:ty, $code:expr) => {
+        impl RefArg for $t {
+            fn arg_type(&self) -> libc::c_int { $code }
+            fn signature(&self) -> String { <$t as Append>::signature() }
+            fn append(&self, i: &mut IterAppend) -> Result<(), Error> { i.append(*self) }
+            fn as_any(&self) -> &Any { self }
+            fn box_clone(&self) -> Box<RefArg> { box *self }
+            fn as_i64(&self) -> Option<i64> { self.to_i64() }
+            fn as_u64(&self) -> Option<u64> { self.to_u64() }
+            fn as_f64(&self) -> Option<f64> { self.to_f64() }
+        }
+    }
+}
+
+int_ref_arg!(u8, ffi::DBUS_TYPE_BYTE);
+int_ref_arg!(i16, ffi::DBUS_TYPE_INT16);
+int_ref_arg!(u16, ffi::DBUS_TYPE_UINT16);
+int_ref_arg!(i32, ffi::DBUS_TYPE_INT32);
+int_ref_arg!(u32, ffi::DBUS_TYPE_UINT32);
+int_ref_arg!(i64, ffi::DBUS_TYPE_INT64);
+int_ref_arg!(u64, ffi::DBUS_TYPE_UINT64);
+
+impl RefArg for bool {
+    fn arg_type(&self) -> libc::c_int { ffi::DBUS_TYPE_BOOLEAN }
+    fn signature(&self) -> String { "b".to_string() }
+    fn append(&self, i: &mut IterAppend) -> Result<(), Error> { i.append(*self) }
+    fn as_any(&self) -> &Any { self }
+    fn box_clone(&self) -> Box<RefArg> { box *self }
+}
+
+impl RefArg for f64 {
+    fn arg_type(&self) -> libc::c_int { ffi::DBUS_TYPE_DOUBLE }
+    fn signature(&self) -> String { "d".to_string() }
+    fn append(&self, i: &mut IterAppend) -> Result<(), Error> { i.append(*self) }
+    fn as_any(&self) -> &Any { self }
+    fn box_clone(&self) -> Box<RefArg> { box *self }
+    fn as_f64(&self) -> Option<f64> { Some(*self) }
+}
+
+impl RefArg for String {
+    fn arg_type(&self) -> libc::c_int { ffi::DBUS_TYPE_STRING }
+    fn signature(&self) -> String { "s".to_string() }
+    fn append(&self, i: &mut IterAppend) -> Result<(), Error> { i.append(self.as_slice()) }
+    fn as_any(&self) -> &Any { self }
+    fn box_clone(&self) -> Box<RefArg> { box self.clone() }
+    fn as_str(&self) -> Option<&str> { Some(self.as_slice()) }
+}
+
+fn item_as_i64(i: &MessageItem) -> Option<i64> {
+    match i {
+        &MessageItem::Byte(v) => v.to_i64(),
+        &MessageItem::Int16(v) => v.to_i64(),
+        &MessageItem::Int32(v) => v.to_i64(),
+        &MessageItem::Int64(v) => Some(v),
+        &MessageItem::UInt16(v) => v.to_i64(),
+        &MessageItem::UInt32(v) => v.to_i64(),
+        &MessageItem::UInt64(v) => v.to_i64(),
+        _ => None,
+    }
+}
+
+impl RefArg for MessageItem {
+    fn arg_type(&self) -> libc::c_int { self.array_type() as libc::c_int }
+    fn signature(&self) -> String { self.type_sig() }
+    fn append(&self, i: &mut IterAppend) -> Result<(), Error> {
+        try!(self.check_append());
+        self.iter_append(&mut i.i)
+    }
+    fn as_any(&self) -> &Any { self }
+    fn box_clone(&self) -> Box<RefArg> { box self.clone() }
+    // The inherent as_u64 and as_f64 only match their own variant
+    fn as_i64(&self) -> Option<i64> { item_as_i64(self) }
+    fn as_u64(&self) -> Option<u64> { item_as_i64(self).and_then(|v| v.to_u64()).or(self.as_u64()) }
+    fn as_f64(&self) -> Option<f64> { item_as_i64(self).and_then(|v| v.to_f64()).or(self.as_f64()) }
+    fn as_str(&self) -> Option<&str> { self.as_str().or(self.as_object_path()).or(self.as_signature()) }
+}
+
+impl RefArg for Variant<Box<RefArg>> {
+    fn arg_type(&self) -> libc::c_int { ffi::DBUS_TYPE_VARIANT }
+    fn signature(&self) -> String { "v".to_string() }
+    fn append(&self, i: &mut IterAppend) -> Result<(), Error> {
+        let s = self.0.signature();
+        i.append_container(ffi::DBUS_TYPE_VARIANT, Some(s.as_slice()), |sub| self.0.append(sub))
+    }
+    fn as_any(&self) -> &Any { self }
+    fn box_clone(&self) -> Box<RefArg> { box Variant(self.0.box_clone()) }
+}
+
+impl Append for Variant<Box<RefArg>> {
+    fn signature() -> String { "v".to_string() }
+    fn append(self, i: &mut IterAppend) -> Result<(), Error> { (&self as &RefArg).append(i) }
+}
+
+macro_rules! get_boxed {
+    ($i:expr, $t:ty) => { $i.get::<$t>().map(|v| box v as Box<RefArg>) }
+}
+
+impl Get for Box<RefArg> {
+    fn signature() -> String { "v".to_string() }
+    fn get(i: &mut Iter) -> Option<Box<RefArg>> {
+        match i.arg_type() {
+            ffi::DBUS_TYPE_INVALID => None,
+            ffi::DBUS_TYPE_BOOLEAN => get_boxed!(i, bool),
+            ffi::DBUS_TYPE_BYTE => get_boxed!(i, u8),
+            ffi::DBUS_TYPE_INT16 => get_boxed!(i, i16),
+            ffi::DBUS_TYPE_UINT16 => get_boxed!(i, u16),
+            ffi::DBUS_TYPE_INT32 => get_boxed!(i, i32),
+            ffi::DBUS_TYPE_UINT32 => get_boxed!(i, u32),
+            ffi::DBUS_TYPE_INT64 => get_boxed!(i, i64),
+            ffi::DBUS_TYPE_UINT64 => get_boxed!(i, u64),
+            ffi::DBUS_TYPE_DOUBLE => get_boxed!(i, f64),
+            ffi::DBUS_TYPE_STRING => get_boxed!(i, String),
+            ffi::DBUS_TYPE_VARIANT => get_boxed!(i, Variant<Box<RefArg>>),
+            _ => get_boxed!(i, MessageItem),
+        }
+    }
+}
+
+/// Defines a struct that is appended and read as a D-Bus struct, with the
+/// members in the order of the fields.
+///
+/// # Example
+///
+/// ```ignore
+/// dbus_struct!(#[deriving(Show)] pub struct Inhibitor { pub what: String, pub pid: u32, pub mode: String });
+///
+/// // Appended and read as a "(sus)"
+/// m.append1(Inhibitor { what: "sleep".to_string(), pid: 1234, mode: "delay".to_string() }).unwrap();
+/// let i: Inhibitor = m.read1().unwrap();
+/// ```
+#[macro_export]
+macro_rules! dbus_struct {
+    ($(#[$attr:meta])* pub struct $name:ident { $(pub $field:ident: $t:ty),+ }) => {
+        $(#[$attr])*
+        pub struct $name {
+            $(pub $field: $t),+
+        }
+
+        impl $crate::iter::Append for $name {
+            fn signature() -> String {
+                let mut s = "(".to_string();
+                $(s.push_str(<$t as $crate::iter::Append>::signature().as_slice());)+
+                s.push(')');
+                s
+            }
+            fn append(self, i: &mut $crate::iter::IterAppend) -> Result<(), $crate::Error> {
+                let $name { $($field),+ } = self;
+                i.append_struct(move |sub| {
+                    $(try!(sub.append($field));)+
+                    Ok(())
+                })
+            }
+        }
+
+        impl $crate::iter::Get for $name {
+            fn signature() -> String {
+                let mut s = "(".to_string();
+                $(s.push_str(<$t as $crate::iter::Get>::signature().as_slice());)+
+                s.push(')');
+                s
+            }
+            fn get(i: &mut $crate::iter::Iter) -> Option<$name> {
+                let mut sub = match i.recurse_struct() { Some(s) => s, None => return None };
+                $(let $field = match sub.read::<$t>() { Ok(x) => x, Err(_) => return None };)+
+                // No more members than fields (0 is DBUS_TYPE_INVALID)
+                if sub.arg_type() != 0 { return None }
+                Some($name { $($field: $field),+ })
+            }
+        }
+    }
+}
+
+macro_rules! struct_append_get {
+    ($($t:ident $n:ident),+) => {
+        /// Maps to a struct.
+        impl<$($t: Append),+> Append for ($($t,)+) {
+            fn signature() -> String {
+                let mut s = "(".to_string();
+                $(s.push_str(<$t as Append>::signature().as_slice());)+
+                s.push(')');
+                s
+            }
+            fn append(self, i: &mut IterAppend) -> Result<(), Error> {
+                let ($($n,)+) = self;
+                i.append_container(ffi::DBUS_TYPE_STRUCT, None, |sub| {
+                    $(try!($n.append(sub));)+
+                    Ok(())
+                })
+            }
+        }
+
+        impl<$($t: Get),+> Get for ($($t,)+) {
+            fn signature() -> String {
+                let mut s = "(".to_string();
+                $(s.push_str(<$t as Get>::signature().as_slice());)+
+                s.push(')');
+                s
+            }
+            fn get(i: &mut Iter) -> Option<($($t,)+)> {
+                let mut sub = match i.recurse(ffi::DBUS_TYPE_STRUCT) { Some(s) => s, None => return None };
+                $(let $n = match sub.read() { Ok(x) => x, Err(_) => return None };)+
+                if sub.arg_type() != ffi::DBUS_TYPE_INVALID { return None }
+                Some(($($n,)+))
+            }
+        }
+    }
+}
+
+struct_append_get!(A a);
+struct_append_get!(A a, B b);
+struct_append_get!(A a, B b, C c);
+struct_append_get!(A a, B b, C c, D d);
+
+#[test]
+fn append_get() {
+    let mut m = Message::new_signal("/", "com.example.Foo", "Bar").unwrap();
+    let mut h = HashMap::new();
+    h.insert("a".to_string(), Variant(5u32));
+    {
+        let mut i = IterAppend::new(&mut m);
+        i.append("Hello").unwrap();
+        i.append(vec!(1u8, 2, 3)).unwrap();
+        i.append(h.clone()).unwrap();
+        i.append((true, -1i16)).unwrap();
+    }
+    assert_eq!(m.signature().as_slice(), "saya{sv}(bn)");
+
+    let mut i = Iter::new(&m);
+    assert_eq!(i.read::<String>(), Ok("Hello".to_string()));
+    assert_eq!(i.read::<u32>(), Err(TypeMismatch { expected: "u".to_string(), found: "ay".to_string() }));
+    assert_eq!(i.read::<Vec<u8>>(), Ok(vec!(1u8, 2, 3)));
+    assert_eq!(i.read::<HashMap<String, Variant<u32>>>(), Ok(h));
+    assert_eq!(i.read::<(bool, i16)>(), Ok((true, -1)));
+    assert_eq!(i.read::<Option<u32>>(), Ok(None));
+}
+
+#[test]
+fn parse_limits() {
+    let mut m = Message::new_signal("/", "com.example.Foo", "Bar").unwrap();
+    m.append2(vec!(1u32, 2, 3), "Hello").unwrap();
+    m.set_parse_limits(ParseLimits { max_array_len: 2, ..::std::default::Default::default() });
+    assert!(m.read1::<Vec<u32>>().is_err());
+    m.set_parse_limits(ParseLimits { max_string_len: 4, ..::std::default::Default::default() });
+    assert!(m.read2::<Vec<u32>, String>().is_err());
+    m.set_parse_limits(ParseLimits { max_allocation: 8, ..::std::default::Default::default() });
+    assert!(m.read1::<Vec<u32>>().is_err());
+    m.set_parse_limits(::std::default::Default::default());
+    assert_eq!(m.read2::<Vec<u32>, String>(), Ok((vec!(1, 2, 3), "Hello".to_string())));
+}
+
+#[test]
+fn iterate() {
+    let mut m = Message::new_signal("/", "com.example.Foo", "Bar").unwrap();
+    {
+        let mut i = IterAppend::new(&mut m);
+        i.append(5u32).unwrap();
+        i.append_array("{sv}", |sub| sub.append_dict_entry(|e| {
+            try!(e.append("Name"));
+            e.append_variant("s", |v| v.append("Foo"))
+        })).unwrap();
+    }
+    assert_eq!(m.signature().as_slice(), "ua{sv}");
+
+    let v: Vec<MessageItem> = Iter::new(&m).collect();
+    assert_eq!(v, m.get_items().unwrap());
+
+    let mut i = Iter::new(&m);
+    assert!(i.recurse(ffi::DBUS_TYPE_UINT32).is_none());
+    i.advance();
+    let mut a = i.recurse(ffi::DBUS_TYPE_ARRAY).unwrap();
+    let mut e = a.recurse(ffi::DBUS_TYPE_DICT_ENTRY).unwrap();
+    assert_eq!(e.read::<String>(), Ok("Name".to_string()));
+    assert_eq!(e.recurse(ffi::DBUS_TYPE_VARIANT).unwrap().collect::<Vec<MessageItem>>(),
+        vec!(MessageItem::Str("Foo".to_string())));
+    assert!(!a.advance());
+}
+
+#[test]
+fn structs() {
+    dbus_struct!(#[deriving(Show, PartialEq)] pub struct Inhibitor { pub what: String, pub pid: u32, pub mode: String });
+
+    let a = Inhibitor { what: "sleep".to_string(), pid: 1234, mode: "delay".to_string() };
+    let mut m = Message::new_signal("/", "com.example.Foo", "Bar").unwrap();
+    m.append2(a, vec!(Inhibitor { what: "idle".to_string(), pid: 1, mode: "block".to_string() })).unwrap();
+    assert_eq!(m.signature().as_slice(), "(sus)a(sus)");
+    let (a, b): (Inhibitor, Vec<Inhibitor>) = m.read2().unwrap();
+    assert_eq!(a.pid, 1234);
+    assert_eq!(b[0].mode.as_slice(), "block");
+    assert_eq!(m.read1::<(String, u32)>(), Err(TypeMismatch { expected: "(su)".to_string(), found: "(sus)".to_string() }));
+}
+
+#[test]
+fn fixed_arrays() {
+    let mut m = Message::new_signal("/", "com.example.Foo", "Bar").unwrap();
+    m.append3(vec!(1u8, 2, 3), Vec::<i32>::new(), vec!(-5i32, 6)).unwrap();
+
+    let mut i = Iter::new(&m);
+    assert_eq!(i.get_fixed_array::<u8>(), Some([1u8, 2, 3].as_slice()));
+    assert_eq!(i.get_fixed_array::<i32>(), None);
+    i.advance();
+    assert_eq!(i.get_fixed_array::<i32>(), Some([].as_slice()));
+    i.advance();
+    assert_eq!(i.get_fixed_array::<i32>(), Some([-5i32, 6].as_slice()));
+
+    let mut m = Message::new_signal("/", "com.example.Foo", "Bar").unwrap();
+    let data: Vec<u8> = range(0, 10000u).map(|x| x as u8).collect();
+    IterAppend::new(&mut m).append_bytes(data.as_slice()).unwrap();
+    m.append1([1.5f64, 2.0].as_slice()).unwrap();
+    assert_eq!(m.signature().as_slice(), "ayad");
+    let mut i = Iter::new(&m);
+    assert_eq!(i.get_fixed_array::<u8>(), Some(data.as_slice()));
+    i.advance();
+    assert_eq!(i.read::<Vec<f64>>(), Ok(vec!(1.5, 2.0)));
+}
+
+#[test]
+fn byte_streams() {
+    let data: Vec<u8> = range(0, 200000u).map(|x| (x * 7) as u8).collect();
+    let mut m = Message::new_signal("/", "com.example.Foo", "Bar").unwrap();
+    {
+        let mut i = IterAppend::new(&mut m);
+        i.append_from_reader(&mut BufReader::new(data.as_slice())).unwrap();
+        i.append_byte_stream(|w| w.write_str("Hello")).unwrap();
+    }
+    assert_eq!(m.signature().as_slice(), "ayay");
+
+    let mut i = Iter::new(&m);
+    assert_eq!(i.get_byte_reader().unwrap().read_to_end().unwrap(), data);
+    i.advance();
+    assert_eq!(i.get_byte_reader().unwrap().read_to_string().unwrap().as_slice(), "Hello");
+}
+
+#[test]
+fn ref_args() {
+    let mut m = Message::new_signal("/", "com.example.Foo", "Bar").unwrap();
+    let mut h: HashMap<String, Variant<Box<RefArg>>> = HashMap::new();
+    h.insert("Count".to_string(), Variant(box 7u16 as Box<RefArg>));
+    h.insert("Name".to_string(), Variant(box "Foo".to_string() as Box<RefArg>));
+    h.insert("Path".to_string(), Variant(box MessageItem::ObjectPath(Path::new("/foo").unwrap()) as Box<RefArg>));
+    m.append1(h).unwrap();
+    assert_eq!(m.signature().as_slice(), "a{sv}");
+
+    let h: HashMap<String, Variant<Box<RefArg>>> = m.read1().unwrap();
+    let c = &h.get("Count").unwrap().0;
+    assert_eq!(c.arg_type(), ffi::DBUS_TYPE_UINT16);
+    assert_eq!(c.as_i64(), Some(7));
+    assert_eq!(cast::<u16>(&**c), Some(&7u16));
+    assert_eq!(cast::<u32>(&**c), None);
+    assert_eq!(h.get("Name").unwrap().0.as_str(), Some("Foo"));
+    let p = &h.get("Path").unwrap().0;
+    assert_eq!(p.signature().as_slice(), "o");
+    assert_eq!(p.as_str(), Some("/foo"));
+}
@@ -7,23 +7,49 @@ pub use ffi::DBusNameFlag as NameFlag;
 pub use ffi::DBusRequestNameReply as RequestNameReply;
 pub use ffi::DBusReleaseNameReply as ReleaseNameReply;
 pub use ffi::DBusMessageType as MessageType;
+pub use ffi::DBusDispatchStatus as DispatchStatus;
 
 pub use prop::PropHandler;
 pub use prop::Props;
 pub use objpath::ObjectPath;
+pub use arg::IntoItem;
+pub use fd::OwnedFd;
+pub use path::Path;
 
 use std::c_str::CString;
 use std::ptr;
-use std::collections::DList;
+use std::collections::{DList, HashMap};
 use std::cell::{Cell, RefCell};
 
 mod ffi;
 
+#[macro_use]
+mod signal;
+
 // New experimental module.
 pub mod newdbus;
 
 pub mod prop;
 pub mod objpath;
+pub mod arg;
+pub mod pool;
+pub mod bridge;
+pub mod workers;
+pub mod size;
+pub mod watchdog;
+#[cfg(feature = "fault-injection")]
+pub mod fault;
+pub mod allowlist;
+pub mod networkmanager;
+pub mod matchrule;
+pub mod manager;
+pub mod signature;
+pub mod iter;
+pub mod pretty;
+pub mod fd;
+pub mod path;
+pub mod tree;
+pub mod service;
 
 static INITDBUS: std::sync::Once = std::sync::ONCE_INIT;
 
@@ -49,15 +75,27 @@ fn c_str_to_slice(c: & *const libc::c_char) -> Option<&str> {
     )}).ok() }
 }
 
+/// Like to_c_str, but returns an InvalidArgs error instead of failing on interior NUL bytes.
+fn to_c_str(s: &str) -> Result<CString, Error> {
+    if s.contains_char('\0') {
+        let f = format!("String '{}' contains a NUL byte", s.escape_default());
+        return Err(Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs", f.as_slice()));
+    }
+    Ok(s.to_c_str())
+}
+
+fn escape_nul(s: &str) -> String { s.replace("\0", "\\0") }
+
 impl Error {
 
     pub fn new(e: ffi::DBusError) -> Error {
         Error { e: e }
     }
 
+    /// NUL bytes, e g in an invalid argument echoed back in the message, are escaped as `\\0`.
     pub fn new_custom(name: &str, message: &str) -> Error {
-        let n = name.to_c_str();
-        let m = message.replace("%","%%").to_c_str();
+        let n = escape_nul(name).to_c_str();
+        let m = escape_nul(message.replace("%","%%").as_slice()).to_c_str();
         let mut e = Error::empty();
 
         unsafe { ffi::dbus_set_error(e.get_mut(), n.as_ptr(), m.as_ptr()) };
@@ -126,12 +164,92 @@ fn new_dbus_message_iter() -> ffi::DBusMessageIter {
     }
 }
 
+/// What to do with strings that are not valid UTF-8 when reading messages.
+#[deriving(Show, PartialEq, Copy, Clone)]
+pub enum Utf8Policy {
+    /// Fail with an InvalidArgs error.
+    Strict,
+    /// Replace invalid sequences with U+FFFD.
+    Lossy,
+}
+
+impl std::default::Default for Utf8Policy {
+    fn default() -> Utf8Policy { Utf8Policy::Strict }
+}
+
+/// Limits on the resources used when reading the arguments of a message,
+/// to keep messages from hostile peers from exhausting memory.
+///
+/// Exceeding a limit makes reading fail with a LimitsExceeded error. The default is no limits.
+#[deriving(Show, PartialEq, Copy, Clone)]
+pub struct ParseLimits {
+    /// Maximum number of bytes allocated for all items of a message.
+    pub max_allocation: uint,
+    pub max_array_len: uint,
+    /// Maximum length of a string, in bytes.
+    pub max_string_len: uint,
+}
+
+impl std::default::Default for ParseLimits {
+    fn default() -> ParseLimits {
+        ParseLimits { max_allocation: std::uint::MAX, max_array_len: std::uint::MAX, max_string_len: std::uint::MAX }
+    }
+}
+
+/// Limits on what a connection accepts from its peer, see `Connection::set_limits`.
+///
+/// Fields left as None keep the libdbus defaults, which are generous (e g 32 MB per
+/// message) and meant for trusted peers.
+#[deriving(Show, PartialEq, Copy, Clone, Default)]
+pub struct Limits {
+    /// Maximum size of a single incoming message, in bytes.
+    pub max_message_size: Option<uint>,
+    /// Maximum total size of incoming messages that are queued but not yet dispatched.
+    /// Once it is reached, libdbus stops reading from the socket.
+    pub max_received_size: Option<uint>,
+    pub max_message_unix_fds: Option<uint>,
+    pub max_received_unix_fds: Option<uint>,
+}
+
+fn limits_exceeded(s: String) -> Error {
+    Error::new_custom("org.freedesktop.DBus.Error.LimitsExceeded", s.as_slice())
+}
+
+struct ReadState {
+    policy: Utf8Policy,
+    limits: ParseLimits,
+    allocated: uint,
+}
+
+impl ReadState {
+    fn new(policy: Utf8Policy, limits: ParseLimits) -> ReadState {
+        ReadState { policy: policy, limits: limits, allocated: 0 }
+    }
+
+    fn alloc(&mut self, n: uint) -> Result<(), Error> {
+        self.allocated += n;
+        if self.allocated <= self.limits.max_allocation { return Ok(()) }
+        Err(limits_exceeded(format!("Reading message needs more than the maximum {} bytes", self.limits.max_allocation)))
+    }
+}
+
 #[deriving(Show, PartialEq, PartialOrd, Clone)]
 pub enum MessageItem {
-    Array(Vec<MessageItem>, int),
+    /// An array, with the signature of its elements, e g "s" or "(ii)". The signature can
+    /// be left empty for arrays with at least one element, see also `new_array`.
+    Array(Vec<MessageItem>, String),
     Variant(Box<MessageItem>),
     DictEntry(Box<MessageItem>, Box<MessageItem>),
+    /// A dictionary, "a{kv}", with the signatures of the keys and the values. Like for
+    /// arrays, they can be left empty if there is at least one entry. Dictionaries
+    /// are read as this rather than as an `Array` of `DictEntry`.
+    Dict(Vec<(MessageItem, MessageItem)>, String, String),
+    /// A struct, e g "(sv)". Structs must have at least one field.
+    Struct(Vec<MessageItem>),
     Str(String),
+    ObjectPath(Path),
+    /// A type signature, e g "a{sv}".
+    Signature(String),
     Bool(bool),
     Byte(u8),
     Int16(i16),
@@ -140,6 +258,11 @@ pub enum MessageItem {
     UInt16(u16),
     UInt32(u32),
     UInt64(u64),
+    Double(f64),
+    /// A unix file descriptor, "h". It is closed when the item is dropped, see `OwnedFd`.
+    /// Connections that cannot pass file descriptors fail to send messages with these,
+    /// see `Connection::can_send_type`.
+    UnixFd(OwnedFd),
 }
 
 fn iter_get_basic(i: &mut ffi::DBusMessageIter) -> i64 {
@@ -151,43 +274,168 @@ fn iter_get_basic(i: &mut ffi::DBusMessageIter) -> i64 {
     c
 }
 
-fn iter_append_array(i: &mut ffi::DBusMessageIter, a: &[MessageItem], t: int) {
+/* Strings shorter than this are NUL terminated in a stack buffer when appended,
+   instead of being copied into a CString. Most bus names, paths and arguments fit. */
+const INLINE_STR_LEN: uint = 128;
+
+fn with_c_str<R, F: FnOnce(*const libc::c_char) -> R>(s: &str, f: F) -> Result<R, Error> {
+    // to_c_str also takes care of reporting NUL bytes
+    if s.len() >= INLINE_STR_LEN || s.contains_char('\0') { return to_c_str(s).map(|c| f(c.as_ptr())) }
+    let mut buf = [0u8; INLINE_STR_LEN];
+    std::slice::bytes::copy_memory(&mut buf, s.as_bytes());
+    Ok(f(buf.as_ptr() as *const libc::c_char))
+}
+
+fn no_memory() -> Error {
+    Error::new_custom("org.freedesktop.DBus.Error.NoMemory", "Out of memory")
+}
+
+/* Runs f on a newly opened container. The container is closed if f succeeds and
+   abandoned otherwise. libdbus leaves the message unusable after abandoning a
+   container, so the caller must discard the message if this fails. */
+fn iter_append_container<F>(i: &mut ffi::DBusMessageIter, t: libc::c_int, sig: Option<&str>, f: F) -> Result<(), Error>
+    where F: FnOnce(&mut ffi::DBusMessageIter) -> Result<(), Error>
+{
     let mut subiter = new_dbus_message_iter();
+    let r = match sig {
+        Some(s) => try!(with_c_str(s, |p| unsafe { ffi::dbus_message_iter_open_container(i, t, p, &mut subiter) })),
+        None => unsafe { ffi::dbus_message_iter_open_container(i, t, ptr::null(), &mut subiter) },
+    };
+    if r == 0 { return Err(no_memory()) }
+    if let Err(e) = f(&mut subiter) {
+        unsafe { ffi::dbus_message_iter_abandon_container(i, &mut subiter) };
+        return Err(e);
+    }
+    if unsafe { ffi::dbus_message_iter_close_container(i, &mut subiter) } == 0 { return Err(no_memory()) }
+    Ok(())
+}
+
+/* Object paths are a slash, or slash separated non-empty elements of [A-Za-z0-9_].
+   libdbus aborts the process when appending an invalid one, so check first. */
+fn is_valid_object_path(s: &str) -> bool {
+    if s == "/" { return true }
+    s.starts_with("/") && s.slice_from(1).split('/').all(|e| e.len() > 0 &&
+        e.chars().all(|c| c.is_ascii() && (c.is_alphanumeric() || c == '_')))
+}
 
-    // TODO: This works for simple dictionaries. Not so well for dictionaries of dictionaries, probably.
-    let atype =
-        if t <= 0 {
-            match &a[0] {
-                &MessageItem::DictEntry(ref k, ref v) => format!("{{{}{}}}",
-                    k.array_type() as u8 as char, v.array_type() as u8 as char),
-                _ => format!("{}", a[0].array_type() as u8 as char),
+/* Member names are 1 to 255 of [A-Za-z0-9_], not starting with a digit. */
+fn is_valid_member_name(s: &str) -> bool {
+    s.len() > 0 && s.len() <= 255 && !s.char_at(0).is_digit(10) &&
+        s.chars().all(|c| c.is_ascii() && (c.is_alphanumeric() || c == '_'))
+}
+
+/* Interface names are at least two member names joined by dots, at most 255 bytes in total. */
+fn is_valid_interface_name(s: &str) -> bool {
+    s.len() <= 255 && s.split('.').count() >= 2 && s.split('.').all(is_valid_member_name)
+}
+
+/* Bus names are like interface names, but can have dashes, and unique names (":1.42")
+   can have elements starting with a digit. */
+fn is_valid_bus_name(s: &str) -> bool {
+    let (unique, s2) = if s.starts_with(":") { (true, s.slice_from(1)) } else { (false, s) };
+    s.len() <= 255 && s2.split('.').count() >= 2 && s2.split('.').all(|e| e.len() > 0 &&
+        (unique || !e.char_at(0).is_digit(10)) &&
+        e.chars().all(|c| c.is_ascii() && (c.is_alphanumeric() || c == '_' || c == '-')))
+}
+
+/* The reply to the method call `m`: a method return with the items, or the error with the given
+   name and text. If the items cannot be appended or the error name is invalid, the reply is a
+   Failed error instead, so that the caller gets an answer rather than the handler panicking. */
+fn method_result_reply(m: &Message, r: Result<&[MessageItem], (&str, &str)>) -> Result<Message, Error> {
+    let failed = match r {
+        Ok(items) => {
+            let mut z = try!(Message::new_method_return(m));
+            match z.try_append_items(items) {
+                Ok(()) => return Ok(z),
+                Err(e) => format!("Invalid reply: {}", e.message().unwrap_or("")),
             }
         }
-        else { format!("{}", t as u8 as char) }
-        .to_c_str();
+        Err((name, text)) => match Message::new_error(m, name, escape_nul(text).as_slice()) {
+            Ok(z) => return Ok(z),
+            Err(e) => format!("Invalid error reply: {}", e.message().unwrap_or("")),
+        },
+    };
+    Message::new_error(m, "org.freedesktop.DBus.Error.Failed", escape_nul(failed.as_slice()).as_slice())
+}
 
-    assert!(unsafe { ffi::dbus_message_iter_open_container(i, ffi::DBUS_TYPE_ARRAY, atype.as_ptr(), &mut subiter) } != 0);
-    for item in a.iter() {
-        assert!(t < 0 || item.array_type() == t as int);
-        item.iter_append(&mut subiter);
+fn iter_append_str(i: &mut ffi::DBusMessageIter, t: libc::c_int, s: &str) -> Result<(), Error> {
+    // append_basic takes a pointer to the char pointer
+    let r = try!(with_c_str(s, |p| unsafe {
+        ffi::dbus_message_iter_append_basic(i, t, std::mem::transmute(&p))
+    }));
+    if r == 0 { Err(no_memory()) } else { Ok(()) }
+}
+
+/* The signature of the value the iterator points to. */
+fn iter_signature(i: &mut ffi::DBusMessageIter) -> Result<String, Error> {
+    let s = unsafe { ffi::dbus_message_iter_get_signature(i) };
+    if s == ptr::null_mut() { return Err(no_memory()) }
+    let r = c_str_to_slice(&(s as *const libc::c_char)).unwrap_or("").to_string();
+    unsafe { ffi::dbus_free(s as *mut libc::c_void) };
+    Ok(r)
+}
+
+fn array_elem_sig(a: &[MessageItem], t: &str) -> Result<String, Error> {
+    if t.len() > 0 { return Ok(t.to_string()) }
+    match a.get(0) {
+        Some(x) => Ok(x.type_sig()),
+        None => Err(Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs",
+            "Cannot append an empty array without element type")),
     }
-    assert!(unsafe { ffi::dbus_message_iter_close_container(i, &mut subiter) } != 0);
 }
 
-fn iter_append_variant(i: &mut ffi::DBusMessageIter, a: &MessageItem) {
-    let mut subiter = new_dbus_message_iter();
-    let atype = format!("{}", a.array_type() as u8 as char).to_c_str();
-    assert!(unsafe { ffi::dbus_message_iter_open_container(i, ffi::DBUS_TYPE_VARIANT, atype.as_ptr(), &mut subiter) } != 0);
-    a.iter_append(&mut subiter);
-    assert!(unsafe { ffi::dbus_message_iter_close_container(i, &mut subiter) } != 0);
+fn iter_append_array(i: &mut ffi::DBusMessageIter, a: &[MessageItem], t: &str) -> Result<(), Error> {
+    let atype = try!(array_elem_sig(a, t));
+    iter_append_container(i, ffi::DBUS_TYPE_ARRAY, Some(atype.as_slice()), |subiter| {
+        for item in a.iter() {
+            if !item.has_type(atype.as_slice()) {
+                let f = format!("Array element {} does not match array type {}", item, atype);
+                return Err(Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs", f.as_slice()));
+            }
+            try!(item.iter_append(subiter));
+        }
+        Ok(())
+    })
 }
 
-fn iter_append_dict(i: &mut ffi::DBusMessageIter, k: &MessageItem, v: &MessageItem) {
-    let mut subiter = new_dbus_message_iter();
-    assert!(unsafe { ffi::dbus_message_iter_open_container(i, ffi::DBUS_TYPE_DICT_ENTRY, ptr::null(), &mut subiter) } != 0);
-    k.iter_append(&mut subiter);
-    v.iter_append(&mut subiter);
-    assert!(unsafe { ffi::dbus_message_iter_close_container(i, &mut subiter) } != 0);
+fn iter_append_variant(i: &mut ffi::DBusMessageIter, a: &MessageItem) -> Result<(), Error> {
+    match a {
+        &MessageItem::Array(..) | &MessageItem::Dict(..) | &MessageItem::DictEntry(..) | &MessageItem::Struct(_) => {
+            let atype = a.type_sig();
+            iter_append_container(i, ffi::DBUS_TYPE_VARIANT, Some(atype.as_slice()), |subiter| a.iter_append(subiter))
+        }
+        _ => {
+            let atype = [a.array_type() as u8];
+            let atype = std::str::from_utf8(&atype).unwrap();
+            iter_append_container(i, ffi::DBUS_TYPE_VARIANT, Some(atype), |subiter| a.iter_append(subiter))
+        }
+    }
+}
+
+fn iter_append_struct(i: &mut ffi::DBusMessageIter, a: &[MessageItem]) -> Result<(), Error> {
+    iter_append_container(i, ffi::DBUS_TYPE_STRUCT, None, |subiter| MessageItem::copy_to_iter(subiter, a))
+}
+
+fn dict_entry_sig(a: &[(MessageItem, MessageItem)], kt: &str, vt: &str) -> String {
+    match a.get(0) {
+        Some(&(ref k, ref v)) if kt.len() == 0 || vt.len() == 0 => format!("{{{}{}}}", k.type_sig(), v.type_sig()),
+        _ => format!("{{{}{}}}", kt, vt),
+    }
+}
+
+fn iter_append_dict_items(i: &mut ffi::DBusMessageIter, a: &[(MessageItem, MessageItem)], kt: &str, vt: &str) -> Result<(), Error> {
+    let esig = dict_entry_sig(a, kt, vt);
+    iter_append_container(i, ffi::DBUS_TYPE_ARRAY, Some(esig.as_slice()), |subiter| {
+        for &(ref k, ref v) in a.iter() { try!(iter_append_dict(subiter, k, v)); }
+        Ok(())
+    })
+}
+
+fn iter_append_dict(i: &mut ffi::DBusMessageIter, k: &MessageItem, v: &MessageItem) -> Result<(), Error> {
+    iter_append_container(i, ffi::DBUS_TYPE_DICT_ENTRY, None, |subiter| {
+        try!(k.iter_append(subiter));
+        v.iter_append(subiter)
+    })
 }
 
 impl MessageItem {
@@ -195,6 +443,8 @@ impl MessageItem {
     pub fn array_type(&self) -> int {
         let s = match self {
             &MessageItem::Str(_) => ffi::DBUS_TYPE_STRING,
+            &MessageItem::ObjectPath(_) => ffi::DBUS_TYPE_OBJECT_PATH,
+            &MessageItem::Signature(_) => ffi::DBUS_TYPE_SIGNATURE,
             &MessageItem::Bool(_) => ffi::DBUS_TYPE_BOOLEAN,
             &MessageItem::Byte(_) => ffi::DBUS_TYPE_BYTE,
             &MessageItem::Int16(_) => ffi::DBUS_TYPE_INT16,
@@ -203,82 +453,295 @@ impl MessageItem {
             &MessageItem::UInt16(_) => ffi::DBUS_TYPE_UINT16,
             &MessageItem::UInt32(_) => ffi::DBUS_TYPE_UINT32,
             &MessageItem::UInt64(_) => ffi::DBUS_TYPE_UINT64,
-            &MessageItem::Array(_,_) => ffi::DBUS_TYPE_ARRAY,
+            &MessageItem::Double(_) => ffi::DBUS_TYPE_DOUBLE,
+            &MessageItem::UnixFd(_) => ffi::DBUS_TYPE_UNIX_FD,
+            &MessageItem::Array(_,_) | &MessageItem::Dict(..) => ffi::DBUS_TYPE_ARRAY,
             &MessageItem::Variant(_) => ffi::DBUS_TYPE_VARIANT,
             &MessageItem::DictEntry(_,_) => ffi::DBUS_TYPE_DICT_ENTRY,
+            &MessageItem::Struct(_) => ffi::DBUS_TYPE_STRUCT,
         };
         s as int
     }
 
-    fn from_iter(i: &mut ffi::DBusMessageIter) -> Vec<MessageItem> {
+    /* The complete type signature of the item, e g "a{sv}" rather than just 'a'. */
+    fn type_sig(&self) -> String {
+        match self {
+            &MessageItem::Array(ref a, ref t) => {
+                if t.len() == 0 && a.len() > 0 { format!("a{}", a[0].type_sig()) }
+                else { format!("a{}", t) }
+            }
+            &MessageItem::DictEntry(ref k, ref v) => format!("{{{}{}}}", k.type_sig(), v.type_sig()),
+            &MessageItem::Dict(ref a, ref kt, ref vt) => format!("a{}", dict_entry_sig(a.as_slice(), kt.as_slice(), vt.as_slice())),
+            &MessageItem::Struct(ref a) => {
+                let mut s = "(".to_string();
+                for x in a.iter() { s.push_str(x.type_sig().as_slice()) }
+                s.push(')');
+                s
+            }
+            _ => format!("{}", self.array_type() as u8 as char),
+        }
+    }
+
+    /* True if the item has the given complete type signature. */
+    fn has_type(&self, sig: &str) -> bool {
+        match self {
+            &MessageItem::Array(..) | &MessageItem::Dict(..) | &MessageItem::DictEntry(..) |
+                &MessageItem::Struct(_) => self.type_sig().as_slice() == sig,
+            // No need to allocate a signature for the basic types
+            _ => sig.len() == 1 && self.array_type() == sig.as_bytes()[0] as int,
+        }
+    }
+
+    /// Creates an array of items of type `T`. Unlike building an `Array` directly, this
+    /// works for empty arrays, whose element type cannot be derived from the elements.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dbus::MessageItem;
+    ///
+    /// let a = MessageItem::new_array::<String>(vec!());
+    /// assert_eq!(a, MessageItem::Array(vec!(), "s".to_string()));
+    /// ```
+    pub fn new_array<T: arg::Arg>(v: Vec<MessageItem>) -> MessageItem {
+        MessageItem::Array(v, <T as arg::Arg>::signature())
+    }
+
+    /// The complete type signature of the item, e g "a{sv}".
+    ///
+    /// An empty `Array` or `Dict` without element signatures gives an invalid signature.
+    pub fn signature(&self) -> signature::Signature { signature::Signature::of_items(std::slice::ref_slice(self)) }
+
+    /// Reads the item as a Rust type, e g a `Vec<String>` from an "as".
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dbus::MessageItem;
+    ///
+    /// let i = MessageItem::UInt32(5);
+    /// assert_eq!(i.get::<u32>(), Ok(5));
+    /// assert!(i.get::<String>().is_err());
+    /// ```
+    pub fn get<T: arg::Arg>(&self) -> Result<T, arg::TypeMismatch> {
+        match arg::Arg::from_item(self) {
+            Some(v) => Ok(v),
+            None => Err(arg::TypeMismatch { expected: <T as arg::Arg>::signature(), found: self.type_sig() }),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> { match self { &MessageItem::Str(ref s) => Some(s.as_slice()), _ => None } }
+    pub fn as_object_path(&self) -> Option<&str> { match self { &MessageItem::ObjectPath(ref s) => Some(s.as_str()), _ => None } }
+    pub fn as_signature(&self) -> Option<&str> { match self { &MessageItem::Signature(ref s) => Some(s.as_slice()), _ => None } }
+    pub fn as_bool(&self) -> Option<bool> { match self { &MessageItem::Bool(v) => Some(v), _ => None } }
+    pub fn as_u8(&self) -> Option<u8> { match self { &MessageItem::Byte(v) => Some(v), _ => None } }
+    pub fn as_i16(&self) -> Option<i16> { match self { &MessageItem::Int16(v) => Some(v), _ => None } }
+    pub fn as_i32(&self) -> Option<i32> { match self { &MessageItem::Int32(v) => Some(v), _ => None } }
+    pub fn as_i64(&self) -> Option<i64> { match self { &MessageItem::Int64(v) => Some(v), _ => None } }
+    pub fn as_u16(&self) -> Option<u16> { match self { &MessageItem::UInt16(v) => Some(v), _ => None } }
+    pub fn as_u32(&self) -> Option<u32> { match self { &MessageItem::UInt32(v) => Some(v), _ => None } }
+    pub fn as_u64(&self) -> Option<u64> { match self { &MessageItem::UInt64(v) => Some(v), _ => None } }
+    pub fn as_f64(&self) -> Option<f64> { match self { &MessageItem::Double(v) => Some(v), _ => None } }
+    /// The elements, if this is an array.
+    pub fn as_array(&self) -> Option<&[MessageItem]> { match self { &MessageItem::Array(ref a, _) => Some(a.as_slice()), _ => None } }
+    /// The entries, if this is a dictionary.
+    pub fn as_dict(&self) -> Option<&[(MessageItem, MessageItem)]> { match self { &MessageItem::Dict(ref a, _, _) => Some(a.as_slice()), _ => None } }
+
+    /// Reads the value inside a variant as a Rust type, e g a property value as a `u32`.
+    /// Returns None if this is not a variant, or if it does not contain a `T`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dbus::MessageItem;
+    ///
+    /// let v = MessageItem::Variant(box MessageItem::Str("eth0".to_string()));
+    /// assert_eq!(v.inner::<String>(), Some("eth0".to_string()));
+    /// assert_eq!(v.inner::<u32>(), None);
+    /// ```
+    pub fn inner<T: arg::Arg>(&self) -> Option<T> {
+        match self {
+            &MessageItem::Variant(ref v) => arg::Arg::from_item(&**v),
+            _ => None,
+        }
+    }
+
+    fn from_iter(i: &mut ffi::DBusMessageIter, r: &mut ReadState) -> Result<Vec<MessageItem>, Error> {
+        MessageItem::from_array_iter(i, r, std::uint::MAX, "")
+    }
+
+    /* Fails as soon as there are more than max items, before reading the rest of them. */
+    fn from_array_iter(i: &mut ffi::DBusMessageIter, r: &mut ReadState, max: uint, what: &str) -> Result<Vec<MessageItem>, Error> {
         let mut v = Vec::new();
         loop {
             let t = unsafe { ffi::dbus_message_iter_get_arg_type(i) };
-            match t {
-                ffi::DBUS_TYPE_INVALID => { return v },
-                ffi::DBUS_TYPE_DICT_ENTRY => {
-                    let mut subiter = new_dbus_message_iter();
-                    unsafe { ffi::dbus_message_iter_recurse(i, &mut subiter) };
-                    let a = MessageItem::from_iter(&mut subiter);
-                    if a.len() != 2 { panic!("D-Bus dict entry error"); }
-                    let mut a = a.into_iter();
-                    let key = box a.next().unwrap();
-                    let value = box a.next().unwrap();
-                    v.push(MessageItem::DictEntry(key, value));
-                }
-                ffi::DBUS_TYPE_VARIANT => {
-                    let mut subiter = new_dbus_message_iter();
-                    unsafe { ffi::dbus_message_iter_recurse(i, &mut subiter) };
-                    let a = MessageItem::from_iter(&mut subiter);
-                    if a.len() != 1 { panic!("D-Bus variant error"); }
-                    v.push(MessageItem::Variant(box a.into_iter().next().unwrap()));
-                }
-                ffi::DBUS_TYPE_ARRAY => {
-                    let mut subiter = new_dbus_message_iter();
-                    unsafe { ffi::dbus_message_iter_recurse(i, &mut subiter) };
-                    let a = MessageItem::from_iter(&mut subiter);
-                    let t = if a.len() > 0 { a[0].array_type() } else { 0 };
-                    v.push(MessageItem::Array(a, t));
-                },
-                ffi::DBUS_TYPE_STRING => {
-                    let mut c: *const libc::c_char = ptr::null();
-                    let s = unsafe {
-                        let p: *mut libc::c_void = std::mem::transmute(&mut c);
-                        ffi::dbus_message_iter_get_basic(i, p);
-                        CString::new(c, false)
-                    };
-                    v.push(MessageItem::Str(s.to_string()));
-                },
-                ffi::DBUS_TYPE_BOOLEAN => v.push(MessageItem::Bool((iter_get_basic(i) as u32) != 0)),
-                ffi::DBUS_TYPE_BYTE => v.push(MessageItem::Byte(iter_get_basic(i) as u8)),
-                ffi::DBUS_TYPE_INT16 => v.push(MessageItem::Int16(iter_get_basic(i) as i16)),
-                ffi::DBUS_TYPE_INT32 => v.push(MessageItem::Int32(iter_get_basic(i) as i32)),
-                ffi::DBUS_TYPE_INT64 => v.push(MessageItem::Int64(iter_get_basic(i) as i64)),
-                ffi::DBUS_TYPE_UINT16 => v.push(MessageItem::UInt16(iter_get_basic(i) as u16)),
-                ffi::DBUS_TYPE_UINT32 => v.push(MessageItem::UInt32(iter_get_basic(i) as u32)),
-                ffi::DBUS_TYPE_UINT64 => v.push(MessageItem::UInt64(iter_get_basic(i) as u64)),
-
-                _ => { panic!("D-Bus unsupported message type {} ({})", t, t as u8 as char); }
+            if t == ffi::DBUS_TYPE_INVALID { return Ok(v) }
+            if v.len() == max {
+                return Err(limits_exceeded(format!("{} has more than the maximum {} elements", what, max)));
             }
+            v.push(try!(MessageItem::from_iter_single(i, r, t)));
             unsafe { ffi::dbus_message_iter_next(i) };
         }
     }
 
-    fn iter_append_basic(&self, i: &mut ffi::DBusMessageIter, v: i64) {
+    /* Reads the item the iterator points to, which has type t. */
+    fn from_iter_single(i: &mut ffi::DBusMessageIter, r: &mut ReadState, t: libc::c_int) -> Result<MessageItem, Error> {
+        try!(r.alloc(std::mem::size_of::<MessageItem>()));
+        Ok(match t {
+            ffi::DBUS_TYPE_DICT_ENTRY => {
+                let mut subiter = new_dbus_message_iter();
+                unsafe { ffi::dbus_message_iter_recurse(i, &mut subiter) };
+                let a = try!(MessageItem::from_iter(&mut subiter, r));
+                if a.len() != 2 { panic!("D-Bus dict entry error"); }
+                let mut a = a.into_iter();
+                let key = box a.next().unwrap();
+                let value = box a.next().unwrap();
+                MessageItem::DictEntry(key, value)
+            }
+            ffi::DBUS_TYPE_VARIANT => {
+                let mut subiter = new_dbus_message_iter();
+                unsafe { ffi::dbus_message_iter_recurse(i, &mut subiter) };
+                let a = try!(MessageItem::from_iter(&mut subiter, r));
+                if a.len() != 1 { panic!("D-Bus variant error"); }
+                MessageItem::Variant(box a.into_iter().next().unwrap())
+            }
+            ffi::DBUS_TYPE_STRUCT => {
+                let mut subiter = new_dbus_message_iter();
+                unsafe { ffi::dbus_message_iter_recurse(i, &mut subiter) };
+                MessageItem::Struct(try!(MessageItem::from_iter(&mut subiter, r)))
+            }
+            ffi::DBUS_TYPE_ARRAY if unsafe { ffi::dbus_message_iter_get_element_type(i) } == ffi::DBUS_TYPE_DICT_ENTRY => {
+                // Get the types from the signature, so that they are known for empty dictionaries too.
+                // It is "a{kv}", where keys are always basic types and values can be anything.
+                let sig = try!(iter_signature(i));
+                let (kt, vt) = (sig.slice(2, 3).to_string(), sig.slice(3, sig.len() - 1).to_string());
+
+                let mut subiter = new_dbus_message_iter();
+                unsafe { ffi::dbus_message_iter_recurse(i, &mut subiter) };
+                let max = r.limits.max_array_len;
+                let a = try!(MessageItem::from_array_iter(&mut subiter, r, max, "Dictionary"));
+                let entries = a.into_iter().map(|e| match e {
+                    MessageItem::DictEntry(k, v) => (*k, *v),
+                    _ => panic!("D-Bus dict entry error"),
+                }).collect();
+                MessageItem::Dict(entries, kt, vt)
+            }
+            ffi::DBUS_TYPE_ARRAY => {
+                let t = try!(iter_signature(i)).slice_from(1).to_string();
+                let mut subiter = new_dbus_message_iter();
+                unsafe { ffi::dbus_message_iter_recurse(i, &mut subiter) };
+                let max = r.limits.max_array_len;
+                let a = try!(MessageItem::from_array_iter(&mut subiter, r, max, "Array"));
+                MessageItem::Array(a, t)
+            },
+            ffi::DBUS_TYPE_STRING | ffi::DBUS_TYPE_OBJECT_PATH | ffi::DBUS_TYPE_SIGNATURE => {
+                let mut c: *const libc::c_char = ptr::null();
+                let s = unsafe {
+                    let p: *mut libc::c_void = std::mem::transmute(&mut c);
+                    ffi::dbus_message_iter_get_basic(i, p);
+                    CString::new(c, false)
+                };
+                let b = s.as_bytes_no_nul();
+                if b.len() > r.limits.max_string_len {
+                    return Err(limits_exceeded(format!("String is {} bytes long, more than the maximum {}",
+                        b.len(), r.limits.max_string_len)));
+                }
+                try!(r.alloc(b.len()));
+                let s = match r.policy {
+                    Utf8Policy::Strict => match std::str::from_utf8(b) {
+                        Ok(s) => s.to_string(),
+                        Err(_) => return Err(Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs",
+                            "String argument is not valid UTF-8")),
+                    },
+                    Utf8Policy::Lossy => String::from_utf8_lossy(b).into_owned(),
+                };
+                match t {
+                    ffi::DBUS_TYPE_STRING => MessageItem::Str(s),
+                    ffi::DBUS_TYPE_OBJECT_PATH => MessageItem::ObjectPath(try!(Path::new(s.as_slice()))),
+                    _ => MessageItem::Signature(s),
+                }
+            },
+            ffi::DBUS_TYPE_BOOLEAN => MessageItem::Bool((iter_get_basic(i) as u32) != 0),
+            ffi::DBUS_TYPE_BYTE => MessageItem::Byte(iter_get_basic(i) as u8),
+            ffi::DBUS_TYPE_INT16 => MessageItem::Int16(iter_get_basic(i) as i16),
+            ffi::DBUS_TYPE_INT32 => MessageItem::Int32(iter_get_basic(i) as i32),
+            ffi::DBUS_TYPE_INT64 => MessageItem::Int64(iter_get_basic(i) as i64),
+            ffi::DBUS_TYPE_UINT16 => MessageItem::UInt16(iter_get_basic(i) as u16),
+            ffi::DBUS_TYPE_UINT32 => MessageItem::UInt32(iter_get_basic(i) as u32),
+            ffi::DBUS_TYPE_UINT64 => MessageItem::UInt64(iter_get_basic(i) as u64),
+            ffi::DBUS_TYPE_DOUBLE => MessageItem::Double(unsafe { std::mem::transmute(iter_get_basic(i)) }),
+            // libdbus returns a new duplicate here, so it is ours to close
+            ffi::DBUS_TYPE_UNIX_FD => MessageItem::UnixFd(unsafe { OwnedFd::new(iter_get_basic(i) as libc::c_int) }),
+
+            _ => { panic!("D-Bus unsupported message type {} ({})", t, t as u8 as char); }
+        })
+    }
+
+    /* Checks everything that can make appending fail, except for running out of memory. */
+    fn check_append(&self) -> Result<(), Error> {
+        match self {
+            &MessageItem::Str(ref s) => to_c_str(s.as_slice()).map(|_| ()),
+            &MessageItem::Signature(ref s) => {
+                // This also rejects NUL bytes, since the signature is cut off there
+                let c = try!(to_c_str(s.as_slice()));
+                let mut e = Error::empty();
+                if unsafe { ffi::dbus_signature_validate(c.as_ptr(), e.get_mut()) } == 0 { Err(e) } else { Ok(()) }
+            },
+            &MessageItem::Array(ref a, ref t) => {
+                let t = try!(array_elem_sig(a.as_slice(), t.as_slice()));
+                // Containers also need their contents to match, or libdbus aborts
+                for i in a.iter() {
+                    if !i.has_type(t.as_slice()) {
+                        let f = format!("Array element {} does not match array type {}", i, t);
+                        return Err(Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs", f.as_slice()));
+                    }
+                    try!(i.check_append());
+                }
+                Ok(())
+            },
+            &MessageItem::Variant(ref v) => v.check_append(),
+            &MessageItem::DictEntry(ref k, ref v) => { try!(k.check_append()); v.check_append() },
+            &MessageItem::Dict(ref a, ref kt, ref vt) => {
+                if a.len() == 0 && (kt.len() == 0 || vt.len() == 0) {
+                    return Err(Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs",
+                        "Cannot append an empty dictionary without key and value types"));
+                }
+                let sig = dict_entry_sig(a.as_slice(), kt.as_slice(), vt.as_slice());
+                for &(ref k, ref v) in a.iter() {
+                    if format!("{{{}{}}}", k.type_sig(), v.type_sig()) != sig {
+                        let f = format!("Dictionary entry {}: {} does not match dictionary type {}", k, v, sig);
+                        return Err(Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs", f.as_slice()));
+                    }
+                    try!(k.check_append());
+                    try!(v.check_append());
+                }
+                Ok(())
+            },
+            &MessageItem::Struct(ref a) => {
+                if a.len() == 0 {
+                    return Err(Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs", "Cannot append an empty struct"));
+                }
+                for i in a.iter() { try!(i.check_append()); }
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn iter_append_basic(&self, i: &mut ffi::DBusMessageIter, v: i64) -> Result<(), Error> {
         let t = self.array_type();
-        unsafe {
+        let r = unsafe {
             let p: *const libc::c_void = std::mem::transmute(&v);
-            ffi::dbus_message_iter_append_basic(i, t as libc::c_int, p);
-        }
+            ffi::dbus_message_iter_append_basic(i, t as libc::c_int, p)
+        };
+        if r == 0 { Err(no_memory()) } else { Ok(()) }
     }
 
-    fn iter_append(&self, i: &mut ffi::DBusMessageIter) {
+    fn iter_append(&self, i: &mut ffi::DBusMessageIter) -> Result<(), Error> {
         match self {
-            &MessageItem::Str(ref s) => unsafe {
-                let c = s.to_c_str();
-                let p = std::mem::transmute(&c);
-                ffi::dbus_message_iter_append_basic(i, ffi::DBUS_TYPE_STRING, p);
-            },
+            &MessageItem::Str(ref s) => iter_append_str(i, ffi::DBUS_TYPE_STRING, s.as_slice()),
+            &MessageItem::ObjectPath(ref s) => iter_append_str(i, ffi::DBUS_TYPE_OBJECT_PATH, s.as_str()),
+            &MessageItem::Signature(ref s) => iter_append_str(i, ffi::DBUS_TYPE_SIGNATURE, s.as_slice()),
             &MessageItem::Bool(b) => self.iter_append_basic(i, b as i64),
             &MessageItem::Byte(b) => self.iter_append_basic(i, b as i64),
             &MessageItem::Int16(b) => self.iter_append_basic(i, b as i64),
@@ -287,72 +750,283 @@ impl MessageItem {
             &MessageItem::UInt16(b) => self.iter_append_basic(i, b as i64),
             &MessageItem::UInt32(b) => self.iter_append_basic(i, b as i64),
             &MessageItem::UInt64(b) => self.iter_append_basic(i, b as i64),
-            &MessageItem::Array(ref b, t) => iter_append_array(i, b.as_slice(), t),
+            &MessageItem::Double(b) => self.iter_append_basic(i, unsafe { std::mem::transmute(b) }),
+            // libdbus duplicates the fd
+            &MessageItem::UnixFd(ref b) => self.iter_append_basic(i, b.as_raw_fd() as i64),
+            &MessageItem::Array(ref b, ref t) => iter_append_array(i, b.as_slice(), t.as_slice()),
             &MessageItem::Variant(ref b) => iter_append_variant(i, &**b),
             &MessageItem::DictEntry(ref k, ref v) => iter_append_dict(i, &**k, &**v),
+            &MessageItem::Dict(ref a, ref kt, ref vt) => iter_append_dict_items(i, a.as_slice(), kt.as_slice(), vt.as_slice()),
+            &MessageItem::Struct(ref a) => iter_append_struct(i, a.as_slice()),
         }
     }
 
-    fn copy_to_iter(i: &mut ffi::DBusMessageIter, v: &[MessageItem]) {
+    fn copy_to_iter(i: &mut ffi::DBusMessageIter, v: &[MessageItem]) -> Result<(), Error> {
         for item in v.iter() {
-            item.iter_append(i);
+            try!(item.iter_append(i));
+        }
+        Ok(())
+    }
+}
+
+/// An argument that may borrow its data, to avoid copying strings into `MessageItem`s.
+///
+/// Used with `Message::append_refs` to append string slices and existing items directly,
+/// and returned by `Message::get_item_refs`, where strings borrow from the message.
+#[deriving(Show, PartialEq, Clone)]
+pub enum MessageItemRef<'a> {
+    Str(&'a str),
+    Borrowed(&'a MessageItem),
+    Owned(MessageItem),
+}
+
+impl<'a> MessageItemRef<'a> {
+    pub fn to_item(&self) -> MessageItem {
+        match self {
+            &MessageItemRef::Str(s) => MessageItem::Str(s.to_string()),
+            &MessageItemRef::Borrowed(i) => i.clone(),
+            &MessageItemRef::Owned(ref i) => i.clone(),
+        }
+    }
+
+    /// The string, if this is a string argument (borrowed or not).
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            &MessageItemRef::Str(s) => Some(s),
+            &MessageItemRef::Borrowed(&MessageItem::Str(ref s)) => Some(s.as_slice()),
+            &MessageItemRef::Owned(MessageItem::Str(ref s)) => Some(s.as_slice()),
+            _ => None,
+        }
+    }
+
+    fn check_append(&self) -> Result<(), Error> {
+        match self {
+            &MessageItemRef::Str(s) => to_c_str(s).map(|_| ()),
+            &MessageItemRef::Borrowed(i) => i.check_append(),
+            &MessageItemRef::Owned(ref i) => i.check_append(),
+        }
+    }
+
+    fn iter_append(&self, i: &mut ffi::DBusMessageIter) -> Result<(), Error> {
+        match self {
+            &MessageItemRef::Str(s) => iter_append_str(i, ffi::DBUS_TYPE_STRING, s),
+            &MessageItemRef::Borrowed(b) => b.iter_append(i),
+            &MessageItemRef::Owned(ref b) => b.iter_append(i),
         }
     }
 }
 
 pub struct Message {
     msg: *mut ffi::DBusMessage,
+    limits: ParseLimits,
 }
 
 impl Message {
-    pub fn new_method_call(destination: &str, path: &str, iface: &str, method: &str) -> Option<Message> {
+    pub fn new_method_call(destination: &str, path: &str, iface: &str, method: &str) -> Result<Message, Error> {
         init_dbus();
-        let (d, p, i, m) = (destination.to_c_str(), path.to_c_str(), iface.to_c_str(), method.to_c_str());
+        try!(Path::new(path));
+        let (d, p, i, m) = (try!(to_c_str(destination)), try!(to_c_str(path)), try!(to_c_str(iface)), try!(to_c_str(method)));
         let ptr = unsafe {
             ffi::dbus_message_new_method_call(d.as_ptr(), p.as_ptr(), i.as_ptr(), m.as_ptr())
         };
-        if ptr == ptr::null_mut() { None } else { Some(Message { msg: ptr} ) }
+        Message::from_new_ptr(ptr)
     }
 
-    pub fn new_signal(path: &str, iface: &str, method: &str) -> Option<Message> {
+    pub fn new_signal(path: &str, iface: &str, method: &str) -> Result<Message, Error> {
         init_dbus();
-        let (p, i, m) = (path.to_c_str(), iface.to_c_str(), method.to_c_str());
+        try!(Path::new(path));
+        let (p, i, m) = (try!(to_c_str(path)), try!(to_c_str(iface)), try!(to_c_str(method)));
         let ptr = unsafe {
             ffi::dbus_message_new_signal(p.as_ptr(), i.as_ptr(), m.as_ptr())
         };
-        if ptr == ptr::null_mut() { None } else { Some(Message { msg: ptr} ) }
+        Message::from_new_ptr(ptr)
     }
 
-    pub fn new_method_return(m: &Message) -> Option<Message> {
+    pub fn new_method_return(m: &Message) -> Result<Message, Error> {
         let ptr = unsafe { ffi::dbus_message_new_method_return(m.msg) };
-        if ptr == ptr::null_mut() { None } else { Some(Message { msg: ptr} ) }
+        Message::from_new_ptr(ptr)
     }
 
-    pub fn new_error(m: &Message, error_name: &str, error_message: &str) -> Option<Message> {
-        let (en, em) = (error_name.to_c_str(), error_message.to_c_str());
+    /// Fails if `error_name` is not a valid error name, which would make libdbus abort.
+    pub fn new_error(m: &Message, error_name: &str, error_message: &str) -> Result<Message, Error> {
+        if !is_valid_interface_name(error_name) {
+            let f = format!("'{}' is not a valid error name", error_name);
+            return Err(Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs", f.as_slice()));
+        }
+        let (en, em) = (try!(to_c_str(error_name)), try!(to_c_str(error_message)));
         let ptr = unsafe { ffi::dbus_message_new_error(m.msg, en.as_ptr(), em.as_ptr()) };
-        if ptr == ptr::null_mut() { None } else { Some(Message { msg: ptr} ) }
+        Message::from_new_ptr(ptr)
+    }
+
+    /// A deep copy of the message, with headers and arguments, that can be changed and
+    /// sent on its own, e g to retry or relay a call. The copy has no serial, so sending
+    /// it gives it a new one.
+    pub fn duplicate(&self) -> Result<Message, Error> {
+        let mut m = try!(Message::from_new_ptr(unsafe { ffi::dbus_message_copy(self.msg as *const ffi::DBusMessage) }));
+        m.limits = self.limits;
+        Ok(m)
+    }
+
+    fn from_new_ptr(ptr: *mut ffi::DBusMessage) -> Result<Message, Error> {
+        if ptr == ptr::null_mut() { Err(Error::new_custom("org.freedesktop.DBus.Error.NoMemory", "Out of memory")) }
+        else { Ok(Message { msg: ptr, limits: std::default::Default::default() }) }
     }
 
     fn from_ptr(ptr: *mut ffi::DBusMessage, add_ref: bool) -> Message {
         if add_ref {
             unsafe { ffi::dbus_message_ref(ptr) };
         }
-        Message { msg: ptr }
+        Message { msg: ptr, limits: std::default::Default::default() }
     }
 
-    pub fn get_items(&mut self) -> Vec<MessageItem> {
+    /// Reads all arguments. Fails with InvalidArgs if a string argument is not valid UTF-8,
+    /// or with LimitsExceeded if the message is over its `ParseLimits`.
+    pub fn get_items(&mut self) -> Result<Vec<MessageItem>, Error> {
+        self.get_items_checked(Utf8Policy::Strict)
+    }
+
+    pub fn get_items_checked(&mut self, p: Utf8Policy) -> Result<Vec<MessageItem>, Error> {
         let mut i = new_dbus_message_iter();
         match unsafe { ffi::dbus_message_iter_init(self.msg, &mut i) } {
-            0 => Vec::new(),
-            _ => MessageItem::from_iter(&mut i)
+            0 => Ok(Vec::new()),
+            _ => MessageItem::from_iter(&mut i, &mut ReadState::new(p, self.limits))
         }
     }
 
-    pub fn append_items(&mut self, v: &[MessageItem]) {
+    /// Reads the arguments with top-level strings borrowed from the message, so reading
+    /// e g a bus name does not copy it. Strings must be valid UTF-8.
+    pub fn get_item_refs(&self) -> Result<Vec<MessageItemRef>, Error> {
+        let mut v = Vec::new();
+        let mut i = new_dbus_message_iter();
+        if unsafe { ffi::dbus_message_iter_init(self.msg, &mut i) } == 0 { return Ok(v) }
+        let mut r = ReadState::new(Utf8Policy::Strict, self.limits);
+        loop {
+            let t = unsafe { ffi::dbus_message_iter_get_arg_type(&mut i) };
+            if t == ffi::DBUS_TYPE_INVALID { return Ok(v) }
+            if t == ffi::DBUS_TYPE_STRING {
+                let mut c: *const libc::c_char = ptr::null();
+                unsafe { ffi::dbus_message_iter_get_basic(&mut i, std::mem::transmute(&mut c)) };
+                let len = unsafe { libc::strlen(c) } as uint;
+                if len > r.limits.max_string_len {
+                    return Err(limits_exceeded(format!("String is {} bytes long, more than the maximum {}",
+                        len, r.limits.max_string_len)));
+                }
+                match self.borrowed_str(c) {
+                    Some(s) => v.push(MessageItemRef::Str(s)),
+                    None => return Err(Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs",
+                        "String argument is not valid UTF-8")),
+                }
+            }
+            else { v.push(MessageItemRef::Owned(try!(MessageItem::from_iter_single(&mut i, &mut r, t)))); }
+            unsafe { ffi::dbus_message_iter_next(&mut i) };
+        }
+    }
+
+    /// Sets limits for reading this message's arguments, see `ParseLimits`.
+    pub fn set_parse_limits(&mut self, l: ParseLimits) { self.limits = l; }
+
+    /// Like `append_items`, but returns an error instead of failing if the items cannot be appended,
+    /// e g because a string contains a NUL byte.
+    ///
+    /// The items are checked before anything is appended, so nothing is appended if one
+    /// of them is invalid. If appending fails after that, e g because of running out of
+    /// memory, the message is left unusable and must be discarded.
+    pub fn try_append_items(&mut self, v: &[MessageItem]) -> Result<(), Error> {
+        for item in v.iter() { try!(item.check_append()); }
         let mut i = new_dbus_message_iter();
         unsafe { ffi::dbus_message_iter_init_append(self.msg, &mut i) };
-        MessageItem::copy_to_iter(&mut i, v);
+        MessageItem::copy_to_iter(&mut i, v)
+    }
+
+    /// Like `try_append_items`, but string slices and borrowed items are appended without
+    /// first being copied into owned `MessageItem`s.
+    pub fn append_refs(&mut self, v: &[MessageItemRef]) -> Result<(), Error> {
+        for item in v.iter() { try!(item.check_append()); }
+        let mut i = new_dbus_message_iter();
+        unsafe { ffi::dbus_message_iter_init_append(self.msg, &mut i) };
+        for item in v.iter() { try!(item.iter_append(&mut i)); }
+        Ok(())
+    }
+
+    pub fn append_items(&mut self, v: &[MessageItem]) {
+        if let Err(e) = self.try_append_items(v) { panic!("{}", e) }
+    }
+
+    /// Stages arguments in an `ArgBuilder`, and appends them only if `f` succeeds.
+    ///
+    /// The staged arguments are checked before anything is appended, so that either all
+    /// of them end up in the message, or none of them.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// m.append_transaction(|b| {
+    ///     b.arg("Hello".to_string());
+    ///     b.item(try!(make_item()));
+    ///     Ok(())
+    /// }).unwrap();
+    /// ```
+    pub fn append_transaction<F>(&mut self, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut arg::ArgBuilder) -> Result<(), Error>
+    {
+        let mut b = arg::ArgBuilder::new();
+        try!(f(&mut b));
+        self.try_append_items(b.items())
+    }
+
+    /// Appends an argument directly from a Rust type, see the `iter` module.
+    ///
+    /// If appending fails inside a container, the message is left unusable and must be
+    /// discarded, like with `try_append_items`.
+    pub fn append1<A: iter::Append>(&mut self, a: A) -> Result<(), Error> {
+        iter::IterAppend::new(self).append(a)
+    }
+
+    pub fn append2<A: iter::Append, B: iter::Append>(&mut self, a: A, b: B) -> Result<(), Error> {
+        let mut i = iter::IterAppend::new(self);
+        try!(i.append(a));
+        i.append(b)
+    }
+
+    pub fn append3<A: iter::Append, B: iter::Append, C: iter::Append>(&mut self, a: A, b: B, c: C) -> Result<(), Error> {
+        let mut i = iter::IterAppend::new(self);
+        try!(i.append(a));
+        try!(i.append(b));
+        i.append(c)
+    }
+
+    /// Reads the first argument directly into a Rust type, see the `iter` module.
+    pub fn read1<A: iter::Get>(&self) -> Result<A, arg::TypeMismatch> {
+        iter::Iter::new(self).read()
+    }
+
+    pub fn read2<A: iter::Get, B: iter::Get>(&self) -> Result<(A, B), arg::TypeMismatch> {
+        let mut i = iter::Iter::new(self);
+        let a = try!(i.read());
+        Ok((a, try!(i.read())))
+    }
+
+    pub fn read3<A: iter::Get, B: iter::Get, C: iter::Get>(&self) -> Result<(A, B, C), arg::TypeMismatch> {
+        let mut i = iter::Iter::new(self);
+        let a = try!(i.read());
+        let b = try!(i.read());
+        Ok((a, b, try!(i.read())))
+    }
+
+    /// Reads all arguments into a tuple of Rust types, returns None if they do not match.
+    pub fn read_args<L: arg::ArgList>(&mut self, o: &arg::ReadOptions) -> Option<L> {
+        self.get_items().ok().and_then(|i| arg::ArgList::read(i.as_slice(), o))
+    }
+
+    /// The signatures of the top-level arguments, e g ["s", "a{sv}"], without reading their values.
+    pub fn arg_types(&self) -> Vec<String> {
+        let mut v = Vec::new();
+        let mut i = iter::Iter::new(self);
+        while i.arg_type() != ffi::DBUS_TYPE_INVALID {
+            v.push(i.signature());
+            i.advance();
+        }
+        v
     }
 
     pub fn msg_type(&self) -> MessageType {
@@ -364,6 +1038,141 @@ impl Message {
         c_str_to_slice(&s).map(|s| s.to_string())
     }
 
+    /// The signature of the message's arguments, e g "su".
+    pub fn signature(&self) -> String {
+        let s = unsafe { ffi::dbus_message_get_signature(self.msg) };
+        c_str_to_slice(&s).unwrap_or("").to_string()
+    }
+
+    /// An upper bound of the size of this message when sent, see `size::message_size_bound`.
+    /// Fails if the arguments cannot be read.
+    pub fn estimated_size(&mut self) -> Result<uint, Error> {
+        let items = try!(self.get_items_checked(Utf8Policy::Lossy));
+        Ok(self.size_bound(size::body_size(items.as_slice())))
+    }
+
+    fn size_bound(&self, body: uint) -> uint {
+        let (_, p, i, m) = self.headers();
+        let (d, s) = (self.destination(), self.sender());
+        size::message_size_bound(p.as_ref().map(|x| x.as_slice()), i.as_ref().map(|x| x.as_slice()),
+            m.as_ref().map(|x| x.as_slice()), d.as_ref().map(|x| x.as_slice()),
+            s.as_ref().map(|x| x.as_slice()), self.signature().as_slice(), body)
+    }
+
+    /// Fails with LimitsExceeded if the message might be too large for the bus daemon.
+    pub fn check_size(&mut self) -> Result<uint, Error> {
+        let n = try!(self.estimated_size());
+        if n <= size::MAX_MESSAGE_SIZE { return Ok(n) };
+        let f = format!("Message size ({} bytes) exceeds the maximum of {} bytes", n, size::MAX_MESSAGE_SIZE);
+        Err(Error::new_custom("org.freedesktop.DBus.Error.LimitsExceeded", f.as_slice()))
+    }
+
+    /// Checks the message against the protocol limits, such as the maximum name length
+    /// and nesting depth, and returns its estimated size. The bus daemon disconnects
+    /// clients that send messages exceeding them. Arguments that cannot be read
+    /// fail with `LimitViolation::UnreadableBody`, since they cannot be checked.
+    pub fn check_limits(&mut self) -> Result<uint, size::LimitViolation> {
+        let (_, p, i, m) = self.headers();
+        let d = self.destination();
+        let items = try!(self.get_items_checked(Utf8Policy::Lossy).map_err(|e|
+            size::LimitViolation::UnreadableBody(e.message().unwrap_or("").to_string())));
+        try!(size::check_limits(&[p.as_ref().map(|x| x.as_slice()), i.as_ref().map(|x| x.as_slice()),
+            m.as_ref().map(|x| x.as_slice()), d.as_ref().map(|x| x.as_slice())], items.as_slice()));
+        let n = self.size_bound(size::body_size(items.as_slice()));
+        if n > size::MAX_MESSAGE_SIZE { Err(size::LimitViolation::MessageTooLarge(n)) } else { Ok(n) }
+    }
+
+    /// The serial, or 0 if the message has not been sent.
+    pub fn serial(&self) -> u32 {
+        unsafe { ffi::dbus_message_get_serial(self.msg) }
+    }
+
+    /// Normally the connection sets the serial when sending, but `from_bytes` needs one.
+    /// Sending assigns no new serial to a message that already has one.
+    pub fn set_serial(&mut self, serial: u32) {
+        unsafe { ffi::dbus_message_set_serial(self.msg, serial) }
+    }
+
+    /// The message in the D-Bus wire format, e g for other transports or for storing it.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut p: *mut libc::c_char = ptr::null_mut();
+        let mut len: libc::c_int = 0;
+        if unsafe { ffi::dbus_message_marshal(self.msg, &mut p, &mut len) } == 0 { return Err(no_memory()) }
+        let v = unsafe {
+            let s: &[u8] = std::mem::transmute(std::raw::Slice { data: p as *const u8, len: len as uint });
+            s.to_vec()
+        };
+        unsafe { ffi::dbus_free(p as *mut libc::c_void) };
+        Ok(v)
+    }
+
+    /// Reads a message in the wire format. This fails for invalid data, including
+    /// messages with serial 0, which is what unsent messages have; see `set_serial`.
+    pub fn from_bytes(b: &[u8]) -> Result<Message, Error> {
+        init_dbus();
+        let mut e = Error::empty();
+        let ptr = unsafe { ffi::dbus_message_demarshal(b.as_ptr() as *const libc::c_char, b.len() as libc::c_int, e.get_mut()) };
+        if ptr == ptr::null_mut() { Err(e) } else { Ok(Message::from_ptr(ptr, false)) }
+    }
+
+    pub fn get_no_reply(&self) -> bool {
+        unsafe { ffi::dbus_message_get_no_reply(self.msg) != 0 }
+    }
+
+    pub fn set_no_reply(&mut self, no_reply: bool) {
+        unsafe { ffi::dbus_message_set_no_reply(self.msg, no_reply as u32) }
+    }
+
+    /// True if the message carries unix file descriptors.
+    pub fn contains_unix_fds(&self) -> bool {
+        unsafe { ffi::dbus_message_contains_unix_fds(self.msg) != 0 }
+    }
+
+    /// The number of unix file descriptors among the message's arguments.
+    pub fn unix_fd_count(&self) -> uint {
+        fn count(i: &mut iter::Iter) -> uint {
+            let mut n = 0;
+            loop {
+                match i.arg_type() {
+                    ffi::DBUS_TYPE_INVALID => return n,
+                    ffi::DBUS_TYPE_UNIX_FD => n += 1,
+                    t => if let Some(mut sub) = i.recurse(t) { n += count(&mut sub) },
+                }
+                i.advance();
+            }
+        }
+        if !self.contains_unix_fds() { return 0 }
+        count(&mut iter::Iter::new(self))
+    }
+
+    pub fn destination(&self) -> Option<String> {
+        let s = unsafe { ffi::dbus_message_get_destination(self.msg) };
+        c_str_to_slice(&s).map(|s| s.to_string())
+    }
+
+    fn borrowed_str<'a>(&'a self, p: *const libc::c_char) -> Option<&'a str> {
+        // The string is owned by the message, so it lives as long as the message does.
+        c_str_to_slice(&p).map(|s| unsafe { std::mem::transmute::<&str, &'a str>(s) })
+    }
+
+    /// Like the path in `headers`, but borrowed from the message instead of copied.
+    pub fn path(&self) -> Option<&str> {
+        self.borrowed_str(unsafe { ffi::dbus_message_get_path(self.msg) })
+    }
+
+    pub fn interface(&self) -> Option<&str> {
+        self.borrowed_str(unsafe { ffi::dbus_message_get_interface(self.msg) })
+    }
+
+    pub fn member(&self) -> Option<&str> {
+        self.borrowed_str(unsafe { ffi::dbus_message_get_member(self.msg) })
+    }
+
+    /// Like `signature`, but borrowed from the message instead of copied.
+    pub fn signature_str(&self) -> &str {
+        self.borrowed_str(unsafe { ffi::dbus_message_get_signature(self.msg) }).unwrap_or("")
+    }
+
     pub fn headers(&self) -> (MessageType, Option<String>, Option<String>, Option<String>) {
         let p = unsafe { ffi::dbus_message_get_path(self.msg) };
         let i = unsafe { ffi::dbus_message_get_interface(self.msg) };
@@ -416,18 +1225,101 @@ impl<'a> Iterator<ConnectionItem> for ConnectionItems<'a> {
             let r = unsafe { ffi::dbus_connection_read_write_dispatch(self.c.conn(), self.timeout_ms as libc::c_int) };
             if !self.c.i.pending_items.borrow().is_empty() { continue };
 
-            if r == 0 { return None; }
+            if r == 0 {
+                if !self.c.i.reconnect.get() { return None; }
+                if self.c.reconnect().is_err() {
+                    // The bus is not back yet; wait instead of spinning on a dead connection.
+                    sleep_ms(if self.timeout_ms >= 0 { self.timeout_ms as uint } else { 1000 });
+                }
+            }
             return Some(ConnectionItem::Nothing);
         }
     }
 }
 
+#[cfg(feature = "fault-injection")]
+type FaultState = RefCell<fault::FaultPlan>;
+#[cfg(not(feature = "fault-injection"))]
+type FaultState = ();
+
+#[cfg(feature = "fault-injection")]
+fn no_faults() -> FaultState { RefCell::new(fault::FaultPlan::new()) }
+#[cfg(not(feature = "fault-injection"))]
+fn no_faults() -> FaultState { () }
+
+#[cfg(feature = "fault-injection")]
+fn injected_no_reply() -> Error {
+    Error::new_custom("org.freedesktop.DBus.Error.NoReply", "Did not receive a reply (injected fault)")
+}
+
+fn sleep_ms(ms: uint) {
+    std::io::timer::sleep(std::time::Duration::milliseconds(ms as i64));
+}
+
 /* Since we register callbacks with userdata pointers,
    we need to make sure the connection pointer does not move around.
    Hence this extra indirection. */
 struct IConnection {
     conn: Cell<*mut ffi::DBusConnection>,
     pending_items: RefCell<DList<ConnectionItem>>,
+    signal_routes: RefCell<HashMap<(String, String), SignalRoutes>>,
+    parse_limits: Cell<ParseLimits>,
+    name_lost_cbs: RefCell<Vec<(String, Box<FnMut(&str) + 'static>)>>,
+    faults: FaultState,
+    stats: Cell<ConnectionStats>,
+    validate_outgoing: Cell<bool>,
+    limits: Cell<Limits>,
+    bus: BusType,
+    reconnect: Cell<bool>,
+    restore: RefCell<RestoreState>,
+    reconnected_cbs: RefCell<Vec<Box<FnMut(&Connection) + 'static>>>,
+    deferred: RefCell<HashMap<u32, Message>>,
+    next_deferred: Cell<u32>,
+    // By unique name, until NameOwnerChanged says the name is gone
+    credentials: RefCell<HashMap<String, Credentials>>,
+    credentials_watched: Cell<bool>,
+}
+
+/* What to set up again on a new connection after a reconnect. */
+#[deriving(Default)]
+struct RestoreState {
+    names: Vec<(String, u32)>,
+    match_rules: Vec<String>,
+    object_paths: Vec<String>,
+    fallbacks: Vec<String>,
+}
+
+/// Counters for messages dispatched to a connection's filter, see `Connection::stats`.
+#[deriving(Show, Copy, Clone, PartialEq, Default)]
+pub struct ConnectionStats {
+    pub messages_received: u64,
+    /// Messages that carried unix file descriptors.
+    pub messages_with_unix_fds: u64,
+    /// The total number of unix file descriptors received.
+    pub unix_fds_received: u64,
+}
+
+/// A method call kept by `Connection::defer_reply`, to be replied to with `Connection::reply`.
+///
+/// Tokens can be sent to other threads, e g together with the work the reply depends on.
+#[deriving(Show, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ReplyToken(u32);
+
+/// What the bus knows about the owner of a name, see `Connection::get_connection_credentials`.
+#[deriving(Show, Clone, PartialEq)]
+pub struct Credentials {
+    /// The name that was asked about.
+    pub name: String,
+    pub unix_user_id: Option<u32>,
+    pub process_id: Option<u32>,
+}
+
+/* Signal filtering state for one interface and member. Lookups are by hash,
+   so thousands of watched arg0 values cost no more per signal than one. */
+#[deriving(Default)]
+struct SignalRoutes {
+    predicates: Vec<Box<Fn(&[MessageItem]) -> bool + 'static>>,
+    arg0_watches: HashMap<String, uint>,
 }
 
 pub struct Connection {
@@ -437,14 +1329,27 @@ pub struct Connection {
 extern "C" fn filter_message_cb(conn: *mut ffi::DBusConnection, msg: *mut ffi::DBusMessage,
     user_data: *mut libc::c_void) -> ffi::DBusHandlerResult {
 
-    let m = Message::from_ptr(msg, true);
+    let mut m = Message::from_ptr(msg, true);
     let c = Connection { i: unsafe { std::mem::transmute(user_data) } };
+    m.set_parse_limits(c.i.parse_limits.get());
     assert_eq!(c.conn(), conn);
 
+    let mut stats = c.i.stats.get();
+    stats.messages_received += 1;
+    if m.contains_unix_fds() {
+        stats.messages_with_unix_fds += 1;
+        stats.unix_fds_received += m.unix_fd_count() as u64;
+    }
+    c.i.stats.set(stats);
+
     let mtype: ffi::DBusMessageType = unsafe { std::mem::transmute(ffi::dbus_message_get_type(msg)) };
     let r = match mtype {
         ffi::DBusMessageType::Signal => {
-            c.i.pending_items.borrow_mut().push_back(ConnectionItem::Signal(m));
+            c.handle_name_lost(&mut m);
+            c.handle_name_owner_changed(&mut m);
+            if !c.is_credentials_signal(&m) && c.signal_accepted(&mut m) {
+                c.i.pending_items.borrow_mut().push_back(ConnectionItem::Signal(m));
+            }
             ffi::DBusHandlerResult::Handled
         }
         _ => ffi::DBusHandlerResult::NotYetHandled,
@@ -467,8 +1372,9 @@ extern "C" fn object_path_message_cb(_: *mut ffi::DBusConnection, _: *mut ffi::D
 extern "C" fn object_path_message_cb(conn: *mut ffi::DBusConnection, msg: *mut ffi::DBusMessage,
     user_data: *mut libc::c_void) -> ffi::DBusHandlerResult {
 
-    let m = Message::from_ptr(msg, true);
+    let mut m = Message::from_ptr(msg, true);
     let c = Connection { i: unsafe { std::mem::transmute(user_data) } };
+    m.set_parse_limits(c.i.parse_limits.get());
     assert!(c.conn() == conn);
     c.i.pending_items.borrow_mut().push_back(ConnectionItem::MethodCall(m));
     unsafe { std::mem::forget(c) };
@@ -488,17 +1394,121 @@ impl Connection {
         if conn == ptr::null_mut() {
             return Err(e)
         }
-        let c = Connection { i: box IConnection { conn: Cell::new(conn), pending_items: RefCell::new(DList::new()) } };
+        let c = Connection { i: box IConnection { conn: Cell::new(conn), pending_items: RefCell::new(DList::new()),
+            signal_routes: RefCell::new(HashMap::new()), faults: no_faults(),
+            stats: Cell::new(std::default::Default::default()), validate_outgoing: Cell::new(false), name_lost_cbs: RefCell::new(Vec::new()),
+            parse_limits: Cell::new(std::default::Default::default()), limits: Cell::new(std::default::Default::default()), bus: bus, reconnect: Cell::new(false),
+            restore: RefCell::new(std::default::Default::default()), reconnected_cbs: RefCell::new(Vec::new()),
+            deferred: RefCell::new(HashMap::new()), next_deferred: Cell::new(0),
+            credentials: RefCell::new(HashMap::new()), credentials_watched: Cell::new(false) } };
+        c.setup(conn);
+        Ok(c)
+    }
 
+    fn setup(&self, conn: *mut ffi::DBusConnection) {
         /* No, we don't want our app to suddenly quit if dbus goes down */
         unsafe { ffi::dbus_connection_set_exit_on_disconnect(conn, 0) };
         assert!(unsafe {
-            ffi::dbus_connection_add_filter(c.conn(), Some(filter_message_cb as ffi::DBusCallback), std::mem::transmute(&*c.i), None)
+            ffi::dbus_connection_add_filter(conn, Some(filter_message_cb as ffi::DBusCallback), std::mem::transmute(&*self.i), None)
         } != 0);
-        Ok(c)
+        self.apply_limits(conn);
+    }
+
+    fn apply_limits(&self, conn: *mut ffi::DBusConnection) {
+        let l = self.i.limits.get();
+        unsafe {
+            if let Some(n) = l.max_message_size { ffi::dbus_connection_set_max_message_size(conn, n as libc::c_long) }
+            if let Some(n) = l.max_received_size { ffi::dbus_connection_set_max_received_size(conn, n as libc::c_long) }
+            if let Some(n) = l.max_message_unix_fds { ffi::dbus_connection_set_max_message_unix_fds(conn, n as libc::c_long) }
+            if let Some(n) = l.max_received_unix_fds { ffi::dbus_connection_set_max_received_unix_fds(conn, n as libc::c_long) }
+        }
+    }
+
+    /// Bounds the memory used for messages from the peer, e g when serving untrusted clients.
+    /// Peers sending messages over the limits are disconnected by libdbus.
+    ///
+    /// The limits are kept when reconnecting.
+    pub fn set_limits(&self, l: Limits) {
+        self.i.limits.set(l);
+        self.apply_limits(self.conn());
+    }
+
+    /// The limits in effect, including libdbus defaults for those not set with `set_limits`.
+    pub fn limits(&self) -> Limits {
+        let c = self.conn();
+        unsafe { Limits {
+            max_message_size: Some(ffi::dbus_connection_get_max_message_size(c) as uint),
+            max_received_size: Some(ffi::dbus_connection_get_max_received_size(c) as uint),
+            max_message_unix_fds: Some(ffi::dbus_connection_get_max_message_unix_fds(c) as uint),
+            max_received_unix_fds: Some(ffi::dbus_connection_get_max_received_unix_fds(c) as uint),
+        } }
+    }
+
+    /// Makes `iter` reconnect when the connection to the bus is lost, e g because the bus
+    /// was restarted, instead of ending.
+    ///
+    /// On the new connection, the names, match rules and object paths registered through this
+    /// connection are set up again, and then the `on_reconnected` callbacks are called.
+    /// Note that the unique name changes, and that signal predicates and pending method
+    /// calls are not affected.
+    pub fn set_reconnect(&self, enable: bool) {
+        self.i.reconnect.set(enable);
+    }
+
+    /// Registers a callback that is called after a successful reconnect, see `set_reconnect`.
+    /// This is the place to re-export state that lives outside this connection.
+    pub fn on_reconnected<F>(&self, f: F) where F: FnMut(&Connection) + 'static {
+        self.i.reconnected_cbs.borrow_mut().push(box f);
+    }
+
+    /// Opens a new connection to the bus and restores names, match rules and object paths on it.
+    ///
+    /// This is done automatically by `iter` if `set_reconnect` is enabled.
+    pub fn reconnect(&self) -> Result<(), Error> {
+        let mut e = Error::empty();
+        let conn = unsafe { ffi::dbus_bus_get_private(self.i.bus, e.get_mut()) };
+        if conn == ptr::null_mut() {
+            return Err(e)
+        }
+        let old = self.conn();
+        unsafe {
+            ffi::dbus_connection_remove_filter(old, Some(filter_message_cb as ffi::DBusCallback), std::mem::transmute(&*self.i));
+            ffi::dbus_connection_close(old);
+            ffi::dbus_connection_unref(old);
+        }
+        self.i.conn.set(conn);
+        self.setup(conn);
+        // Unique names are only valid on the connection they were seen on, and the
+        // callers of deferred calls cannot get a reply through the new one
+        self.i.credentials.borrow_mut().clear();
+        self.i.deferred.borrow_mut().clear();
+        self.i.credentials_watched.set(false);
+
+        // Best effort: one name or rule failing should not prevent restoring the others.
+        let (names, rules, paths, fallbacks) = {
+            let r = self.i.restore.borrow();
+            (r.names.clone(), r.match_rules.clone(), r.object_paths.clone(), r.fallbacks.clone())
+        };
+        for p in paths.iter() { let _ = self.register_object_path_raw(p.as_slice(), false); }
+        for p in fallbacks.iter() { let _ = self.register_object_path_raw(p.as_slice(), true); }
+        for &(ref n, flags) in names.iter() { let _ = self.request_name_raw(n.as_slice(), flags); }
+        for r in rules.iter() { let _ = self.add_match_raw(r.as_slice()); }
+
+        // Callbacks may register more callbacks, so do not hold the borrow while calling them.
+        let mut cbs = std::mem::replace(&mut *self.i.reconnected_cbs.borrow_mut(), vec!());
+        for cb in cbs.iter_mut() { (**cb)(self) }
+        let mut r = self.i.reconnected_cbs.borrow_mut();
+        let added = std::mem::replace(&mut *r, vec!());
+        *r = cbs;
+        r.extend(added.into_iter());
+        Ok(())
     }
 
-    pub fn send_with_reply_and_block(&self, message: Message, timeout_ms: int) -> Result<Message, Error> {
+    pub fn send_with_reply_and_block(&self, mut message: Message, timeout_ms: int) -> Result<Message, Error> {
+        if self.i.validate_outgoing.get() {
+            if let Err(v) = message.check_limits() { return Err(v.to_error()) }
+        }
+        let delay = try!(self.inject_fault_before_call(&message, timeout_ms));
         let mut e = Error::empty();
         let response = unsafe {
             ffi::dbus_connection_send_with_reply_and_block(self.conn(), message.msg, timeout_ms as libc::c_int, e.get_mut())
@@ -506,16 +1516,201 @@ impl Connection {
         if response == ptr::null_mut() {
             return Err(e);
         }
-        Ok(Message::from_ptr(response, false))
+        let mut m = Message::from_ptr(response, false);
+        m.set_parse_limits(self.i.parse_limits.get());
+        if let Some(ms) = delay { sleep_ms(ms) }
+        Ok(m)
+    }
+
+    /// Makes `send_with_reply_and_block` fail or slow down for matching calls, for testing
+    /// how the application handles misbehaving services. See `fault::FaultPlan::add`.
+    ///
+    /// Only available with the "fault-injection" feature.
+    #[cfg(feature = "fault-injection")]
+    pub fn inject_fault(&self, iface: Option<&str>, member: Option<&str>, f: fault::Fault, times: Option<uint>) {
+        self.i.faults.borrow_mut().add(iface, member, f, times);
+    }
+
+    #[cfg(feature = "fault-injection")]
+    pub fn clear_faults(&self) {
+        self.i.faults.borrow_mut().clear();
+    }
+
+    /* Applies the fault planned for the call, if any. Returns how long to delay the reply. */
+    #[cfg(feature = "fault-injection")]
+    fn inject_fault_before_call(&self, m: &Message, timeout_ms: int) -> Result<Option<uint>, Error> {
+        match self.i.faults.borrow_mut().take(m) {
+            Some(fault::Fault::NoReply) => Err(injected_no_reply()),
+            Some(fault::Fault::Error(ref n, ref m)) => Err(Error::new_custom(n.as_slice(), m.as_slice())),
+            Some(fault::Fault::Disconnect) => { unsafe { ffi::dbus_connection_close(self.conn()) }; Ok(None) }
+            Some(fault::Fault::Delay(ms)) if timeout_ms >= 0 && ms as int >= timeout_ms => {
+                sleep_ms(timeout_ms as uint);
+                Err(injected_no_reply())
+            }
+            Some(fault::Fault::Delay(ms)) => Ok(Some(ms)),
+            None => Ok(None),
+        }
     }
 
-    pub fn send(&self, message: Message) -> Result<(),()> {
+    #[cfg(not(feature = "fault-injection"))]
+    fn inject_fault_before_call(&self, _: &Message, _: int) -> Result<Option<uint>, Error> { Ok(None) }
+
+    pub fn send(&self, mut message: Message) -> Result<(),()> {
+        if self.i.validate_outgoing.get() && message.check_limits().is_err() { return Err(()) }
         let r = unsafe { ffi::dbus_connection_send(self.conn(), message.msg, ptr::null_mut()) };
         if r == 0 { return Err(()); }
         unsafe { ffi::dbus_connection_flush(self.conn()) };
         Ok(())
     }
 
+    /// Keeps the method call `m` so that it can be replied to later with `reply`, after the
+    /// handler has returned.
+    pub fn defer_reply(&self, m: &Message) -> ReplyToken {
+        let t = self.i.next_deferred.get() + 1;
+        self.i.next_deferred.set(t);
+        self.i.deferred.borrow_mut().insert(t, Message::from_ptr(m.msg, true));
+        ReplyToken(t)
+    }
+
+    /// Sends the method return or error for a method call kept with `defer_reply`.
+    ///
+    /// Fails if the token has already been replied to, or if the connection has been
+    /// reconnected since the call was kept, since the caller can no longer get the reply.
+    /// Items that cannot be appended are replied to with a Failed error instead. If not even
+    /// that can be built, the call is kept and the token can be used again.
+    pub fn reply(&self, token: ReplyToken, r: tree::MethodResult) -> Result<(), ()> {
+        let m = match self.i.deferred.borrow_mut().remove(&token.0) { Some(m) => m, None => return Err(()) };
+        if m.get_no_reply() { return Ok(()) }
+        let r = match r { Ok(ref items) => Ok(items.as_slice()), Err(ref e) => Err((e.errorname(), e.description())) };
+        let reply = match method_result_reply(&m, r) {
+            Ok(reply) => reply,
+            Err(_) => { self.i.deferred.borrow_mut().insert(token.0, m); return Err(()) }
+        };
+        self.send(reply)
+    }
+
+    /// Checks outgoing messages with `Message::check_limits` before sending them, so that
+    /// a message exceeding the protocol limits fails locally instead of getting the
+    /// connection dropped by the bus. This costs reading the arguments of every message.
+    pub fn set_validate_outgoing(&self, enable: bool) {
+        self.i.validate_outgoing.set(enable);
+    }
+
+    /// Sets limits for reading the arguments of messages received on this connection.
+    pub fn set_parse_limits(&self, l: ParseLimits) {
+        self.i.parse_limits.set(l);
+    }
+
+    /// Counters for the incoming messages seen so far, e g to find leaks of passed file descriptors.
+    pub fn stats(&self) -> ConnectionStats { self.i.stats.get() }
+
+    pub fn is_connected(&self) -> bool {
+        unsafe { ffi::dbus_connection_get_is_connected(self.conn()) != 0 }
+    }
+
+    /// Whether values of type `t` can be sent on this connection, e g 'h' for unix file
+    /// descriptors, which need a unix socket and a peer that supports them.
+    pub fn can_send_type(&self, t: char) -> bool {
+        unsafe { ffi::dbus_connection_can_send_type(self.conn(), t as libc::c_int) != 0 }
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        unsafe { ffi::dbus_connection_get_is_authenticated(self.conn()) != 0 }
+    }
+
+    /// True if the connection was authenticated as anonymous, which peer-to-peer
+    /// servers may allow.
+    pub fn is_anonymous(&self) -> bool {
+        unsafe { ffi::dbus_connection_get_is_anonymous(self.conn()) != 0 }
+    }
+
+    /// The globally unique ID of the server (or bus) on the other end, once authenticated.
+    ///
+    /// A server keeps its ID for its whole lifetime, so this can be used to tell whether
+    /// a new connection reached the same instance as an earlier one.
+    pub fn server_id(&self) -> Option<String> {
+        let s = unsafe { ffi::dbus_connection_get_server_id(self.conn()) };
+        if s == ptr::null_mut() { return None; }
+        let r = c_str_to_slice(&(s as *const libc::c_char)).map(|s| s.to_string());
+        unsafe { ffi::dbus_free(s as *mut libc::c_void) };
+        r
+    }
+
+    /// The user ID of the process on the other end, if known. On a bus connection this
+    /// is the user of the bus daemon.
+    pub fn unix_user(&self) -> Option<u32> {
+        let mut uid = 0;
+        if unsafe { ffi::dbus_connection_get_unix_user(self.conn(), &mut uid) } == 0 { None }
+        else { Some(uid as u32) }
+    }
+
+    /// The process ID of the process on the other end, if known.
+    pub fn unix_process_id(&self) -> Option<u32> {
+        let mut pid = 0;
+        if unsafe { ffi::dbus_connection_get_unix_process_id(self.conn(), &mut pid) } == 0 { None }
+        else { Some(pid as u32) }
+    }
+
+    /// Asks the bus for the Linux security label (e g the SELinux context or AppArmor
+    /// profile) of the connection owning `name`, via GetConnectionCredentials.
+    ///
+    /// Returns None if the bus does not know the label. The trailing NUL byte is removed.
+    pub fn get_security_label(&self, name: &str) -> Result<Option<Vec<u8>>, Error> {
+        let dict = try!(self.connection_credentials(name));
+        for &(ref k, ref v) in dict.iter() {
+            if *k != MessageItem::Str("LinuxSecurityLabel".to_string()) { continue }
+            if let &MessageItem::Variant(ref v) = v {
+                if let MessageItem::Array(ref a, _) = **v {
+                    let mut label: Vec<u8> = a.iter().filter_map(|b| match b { &MessageItem::Byte(b) => Some(b), _ => None }).collect();
+                    if label.last() == Some(&0) { label.pop(); }
+                    return Ok(Some(label));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Asks the bus for the unix user and process id of the connection owning `name`,
+    /// via GetConnectionCredentials.
+    ///
+    /// The credentials of unique names (e g ":1.42") are kept until the name's owner
+    /// disconnects, so only the first call for each blocks. To notice that, a match rule
+    /// for NameOwnerChanged is added. Its signals are not returned from `iter`, unless a
+    /// rule added with `add_match` matches them too.
+    pub fn get_connection_credentials(&self, name: &str) -> Result<Credentials, Error> {
+        let unique = name.starts_with(":");
+        if unique {
+            if let Some(cr) = self.i.credentials.borrow().get(name) { return Ok(cr.clone()) }
+        }
+        let dict = try!(self.connection_credentials(name));
+        let get = |&: key: &str| dict.iter().filter(|e| e.0.as_str() == Some(key))
+            .filter_map(|e| e.1.inner::<u32>()).next();
+        let cr = Credentials { name: name.to_string(), unix_user_id: get("UnixUserID"), process_id: get("ProcessID") };
+        if unique && self.watch_name_owners() {
+            self.i.credentials.borrow_mut().insert(name.to_string(), cr.clone());
+        }
+        Ok(cr)
+    }
+
+    // Without the match rule, cached credentials could outlive their owner, so nothing is cached.
+    fn watch_name_owners(&self) -> bool {
+        if self.i.credentials_watched.get() { return true }
+        if self.add_match_raw("type='signal',sender='org.freedesktop.DBus',interface='org.freedesktop.DBus',member='NameOwnerChanged'").is_err() {
+            return false
+        }
+        self.i.credentials_watched.set(true);
+        true
+    }
+
+    fn connection_credentials(&self, name: &str) -> Result<Vec<(MessageItem, MessageItem)>, Error> {
+        let mut m = try!(Message::new_method_call("org.freedesktop.DBus", "/org/freedesktop/DBus",
+            "org.freedesktop.DBus", "GetConnectionCredentials"));
+        try!(m.try_append_items(&[MessageItem::Str(name.to_string())]));
+        let mut r = try!(self.send_with_reply_and_block(m, 25000));
+        let items = try!(r.get_items_checked(Utf8Policy::Strict));
+        match items.into_iter().next() { Some(MessageItem::Dict(a, _, _)) => Ok(a), _ => Ok(vec!()) }
+    }
+
     pub fn unique_name(&self) -> String {
         let c = unsafe { ffi::dbus_bus_get_unique_name(self.conn()) };
         if c == ptr::null() {
@@ -532,8 +1727,23 @@ impl Connection {
     }
 
     pub fn register_object_path(&self, path: &str) -> Result<(), Error> {
+        try!(self.register_object_path_raw(path, false));
+        self.i.restore.borrow_mut().object_paths.push(path.to_string());
+        Ok(())
+    }
+
+    /// Like `register_object_path`, but method calls to all paths below `path` that are
+    /// not registered themselves are delivered too, e g "/org/example/items/42" for
+    /// "/org/example/items". Use `unregister_object_path` to unregister it.
+    pub fn register_fallback(&self, path: &str) -> Result<(), Error> {
+        try!(self.register_object_path_raw(path, true));
+        self.i.restore.borrow_mut().fallbacks.push(path.to_string());
+        Ok(())
+    }
+
+    fn register_object_path_raw(&self, path: &str, fallback: bool) -> Result<(), Error> {
         let mut e = Error::empty();
-        let p = path.to_c_str();
+        let p = try!(to_c_str(path));
         let vtable = ffi::DBusObjectPathVTable {
             unregister_function: None,
             message_function: Some(object_path_message_cb as ffi::DBusCallback),
@@ -544,45 +1754,198 @@ impl Connection {
         };
         let r = unsafe {
             let user_data: *mut libc::c_void = std::mem::transmute(&*self.i);
-            ffi::dbus_connection_try_register_object_path(self.conn(), p.as_ptr(), &vtable, user_data, e.get_mut())
+            if fallback { ffi::dbus_connection_try_register_fallback(self.conn(), p.as_ptr(), &vtable, user_data, e.get_mut()) }
+            else { ffi::dbus_connection_try_register_object_path(self.conn(), p.as_ptr(), &vtable, user_data, e.get_mut()) }
         };
         if r == 0 { Err(e) } else { Ok(()) }
     }
 
     pub fn unregister_object_path(&self, path: &str) {
-        let p = path.to_c_str();
+        // Nothing can be registered at a path with a NUL byte
+        let p = match to_c_str(path) { Ok(p) => p, Err(_) => return };
         let r = unsafe { ffi::dbus_connection_unregister_object_path(self.conn(), p.as_ptr()) };
         if r == 0 { panic!("Out of memory"); }
+        let mut r = self.i.restore.borrow_mut();
+        r.object_paths.retain(|x| x.as_slice() != path);
+        r.fallbacks.retain(|x| x.as_slice() != path);
     }
 
     pub fn register_name(&self, name: &str, flags: u32) -> Result<RequestNameReply, Error> {
+        let r = try!(self.request_name_raw(name, flags));
+        let mut s = self.i.restore.borrow_mut();
+        s.names.retain(|x| x.0.as_slice() != name);
+        s.names.push((name.to_string(), flags));
+        Ok(r)
+    }
+
+    fn request_name_raw(&self, name: &str, flags: u32) -> Result<RequestNameReply, Error> {
         let mut e = Error::empty();
-        let n = name.to_c_str();
+        let n = try!(to_c_str(name));
         let r = unsafe { ffi::dbus_bus_request_name(self.conn(), n.as_ptr(), flags, e.get_mut()) };
         if r == -1 { Err(e) } else { Ok(unsafe { std::mem::transmute(r) }) }
     }
 
     pub fn release_name(&self, name: &str) -> Result<ReleaseNameReply, Error> {
         let mut e = Error::empty();
-        let n = name.to_c_str();
+        let n = try!(to_c_str(name));
         let r = unsafe { ffi::dbus_bus_release_name(self.conn(), n.as_ptr(), e.get_mut()) };
+        self.i.restore.borrow_mut().names.retain(|x| x.0.as_slice() != name);
         if r == -1 { Err(e) } else { Ok(unsafe { std::mem::transmute(r) }) }
     }
 
+    /// Requests a name, allowing another instance to take it over later with `take_over_name`.
+    pub fn register_name_replaceable(&self, name: &str) -> Result<RequestNameReply, Error> {
+        self.register_name(name, NameFlag::AllowReplacement as u32 | NameFlag::DoNotQueue as u32)
+    }
+
+    /// Takes over a name from its current owner, if that owner allowed replacement.
+    ///
+    /// The name is requested with replacement allowed, so this instance can in turn be
+    /// replaced by the next one.
+    pub fn take_over_name(&self, name: &str) -> Result<RequestNameReply, Error> {
+        self.register_name(name, NameFlag::ReplaceExisting as u32 |
+            NameFlag::AllowReplacement as u32 | NameFlag::DoNotQueue as u32)
+    }
+
+    /// Registers a callback that is called when this connection loses the given name,
+    /// e g because another instance took it over. This is the time to flush state and exit.
+    ///
+    /// The callback is called when the NameLost signal is read from the connection,
+    /// i e while iterating over `iter`.
+    pub fn on_name_lost<F>(&self, name: &str, f: F) where F: FnMut(&str) + 'static {
+        self.i.name_lost_cbs.borrow_mut().push((name.to_string(), box f));
+    }
+
+    fn handle_name_lost(&self, m: &mut Message) {
+        let (_, _, i, mb) = m.headers();
+        if i.as_ref().map(|s| s.as_slice()) != Some("org.freedesktop.DBus") { return; }
+        if mb.as_ref().map(|s| s.as_slice()) != Some("NameLost") { return; }
+        if m.sender().as_ref().map(|s| s.as_slice()) != Some("org.freedesktop.DBus") { return; }
+        let items = m.get_items_checked(Utf8Policy::Strict).unwrap_or(vec!());
+        let name = match items.into_iter().next() {
+            Some(MessageItem::Str(s)) => s,
+            _ => return,
+        };
+        for cb in self.i.name_lost_cbs.borrow_mut().iter_mut() {
+            if cb.0 == name { (*cb.1)(name.as_slice()) }
+        }
+    }
+
+    fn handle_name_owner_changed(&self, m: &mut Message) {
+        if self.i.credentials.borrow().is_empty() { return }
+        let (_, _, i, mb) = m.headers();
+        if i.as_ref().map(|s| s.as_slice()) != Some("org.freedesktop.DBus") { return; }
+        if mb.as_ref().map(|s| s.as_slice()) != Some("NameOwnerChanged") { return; }
+        if m.sender().as_ref().map(|s| s.as_slice()) != Some("org.freedesktop.DBus") { return; }
+        let items = m.get_items_checked(Utf8Policy::Strict).unwrap_or(vec!());
+        if let Some(&MessageItem::Str(ref name)) = items.get(0) {
+            self.i.credentials.borrow_mut().remove(name.as_slice());
+        }
+    }
+
+    // NameOwnerChanged only arrives for the credentials cache, unless one of the user's own
+    // match rules asks for it too. Rules that cannot be parsed are taken to ask for it.
+    fn is_credentials_signal(&self, m: &Message) -> bool {
+        if !self.i.credentials_watched.get() { return false }
+        if m.interface() != Some("org.freedesktop.DBus") || m.member() != Some("NameOwnerChanged") { return false }
+        if m.sender().as_ref().map(|s| s.as_slice()) != Some("org.freedesktop.DBus") { return false }
+        !self.i.restore.borrow().match_rules.iter().any(|r|
+            matchrule::MatchRule::parse(r.as_slice()).map_or(true, |r| r.matches(m)))
+    }
+
     pub fn add_match(&self, rule: &str) -> Result<(), Error> {
+        try!(self.add_match_raw(rule));
+        self.i.restore.borrow_mut().match_rules.push(rule.to_string());
+        Ok(())
+    }
+
+    fn add_match_raw(&self, rule: &str) -> Result<(), Error> {
         let mut e = Error::empty();
-        let n = rule.to_c_str();
+        let n = try!(to_c_str(rule));
         unsafe { ffi::dbus_bus_add_match(self.conn(), n.as_ptr(), e.get_mut()) };
         if e.name().is_some() { Err(e) } else { Ok(()) }
     }
 
     pub fn remove_match(&self, rule: &str) -> Result<(), Error> {
         let mut e = Error::empty();
-        let n = rule.to_c_str();
+        let n = try!(to_c_str(rule));
         unsafe { ffi::dbus_bus_remove_match(self.conn(), n.as_ptr(), e.get_mut()) };
+        {
+            let mut r = self.i.restore.borrow_mut();
+            if let Some(i) = r.match_rules.iter().position(|x| x.as_slice() == rule) { r.match_rules.remove(i); }
+        }
         if e.name().is_some() { Err(e) } else { Ok(()) }
     }
 
+    /// Adds a predicate on the arguments of incoming signals with the given interface and member.
+    ///
+    /// Such signals are only returned from `iter` if all their predicates return true, which
+    /// allows filtering on things match rules cannot express, e g a key inside a dictionary.
+    pub fn add_signal_predicate<F>(&self, iface: &str, member: &str, f: F)
+        where F: Fn(&[MessageItem]) -> bool + 'static
+    {
+        let mut r = self.i.signal_routes.borrow_mut();
+        let key = (iface.to_string(), member.to_string());
+        if !r.contains_key(&key) { r.insert(key.clone(), std::default::Default::default()); }
+        r.get_mut(&key).unwrap().predicates.push(box f);
+    }
+
+    /// Removes all predicates added for the given interface and member.
+    pub fn remove_signal_predicates(&self, iface: &str, member: &str) {
+        let mut r = self.i.signal_routes.borrow_mut();
+        let key = (iface.to_string(), member.to_string());
+        let empty = match r.get_mut(&key) {
+            Some(s) => { s.predicates.clear(); s.arg0_watches.len() == 0 }
+            None => false,
+        };
+        if empty { r.remove(&key); }
+    }
+
+    /// Only lets signals with the given interface and member through `iter` if their
+    /// first argument is a string that has been watched.
+    ///
+    /// This is meant for subscriptions like NameOwnerChanged for many bus names: the
+    /// check is a hash lookup, no matter how many values are watched. Watches are
+    /// counted, so each call needs a matching `unwatch_signal_arg0`.
+    pub fn watch_signal_arg0(&self, iface: &str, member: &str, arg0: &str) {
+        let mut r = self.i.signal_routes.borrow_mut();
+        let key = (iface.to_string(), member.to_string());
+        if !r.contains_key(&key) { r.insert(key.clone(), std::default::Default::default()); }
+        let w = &mut r.get_mut(&key).unwrap().arg0_watches;
+        let n = w.get(arg0).map(|n| *n).unwrap_or(0);
+        w.insert(arg0.to_string(), n + 1);
+    }
+
+    pub fn unwatch_signal_arg0(&self, iface: &str, member: &str, arg0: &str) {
+        let mut r = self.i.signal_routes.borrow_mut();
+        let key = (iface.to_string(), member.to_string());
+        let empty = match r.get_mut(&key) {
+            Some(s) => {
+                let n = s.arg0_watches.get(arg0).map(|n| *n).unwrap_or(0);
+                if n > 1 { s.arg0_watches.insert(arg0.to_string(), n - 1); }
+                else { s.arg0_watches.remove(arg0); }
+                s.arg0_watches.len() == 0 && s.predicates.len() == 0
+            }
+            None => false,
+        };
+        if empty { r.remove(&key); }
+    }
+
+    fn signal_accepted(&self, m: &mut Message) -> bool {
+        let (_, _, i, mb) = m.headers();
+        let (i, mb) = match (i, mb) { (Some(i), Some(mb)) => (i, mb), _ => return true };
+        let routes = self.i.signal_routes.borrow();
+        let r = match routes.get(&(i, mb)) { Some(r) => r, None => return true };
+        let items = match m.get_items_checked(Utf8Policy::Strict) { Ok(v) => v, Err(_) => return false };
+        if r.arg0_watches.len() > 0 {
+            match items.get(0) {
+                Some(&MessageItem::Str(ref s)) if r.arg0_watches.contains_key(s.as_slice()) => {},
+                _ => return false,
+            }
+        }
+        r.predicates.iter().all(|p| (**p)(items.as_slice()))
+    }
+
 }
 
 impl Drop for Connection {
@@ -621,7 +1984,7 @@ mod test {
         let c = Connection::get_private(BusType::Session).unwrap();
         let m = Message::new_method_call("org.freedesktop.DBus", "/", "org.freedesktop.DBus", "ListNames").unwrap();
         let mut r = c.send_with_reply_and_block(m, 2000).unwrap();
-        let reply = r.get_items();
+        let reply = r.get_items().unwrap();
         println!("{}", reply);
     }
 
@@ -631,7 +1994,7 @@ mod test {
         let mut m = Message::new_method_call("org.freedesktop.DBus", "/", "org.freedesktop.DBus", "NameHasOwner").unwrap();
         m.append_items(&[MessageItem::Str("org.freedesktop.DBus".to_string())]);
         let mut r = c.send_with_reply_and_block(m, 2000).unwrap();
-        let reply = r.get_items();
+        let reply = r.get_items().unwrap();
         println!("{}", reply);
         assert_eq!(reply, vec!(MessageItem::Bool(true)));
     }
@@ -663,7 +2026,7 @@ mod test {
         let m = Message::new_method_call(n.as_slice(), "/hello", "com.example.hello", "Hello").unwrap();
         println!("Sending...");
         let mut r = c.send_with_reply_and_block(m, 8000).unwrap();
-        let reply = r.get_items();
+        let reply = r.get_items().unwrap();
         println!("{}", reply);
     }
 
@@ -674,22 +2037,22 @@ mod test {
         let mut m = Message::new_method_call(c.unique_name().as_slice(), "/hello", "com.example.hello", "Hello").unwrap();
         m.append_items(&[
             MessageItem::UInt16(2000),
-            MessageItem::Array(vec!(MessageItem::Byte(129)), -1),
+            MessageItem::Array(vec!(MessageItem::Byte(129)), "y".to_string()),
             MessageItem::UInt64(987654321),
             MessageItem::Int32(-1),
             MessageItem::Str("Hello world".to_string()),
-            MessageItem::Array(vec!(
-                MessageItem::DictEntry(box MessageItem::UInt32(123543), box MessageItem::Bool(true))
-            ), -1)
+            MessageItem::Dict(vec!(
+                (MessageItem::UInt32(123543), MessageItem::Bool(true))
+            ), "u".to_string(), "b".to_string())
         ]);
-        let sending = format!("{}", m.get_items());
+        let sending = format!("{}", m.get_items().unwrap());
         println!("Sending {}", sending);
         c.send(m).unwrap();
 
         for n in c.iter(1000) {
             match n {
                 ConnectionItem::MethodCall(mut m) => {
-                    let receiving = format!("{}", m.get_items());
+                    let receiving = format!("{}", m.get_items().unwrap());
                     println!("Receiving {}", receiving);
                     assert_eq!(sending, receiving);
                     break;
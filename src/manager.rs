@@ -0,0 +1,193 @@
+use super::{MessageItem, Error, BusType};
+use super::ffi;
+use super::newdbus::{Connection, Message, MethodCall, IncomingMessage};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
+use std::thread::Thread;
+use libc;
+
+/// How long calls wait for their reply, the same as the libdbus default.
+const CALL_TIMEOUT_MS: u64 = 25000;
+
+pub type CallResult = Result<Vec<MessageItem>, Error>;
+
+enum Request {
+    Call(String, String, String, String, Vec<MessageItem>, Sender<CallResult>),
+    Emit(String, String, String, Vec<MessageItem>, Sender<Result<u32, Error>>),
+}
+
+/* The write end of a pipe the dispatcher thread waits on together with the bus, so that
+   it sleeps until one of them has something for it. Closing it wakes the thread up too. */
+struct Waker(libc::c_int);
+
+impl Waker {
+    fn wake(&self) {
+        let b = 0u8;
+        // If the pipe is full, the thread has wakeups pending already.
+        unsafe { libc::write(self.0, &b as *const u8 as *const libc::c_void, 1) };
+    }
+}
+
+impl Drop for Waker {
+    fn drop(&mut self) { unsafe { libc::close(self.0) }; }
+}
+
+/// Runs a connection on a background thread, so that the thread using it never blocks on D-Bus.
+///
+/// The thread sleeps until a request or a message arrives, and waits for the replies of
+/// several calls at once, so a slow service only delays its own replies.
+///
+/// # Example
+///
+/// ```ignore
+/// let h = ConnectionManager::start(BusType::Session).unwrap();
+/// let reply = h.call("org.freedesktop.DBus", "/", "org.freedesktop.DBus", "ListNames", vec!());
+/// // ...later, e g from the UI's idle handler:
+/// if let Ok(r) = reply.try_recv() { println!("{}", r); }
+/// ```
+pub struct ConnectionManager;
+
+impl ConnectionManager {
+    /// Connects to the bus on a new thread. The thread stops when all handles are dropped.
+    pub fn start(bus: BusType) -> Result<ConnectionHandle, Error> {
+        let mut fds = [0 as libc::c_int; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(Error::new_custom("org.freedesktop.DBus.Error.Failed", "Could not create a pipe"));
+        }
+        for fd in fds.iter() { unsafe { libc::fcntl(*fd, libc::F_SETFL, libc::O_NONBLOCK) }; }
+        let (wake_rx, waker) = (fds[0], Arc::new(Waker(fds[1])));
+
+        let (tx, rx) = channel();
+        let (started_tx, started_rx) = channel();
+        Thread::spawn(move || {
+            let c = match Connection::new_for_type(bus) {
+                Ok(c) => { started_tx.send(Ok(())).unwrap(); c }
+                Err(e) => { started_tx.send(Err(e)).unwrap(); unsafe { libc::close(wake_rx) }; return }
+            };
+            run(c, rx, wake_rx);
+            unsafe { libc::close(wake_rx) };
+        }).detach();
+        match started_rx.recv() {
+            Ok(Ok(())) => Ok(ConnectionHandle { tx: tx, waker: waker }),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(Error::new_custom("org.freedesktop.DBus.Error.Failed", "Dispatcher thread failed to start")),
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    let mut t = ffi::Timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { ffi::clock_gettime(ffi::CLOCK_MONOTONIC, &mut t) };
+    t.tv_sec as u64 * 1000 + t.tv_nsec as u64 / 1000000
+}
+
+fn start_call(c: &Connection, d: &str, p: &str, i: &str, m: &str, args: Vec<MessageItem>) -> Result<u32, Error> {
+    let mut b = MethodCall::builder();
+    b.path(p).interface(i).member(m);
+    if d.len() > 0 { b.destination(d); }
+    for a in args.into_iter() { b.arg(a); }
+    c.send(&try!(b.build()))
+}
+
+fn error_from_message(m: &IncomingMessage) -> Error {
+    let mut e = Error::empty();
+    unsafe { ffi::dbus_set_error_from_message(e.get_mut(), m.msg_ptr()) };
+    e
+}
+
+/* Sleeps until the bus or a handle has something for us, or `timeout_ms` has passed. */
+fn wait(c: &Connection, wake_rx: libc::c_int, timeout_ms: int) {
+    let mut fds = vec!(ffi::PollFd { fd: wake_rx, events: ffi::POLLIN, revents: 0 });
+    if let Some(fd) = c.as_raw_fd() { fds.push(ffi::PollFd { fd: fd, events: ffi::POLLIN, revents: 0 }) }
+    unsafe { ffi::poll(fds.as_mut_ptr(), fds.len() as libc::c_ulong, timeout_ms as libc::c_int) };
+    let mut buf = [0u8; 64];
+    while unsafe { libc::read(wake_rx, buf.as_mut_ptr() as *mut libc::c_void, buf.len() as libc::size_t) } > 0 {}
+}
+
+fn run(c: Connection, rx: Receiver<Request>, wake_rx: libc::c_int) {
+    // Calls waiting for their reply, by serial, with their deadlines
+    let mut calls: HashMap<u32, (u64, Sender<CallResult>)> = HashMap::new();
+    loop {
+        loop {
+            match rx.try_recv() {
+                Ok(Request::Call(d, p, i, m, args, reply)) => {
+                    match start_call(&c, d.as_slice(), p.as_slice(), i.as_slice(), m.as_slice(), args) {
+                        Ok(serial) => { calls.insert(serial, (now_ms() + CALL_TIMEOUT_MS, reply)); }
+                        Err(e) => { let _ = reply.send(Err(e)); }
+                    }
+                }
+                Ok(Request::Emit(p, i, m, args, reply)) => {
+                    let _ = reply.send(c.emit_signal(p.as_slice(), i.as_slice(), m.as_slice(), args.as_slice()));
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+        c.flush();
+
+        let timeout = match calls.values().map(|&(d, _)| d).min() {
+            Some(d) => { let now = now_ms(); if d > now { (d - now) as int } else { 0 } }
+            None => -1,
+        };
+        wait(&c, wake_rx, timeout);
+        if !c.read_write(0) {
+            for (_, (_, reply)) in calls.drain() { let _ = reply.send(Err(stopped())); }
+            return;
+        }
+
+        while let Some(m) = c.pop_message() {
+            let serial = unsafe { ffi::dbus_message_get_reply_serial(m.msg_ptr()) };
+            let r = match m {
+                IncomingMessage::MethodReturn(ref r) => r.get_items(),
+                IncomingMessage::Error(_) => Err(error_from_message(&m)),
+                _ => continue,
+            };
+            if let Some((_, reply)) = calls.remove(&serial) { let _ = reply.send(r); }
+        }
+
+        let now = now_ms();
+        let expired: Vec<u32> = calls.iter().filter(|&(_, &(d, _))| d <= now).map(|(s, _)| *s).collect();
+        for s in expired.iter() {
+            let (_, reply) = calls.remove(s).unwrap();
+            let _ = reply.send(Err(Error::new_custom("org.freedesktop.DBus.Error.NoReply", "Did not receive a reply")));
+        }
+    }
+}
+
+/// A handle to a connection run by `ConnectionManager`. Handles can be cloned and sent to
+/// other threads; replies come back on the returned channels.
+#[deriving(Clone)]
+pub struct ConnectionHandle {
+    tx: Sender<Request>,
+    waker: Arc<Waker>,
+}
+
+fn stopped() -> Error {
+    Error::new_custom("org.freedesktop.DBus.Error.Disconnected", "The dispatcher thread has stopped")
+}
+
+impl ConnectionHandle {
+    /// Calls a method; the reply's arguments (or the error) arrive on the returned channel.
+    pub fn call(&self, destination: &str, path: &str, iface: &str, method: &str, args: Vec<MessageItem>)
+                -> Receiver<CallResult> {
+        let (tx, rx) = channel();
+        let r = Request::Call(destination.to_string(), path.to_string(), iface.to_string(), method.to_string(), args, tx);
+        match self.tx.send(r) {
+            Ok(()) => self.waker.wake(),
+            Err(e) => if let Request::Call(_, _, _, _, _, tx) = e.0 { let _ = tx.send(Err(stopped())); },
+        }
+        rx
+    }
+
+    /// Emits a signal; its serial (or an error) arrives on the returned channel.
+    pub fn emit(&self, path: &str, iface: &str, member: &str, args: Vec<MessageItem>) -> Receiver<Result<u32, Error>> {
+        let (tx, rx) = channel();
+        let r = Request::Emit(path.to_string(), iface.to_string(), member.to_string(), args, tx);
+        match self.tx.send(r) {
+            Ok(()) => self.waker.wake(),
+            Err(e) => if let Request::Emit(_, _, _, _, tx) = e.0 { let _ = tx.send(Err(stopped())); },
+        }
+        rx
+    }
+}
@@ -0,0 +1,147 @@
+use super::{Message, MessageType, MessageItemRef};
+use std::fmt;
+
+/// A match rule, as passed to `add_match`, built from its parts instead of written by hand.
+///
+/// # Example
+///
+/// ```ignore
+/// let mut r = MatchRule::new();
+/// r.msg_type(MessageType::Signal).interface("org.freedesktop.DBus").member("NameOwnerChanged")
+///  .arg0("com.example.Foo");
+/// c.add_match(r.to_string().as_slice()).unwrap();
+/// ```
+#[deriving(Clone, PartialEq, Default)]
+pub struct MatchRule {
+    msg_type: Option<MessageType>,
+    sender: Option<String>,
+    interface: Option<String>,
+    member: Option<String>,
+    path: Option<String>,
+    arg0: Option<String>,
+}
+
+fn type_name(t: MessageType) -> &'static str {
+    match t {
+        MessageType::MethodCall => "method_call",
+        MessageType::MethodReturn => "method_return",
+        MessageType::Error => "error",
+        MessageType::Signal => "signal",
+        MessageType::Invalid => "invalid",
+    }
+}
+
+impl MatchRule {
+    pub fn new() -> MatchRule { ::std::default::Default::default() }
+
+    pub fn msg_type(&mut self, t: MessageType) -> &mut MatchRule { self.msg_type = Some(t); self }
+    pub fn sender(&mut self, s: &str) -> &mut MatchRule { self.sender = Some(s.to_string()); self }
+    pub fn interface(&mut self, s: &str) -> &mut MatchRule { self.interface = Some(s.to_string()); self }
+    pub fn member(&mut self, s: &str) -> &mut MatchRule { self.member = Some(s.to_string()); self }
+    pub fn path(&mut self, s: &str) -> &mut MatchRule { self.path = Some(s.to_string()); self }
+    /// Matches messages whose first argument is this string.
+    pub fn arg0(&mut self, s: &str) -> &mut MatchRule { self.arg0 = Some(s.to_string()); self }
+
+    /// Parses a rule as written by `to_string` or passed to `add_match`. Returns None if the
+    /// rule is malformed, or has keys this type cannot hold, such as arg1 or path_namespace.
+    pub fn parse(s: &str) -> Option<MatchRule> {
+        let mut r = MatchRule::new();
+        let mut it = s.chars().peekable();
+        loop {
+            let mut key = String::new();
+            let mut c = it.next();
+            while let Some(ch) = c { if ch == '=' { break } key.push(ch); c = it.next(); }
+            if c.is_none() { return if key.trim().len() == 0 { Some(r) } else { None } }
+
+            // Quotes may cover any part of the value; outside them, \' is a quote.
+            let (mut value, mut quoted, mut last) = (String::new(), false, true);
+            while let Some(ch) = it.next() {
+                if quoted { if ch == '\'' { quoted = false } else { value.push(ch) } }
+                else if ch == '\'' { quoted = true }
+                else if ch == '\\' && it.peek() == Some(&'\'') { it.next(); value.push('\'') }
+                else if ch == ',' { last = false; break }
+                else { value.push(ch) }
+            }
+            if quoted { return None }
+            match key.trim() {
+                "type" => r.msg_type = Some(match value.as_slice() {
+                    "method_call" => MessageType::MethodCall,
+                    "method_return" => MessageType::MethodReturn,
+                    "error" => MessageType::Error,
+                    "signal" => MessageType::Signal,
+                    _ => return None,
+                }),
+                "sender" => r.sender = Some(value),
+                "interface" => r.interface = Some(value),
+                "member" => r.member = Some(value),
+                "path" => r.path = Some(value),
+                "arg0" => r.arg0 = Some(value),
+                _ => return None,
+            }
+            if last { return Some(r) }
+        }
+    }
+
+    /// True if the message matches all parts of the rule.
+    ///
+    /// Senders are compared as they are, so a rule with a well-known sender name does
+    /// not match messages from the unique name owning it.
+    pub fn matches(&self, m: &Message) -> bool {
+        fn check(rule: &Option<String>, v: Option<&str>) -> bool {
+            rule.as_ref().map_or(true, |r| Some(r.as_slice()) == v)
+        }
+        if self.msg_type.map_or(false, |t| t != m.msg_type()) { return false }
+        let sender = m.sender();
+        if !check(&self.sender, sender.as_ref().map(|s| s.as_slice())) { return false }
+        if !check(&self.interface, m.interface()) || !check(&self.member, m.member()) ||
+            !check(&self.path, m.path()) { return false }
+        match self.arg0 {
+            None => true,
+            Some(ref a) => match m.get_item_refs() {
+                Ok(items) => items.get(0).and_then(|i: &MessageItemRef| i.as_str()) == Some(a.as_slice()),
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+impl fmt::Show for MatchRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = vec!();
+        if let Some(t) = self.msg_type { parts.push(("type", type_name(t).to_string())) }
+        let strs = [("sender", &self.sender), ("interface", &self.interface), ("member", &self.member),
+            ("path", &self.path), ("arg0", &self.arg0)];
+        for &(k, v) in strs.iter() {
+            if let &Some(ref v) = v { parts.push((k, v.clone())) }
+        }
+        for (i, &(k, ref v)) in parts.iter().enumerate() {
+            if i > 0 { try!(write!(f, ",")) }
+            // Inside quotes, a quote is written as '\''
+            try!(write!(f, "{}='{}'", k, v.replace("'", "'\\''")));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn match_rule() {
+    let mut r = MatchRule::new();
+    r.msg_type(MessageType::Signal).interface("com.example.Foo").arg0("it's");
+    assert_eq!(r.to_string().as_slice(), "type='signal',interface='com.example.Foo',arg0='it'\\''s'");
+
+    let mut m = Message::new_signal("/", "com.example.Foo", "Changed").unwrap();
+    m.append_items(&[super::MessageItem::Str("it's".to_string())]);
+    assert!(r.matches(&m));
+    r.member("Other");
+    assert!(!r.matches(&m));
+}
+
+#[test]
+fn match_rule_parse() {
+    let mut r = MatchRule::new();
+    r.msg_type(MessageType::Signal).sender("org.freedesktop.DBus").arg0("it's");
+    assert_eq!(MatchRule::parse(r.to_string().as_slice()), Some(r));
+    assert_eq!(MatchRule::parse(""), Some(MatchRule::new()));
+    assert_eq!(MatchRule::parse("type='signal',arg1='x'"), None);
+    assert_eq!(MatchRule::parse("member='Open"), None);
+}
@@ -0,0 +1,256 @@
+//! Typed client for the core NetworkManager objects.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let c = Connection::get_private(BusType::System).unwrap();
+//! let nm = Manager::new(&c);
+//! println!("NetworkManager is {}", nm.state().unwrap());
+//! for d in nm.get_devices().unwrap().iter() {
+//!     println!("{}: {} ({})", d.interface().unwrap(), d.device_type().unwrap(), d.state().unwrap());
+//! }
+//! ```
+
+use super::{Connection, Message, MessageItem, Error, Props};
+
+pub const SERVICE: &'static str = "org.freedesktop.NetworkManager";
+pub const MANAGER_PATH: &'static str = "/org/freedesktop/NetworkManager";
+pub const MANAGER_INTERFACE: &'static str = "org.freedesktop.NetworkManager";
+pub const DEVICE_INTERFACE: &'static str = "org.freedesktop.NetworkManager.Device";
+pub const ACTIVE_CONNECTION_INTERFACE: &'static str = "org.freedesktop.NetworkManager.Connection.Active";
+
+const TIMEOUT_MS: int = 10000;
+
+dbus_signal!(StateChanged, "org.freedesktop.NetworkManager", "StateChanged", { state: UInt32(u32) });
+
+dbus_signal!(DeviceStateChanged, "org.freedesktop.NetworkManager.Device", "StateChanged",
+    { new_state: UInt32(u32), old_state: UInt32(u32), reason: UInt32(u32) });
+
+macro_rules! nm_enum {
+    ($name:ident, $($variant:ident = $value:expr),+) => {
+        #[deriving(Show, PartialEq, Eq, Copy, Clone)]
+        pub enum $name { $($variant),+ }
+
+        impl $name {
+            /// Values this crate does not know about are mapped to `Unknown`.
+            pub fn from_u32(v: u32) -> $name {
+                $(if v == $value { return $name::$variant })+
+                $name::Unknown
+            }
+        }
+    }
+}
+
+nm_enum!(NMState, Unknown = 0, Asleep = 10, Disconnected = 20, Disconnecting = 30, Connecting = 40,
+    ConnectedLocal = 50, ConnectedSite = 60, ConnectedGlobal = 70);
+
+nm_enum!(DeviceState, Unknown = 0, Unmanaged = 10, Unavailable = 20, Disconnected = 30, Prepare = 40,
+    Config = 50, NeedAuth = 60, IpConfig = 70, IpCheck = 80, Secondaries = 90, Activated = 100,
+    Deactivating = 110, Failed = 120);
+
+nm_enum!(DeviceType, Unknown = 0, Ethernet = 1, Wifi = 2, Bluetooth = 5, OlpcMesh = 6, Wimax = 7,
+    Modem = 8, Infiniband = 9, Bond = 10, Vlan = 11, Adsl = 12, Bridge = 13, Generic = 14, Team = 15);
+
+nm_enum!(ActiveConnectionState, Unknown = 0, Activating = 1, Activated = 2, Deactivating = 3, Deactivated = 4);
+
+fn invalid_reply(what: &str, i: &MessageItem) -> Error {
+    let f = format!("Invalid value for {}: '{}'", what, i);
+    Error::new_custom("org.freedesktop.DBus.Error.InvalidSignature", f.as_slice())
+}
+
+fn get_prop(c: &Connection, path: &str, iface: &str, name: &str) -> Result<MessageItem, Error> {
+    Props::new(c, SERVICE, path, iface, TIMEOUT_MS).get(name)
+}
+
+fn get_u32(c: &Connection, path: &str, iface: &str, name: &str) -> Result<u32, Error> {
+    match try!(get_prop(c, path, iface, name)) {
+        MessageItem::UInt32(v) => Ok(v),
+        i => Err(invalid_reply(name, &i)),
+    }
+}
+
+fn get_bool(c: &Connection, path: &str, iface: &str, name: &str) -> Result<bool, Error> {
+    match try!(get_prop(c, path, iface, name)) {
+        MessageItem::Bool(v) => Ok(v),
+        i => Err(invalid_reply(name, &i)),
+    }
+}
+
+fn get_string(c: &Connection, path: &str, iface: &str, name: &str) -> Result<String, Error> {
+    match try!(get_prop(c, path, iface, name)) {
+        MessageItem::Str(v) => Ok(v),
+        i => Err(invalid_reply(name, &i)),
+    }
+}
+
+/* NetworkManager uses "/" for object path properties that are not set. */
+fn get_path(c: &Connection, path: &str, iface: &str, name: &str) -> Result<Option<String>, Error> {
+    match try!(get_prop(c, path, iface, name)) {
+        MessageItem::ObjectPath(ref p) if p.as_str() == "/" => Ok(None),
+        MessageItem::ObjectPath(p) => Ok(Some(p.into_string())),
+        i => Err(invalid_reply(name, &i)),
+    }
+}
+
+fn paths(what: &str, i: &MessageItem) -> Result<Vec<String>, Error> {
+    if let &MessageItem::Array(ref a, _) = i {
+        let mut v = vec!();
+        for x in a.iter() {
+            match x {
+                &MessageItem::ObjectPath(ref p) => v.push(p.as_str().to_string()),
+                _ => return Err(invalid_reply(what, i)),
+            }
+        }
+        return Ok(v);
+    }
+    Err(invalid_reply(what, i))
+}
+
+/// The NetworkManager object, which knows about all devices and active connections.
+pub struct Manager<'a> {
+    conn: &'a Connection,
+}
+
+impl<'a> Manager<'a> {
+    pub fn new(conn: &'a Connection) -> Manager<'a> { Manager { conn: conn } }
+
+    pub fn version(&self) -> Result<String, Error> {
+        get_string(self.conn, MANAGER_PATH, MANAGER_INTERFACE, "Version")
+    }
+
+    pub fn state(&self) -> Result<NMState, Error> {
+        get_u32(self.conn, MANAGER_PATH, MANAGER_INTERFACE, "State").map(NMState::from_u32)
+    }
+
+    pub fn networking_enabled(&self) -> Result<bool, Error> {
+        get_bool(self.conn, MANAGER_PATH, MANAGER_INTERFACE, "NetworkingEnabled")
+    }
+
+    pub fn wireless_enabled(&self) -> Result<bool, Error> {
+        get_bool(self.conn, MANAGER_PATH, MANAGER_INTERFACE, "WirelessEnabled")
+    }
+
+    /// The network devices NetworkManager manages, from GetDevices.
+    pub fn get_devices(&self) -> Result<Vec<Device<'a>>, Error> {
+        let m = try!(Message::new_method_call(SERVICE, MANAGER_PATH, MANAGER_INTERFACE, "GetDevices"));
+        let mut r = try!(self.conn.send_with_reply_and_block(m, TIMEOUT_MS));
+        let items = try!(try!(r.as_result()).get_items());
+        let p = match items.get(0) { Some(i) => try!(paths("GetDevices", i)), None => vec!() };
+        Ok(p.into_iter().map(|p| Device { conn: self.conn, path: p }).collect())
+    }
+
+    pub fn active_connections(&self) -> Result<Vec<ActiveConnection<'a>>, Error> {
+        let i = try!(get_prop(self.conn, MANAGER_PATH, MANAGER_INTERFACE, "ActiveConnections"));
+        let p = try!(paths("ActiveConnections", &i));
+        Ok(p.into_iter().map(|p| ActiveConnection { conn: self.conn, path: p }).collect())
+    }
+
+    /// Adds a match rule for the manager's StateChanged signal; use `state_changed` to
+    /// decode the incoming signals.
+    pub fn watch_state(&self) -> Result<(), Error> {
+        self.conn.add_match(format!("type='signal',sender='{}',path='{}',interface='{}',member='StateChanged'",
+            SERVICE, MANAGER_PATH, MANAGER_INTERFACE).as_slice())
+    }
+
+    /// Returns the new state if the message is the manager's StateChanged signal.
+    pub fn state_changed(m: &mut Message) -> Option<NMState> {
+        StateChanged::from_message(m).map(|s| NMState::from_u32(s.state))
+    }
+}
+
+/// A network device, such as an ethernet card or a wifi adapter.
+pub struct Device<'a> {
+    conn: &'a Connection,
+    path: String,
+}
+
+impl<'a> Device<'a> {
+    pub fn new(conn: &'a Connection, path: &str) -> Device<'a> { Device { conn: conn, path: path.to_string() } }
+
+    pub fn path(&self) -> &str { self.path.as_slice() }
+
+    /// The kernel interface name, e g "eth0".
+    pub fn interface(&self) -> Result<String, Error> {
+        get_string(self.conn, self.path.as_slice(), DEVICE_INTERFACE, "Interface")
+    }
+
+    pub fn driver(&self) -> Result<String, Error> {
+        get_string(self.conn, self.path.as_slice(), DEVICE_INTERFACE, "Driver")
+    }
+
+    pub fn device_type(&self) -> Result<DeviceType, Error> {
+        get_u32(self.conn, self.path.as_slice(), DEVICE_INTERFACE, "DeviceType").map(DeviceType::from_u32)
+    }
+
+    pub fn state(&self) -> Result<DeviceState, Error> {
+        get_u32(self.conn, self.path.as_slice(), DEVICE_INTERFACE, "State").map(DeviceState::from_u32)
+    }
+
+    pub fn active_connection(&self) -> Result<Option<ActiveConnection<'a>>, Error> {
+        let p = try!(get_path(self.conn, self.path.as_slice(), DEVICE_INTERFACE, "ActiveConnection"));
+        Ok(p.map(|p| ActiveConnection { conn: self.conn, path: p }))
+    }
+
+    /// Adds a match rule for this device's StateChanged signal.
+    pub fn watch_state(&self) -> Result<(), Error> {
+        self.conn.add_match(format!("type='signal',sender='{}',path='{}',interface='{}',member='StateChanged'",
+            SERVICE, self.path, DEVICE_INTERFACE).as_slice())
+    }
+
+    /// Returns the new state, old state and reason code if the message is a device's
+    /// StateChanged signal. Use `Message::path` to find out which device it is from.
+    pub fn state_changed(m: &mut Message) -> Option<(DeviceState, DeviceState, u32)> {
+        DeviceStateChanged::from_message(m).map(|s|
+            (DeviceState::from_u32(s.new_state), DeviceState::from_u32(s.old_state), s.reason))
+    }
+}
+
+/// A connection profile that is currently active on one or more devices.
+pub struct ActiveConnection<'a> {
+    conn: &'a Connection,
+    path: String,
+}
+
+impl<'a> ActiveConnection<'a> {
+    pub fn new(conn: &'a Connection, path: &str) -> ActiveConnection<'a> {
+        ActiveConnection { conn: conn, path: path.to_string() }
+    }
+
+    pub fn path(&self) -> &str { self.path.as_slice() }
+
+    /// The human readable name of the connection profile.
+    pub fn id(&self) -> Result<String, Error> {
+        get_string(self.conn, self.path.as_slice(), ACTIVE_CONNECTION_INTERFACE, "Id")
+    }
+
+    pub fn uuid(&self) -> Result<String, Error> {
+        get_string(self.conn, self.path.as_slice(), ACTIVE_CONNECTION_INTERFACE, "Uuid")
+    }
+
+    /// The connection type, e g "802-3-ethernet" or "802-11-wireless".
+    pub fn connection_type(&self) -> Result<String, Error> {
+        get_string(self.conn, self.path.as_slice(), ACTIVE_CONNECTION_INTERFACE, "Type")
+    }
+
+    pub fn state(&self) -> Result<ActiveConnectionState, Error> {
+        get_u32(self.conn, self.path.as_slice(), ACTIVE_CONNECTION_INTERFACE, "State")
+            .map(ActiveConnectionState::from_u32)
+    }
+
+    pub fn devices(&self) -> Result<Vec<Device<'a>>, Error> {
+        let i = try!(get_prop(self.conn, self.path.as_slice(), ACTIVE_CONNECTION_INTERFACE, "Devices"));
+        let p = try!(paths("Devices", &i));
+        Ok(p.into_iter().map(|p| Device { conn: self.conn, path: p }).collect())
+    }
+}
+
+#[test]
+fn nm_enums() {
+    assert_eq!(NMState::from_u32(70), NMState::ConnectedGlobal);
+    assert_eq!(DeviceState::from_u32(100), DeviceState::Activated);
+    assert_eq!(DeviceType::from_u32(999), DeviceType::Unknown);
+    let s = DeviceStateChanged { new_state: 100, old_state: 90, reason: 0 };
+    let mut m = s.to_message("/org/freedesktop/NetworkManager/Devices/0").unwrap();
+    assert_eq!(Device::state_changed(&mut m), Some((DeviceState::Activated, DeviceState::Secondaries, 0)));
+    assert_eq!(Manager::state_changed(&mut m), None);
+}
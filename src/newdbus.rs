@@ -1,11 +1,85 @@
 use super::ffi;
-use super::MessageItem;
+use super::{MessageItem, Path, IntoItem};
+use super::iter::{Append, Get, Iter, IterAppend};
+use super::arg::TypeMismatch;
 
 use std;
 use std::ptr;
+use libc;
 
+/// What a filter did with a message, see `Connection::add_filter`.
+#[deriving(Show, PartialEq, Copy, Clone)]
+pub enum HandlerResult {
+    /// The message was handled; later filters and handlers will not see it.
+    Handled,
+    /// The message should be passed on to later filters and handlers.
+    NotYetHandled,
+}
+
+type FilterFn = Box<FnMut(&super::Message) -> HandlerResult + 'static>;
+
+/// Identifies a filter added with `Connection::add_filter`.
+#[allow(missing_copy_implementations)]
+pub struct FilterToken(*mut libc::c_void);
+
+extern "C" fn filter_cb(_: *mut ffi::DBusConnection, msg: *mut ffi::DBusMessage,
+    user_data: *mut libc::c_void) -> ffi::DBusHandlerResult {
+
+    let f: &mut FilterFn = unsafe { std::mem::transmute(user_data) };
+    let m = super::Message::from_ptr(msg, true);
+    match (*f)(&m) {
+        HandlerResult::Handled => ffi::DBusHandlerResult::Handled,
+        HandlerResult::NotYetHandled => ffi::DBusHandlerResult::NotYetHandled,
+    }
+}
+
+extern "C" fn free_filter_cb(user_data: *mut libc::c_void) {
+    let _: Box<FilterFn> = unsafe { std::mem::transmute(user_data) };
+}
+
+type ObjectPathFn = Box<FnMut(&MethodCall) -> Option<MethodReturn> + 'static>;
+
+extern "C" fn object_path_cb(conn: *mut ffi::DBusConnection, msg: *mut ffi::DBusMessage,
+    user_data: *mut libc::c_void) -> ffi::DBusHandlerResult {
+
+    // The message is only borrowed by the callback, so take a reference of our own.
+    let m = match IncomingMessage::from_ptr(unsafe { ffi::dbus_message_ref(msg) }) {
+        Some(IncomingMessage::MethodCall(m)) => m,
+        _ => return ffi::DBusHandlerResult::NotYetHandled,
+    };
+
+    let f: &mut ObjectPathFn = unsafe { std::mem::transmute(user_data) };
+    match (*f)(&m) {
+        Some(r) => {
+            unsafe { ffi::dbus_connection_send(conn, r.0, ptr::null_mut()) };
+            ffi::DBusHandlerResult::Handled
+        }
+        None => ffi::DBusHandlerResult::NotYetHandled,
+    }
+}
+
+extern "C" fn unregister_object_path_cb(_: *mut ffi::DBusConnection, user_data: *mut libc::c_void) {
+    let _: Box<ObjectPathFn> = unsafe { std::mem::transmute(user_data) };
+}
+
+/// An object path registered with `Connection::register_object_path`. The path is
+/// unregistered, and the handler dropped, when this is dropped.
+pub struct ObjectPathRegistration<'a> {
+    conn: &'a Connection,
+    path: std::c_str::CString,
+}
+
+#[unsafe_destructor]
+impl<'a> Drop for ObjectPathRegistration<'a> {
+    fn drop(&mut self) {
+        unsafe { ffi::dbus_connection_unregister_object_path(self.conn.0, self.path.as_ptr()) };
+    }
+}
+
+/// A connection to a bus or peer. The flag is true for shared connections, which are
+/// owned by libdbus and must not be closed.
 #[allow(missing_copy_implementations)]
-pub struct Connection(*mut ffi::DBusConnection);
+pub struct Connection(*mut ffi::DBusConnection, bool);
 
 impl Connection {
     /// Creates a new private session on the session bus.
@@ -25,6 +99,7 @@ impl Connection {
 
     /// Creates a new private session on the given bus.
     pub fn new_for_type(bus: super::BusType) -> Result<Connection, super::Error> {
+        super::init_dbus();
         let mut e = super::Error::empty();
         let c = unsafe { ffi::dbus_bus_get_private(bus, e.get_mut()) };
         if c == ptr::null_mut() {
@@ -34,15 +109,71 @@ impl Connection {
         /* No, we don't want our app to suddenly quit if dbus goes down */
         unsafe { ffi::dbus_connection_set_exit_on_disconnect(c, 0) };
 
-        Ok(Connection(c))
+        Ok(Connection(c, false))
+    }
+
+    /// Gets the process-wide shared connection to the given bus, creating it if needed.
+    ///
+    /// All shared connections to a bus are the same underlying connection, so this is
+    /// cheaper than a private one when several parts of a process need the bus. Dropping
+    /// a shared connection only releases this reference to it; it is never closed.
+    pub fn shared(bus: super::BusType) -> Result<Connection, super::Error> {
+        super::init_dbus();
+        let mut e = super::Error::empty();
+        let c = unsafe { ffi::dbus_bus_get(bus, e.get_mut()) };
+        if c == ptr::null_mut() {
+            return Err(e);
+        }
+
+        unsafe { ffi::dbus_connection_set_exit_on_disconnect(c, 0) };
+
+        Ok(Connection(c, true))
+    }
+
+    /// True if this connection was created with `shared`.
+    pub fn is_shared(&self) -> bool { self.1 }
+
+    /// Opens a private connection to the given address, e g `"unix:path=/run/mybus"`.
+    ///
+    /// The connection is not registered with a bus, so this works for peer-to-peer
+    /// sockets as well. When connecting to a bus daemon, call `bus_register` before
+    /// doing anything else.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use dbus::newdbus::Connection;
+    ///
+    /// let conn = Connection::open_address("unix:path=/run/mybus").unwrap();
+    /// conn.bus_register().unwrap();
+    /// ```
+    pub fn open_address(address: &str) -> Result<Connection, super::Error> {
+        super::init_dbus();
+        let a = try!(super::to_c_str(address));
+        let mut e = super::Error::empty();
+        let c = unsafe { ffi::dbus_connection_open_private(a.as_ptr(), e.get_mut()) };
+        if c == ptr::null_mut() {
+            return Err(e);
+        }
+
+        unsafe { ffi::dbus_connection_set_exit_on_disconnect(c, 0) };
+
+        Ok(Connection(c, false))
+    }
+
+    /// Sends the Hello message that registers a connection opened with `open_address`
+    /// on the bus, and gives it a unique name.
+    pub fn bus_register(&self) -> Result<(), super::Error> {
+        let mut e = super::Error::empty();
+        if unsafe { ffi::dbus_bus_register(self.0, e.get_mut()) } == 0 { Err(e) } else { Ok(()) }
     }
 
     /// Utility method for sending a message and synchronously waiting for its response.
-    unsafe fn send_sync(&self, msg: *mut ffi::DBusMessage)
+    /// A timeout of -1 tells DBus to use the default timeout.
+    unsafe fn send_sync(&self, msg: *mut ffi::DBusMessage, timeout_ms: int)
                         -> Result<(*mut ffi::DBusMessage, super::MessageType), super::Error> {
         let mut e = super::Error::empty();
-        // -1 tells DBus to use the default timeout.
-        let resp = ffi::dbus_connection_send_with_reply_and_block(self.0, msg, -1, e.get_mut());
+        let resp = ffi::dbus_connection_send_with_reply_and_block(self.0, msg, timeout_ms as libc::c_int, e.get_mut());
         if resp != ptr::null_mut() {
             Ok((resp, std::mem::transmute(ffi::dbus_message_get_type(resp))))
         } else {
@@ -83,13 +214,19 @@ impl Connection {
     ///
     /// Panics if the underlying DBus method returns NULL, which only happens if the system
     /// has run out of memory.
-    pub fn call_method_sync<D, P, I, M>(&self, destination: D, path: P, iface: I, method: M, args: &[MessageItem])
-                                       -> Result<MethodReturn, super::Error>
-        where D: ToCStr, P: ToCStr, I: ToCStr, M: ToCStr
+    pub fn call_method_sync<D, I, M>(&self, destination: D, path: &str, iface: I, method: M, args: &[MessageItem])
+                                    -> Result<MethodReturn, super::Error>
+        where D: ToCStr, I: ToCStr, M: ToCStr
     {
-        let msg = MethodCall::new(destination, path, iface, method);
-        msg.append_items(args);
-        match unsafe { self.send_sync(msg.0) } {
+        let mut msg = try!(MethodCall::new(destination, &try!(Path::new(path)), iface, method));
+        try!(msg.try_append_items(args));
+        self.send_with_reply_and_block(&msg, -1)
+    }
+
+    /// Sends a method call and waits at most `timeout_ms` for the reply, or for the
+    /// default timeout if it is -1.
+    pub fn send_with_reply_and_block(&self, msg: &MethodCall, timeout_ms: int) -> Result<MethodReturn, super::Error> {
+        match unsafe { self.send_sync(msg.0, timeout_ms) } {
             Ok((resp, typ)) => match typ {
                 super::MessageType::MethodReturn => Ok(MethodReturn(resp)),
                 _ => panic!("method call received non-method-return value in response: {}", typ),
@@ -98,29 +235,418 @@ impl Connection {
         }
     }
 
-    pub fn stub<D, P>(&mut self, destination: D, path: P) -> Object
+    /// The unique name the bus gave this connection, e g ":1.42", or an empty string
+    /// if the connection is not registered with a bus.
+    pub fn unique_name(&self) -> &str {
+        let c = unsafe { ffi::dbus_bus_get_unique_name(self.0) };
+        // The name is owned by the connection, so it lives as long as we do.
+        super::c_str_to_slice(&c).map(|s| unsafe { std::mem::transmute::<&str, &str>(s) }).unwrap_or("")
+    }
+
+    /// Asks the bus to send us messages matching the rule, e g
+    /// `"type='signal',interface='org.freedesktop.DBus'"`.
+    pub fn add_match(&self, rule: &str) -> Result<(), super::Error> {
+        let r = try!(super::to_c_str(rule));
+        let mut e = super::Error::empty();
+        unsafe { ffi::dbus_bus_add_match(self.0, r.as_ptr(), e.get_mut()) };
+        if e.name().is_some() { Err(e) } else { Ok(()) }
+    }
+
+    /// Removes a rule added with `add_match`. The rule must be given exactly as it was added.
+    pub fn remove_match(&self, rule: &str) -> Result<(), super::Error> {
+        let r = try!(super::to_c_str(rule));
+        let mut e = super::Error::empty();
+        unsafe { ffi::dbus_bus_remove_match(self.0, r.as_ptr(), e.get_mut()) };
+        if e.name().is_some() { Err(e) } else { Ok(()) }
+    }
+
+    /// Adds a filter, which is called for every incoming message when the connection is
+    /// dispatched (see `read_write_dispatch`), in the order the filters were added.
+    ///
+    /// The closure is dropped when the filter is removed, or when the connection is.
+    pub fn add_filter<F>(&self, f: F) -> FilterToken
+        where F: FnMut(&super::Message) -> HandlerResult + 'static
+    {
+        let b: Box<FilterFn> = box (box f as FilterFn);
+        let p: *mut libc::c_void = unsafe { std::mem::transmute(b) };
+        if unsafe { ffi::dbus_connection_add_filter(self.0, Some(filter_cb as ffi::DBusCallback), p,
+            Some(free_filter_cb)) } == 0 {
+            free_filter_cb(p);
+            panic!("out of memory!");
+        }
+        FilterToken(p)
+    }
+
+    pub fn remove_filter(&self, token: FilterToken) {
+        // libdbus drops the closure through free_filter_cb.
+        unsafe { ffi::dbus_connection_remove_filter(self.0, Some(filter_cb as ffi::DBusCallback), token.0) };
+    }
+
+    /// Calls `f` for method calls to `path` when the connection is dispatched.
+    ///
+    /// If `f` returns a reply, it is sent back to the caller. If it returns None, the call is
+    /// passed on, and libdbus replies with an UnknownMethod error if nobody else handles it.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use dbus::newdbus::Connection;
+    ///
+    /// let conn = Connection::new().unwrap();
+    /// let _reg = conn.register_object_path("/com/example/Foo", |&mut: m| {
+    ///     Some(m.respond_with(&[]))
+    /// }).unwrap();
+    /// while conn.read_write_dispatch(1000) {}
+    /// ```
+    pub fn register_object_path<F>(&self, path: &str, f: F) -> Result<ObjectPathRegistration, super::Error>
+        where F: FnMut(&MethodCall) -> Option<MethodReturn> + 'static
+    {
+        let p = try!(super::to_c_str(path));
+        let b: Box<ObjectPathFn> = box (box f as ObjectPathFn);
+        let user_data: *mut libc::c_void = unsafe { std::mem::transmute(b) };
+        let vtable = ffi::DBusObjectPathVTable {
+            unregister_function: Some(unregister_object_path_cb),
+            message_function: Some(object_path_cb as ffi::DBusCallback),
+            dbus_internal_pad1: None,
+            dbus_internal_pad2: None,
+            dbus_internal_pad3: None,
+            dbus_internal_pad4: None,
+        };
+        let mut e = super::Error::empty();
+        if unsafe { ffi::dbus_connection_try_register_object_path(self.0, p.as_ptr(), &vtable, user_data, e.get_mut()) } == 0 {
+            // libdbus only calls unregister_function for registered paths
+            unregister_object_path_cb(self.0, user_data);
+            return Err(e);
+        }
+        Ok(ObjectPathRegistration { conn: self, path: p })
+    }
+
+    /// Reads and writes messages, waiting at most `timeout_ms` for something to happen,
+    /// and dispatches one incoming message to the filters.
+    ///
+    /// Returns false once the connection is disconnected and everything has been dispatched.
+    pub fn read_write_dispatch(&self, timeout_ms: int) -> bool {
+        unsafe { ffi::dbus_connection_read_write_dispatch(self.0, timeout_ms as libc::c_int) != 0 }
+    }
+
+    /// The file descriptor of the connection's socket, for registering it with an external
+    /// event loop such as poll or epoll.
+    ///
+    /// When the fd becomes readable, call `read_write(0)` and then `dispatch` until it
+    /// returns `DispatchStatus::Complete`. Do not read from or write to the fd directly.
+    pub fn as_raw_fd(&self) -> Option<libc::c_int> {
+        let mut fd = -1;
+        if unsafe { ffi::dbus_connection_get_unix_fd(self.0, &mut fd) } == 0 { None } else { Some(fd) }
+    }
+
+    /// Whether values of type `t` can be sent on this connection, e g 'h' for unix file descriptors.
+    pub fn can_send_type(&self, t: char) -> bool {
+        unsafe { ffi::dbus_connection_can_send_type(self.0, t as libc::c_int) != 0 }
+    }
+
+    /// Reads and writes messages without dispatching them, waiting at most `timeout_ms`.
+    /// A timeout of zero never blocks.
+    ///
+    /// Returns false if the connection is disconnected.
+    pub fn read_write(&self, timeout_ms: int) -> bool {
+        unsafe { ffi::dbus_connection_read_write(self.0, timeout_ms as libc::c_int) != 0 }
+    }
+
+    /// Dispatches one incoming message, if there is one, to the filters and object paths.
+    pub fn dispatch(&self) -> super::DispatchStatus {
+        unsafe { ffi::dbus_connection_dispatch(self.0) }
+    }
+
+    /// Whether there are incoming messages left to `dispatch`.
+    pub fn dispatch_status(&self) -> super::DispatchStatus {
+        unsafe { ffi::dbus_connection_get_dispatch_status(self.0) }
+    }
+
+    /// Takes the next incoming message off the queue, without passing it to filters or
+    /// object path handlers. For dispatch loops of their own, e g after `read_write`.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// while conn.read_write(1000) {
+    ///     while let Some(m) = conn.pop_message() {
+    ///         if let IncomingMessage::Signal(s) = m { println!("{}", s.member()); }
+    ///     }
+    /// }
+    /// ```
+    pub fn pop_message(&self) -> Option<IncomingMessage> {
+        let m = unsafe { ffi::dbus_connection_pop_message(self.0) };
+        if m == ptr::null_mut() { return None }
+        IncomingMessage::from_ptr(m)
+    }
+
+    /// Queues a message for sending without waiting for a reply, and returns its serial.
+    ///
+    /// The message is sent when the connection is next flushed or dispatched; use `flush`
+    /// to send it right away.
+    pub fn send<M: Message>(&self, msg: &M) -> Result<u32, super::Error> {
+        let mut serial = 0;
+        if unsafe { ffi::dbus_connection_send(self.0, msg.msg_ptr(), &mut serial) } == 0 {
+            return Err(super::no_memory());
+        }
+        Ok(serial)
+    }
+
+    /// Broadcasts a signal from the object at `path`, and returns its serial.
+    ///
+    /// Fails if a name is invalid or an argument cannot be appended.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use dbus::MessageItem;
+    /// use dbus::newdbus::Connection;
+    ///
+    /// let conn = Connection::new().unwrap();
+    /// conn.emit_signal("/com/example/Foo", "com.example.Foo", "Changed", &[MessageItem::UInt32(5)]).unwrap();
+    /// ```
+    pub fn emit_signal(&self, path: &str, iface: &str, member: &str, args: &[MessageItem]) -> Result<u32, super::Error> {
+        let mut s = try!(Signal::new(&try!(Path::new(path)), iface, member));
+        try!(s.try_append_items(args));
+        let r = try!(self.send(&s));
+        self.flush();
+        Ok(r)
+    }
+
+    /// Blocks until all queued messages have been written.
+    pub fn flush(&self) {
+        unsafe { ffi::dbus_connection_flush(self.0) }
+    }
+
+    pub fn stub<D, P>(&self, destination: D, path: P) -> Object
         where D: ToString, P: ToString
     {
         Object::new(self, destination, path)
     }
 }
 
+/// A connection that can be shared between threads, e g in an `Arc`.
+///
+/// libdbus locks the connection internally, so sending and dispatching may happen from
+/// any thread. Filters and object path handlers can be called from whichever thread
+/// dispatches, so they must be `Send`.
+pub struct SyncConnection {
+    conn: Connection,
+}
+
+unsafe impl Send for SyncConnection {}
+unsafe impl Sync for SyncConnection {}
+
+impl SyncConnection {
+    pub fn new_for_type(bus: super::BusType) -> Result<SyncConnection, super::Error> {
+        Connection::new_for_type(bus).map(|c| SyncConnection { conn: c })
+    }
+
+    pub fn open_address(address: &str) -> Result<SyncConnection, super::Error> {
+        Connection::open_address(address).map(|c| SyncConnection { conn: c })
+    }
+
+    pub fn bus_register(&self) -> Result<(), super::Error> { self.conn.bus_register() }
+
+    pub fn unique_name(&self) -> &str { self.conn.unique_name() }
+
+    pub fn call_method_sync(&self, destination: &str, path: &str, iface: &str, method: &str, args: &[MessageItem])
+                            -> Result<MethodReturn, super::Error> {
+        self.conn.call_method_sync(destination, path, iface, method, args)
+    }
+
+    pub fn send<M: Message>(&self, msg: &M) -> Result<u32, super::Error> { self.conn.send(msg) }
+
+    pub fn emit_signal(&self, path: &str, iface: &str, member: &str, args: &[MessageItem]) -> Result<u32, super::Error> {
+        self.conn.emit_signal(path, iface, member, args)
+    }
+
+    pub fn flush(&self) { self.conn.flush() }
+
+    pub fn add_match(&self, rule: &str) -> Result<(), super::Error> { self.conn.add_match(rule) }
+
+    pub fn remove_match(&self, rule: &str) -> Result<(), super::Error> { self.conn.remove_match(rule) }
+
+    pub fn add_filter<F>(&self, f: F) -> FilterToken
+        where F: FnMut(&super::Message) -> HandlerResult + Send + 'static
+    {
+        self.conn.add_filter(f)
+    }
+
+    pub fn remove_filter(&self, token: FilterToken) { self.conn.remove_filter(token) }
+
+    pub fn register_object_path<F>(&self, path: &str, f: F) -> Result<ObjectPathRegistration, super::Error>
+        where F: FnMut(&MethodCall) -> Option<MethodReturn> + Send + 'static
+    {
+        self.conn.register_object_path(path, f)
+    }
+
+    pub fn read_write_dispatch(&self, timeout_ms: int) -> bool { self.conn.read_write_dispatch(timeout_ms) }
+
+    pub fn as_raw_fd(&self) -> Option<libc::c_int> { self.conn.as_raw_fd() }
+
+    pub fn can_send_type(&self, t: char) -> bool { self.conn.can_send_type(t) }
+
+    pub fn read_write(&self, timeout_ms: int) -> bool { self.conn.read_write(timeout_ms) }
+
+    pub fn dispatch(&self) -> super::DispatchStatus { self.conn.dispatch() }
+
+    pub fn dispatch_status(&self) -> super::DispatchStatus { self.conn.dispatch_status() }
+}
+
 impl Drop for Connection {
     fn drop(&mut self) {
         unsafe {
-            ffi::dbus_connection_close(self.0);
+            // libdbus does not allow closing shared connections
+            if !self.1 { ffi::dbus_connection_close(self.0); }
             ffi::dbus_connection_unref(self.0);
         }
     }
 }
 
-pub struct Object {
-    conn: *mut Connection,
+/* Callbacks get a pointer to this, so it is boxed to keep it in place. */
+struct IServer {
+    watches: std::cell::RefCell<Vec<*mut ffi::DBusWatch>>,
+    pending: std::cell::RefCell<std::collections::DList<*mut ffi::DBusConnection>>,
+}
+
+extern "C" fn new_connection_cb(_: *mut ffi::DBusServer, conn: *mut ffi::DBusConnection, user_data: *mut libc::c_void) {
+    let i: &IServer = unsafe { std::mem::transmute(user_data) };
+    // libdbus drops the connection unless we take a reference to it
+    unsafe { ffi::dbus_connection_ref(conn) };
+    i.pending.borrow_mut().push_back(conn);
+}
+
+extern "C" fn add_watch_cb(watch: *mut ffi::DBusWatch, user_data: *mut libc::c_void) -> u32 {
+    let i: &IServer = unsafe { std::mem::transmute(user_data) };
+    i.watches.borrow_mut().push(watch);
+    1
+}
+
+extern "C" fn remove_watch_cb(watch: *mut ffi::DBusWatch, user_data: *mut libc::c_void) {
+    let i: &IServer = unsafe { std::mem::transmute(user_data) };
+    i.watches.borrow_mut().retain(|w| *w != watch);
+}
+
+extern "C" fn toggle_watch_cb(_: *mut ffi::DBusWatch, _: *mut libc::c_void) {
+    // Enabled state is checked on every accept.
+}
+
+/// Listens for peer-to-peer connections, so that two processes can talk D-Bus without
+/// a bus daemon.
+///
+/// # Example
+///
+/// ```
+/// use dbus::newdbus::{Connection, Server};
+///
+/// let server = Server::listen("unix:tmpdir=/tmp").unwrap();
+/// let client = Connection::open_address(server.address().as_slice()).unwrap();
+/// let mut peer = None;
+/// while peer.is_none() {
+///     // Authentication needs the client side to be dispatched as well
+///     client.read_write_dispatch(0);
+///     peer = server.accept(100);
+/// }
+/// ```
+pub struct Server {
+    ptr: *mut ffi::DBusServer,
+    i: Box<IServer>,
+}
+
+impl Server {
+    /// Listens on the given address, e g `"unix:path=/run/myhelper"` or `"unix:tmpdir=/tmp"`.
+    pub fn listen(address: &str) -> Result<Server, super::Error> {
+        super::init_dbus();
+        let a = try!(super::to_c_str(address));
+        let mut e = super::Error::empty();
+        let ptr = unsafe { ffi::dbus_server_listen(a.as_ptr(), e.get_mut()) };
+        if ptr == ptr::null_mut() {
+            return Err(e);
+        }
+        let s = Server { ptr: ptr, i: box IServer {
+            watches: std::cell::RefCell::new(vec!()), pending: std::cell::RefCell::new(std::collections::DList::new()) } };
+        unsafe {
+            let user_data: *mut libc::c_void = std::mem::transmute(&*s.i);
+            ffi::dbus_server_set_new_connection_function(ptr, Some(new_connection_cb), user_data, None);
+            if ffi::dbus_server_set_watch_functions(ptr, Some(add_watch_cb), Some(remove_watch_cb),
+                Some(toggle_watch_cb), user_data, None) == 0 {
+                return Err(super::no_memory());
+            }
+        }
+        Ok(s)
+    }
+
+    /// The address clients connect to. For addresses like `unix:tmpdir=`, this is
+    /// where the server actually ended up listening.
+    pub fn address(&self) -> String {
+        let c = unsafe { ffi::dbus_server_get_address(self.ptr) };
+        let r = super::c_str_to_slice(&(c as *const libc::c_char)).unwrap_or("").to_string();
+        unsafe { ffi::dbus_free(c as *mut libc::c_void) };
+        r
+    }
+
+    pub fn is_connected(&self) -> bool {
+        unsafe { ffi::dbus_server_get_is_connected(self.ptr) != 0 }
+    }
+
+    /// Stops listening. Connections already accepted are not affected.
+    pub fn disconnect(&self) {
+        unsafe { ffi::dbus_server_disconnect(self.ptr) }
+    }
+
+    /// Waits at most `timeout_ms` for a client to connect, and returns the connection to it.
+    ///
+    /// The connection is not to a bus, so there are no names or match rules; messages
+    /// are sent directly to the peer, with an empty destination.
+    pub fn accept(&self, timeout_ms: int) -> Option<Connection> {
+        if let Some(c) = self.i.pending.borrow_mut().pop_front() { return Some(Connection(c, false)) }
+
+        // Handling a watch may add or remove watches, so work on a copy.
+        let watches: Vec<*mut ffi::DBusWatch> = self.i.watches.borrow().iter()
+            .filter(|w| unsafe { ffi::dbus_watch_get_enabled(**w) != 0 }).map(|w| *w).collect();
+        let mut fds: Vec<ffi::PollFd> = watches.iter().map(|w| {
+            let flags = unsafe { ffi::dbus_watch_get_flags(*w) };
+            let mut events = 0;
+            if flags & ffi::DBUS_WATCH_READABLE != 0 { events |= ffi::POLLIN }
+            if flags & ffi::DBUS_WATCH_WRITABLE != 0 { events |= ffi::POLLOUT }
+            ffi::PollFd { fd: unsafe { ffi::dbus_watch_get_unix_fd(*w) }, events: events, revents: 0 }
+        }).collect();
+
+        if unsafe { ffi::poll(fds.as_mut_ptr(), fds.len() as libc::c_ulong, timeout_ms as libc::c_int) } <= 0 {
+            return None;
+        }
+        for (w, fd) in watches.iter().zip(fds.iter()) {
+            let mut flags = 0;
+            if fd.revents & ffi::POLLIN != 0 { flags |= ffi::DBUS_WATCH_READABLE }
+            if fd.revents & ffi::POLLOUT != 0 { flags |= ffi::DBUS_WATCH_WRITABLE }
+            if fd.revents & ffi::POLLERR != 0 { flags |= ffi::DBUS_WATCH_ERROR }
+            if fd.revents & ffi::POLLHUP != 0 { flags |= ffi::DBUS_WATCH_HANGUP }
+            if flags != 0 { unsafe { ffi::dbus_watch_handle(*w, flags) }; }
+        }
+        self.i.pending.borrow_mut().pop_front().map(|c| Connection(c, false))
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::dbus_server_disconnect(self.ptr);
+            ffi::dbus_server_unref(self.ptr);
+        }
+        for c in self.i.pending.borrow_mut().iter() {
+            drop(Connection(*c, false));
+        }
+    }
+}
+
+pub struct Object<'a> {
+    conn: &'a Connection,
     destination: String,
     path: String,
 }
 
-impl Object {
+impl<'a> Object<'a> {
     /// Create a new DBus object stub.
     ///
     /// Object stubs are useful for defining a reusable endpoint, avoiding
@@ -131,7 +657,7 @@ impl Object {
     /// ```
     /// use dbus::newdbus::Connection;
     ///
-    /// let mut conn = match Connection::new() {
+    /// let conn = match Connection::new() {
     ///     Ok(conn) => conn,
     ///     Err(e) => panic!("failed to create connection: {}", e),
     /// };
@@ -149,11 +675,11 @@ impl Object {
     ///     Err(e) => { /* something went wrong */ },
     /// }
     /// ```
-    pub fn new<D, P>(conn: &mut Connection, destination: D, path: P) -> Object
+    pub fn new<D, P>(conn: &'a Connection, destination: D, path: P) -> Object<'a>
         where D: ToString, P: ToString
     {
         Object{
-            conn: conn as *mut Connection,
+            conn: conn,
             destination: destination.to_string(),
             path: path.to_string(),
         }
@@ -162,9 +688,7 @@ impl Object {
     pub fn call_full<I, M>(&self, iface: I, method: M, args: &[MessageItem]) -> Result<MethodReturn, super::Error>
         where I: ToCStr, M: ToCStr
     {
-        unsafe {
-            (*self.conn).call_method_sync(self.destination.as_slice(), self.path.as_slice(), iface, method, args)
-        }
+        self.conn.call_method_sync(self.destination.as_slice(), self.path.as_slice(), iface, method, args)
     }
 
     pub fn call<M>(&self, method: M, args: &[MessageItem]) -> Result<MethodReturn, super::Error>
@@ -183,8 +707,19 @@ macro_rules! define_message_types {
 
         // NOTE: it would be nice if this could hook into #[deriving]
         impl Message for $i {
-            fn get_items(&self) -> Vec<MessageItem> { get_items(self.0) }
-            fn append_items(&self, v: &[MessageItem]) { append_items(self.0, v) }
+            fn get_items(&self) -> Result<Vec<MessageItem>, super::Error> { get_items(self.0) }
+            fn try_append_items(&mut self, v: &[MessageItem]) -> Result<(), super::Error> { append_items(self.0, v) }
+            fn msg_ptr(&self) -> *mut ffi::DBusMessage { self.0 }
+        }
+
+        impl Drop for $i {
+            fn drop(&mut self) { unsafe { ffi::dbus_message_unref(self.0) } }
+        }
+
+        /// Clones are copies, so appending to one does not change the other (or move
+        /// data borrowed from it, e g by `Iter::get_fixed_array`).
+        impl Clone for $i {
+            fn clone(&self) -> $i { $i(unsafe { copy_message(self.0) }) }
         }
     )+}
 }
@@ -205,48 +740,168 @@ macro_rules! check_memory {
 define_message_types! {
     MethodCall,
     MethodReturn,
+    Signal,
     Error
 }
 
 pub trait Message {
-    fn get_items(&self) -> Vec<MessageItem>;
-    fn append_items(&self, v: &[MessageItem]);
+    /// Reads all arguments, see `dbus::Message::get_items`.
+    fn get_items(&self) -> Result<Vec<MessageItem>, super::Error>;
+    /// Appends arguments, see `dbus::Message::try_append_items`.
+    fn try_append_items(&mut self, v: &[MessageItem]) -> Result<(), super::Error>;
+
+    /// Like `try_append_items`, but panics if an item cannot be appended.
+    fn append_items(&mut self, v: &[MessageItem]) {
+        if let Err(e) = self.try_append_items(v) { panic!("{}", e) }
+    }
+
+    #[doc(hidden)]
+    fn msg_ptr(&self) -> *mut ffi::DBusMessage;
+
+    /// Appends an argument directly from a Rust type, see `dbus::iter`.
+    fn append1<A: Append>(&mut self, a: A) -> Result<(), super::Error> {
+        IterAppend::from_message(self).append(a)
+    }
+
+    fn append2<A: Append, B: Append>(&mut self, a: A, b: B) -> Result<(), super::Error> {
+        let mut i = IterAppend::from_message(self);
+        try!(i.append(a));
+        i.append(b)
+    }
+
+    fn append3<A: Append, B: Append, C: Append>(&mut self, a: A, b: B, c: C) -> Result<(), super::Error> {
+        let mut i = IterAppend::from_message(self);
+        try!(i.append(a));
+        try!(i.append(b));
+        i.append(c)
+    }
+
+    /// The object path the message is sent to (method calls) or from (signals).
+    fn path(&self) -> Option<String> { unsafe { msg_str(ffi::dbus_message_get_path(self.msg_ptr())) } }
+
+    fn interface(&self) -> Option<String> { unsafe { msg_str(ffi::dbus_message_get_interface(self.msg_ptr())) } }
+
+    /// The method or signal name.
+    fn member(&self) -> Option<String> { unsafe { msg_str(ffi::dbus_message_get_member(self.msg_ptr())) } }
+
+    /// The unique name of the sender, e g ":1.42". Set by the bus, so None for messages not yet sent.
+    fn sender(&self) -> Option<String> { unsafe { msg_str(ffi::dbus_message_get_sender(self.msg_ptr())) } }
+
+    fn destination(&self) -> Option<String> { unsafe { msg_str(ffi::dbus_message_get_destination(self.msg_ptr())) } }
+
+    /// The serial, or 0 if the message has not been sent.
+    fn serial(&self) -> u32 { unsafe { ffi::dbus_message_get_serial(self.msg_ptr()) } }
+
+    /// The signature of the arguments, e g "sa{sv}".
+    fn signature(&self) -> String {
+        unsafe { msg_str(ffi::dbus_message_get_signature(self.msg_ptr())) }.unwrap_or(String::new())
+    }
+}
+
+/* Copied, since setting a header or appending an argument can move the ones already there. */
+unsafe fn msg_str(p: *const libc::c_char) -> Option<String> {
+    super::c_str_to_slice(&p).map(|s| s.to_string())
+}
+
+/// A message of any type, for matching on what was received.
+pub enum IncomingMessage {
+    MethodCall(MethodCall),
+    MethodReturn(MethodReturn),
+    Signal(Signal),
+    Error(Error),
+}
+
+impl IncomingMessage {
+    /// Wraps a message according to its type, taking over a reference to it.
+    /// Returns None, and releases the reference, for types libdbus does not know.
+    fn from_ptr(ptr: *mut ffi::DBusMessage) -> Option<IncomingMessage> {
+        // Compared as integers, since an unknown type must not end up in a MessageType
+        let t = unsafe { ffi::dbus_message_get_type(ptr) };
+        if t == ffi::DBusMessageType::MethodCall as libc::c_int { Some(IncomingMessage::MethodCall(MethodCall(ptr))) }
+        else if t == ffi::DBusMessageType::MethodReturn as libc::c_int { Some(IncomingMessage::MethodReturn(MethodReturn(ptr))) }
+        else if t == ffi::DBusMessageType::Signal as libc::c_int { Some(IncomingMessage::Signal(Signal(ptr))) }
+        else if t == ffi::DBusMessageType::Error as libc::c_int { Some(IncomingMessage::Error(Error(ptr))) }
+        else {
+            unsafe { ffi::dbus_message_unref(ptr) };
+            None
+        }
+    }
+
+    pub fn msg_type(&self) -> super::MessageType {
+        match self {
+            &IncomingMessage::MethodCall(_) => super::MessageType::MethodCall,
+            &IncomingMessage::MethodReturn(_) => super::MessageType::MethodReturn,
+            &IncomingMessage::Signal(_) => super::MessageType::Signal,
+            &IncomingMessage::Error(_) => super::MessageType::Error,
+        }
+    }
+}
+
+impl Message for IncomingMessage {
+    fn get_items(&self) -> Result<Vec<MessageItem>, super::Error> { get_items(self.msg_ptr()) }
+    fn try_append_items(&mut self, v: &[MessageItem]) -> Result<(), super::Error> { append_items(self.msg_ptr(), v) }
+    fn msg_ptr(&self) -> *mut ffi::DBusMessage {
+        match self {
+            &IncomingMessage::MethodCall(ref m) => m.0,
+            &IncomingMessage::MethodReturn(ref m) => m.0,
+            &IncomingMessage::Signal(ref m) => m.0,
+            &IncomingMessage::Error(ref m) => m.0,
+        }
+    }
 }
 
-fn get_items(ptr: *mut ffi::DBusMessage) -> Vec<MessageItem> {
+fn get_items(ptr: *mut ffi::DBusMessage) -> Result<Vec<MessageItem>, super::Error> {
     let mut i = super::new_dbus_message_iter();
     match unsafe { ffi::dbus_message_iter_init(ptr, &mut i) } {
-        0 => Vec::new(),
-        _ => MessageItem::from_iter(&mut i)
+        0 => Ok(Vec::new()),
+        _ => MessageItem::from_iter(&mut i, &mut super::ReadState::new(super::Utf8Policy::Strict,
+            std::default::Default::default()))
     }
 }
 
-fn append_items(ptr: *mut ffi::DBusMessage, v: &[MessageItem]) {
+/* Unlike dbus_message_copy alone, keeps the serial, so that a copy of a call can be replied to. */
+unsafe fn copy_message(ptr: *mut ffi::DBusMessage) -> *mut ffi::DBusMessage {
+    let c = check_memory!(ffi::dbus_message_copy(ptr as *const ffi::DBusMessage));
+    let serial = ffi::dbus_message_get_serial(ptr);
+    if serial != 0 { ffi::dbus_message_set_serial(c, serial) }
+    c
+}
+
+fn append_items(ptr: *mut ffi::DBusMessage, v: &[MessageItem]) -> Result<(), super::Error> {
+    for item in v.iter() { try!(item.check_append()); }
     let mut i = super::new_dbus_message_iter();
     unsafe { ffi::dbus_message_iter_init_append(ptr, &mut i) };
-    MessageItem::copy_to_iter(&mut i, v);
+    MessageItem::copy_to_iter(&mut i, v)
 }
 
 impl MethodCall {
-    /// Create a new method call.
-    pub fn new<D, P, I, M>(destination: D, path: P, iface: I, method: M) -> MethodCall
-        where D: ToCStr, P: ToCStr, I: ToCStr, M: ToCStr
+    /// Create a new method call. The destination and interface can be empty.
+    ///
+    /// Fails if a name is not valid.
+    pub fn new<D, I, M>(destination: D, path: &Path, iface: I, method: M) -> Result<MethodCall, super::Error>
+        where D: ToCStr, I: ToCStr, M: ToCStr
     {
         super::init_dbus();
 
-        let destination = destination.to_c_str();
-        let path = path.to_c_str();
-        let iface = iface.to_c_str();
-        let method = method.to_c_str();
+        let destination = try!(checked_name(destination, "destination", true, super::is_valid_bus_name));
+        let path = path.as_str().to_c_str();
+        let iface = try!(checked_name(iface, "interface", true, super::is_valid_interface_name));
+        let method = try!(checked_name(method, "member", false, super::is_valid_member_name));
 
-        MethodCall(check_memory!(unsafe {
+        Ok(MethodCall(check_memory!(unsafe {
             ffi::dbus_message_new_method_call(
                 if destination.is_empty() { ptr::null() } else { destination.as_ptr() },
                 path.as_ptr(),
                 if iface.is_empty() { ptr::null() } else { iface.as_ptr() },
                 method.as_ptr(),
             )
-        }))
+        })))
+    }
+
+    /// Starts building a method call, with optional headers and flags given explicitly.
+    pub fn builder() -> MethodCallBuilder {
+        MethodCallBuilder { destination: None, path: None, interface: None, member: None, args: vec!(),
+            no_reply: false, auto_start: true, timeout_ms: -1 }
     }
 
     /// Create a new response for this call.
@@ -257,8 +912,8 @@ impl MethodCall {
     /// Create a new error in response to this call.
     ///
     /// If `name` is empty, then the string `"org.freedesktop.DBus.Error.Failed"` will
-    /// be used instead.
-    pub fn new_error<N, M>(&self, name: N, message: M) -> Error
+    /// be used instead. Fails if `name` is not a valid error name.
+    pub fn new_error<N, M>(&self, name: N, message: M) -> Result<Error, super::Error>
         where N: ToCStr, M: ToCStr
     {
         Error::new(self.0, name, message)
@@ -266,30 +921,267 @@ impl MethodCall {
 
     /// Create a new response for this call and populate it with the provided messages.
     pub fn respond_with(&self, v: &[MessageItem]) -> MethodReturn {
-        let response = self.new_return();
+        let mut response = self.new_return();
         response.append_items(v);
         response
     }
+
+    /// Tells the receiver not to send a reply, for calls whose result the caller ignores.
+    /// The receiver may still send one, so this is only an optimization.
+    pub fn set_no_reply(&self, no_reply: bool) {
+        unsafe { ffi::dbus_message_set_no_reply(self.0, no_reply as u32) }
+    }
+
+    pub fn get_no_reply(&self) -> bool {
+        unsafe { ffi::dbus_message_get_no_reply(self.0) != 0 }
+    }
+
+    /// Whether the bus should start the destination service if it is not running. On by default.
+    pub fn set_auto_start(&self, auto_start: bool) {
+        unsafe { ffi::dbus_message_set_auto_start(self.0, auto_start as u32) }
+    }
+
+    pub fn get_auto_start(&self) -> bool {
+        unsafe { ffi::dbus_message_get_auto_start(self.0) != 0 }
+    }
+}
+
+/// Builds a method call, see `MethodCall::builder`.
+pub struct MethodCallBuilder {
+    destination: Option<String>,
+    path: Option<String>,
+    interface: Option<String>,
+    member: Option<String>,
+    args: Vec<MessageItem>,
+    no_reply: bool,
+    auto_start: bool,
+    timeout_ms: int,
+}
+
+fn missing_header(name: &str) -> super::Error {
+    let f = format!("Method call has no {}", name);
+    super::Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs", f.as_slice())
+}
+
+fn invalid_header(name: &str, value: &str) -> super::Error {
+    let f = format!("'{}' is not a valid {}", value, name);
+    super::Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs", f.as_slice())
+}
+
+/* libdbus returns NULL (or aborts) for invalid names, and ToCStr panics on NUL bytes,
+   so names are checked here instead. */
+fn checked_name<T: ToCStr>(s: T, what: &str, optional: bool, valid: fn(&str) -> bool) -> Result<std::c_str::CString, super::Error> {
+    let c = unsafe { s.to_c_str_unchecked() };
+    let ok = match std::str::from_utf8(c.as_bytes_no_nul()) {
+        Ok(n) => (optional && n.is_empty()) || valid(n),
+        Err(_) => false,
+    };
+    if ok { return Ok(c) }
+    Err(invalid_header(what, String::from_utf8_lossy(c.as_bytes_no_nul()).as_slice()))
+}
+
+impl MethodCallBuilder {
+    /// The bus name to send to. Leave it out on peer-to-peer connections.
+    pub fn destination(&mut self, d: &str) -> &mut MethodCallBuilder {
+        self.destination = Some(d.to_string());
+        self
+    }
+
+    /// Required.
+    pub fn path(&mut self, p: &str) -> &mut MethodCallBuilder {
+        self.path = Some(p.to_string());
+        self
+    }
+
+    /// Can be left out if the member name is unique among the object's interfaces.
+    pub fn interface(&mut self, i: &str) -> &mut MethodCallBuilder {
+        self.interface = Some(i.to_string());
+        self
+    }
+
+    /// The method name. Required.
+    pub fn member(&mut self, m: &str) -> &mut MethodCallBuilder {
+        self.member = Some(m.to_string());
+        self
+    }
+
+    pub fn arg<A: IntoItem>(&mut self, a: A) -> &mut MethodCallBuilder {
+        self.args.push(a.into_item());
+        self
+    }
+
+    /// See `MethodCall::set_no_reply`.
+    pub fn no_reply(&mut self, no_reply: bool) -> &mut MethodCallBuilder {
+        self.no_reply = no_reply;
+        self
+    }
+
+    /// See `MethodCall::set_auto_start`.
+    pub fn auto_start(&mut self, auto_start: bool) -> &mut MethodCallBuilder {
+        self.auto_start = auto_start;
+        self
+    }
+
+    /// How long `call` waits for the reply. Defaults to -1, the libdbus default.
+    pub fn timeout(&mut self, timeout_ms: int) -> &mut MethodCallBuilder {
+        self.timeout_ms = timeout_ms;
+        self
+    }
+
+    /// Checks the headers and arguments, and creates the message. The timeout is
+    /// not part of the message, so it only applies to `call`.
+    pub fn build(&self) -> Result<MethodCall, super::Error> {
+        let path = match self.path { Some(ref p) => try!(Path::new(p.as_slice())), None => return Err(missing_header("path")) };
+        let member = match self.member { Some(ref m) => m.as_slice(), None => return Err(missing_header("member")) };
+        let destination = self.destination.as_ref().map_or("", |s| s.as_slice());
+        let interface = self.interface.as_ref().map_or("", |s| s.as_slice());
+        // libdbus aborts the process on invalid names, so check them first
+        if destination.len() > 0 && !super::is_valid_bus_name(destination) { return Err(invalid_header("destination", destination)) }
+        if interface.len() > 0 && !super::is_valid_interface_name(interface) { return Err(invalid_header("interface", interface)) }
+        if !super::is_valid_member_name(member) { return Err(invalid_header("member", member)) }
+        for a in self.args.iter() { try!(a.check_append()); }
+
+        let m = try!(MethodCall::new(destination, &path, interface, member));
+        let mut i = super::new_dbus_message_iter();
+        unsafe { ffi::dbus_message_iter_init_append(m.0, &mut i) };
+        try!(MessageItem::copy_to_iter(&mut i, self.args.as_slice()));
+        m.set_no_reply(self.no_reply);
+        m.set_auto_start(self.auto_start);
+        Ok(m)
+    }
+
+    /// Builds the message, sends it and waits for the reply.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let r = try!(MethodCall::builder().destination("org.freedesktop.DBus").path("/")
+    ///     .interface("org.freedesktop.DBus").member("GetNameOwner").arg("org.freedesktop.DBus")
+    ///     .timeout(500).call(&conn));
+    /// ```
+    pub fn call(&self, conn: &Connection) -> Result<MethodReturn, super::Error> {
+        let m = try!(self.build());
+        conn.send_with_reply_and_block(&m, self.timeout_ms)
+    }
+}
+
+impl MethodReturn {
+    /// Reads the first return value as a Rust type.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let r = try!(conn.call_method_sync("org.freedesktop.DBus", "/", "org.freedesktop.DBus", "GetNameOwner",
+    ///     &["org.freedesktop.DBus".into_item()]));
+    /// let owner: String = try!(r.read1().map_err(|e| e.to_error()));
+    /// ```
+    pub fn read1<A: Get>(&self) -> Result<A, TypeMismatch> {
+        Iter::from_message(self).read()
+    }
+
+    pub fn read2<A: Get, B: Get>(&self) -> Result<(A, B), TypeMismatch> {
+        let mut i = Iter::from_message(self);
+        let a = try!(i.read());
+        Ok((a, try!(i.read())))
+    }
+
+    pub fn read3<A: Get, B: Get, C: Get>(&self) -> Result<(A, B, C), TypeMismatch> {
+        let mut i = Iter::from_message(self);
+        let a = try!(i.read());
+        let b = try!(i.read());
+        Ok((a, b, try!(i.read())))
+    }
+}
+
+impl Signal {
+    /// Create a new signal, to be sent from the object at `path`.
+    ///
+    /// Fails if `iface` or `member` is not a valid name.
+    pub fn new<I, M>(path: &Path, iface: I, member: M) -> Result<Signal, super::Error>
+        where I: ToCStr, M: ToCStr
+    {
+        super::init_dbus();
+
+        let path = path.as_str().to_c_str();
+        let iface = try!(checked_name(iface, "interface", false, super::is_valid_interface_name));
+        let member = try!(checked_name(member, "member", false, super::is_valid_member_name));
+
+        Ok(Signal(check_memory!(unsafe {
+            ffi::dbus_message_new_signal(path.as_ptr(), iface.as_ptr(), member.as_ptr())
+        })))
+    }
+
+    /// True if this is the signal `member` on the interface `iface`.
+    pub fn is_signal<I, M>(&self, iface: I, member: M) -> bool
+        where I: ToCStr, M: ToCStr
+    {
+        // No signal has an invalid name
+        let iface = match checked_name(iface, "interface", false, super::is_valid_interface_name) { Ok(i) => i, Err(_) => return false };
+        let member = match checked_name(member, "member", false, super::is_valid_member_name) { Ok(m) => m, Err(_) => return false };
+        unsafe { ffi::dbus_message_is_signal(self.0, iface.as_ptr(), member.as_ptr()) != 0 }
+    }
 }
 
 impl Error {
-    /// Helper for constructing error messages.
-    fn new<N, M>(reply_to: *mut ffi::DBusMessage, name: N, message: M) -> Error
+    /// Helper for constructing error messages. NUL bytes in `message` are escaped.
+    fn new<N, M>(reply_to: *mut ffi::DBusMessage, name: N, message: M) -> Result<Error, super::Error>
         where N: ToCStr, M: ToCStr
     {
-        let mut name = name.to_c_str();
-        let message = message.to_c_str();
+        let mut name = try!(checked_name(name, "error name", true, super::is_valid_interface_name));
+        let message = unsafe { message.to_c_str_unchecked() };
+        let message = String::from_utf8_lossy(message.as_bytes_no_nul()).replace("\0", "\\0").to_c_str();
 
         if name.is_empty() {
             name = "org.freedesktop.DBus.Error.Failed".to_c_str();
         }
 
-        Error(check_memory!(unsafe {
+        Ok(Error(check_memory!(unsafe {
             ffi::dbus_message_new_error(
                 reply_to,
                 name.as_ptr(),
                 message.as_ptr(),
             )
-        }))
+        })))
     }
 }
+
+#[test]
+fn builder_checks_names() {
+    let mut b = MethodCall::builder();
+    b.destination("org.freedesktop.DBus").path("/").interface("org.freedesktop.DBus").member("Get-Id");
+    let e = b.build().err().unwrap();
+    assert_eq!(e.name(), Some("org.freedesktop.DBus.Error.InvalidArgs"));
+    b.member("GetId");
+    assert_eq!(b.build().unwrap().member(), Some("GetId".to_string()));
+    b.interface("Introspectable");
+    assert!(b.build().is_err());
+    b.interface("org.freedesktop.DBus").destination("org..freedesktop");
+    assert!(b.build().is_err());
+    b.destination(":1.42");
+    assert!(b.build().is_ok());
+}
+
+#[test]
+fn checked_names() {
+    let p = Path::new("/").unwrap();
+    assert!(Signal::new(&p, "com.example.Foo", "Bar\0").is_err());
+    let s = Signal::new(&p, "com.example.Foo", "Bar").unwrap();
+    assert!(s.is_signal("com.example.Foo", "Bar"));
+    assert!(!s.is_signal("com.example.Foo", "Bar\0"));
+
+    let m = MethodCall::new("", &p, "", "Foo").unwrap();
+    assert!(MethodCall::new("", &p, "com.example\0", "Foo").is_err());
+    assert!(m.new_error("com.example.Error", "Bad \0 input").is_ok());
+    assert!(m.new_error("com.example.Error\0", "").is_err());
+    let e = super::Error::new_custom("com.example.Error", "a\0b");
+    assert_eq!(e.message(), Some("a\\0b"));
+}
+
+#[test]
+fn append_errors() {
+    let mut s = Signal::new(&Path::new("/").unwrap(), "com.example.Foo", "Bar").unwrap();
+    assert!(s.try_append_items(&[MessageItem::Array(vec!(), "".to_string())]).is_err());
+    assert!(s.try_append_items(&[MessageItem::Str("a\0b".to_string())]).is_err());
+    assert!(s.try_append_items(&[MessageItem::Str("ab".to_string())]).is_ok());
+    assert_eq!(s.signature().as_slice(), "s");
+}
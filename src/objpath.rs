@@ -1,19 +1,36 @@
 use super::{Connection, Message, MessageItem, Error};
-use std::collections::BTreeMap;
+use super::arg::{ArgList, ReadOptions};
+use super::watchdog::Watchdog;
+use super::allowlist::SenderAllowList;
+use std::collections::{BTreeMap, HashMap};
 use std::rc::{Rc, Weak};
 use std::cell::{Cell, RefCell};
 
 pub struct Argument<'a> {
     name: &'a str,
-    sig: &'a str,
+    sig: String,
 }
 
 pub type MethodResult<'a> = Result<Vec<MessageItem>, (&'a str, String)>;
 pub type PropertyGetResult = Result<MessageItem, (&'static str, String)>;
 pub type PropertySetResult = Result<(), (&'static str, String)>;
 
+/// Information about an incoming method call, passed to method handlers.
+pub struct Context {
+    /// The number of milliseconds the caller is expected to wait for the reply,
+    /// or None if the caller does not expect a reply at all.
+    ///
+    /// D-Bus does not transmit the caller's timeout, so this is the server's
+    /// default timeout (see `ObjectPath::set_default_timeout`) unless the
+    /// message has the no-reply flag set.
+    pub timeout_ms: Option<int>,
+    /// The Linux security label of the caller, if `ObjectPath::set_fetch_security_label`
+    /// is enabled and the bus knows the label.
+    pub security_label: Option<Vec<u8>>,
+}
+
 pub trait MethodHandler<'a> {
-    fn handle(&self, &mut Message) -> MethodResult;
+    fn handle(&self, &mut Message, &Context) -> MethodResult;
 }
 
 pub struct Method<'a> {
@@ -46,16 +63,93 @@ pub struct Property<'a> {
     access: PropertyAccess<'a>,
 }
 
+impl<'a> Method<'a> {
+    /// Creates a method from a closure taking and returning tuples of Rust types.
+    ///
+    /// The in and out signatures are inferred from the tuple types, and incoming arguments
+    /// that do not match the in signature are replied to with InvalidArgs.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let m = Method::new_typed(&["a", "b"], &["sum"],
+    ///     |&: (a, b): (u32, u32)| Ok((a + b,)));
+    /// ```
+    pub fn new_typed<I, O, F>(in_names: &[&'a str], out_names: &[&'a str], f: F) -> Method<'a>
+        where I: ArgList, O: ArgList, F: Fn(I) -> Result<O, (&'static str, String)> + 'a
+    {
+        let in_sigs = <I as ArgList>::signatures();
+        let out_sigs = <O as ArgList>::signatures();
+        assert_eq!(in_names.len(), in_sigs.len());
+        assert_eq!(out_names.len(), out_sigs.len());
+
+        let h = move |&: msg: &mut Message| -> MethodResult<'static> {
+            let items = try!(msg.get_items().map_err(unreadable_args));
+            match ArgList::read(items.as_slice(), &ReadOptions::strict()) {
+                Some(i) => f(i).map(|o: O| o.to_items()),
+                None => Err(("org.freedesktop.DBus.Error.InvalidArgs", format!("Invalid arguments {}", items))),
+            }
+        };
+
+        Method {
+            in_args: in_names.iter().zip(in_sigs.into_iter()).map(|(n, s)| Argument { name: *n, sig: s }).collect(),
+            out_args: out_names.iter().zip(out_sigs.into_iter()).map(|(n, s)| Argument { name: *n, sig: s }).collect(),
+            cb: Rc::new(box FnHandler(box h) as Box<MethodHandler>),
+        }
+    }
+
+    /// The signature incoming calls to this method must have.
+    pub fn in_signature(&self) -> String {
+        self.in_args.iter().fold(String::new(), |s, a| s + a.sig.as_slice())
+    }
+
+    /// Same as comparing with `in_signature`, without building the string.
+    fn accepts_signature(&self, mut sig: &str) -> bool {
+        for a in self.in_args.iter() {
+            if !sig.starts_with(a.sig.as_slice()) { return false; }
+            sig = sig.slice_from(a.sig.len());
+        }
+        sig.len() == 0
+    }
+}
+
+struct FnHandler<'a>(Box<Fn(&mut Message) -> MethodResult<'static> + 'a>);
+
+impl<'a> MethodHandler<'a> for FnHandler<'a> {
+    fn handle(&self, m: &mut Message, _: &Context) -> MethodResult {
+        (*self.0)(m)
+    }
+}
+
 pub struct Interface<'a> {
     methods: BTreeMap<String, Method<'a>>,
     properties: BTreeMap<String, Property<'a>>,
 //  TODO: signals
 }
 
+impl<'a> Interface<'a> {
+    pub fn new() -> Interface<'a> {
+        Interface { methods: BTreeMap::new(), properties: BTreeMap::new() }
+    }
+
+    pub fn add_method(&mut self, name: &str, m: Method<'a>) {
+        self.methods.insert(name.to_string(), m);
+    }
+}
+
+/// The timeout libdbus uses for method calls when the caller does not specify one.
+pub const DEFAULT_TIMEOUT_MS: int = 25000;
+
 struct IObjectPath<'a> {
     conn: &'a Connection,
     path: String,
     registered: Cell<bool>,
+    default_timeout_ms: Cell<int>,
+    fetch_security_label: Cell<bool>,
+    batch_depth: Cell<uint>,
+    changed_props: RefCell<BTreeMap<String, BTreeMap<String, MessageItem>>>,
+    watchdog: RefCell<Option<Watchdog>>,
+    allowed_senders: RefCell<Option<SenderAllowList>>,
     interfaces: RefCell<BTreeMap<String, Interface<'a>>>,
 }
 
@@ -84,6 +178,39 @@ impl<'a> IObjectPath<'a> {
         Ok(())
     }
 
+    fn property_changed(&self, iface: &str, prop: &str, value: MessageItem) -> Result<(), ()> {
+        {
+            let mut c = self.changed_props.borrow_mut();
+            if !c.contains_key(iface) { c.insert(iface.to_string(), BTreeMap::new()); }
+            c.get_mut(iface).unwrap().insert(prop.to_string(), value);
+        }
+        if self.batch_depth.get() == 0 { self.flush_property_changes() } else { Ok(()) }
+    }
+
+    fn flush_property_changes(&self) -> Result<(), ()> {
+        let changes = ::std::mem::replace(&mut *self.changed_props.borrow_mut(), BTreeMap::new());
+        for (iface, props) in changes.into_iter() {
+            let mut m = Message::new_signal(self.path.as_slice(), "org.freedesktop.DBus.Properties",
+                "PropertiesChanged").unwrap();
+            let changed = props.into_iter().map(|(k, v)|
+                (MessageItem::Str(k), MessageItem::Variant(box v))).collect();
+            m.append_items(&[
+                MessageItem::Str(iface),
+                MessageItem::Dict(changed, "s".to_string(), "v".to_string()),
+                MessageItem::new_array::<String>(vec!()),
+            ]);
+            try!(self.conn.send(m));
+        }
+        Ok(())
+    }
+
+    fn begin_batch(&self) { self.batch_depth.set(self.batch_depth.get() + 1); }
+
+    fn end_batch(&self) -> Result<(), ()> {
+        self.batch_depth.set(self.batch_depth.get() - 1);
+        if self.batch_depth.get() == 0 { self.flush_property_changes() } else { Ok(()) }
+    }
+
     fn introspect<'b>(&self, _: &mut Message) -> MethodResult<'b> {
         let ifacestr = self.interfaces.borrow().iter().fold("".to_string(), |ia, (ik, iv)| {
             format!(r##"{}  <interface name="{}">
@@ -118,16 +245,56 @@ impl<'a> IObjectPath<'a> {
     }
 }
 
+/// A snapshot of the interfaces, methods and property values of an object path, for debugging.
+#[deriving(Show, Clone, PartialEq)]
+pub struct ObjectSnapshot {
+    pub path: String,
+    pub interfaces: Vec<InterfaceSnapshot>,
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub struct InterfaceSnapshot {
+    pub name: String,
+    pub methods: Vec<String>,
+    /// The property value is None if the property is write-only or its getter failed.
+    pub properties: Vec<(String, Option<MessageItem>)>,
+}
+
+// Entries without a value are left out.
+fn dict_item(v: Vec<(String, Option<MessageItem>)>) -> MessageItem {
+    let entries = v.into_iter().filter_map(|(k, v)| v.map(|v|
+        (MessageItem::Str(k), MessageItem::Variant(box v)))).collect();
+    MessageItem::Dict(entries, "s".to_string(), "v".to_string())
+}
+
+impl ObjectSnapshot {
+    /// Converts the snapshot to nested a{sv} dictionaries, e g for sending over D-Bus.
+    pub fn to_item(&self) -> MessageItem {
+        let ifaces = self.interfaces.iter().map(|i| (i.name.clone(), Some(dict_item(vec!(
+            ("methods".to_string(), Some(MessageItem::new_array::<String>(i.methods.iter().map(|m| MessageItem::Str(m.clone())).collect()))),
+            ("properties".to_string(), Some(dict_item(i.properties.iter().map(|&(ref k, ref v)| (k.clone(), v.clone())).collect()))),
+        ))))).collect();
+        dict_item(vec!(
+            ("path".to_string(), Some(MessageItem::Str(self.path.clone()))),
+            ("interfaces".to_string(), Some(dict_item(ifaces))),
+        ))
+    }
+}
+
 struct Introspecter<'a> {
     objpath: Weak<IObjectPath<'a>>,
 }
 
 impl<'a> MethodHandler<'a> for Introspecter<'a> {
-    fn handle(&self, m: &mut Message) -> MethodResult {
+    fn handle(&self, m: &mut Message, _: &Context) -> MethodResult {
         self.objpath.upgrade().unwrap().introspect(m)
     }
 }
 
+fn unreadable_args(e: Error) -> (&'static str, String) {
+    ("org.freedesktop.DBus.Error.InvalidArgs", e.message().unwrap_or("Invalid arguments").to_string())
+}
+
 fn parse_msg_str(a: Option<&MessageItem>) -> Result<&str,(&'static str, String)> {
     let name = if let Some(s) = a { s } else {
         return Err(("org.freedesktop.DBus.Error.InvalidArgs", format!("Invalid argument {}", a)))
@@ -157,8 +324,8 @@ struct PropertyGet<'a> {
 }
 
 impl<'a> MethodHandler<'a> for PropertyGet<'a> {
-    fn handle(&self, msg: &mut Message) -> MethodResult {
-        let items = msg.get_items();
+    fn handle(&self, msg: &mut Message, _: &Context) -> MethodResult {
+        let items = try!(msg.get_items().map_err(unreadable_args));
         let iface_name = try!(parse_msg_str(items.get(0)));
         let prop_name = try!(parse_msg_str(items.get(1)));
 
@@ -186,8 +353,8 @@ struct PropertyGetAll<'a> {
 }
 
 impl<'a> MethodHandler<'a> for PropertyGetAll<'a> {
-    fn handle(&self, msg: &mut Message) -> MethodResult {
-        let items = msg.get_items();
+    fn handle(&self, msg: &mut Message, _: &Context) -> MethodResult {
+        let items = try!(msg.get_items().map_err(unreadable_args));
         let iface_name = try!(parse_msg_str(items.get(0)));
 
         let istmp = self.objpath.upgrade().unwrap();
@@ -213,8 +380,8 @@ struct PropertySet<'a> {
 }
 
 impl<'a> MethodHandler<'a> for PropertySet<'a> {
-    fn handle(&self, msg: &mut Message) -> MethodResult {
-        let items = msg.get_items();
+    fn handle(&self, msg: &mut Message, _: &Context) -> MethodResult {
+        let items = try!(msg.get_items().map_err(unreadable_args));
         let iface_name = try!(parse_msg_str(items.get(0)));
         let prop_name = try!(parse_msg_str(items.get(1)));
         let value = try!(parse_msg_variant(items.get(2)));
@@ -234,6 +401,8 @@ impl<'a> MethodHandler<'a> for PropertySet<'a> {
                 return Err(("org.freedesktop.DBus.Error.PropertyReadOnly", format!("Property {} is read only", prop_name)))
             }
         });
+        // Sending is done after the reply, so a failure here is not the caller's problem.
+        let _ = istmp.property_changed(iface_name, prop_name, value.clone());
         Ok(vec!())
     }
 }
@@ -245,6 +414,12 @@ impl<'a> ObjectPath<'a> {
             conn: conn,
             path: path.to_string(),
             registered: Cell::new(false),
+            default_timeout_ms: Cell::new(DEFAULT_TIMEOUT_MS),
+            fetch_security_label: Cell::new(false),
+            batch_depth: Cell::new(0),
+            changed_props: RefCell::new(BTreeMap::new()),
+            watchdog: RefCell::new(None),
+            allowed_senders: RefCell::new(None),
             interfaces: RefCell::new(BTreeMap::new()),
         };
         let o = ObjectPath { i: Rc::new(i) };
@@ -253,7 +428,7 @@ impl<'a> ObjectPath<'a> {
             let mut m = BTreeMap::new();
             m.insert("Introspect".to_string(), Method {
                 in_args: vec!(),
-                out_args: vec!(Argument { name: "xml_data", sig: "s" }),
+                out_args: vec!(Argument { name: "xml_data", sig: "s".to_string() }),
                 cb: Rc::new(box Introspecter { objpath: o.i.downgrade() } as Box<MethodHandler>),
             });
             o.i.interfaces.borrow_mut().insert("org.freedesktop.DBus.Introspectable".to_string(), Interface {
@@ -269,18 +444,18 @@ impl<'a> ObjectPath<'a> {
 
         let mut m = BTreeMap::new();
         m.insert("Get".to_string(), Method {
-            in_args: vec!(Argument { name: "interface_name", sig: "s" }, Argument { name: "property_name", sig: "s" }),
-            out_args: vec!(Argument { name: "value", sig: "v" }),
+            in_args: vec!(Argument { name: "interface_name", sig: "s".to_string() }, Argument { name: "property_name", sig: "s".to_string() }),
+            out_args: vec!(Argument { name: "value", sig: "v".to_string() }),
             cb: Rc::new(box PropertyGet { objpath: self.i.downgrade() } as Box<MethodHandler>),
         });
         m.insert("GetAll".to_string(), Method {
-            in_args: vec!(Argument { name: "interface_name", sig: "s" }),
-            out_args: vec!(Argument { name: "props", sig: "a{sv}" }),
+            in_args: vec!(Argument { name: "interface_name", sig: "s".to_string() }),
+            out_args: vec!(Argument { name: "props", sig: "a{sv}".to_string() }),
             cb: Rc::new(box PropertyGetAll { objpath: self.i.downgrade() } as Box<MethodHandler>),
         });
         m.insert("Set".to_string(), Method {
-            in_args: vec!(Argument { name: "interface_name", sig: "s" }, Argument { name: "property_name", sig: "s" },
-                Argument { name: "value", sig: "v" }),
+            in_args: vec!(Argument { name: "interface_name", sig: "s".to_string() }, Argument { name: "property_name", sig: "s".to_string() },
+                Argument { name: "value", sig: "v".to_string() }),
             out_args: vec!(),
             cb: Rc::new(box PropertySet { objpath: self.i.downgrade() } as Box<MethodHandler>),
         });
@@ -297,6 +472,63 @@ impl<'a> ObjectPath<'a> {
         self.i.interfaces.borrow_mut().insert(name, i);
     }
 
+    /// Sets the timeout passed to method handlers in their `Context`.
+    pub fn set_default_timeout(&mut self, timeout_ms: int) {
+        self.i.default_timeout_ms.set(timeout_ms);
+    }
+
+    /// Only accepts method calls from the names in the list, and replies AccessDenied to
+    /// everyone else before any handler runs. None accepts calls from everyone.
+    pub fn set_allowed_senders(&mut self, l: Option<SenderAllowList>) {
+        *self.i.allowed_senders.borrow_mut() = l;
+    }
+
+    /// Makes method handlers get the caller's security label in their `Context`.
+    ///
+    /// This costs a blocking call to the bus for every incoming method call.
+    pub fn set_fetch_security_label(&mut self, enable: bool) {
+        self.i.fetch_security_label.set(enable);
+    }
+
+    /// Notes that a property has changed, so that PropertiesChanged can be emitted.
+    ///
+    /// Changes made while handling a method call, or inside `property_batch`, are coalesced
+    /// into one PropertiesChanged signal per interface. Other changes are emitted directly.
+    pub fn property_changed(&self, iface: &str, prop: &str, value: MessageItem) -> Result<(), ()> {
+        self.i.property_changed(iface, prop, value)
+    }
+
+    /// Runs `f`, and emits the property changes noted during it when it returns.
+    pub fn property_batch<R, F: FnOnce() -> R>(&self, f: F) -> (R, Result<(), ()>) {
+        self.i.begin_batch();
+        let r = f();
+        (r, self.i.end_batch())
+    }
+
+    /// Returns the currently exported interfaces, methods and property values.
+    pub fn snapshot(&self) -> ObjectSnapshot {
+        let is = self.i.interfaces.borrow();
+        ObjectSnapshot {
+            path: self.i.path.clone(),
+            interfaces: is.iter().map(|(iname, i)| InterfaceSnapshot {
+                name: iname.clone(),
+                methods: i.methods.keys().map(|k| k.clone()).collect(),
+                properties: i.properties.iter().map(|(pname, p)| (pname.clone(), match p.access {
+                    PropertyAccess::RO(ref cb) => cb.get().ok(),
+                    PropertyAccess::RW(ref cb) => cb.get().ok(),
+                    PropertyAccess::WO(_) => None,
+                })).collect(),
+            }).collect(),
+        }
+    }
+
+    /// Sets a watchdog that reports method handlers running longer than its time budget.
+    pub fn set_watchdog(&mut self, w: Option<Watchdog>) {
+        *self.i.watchdog.borrow_mut() = w;
+    }
+
+    pub fn path(&self) -> &str { self.i.path.as_slice() }
+
     pub fn is_registered(&self) -> bool {
         self.i.registered.get()
     }
@@ -311,16 +543,26 @@ impl<'a> ObjectPath<'a> {
        Some(Ok()) => message reply send ok */
 
     pub fn handle_message(&mut self, msg: &mut Message) -> Option<Result<(), ()>> {
-        let (_, path, iface, method) = msg.headers();
-        if path.is_none() || path.unwrap() != self.i.path { return None; }
-        if iface.is_none() { return None; }
+        // Headers are borrowed from the message, so that successful calls do not allocate
+        // on our side unless the handler or the watchdog does.
+        if msg.path() != Some(self.i.path.as_slice()) { return None; }
+        let iface = match msg.interface() { Some(i) => i, None => return None };
+        if let Some(ref l) = *self.i.allowed_senders.borrow() {
+            if !l.allows(msg.sender().unwrap_or("".to_string()).as_slice()) {
+                return Some(self.i.conn.send(Message::new_error(msg,
+                    "org.freedesktop.DBus.Error.AccessDenied", "Sender is not allowed to call this object").unwrap()));
+            }
+        }
+        let call_name = if self.i.watchdog.borrow().is_some() {
+            Some(format!("{}.{} on {}", iface, msg.member().unwrap_or(""), self.i.path))
+        } else { None };
 
-        let method = {
+        let (method, in_sig) = {
             // This is because we don't want to hold the refcell lock when we call the
             // callback - maximum flexibility for clients.
-            if let Some(i) = self.i.interfaces.borrow().get(&iface.unwrap()) {
-                if let Some(Some(m)) = method.map(|m| i.methods.get(&m)) {
-                    m.cb.clone()
+            if let Some(i) = self.i.interfaces.borrow().get(iface) {
+                if let Some(Some(m)) = msg.member().map(|m| i.methods.get(m)) {
+                    (m.cb.clone(), if m.accepts_signature(msg.signature_str()) { None } else { Some(m.in_signature()) })
                 } else {
                     return Some(self.i.conn.send(Message::new_error(
                         msg, "org.freedesktop.DBus.Error.UnknownMethod", "Unknown method").unwrap()));
@@ -331,7 +573,23 @@ impl<'a> ObjectPath<'a> {
             }
         };
 
-        let reply = match method.handle(msg) {
+        if let Some(in_sig) = in_sig {
+            let e = format!("Invalid arguments: expected signature '{}', got '{}'", in_sig, msg.signature_str());
+            return Some(self.i.conn.send(Message::new_error(msg,
+                "org.freedesktop.DBus.Error.InvalidArgs", e.as_slice()).unwrap()));
+        }
+
+        let label = if !self.i.fetch_security_label.get() { None }
+            else { msg.sender().and_then(|s| self.i.conn.get_security_label(s.as_slice()).ok()).and_then(|l| l) };
+        let ctx = Context {
+            timeout_ms: if msg.get_no_reply() { None } else { Some(self.i.default_timeout_ms.get()) },
+            security_label: label,
+        };
+        self.i.begin_batch();
+        if let (Some(w), Some(n)) = (self.i.watchdog.borrow().as_ref(), call_name) { w.begin(n) }
+        let r = method.handle(msg, &ctx);
+        if let Some(ref w) = *self.i.watchdog.borrow() { w.end() }
+        let reply = match r {
             Ok(r) => {
                 let mut z = Message::new_method_return(msg).unwrap();
                 z.append_items(r.as_slice());
@@ -340,25 +598,80 @@ impl<'a> ObjectPath<'a> {
             Err((aa,bb)) => Message::new_error(msg, aa, bb.as_slice()).unwrap(),
         };
 
-        Some(self.i.conn.send(reply))
+        let r = self.i.conn.send(reply);
+        let _ = self.i.end_batch();
+        Some(r)
     }
 }
 
-#[cfg(test)]
-impl<'a> MethodHandler<'a> for int {
-    fn handle(&self, _: &mut Message) -> MethodResult {
-        Err(("dummy", "dummy".to_string()))
+/// Routes incoming method calls to many object paths with a hash lookup, instead of
+/// calling `handle_message` on each of them in turn.
+///
+/// Calls to paths without an object go to the subtree handler registered for the
+/// nearest parent path, if any. Note that libdbus only delivers method calls for paths
+/// registered with the connection.
+pub struct ObjectRegistry<'a> {
+    objects: HashMap<String, ObjectPath<'a>>,
+    subtrees: HashMap<String, Box<FnMut(&mut Message) -> Option<Result<(), ()>> + 'a>>,
+}
+
+impl<'a> ObjectRegistry<'a> {
+    pub fn new() -> ObjectRegistry<'a> {
+        ObjectRegistry { objects: HashMap::new(), subtrees: HashMap::new() }
+    }
+
+    /// Adds an object, replacing (and returning) any earlier object with the same path.
+    pub fn insert(&mut self, o: ObjectPath<'a>) -> Option<ObjectPath<'a>> {
+        self.objects.insert(o.path().to_string(), o)
+    }
+
+    pub fn remove(&mut self, path: &str) -> Option<ObjectPath<'a>> {
+        self.objects.remove(path)
+    }
+
+    pub fn get(&self, path: &str) -> Option<&ObjectPath<'a>> {
+        self.objects.get(path)
+    }
+
+    pub fn get_mut(&mut self, path: &str) -> Option<&mut ObjectPath<'a>> {
+        self.objects.get_mut(path)
+    }
+
+    /// Sets a handler for calls to `path` and all paths below it that have no object.
+    /// The handler's return value has the same meaning as for `handle_message`.
+    pub fn add_subtree<F>(&mut self, path: &str, f: F)
+        where F: FnMut(&mut Message) -> Option<Result<(), ()>> + 'a
+    {
+        self.subtrees.insert(path.to_string(), box f);
+    }
+
+    pub fn remove_subtree(&mut self, path: &str) {
+        self.subtrees.remove(path);
+    }
+
+    /// Same as `ObjectPath::handle_message`, for the object (or subtree) the call is for.
+    pub fn handle_message(&mut self, msg: &mut Message) -> Option<Result<(), ()>> {
+        let o = match msg.path() { Some(p) => self.objects.get_mut(p), None => return None };
+        if let Some(o) = o { return o.handle_message(msg); }
+
+        let f = {
+            let mut p = msg.path().unwrap();
+            loop {
+                if self.subtrees.contains_key(p) { break; }
+                if p == "/" { return None; }
+                p = match p.rfind('/') { Some(0) => "/", Some(i) => p.slice_to(i), None => return None };
+            }
+            self.subtrees.get_mut(p).unwrap()
+        };
+        (*f)(msg)
     }
 }
 
 #[cfg(test)]
 fn make_objpath<'a>(c: &'a Connection) -> ObjectPath<'a> {
     let mut im = BTreeMap::new();
-    im.insert("Echo".to_string(), Method {
-        in_args: vec!( Argument { name: "request", sig: "s"} ),  
-        out_args: vec!( Argument { name: "reply", sig: "s"} ),
-        cb: Rc::new(box 3i as Box<MethodHandler>),
-    });
+    im.insert("Echo".to_string(), Method::new_typed(&["request"], &["reply"],
+        |&: (s,): (String,)| Ok((s,))));
     let mut ip = BTreeMap::new();
     ip.insert("EchoCount".to_string(), Property { sig: "i", access: PropertyAccess::RO(box MessageItem::Int32(7))});
     let mut o = ObjectPath::new(c, "/echo", true);
@@ -437,3 +750,30 @@ fn test_introspect() {
 
 }
 
+#[test]
+fn test_snapshot() {
+    let c = Connection::get_private(super::BusType::Session).unwrap();
+    let o = make_objpath(&c);
+    let s = o.snapshot();
+    assert_eq!(s.path.as_slice(), "/echo");
+    let echo = s.interfaces.iter().find(|i| i.name.as_slice() == "com.example.echo").unwrap();
+    assert_eq!(echo.methods, vec!("Echo".to_string()));
+    assert_eq!(echo.properties, vec!(("EchoCount".to_string(), Some(MessageItem::Int32(7)))));
+}
+
+#[test]
+fn test_registry_routing() {
+    let c = Connection::get_private(super::BusType::Session).unwrap();
+    let mut r = ObjectRegistry::new();
+    r.insert(make_objpath(&c));
+    let hits = Rc::new(Cell::new(0u));
+    let h2 = hits.clone();
+    r.add_subtree("/items", move |&mut: _: &mut Message| { h2.set(h2.get() + 1); Some(Ok(())) });
+
+    let mut m = Message::new_method_call("com.example.echoserver", "/items/1/a", "com.example.Item", "Get").unwrap();
+    assert_eq!(r.handle_message(&mut m), Some(Ok(())));
+    let mut m = Message::new_method_call("com.example.echoserver", "/other", "com.example.Item", "Get").unwrap();
+    assert_eq!(r.handle_message(&mut m), None);
+    assert_eq!(hits.get(), 1);
+    assert!(r.get("/echo").is_some());
+}
@@ -0,0 +1,37 @@
+use super::{Error, is_valid_object_path};
+use std::fmt;
+
+/// A D-Bus object path, e g "/org/freedesktop/DBus", checked to be valid.
+///
+/// libdbus aborts the process when given an invalid object path, so paths are
+/// checked when this is created rather than when they are used.
+///
+/// # Example
+///
+/// ```
+/// use dbus::path::Path;
+///
+/// assert_eq!(Path::new("/com/example/Foo_1").unwrap().as_str(), "/com/example/Foo_1");
+/// assert!(Path::new("/com/example/").is_err());
+/// assert!(Path::new("com.example").is_err());
+/// assert!(Path::new("/a\0b").is_err());
+/// ```
+#[deriving(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Path(String);
+
+impl Path {
+    /// Checks that `s` is "/", or slash separated non-empty elements of [A-Za-z0-9_].
+    pub fn new(s: &str) -> Result<Path, Error> {
+        if is_valid_object_path(s) { return Ok(Path(s.to_string())) }
+        let f = format!("'{}' is not a valid object path", s);
+        Err(Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs", f.as_slice()))
+    }
+
+    pub fn as_str(&self) -> &str { self.0.as_slice() }
+
+    pub fn into_string(self) -> String { self.0 }
+}
+
+impl fmt::Show for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{}", self.0) }
+}
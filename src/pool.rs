@@ -0,0 +1,131 @@
+use super::{Connection, ConnectionItems, BusType, Message, Error};
+use std::cell::Cell;
+
+/// A fixed number of private connections to the same bus.
+///
+/// Calls are distributed round-robin across the connections, so one large reply
+/// does not block all other calls behind it on the same socket.
+pub struct Pool {
+    bus: BusType,
+    conns: Vec<Connection>,
+    next: Cell<uint>,
+}
+
+impl Pool {
+    /// Opens `size` private connections to the given bus.
+    pub fn new(bus: BusType, size: uint) -> Result<Pool, Error> {
+        assert!(size > 0);
+        let mut conns = Vec::with_capacity(size);
+        for _ in range(0, size) {
+            conns.push(try!(Connection::get_private(bus)));
+        }
+        Ok(Pool { bus: bus, conns: conns, next: Cell::new(0) })
+    }
+
+    pub fn len(&self) -> uint { self.conns.len() }
+
+    fn next_index(&self) -> uint {
+        let n = self.next.get();
+        self.next.set((n + 1) % self.conns.len());
+        n
+    }
+
+    /// Returns the next connection in round-robin order.
+    pub fn get(&self) -> &Connection {
+        &self.conns[self.next_index()]
+    }
+
+    /// Closes the connection at the given index and opens a new one in its place.
+    pub fn replace(&mut self, idx: uint) -> Result<(), Error> {
+        self.conns[idx] = try!(Connection::get_private(self.bus));
+        Ok(())
+    }
+
+    pub fn connections(&self) -> &[Connection] { self.conns.as_slice() }
+
+    pub fn send_with_reply_and_block(&self, message: Message, timeout_ms: int) -> Result<Message, Error> {
+        self.get().send_with_reply_and_block(message, timeout_ms)
+    }
+
+    pub fn send(&self, message: Message) -> Result<(),()> {
+        self.get().send(message)
+    }
+}
+
+/// Distributes blocking calls across a `Pool`, replacing members that have disconnected.
+///
+/// Match rules and incoming signals are pinned to the first connection in the pool, so
+/// they are not duplicated or spread out; if that connection is replaced, the match rules
+/// are added again on the new one.
+pub struct Balancer {
+    pool: Pool,
+    matches: Vec<String>,
+}
+
+impl Balancer {
+    pub fn new(bus: BusType, size: uint) -> Result<Balancer, Error> {
+        Ok(Balancer { pool: try!(Pool::new(bus, size)), matches: vec!() })
+    }
+
+    pub fn pool(&self) -> &Pool { &self.pool }
+
+    /// The connection that match rules are added to and signals should be read from.
+    pub fn signal_connection(&self) -> &Connection { &self.pool.conns[0] }
+
+    pub fn send_with_reply_and_block(&mut self, message: Message, timeout_ms: int) -> Result<Message, Error> {
+        let idx = self.pool.next_index();
+        let r = self.pool.conns[idx].send_with_reply_and_block(message, timeout_ms);
+        if r.is_err() && !self.pool.conns[idx].is_connected() {
+            try!(self.replace(idx));
+        }
+        r
+    }
+
+    /// Replaces all connections that are no longer connected. Returns the number replaced.
+    pub fn check_health(&mut self) -> Result<uint, Error> {
+        let mut count = 0;
+        for idx in range(0, self.pool.len()) {
+            if self.pool.conns[idx].is_connected() { continue; }
+            try!(self.replace(idx));
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn replace(&mut self, idx: uint) -> Result<(), Error> {
+        try!(self.pool.replace(idx));
+        if idx == 0 {
+            for rule in self.matches.iter() {
+                try!(self.pool.conns[0].add_match(rule.as_slice()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_match(&mut self, rule: &str) -> Result<(), Error> {
+        try!(self.signal_connection().add_match(rule));
+        self.matches.push(rule.to_string());
+        Ok(())
+    }
+
+    pub fn remove_match(&mut self, rule: &str) -> Result<(), Error> {
+        try!(self.signal_connection().remove_match(rule));
+        if let Some(idx) = self.matches.iter().position(|r| r.as_slice() == rule) {
+            self.matches.remove(idx);
+        }
+        Ok(())
+    }
+
+    pub fn iter(&self, timeout_ms: int) -> ConnectionItems {
+        self.signal_connection().iter(timeout_ms)
+    }
+}
+
+#[test]
+fn pool_round_robin() {
+    let p = Pool::new(BusType::Session, 3).unwrap();
+    let names: Vec<String> = range(0, 4u).map(|_| p.get().unique_name()).collect();
+    assert!(names[0] != names[1]);
+    assert!(names[1] != names[2]);
+    assert_eq!(names[0], names[3]);
+}
@@ -0,0 +1,158 @@
+//! Renders messages and their arguments the way `dbus-monitor` does, for debugging.
+//!
+//! # Example
+//!
+//! ```ignore
+//! for n in c.iter(1000) {
+//!     if let ConnectionItem::Signal(m) = n { println!("{}", Pretty(&m)); }
+//! }
+//! ```
+//!
+//! prints something like:
+//!
+//! ```text
+//! signal sender=org.freedesktop.DBus -> destination=:1.5 serial=2 path=/org/freedesktop/DBus; interface=org.freedesktop.DBus; member=NameAcquired
+//!    string ":1.5"
+//! ```
+
+use super::{ffi, Message, MessageItem, MessageType};
+#[cfg(test)]
+use super::Path;
+use super::iter::Iter;
+use std::fmt;
+
+/// Wraps a `Message` or a `MessageItem` so that it is shown in `dbus-monitor` style.
+pub struct Pretty<'a, T: 'a>(pub &'a T);
+
+fn indent(f: &mut fmt::Formatter, level: uint) -> fmt::Result {
+    for _ in range(0, level) { try!(write!(f, "   ")); }
+    Ok(())
+}
+
+fn show_item(f: &mut fmt::Formatter, i: &MessageItem, level: uint) -> fmt::Result {
+    try!(indent(f, level));
+    show_value(f, i, level)
+}
+
+/* Shows an item without the leading indentation, so that variants can put their contents on the same line. */
+fn show_value(f: &mut fmt::Formatter, i: &MessageItem, level: uint) -> fmt::Result {
+    match i {
+        &MessageItem::Str(ref s) => writeln!(f, "string \"{}\"", s),
+        &MessageItem::ObjectPath(ref s) => writeln!(f, "object path \"{}\"", s),
+        &MessageItem::Signature(ref s) => writeln!(f, "signature \"{}\"", s),
+        &MessageItem::Bool(b) => writeln!(f, "boolean {}", b),
+        &MessageItem::Byte(b) => writeln!(f, "byte {}", b),
+        &MessageItem::Int16(b) => writeln!(f, "int16 {}", b),
+        &MessageItem::Int32(b) => writeln!(f, "int32 {}", b),
+        &MessageItem::Int64(b) => writeln!(f, "int64 {}", b),
+        &MessageItem::UInt16(b) => writeln!(f, "uint16 {}", b),
+        &MessageItem::UInt32(b) => writeln!(f, "uint32 {}", b),
+        &MessageItem::UInt64(b) => writeln!(f, "uint64 {}", b),
+        &MessageItem::Double(b) => writeln!(f, "double {}", b),
+        &MessageItem::UnixFd(ref b) => writeln!(f, "file descriptor {}", b.as_raw_fd()),
+        &MessageItem::Variant(ref v) => {
+            try!(write!(f, "variant       "));
+            show_value(f, &**v, level)
+        }
+        &MessageItem::Array(ref a, _) => {
+            try!(writeln!(f, "array ["));
+            for x in a.iter() { try!(show_item(f, x, level + 1)); }
+            try!(indent(f, level));
+            writeln!(f, "]")
+        }
+        &MessageItem::Dict(ref a, _, _) => {
+            try!(writeln!(f, "array ["));
+            for &(ref k, ref v) in a.iter() {
+                try!(indent(f, level + 1));
+                try!(show_entry(f, k, v, level + 1));
+            }
+            try!(indent(f, level));
+            writeln!(f, "]")
+        }
+        &MessageItem::DictEntry(ref k, ref v) => show_entry(f, &**k, &**v, level),
+        &MessageItem::Struct(ref a) => {
+            try!(writeln!(f, "struct {{"));
+            for x in a.iter() { try!(show_item(f, x, level + 1)); }
+            try!(indent(f, level));
+            writeln!(f, "}}")
+        }
+    }
+}
+
+fn show_entry(f: &mut fmt::Formatter, k: &MessageItem, v: &MessageItem, level: uint) -> fmt::Result {
+    try!(writeln!(f, "dict entry("));
+    try!(show_item(f, k, level + 1));
+    try!(show_item(f, v, level + 1));
+    try!(indent(f, level));
+    writeln!(f, ")")
+}
+
+fn opt(s: Option<&str>, none: &str) -> String { s.unwrap_or(none).to_string() }
+
+impl<'a> fmt::Show for Pretty<'a, MessageItem> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { show_item(f, self.0, 1) }
+}
+
+impl<'a> fmt::Show for Pretty<'a, Message> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let m = self.0;
+        let sender = opt(m.sender().as_ref().map(|s| s.as_slice()), "(null sender)");
+        let dest = opt(m.destination().as_ref().map(|s| s.as_slice()), "(null destination)");
+        let (serial, reply_serial) = unsafe { (ffi::dbus_message_get_serial(m.msg), ffi::dbus_message_get_reply_serial(m.msg)) };
+        match m.msg_type() {
+            MessageType::MethodCall | MessageType::Signal => {
+                let t = if m.msg_type() == MessageType::Signal { "signal" } else { "method call" };
+                try!(writeln!(f, "{} sender={} -> destination={} serial={} path={}; interface={}; member={}",
+                    t, sender, dest, serial, opt(m.path(), "(null path)"),
+                    opt(m.interface(), "(null interface)"), opt(m.member(), "(null member)")));
+            }
+            MessageType::MethodReturn => {
+                try!(writeln!(f, "method return sender={} -> destination={} serial={} reply_serial={}",
+                    sender, dest, serial, reply_serial));
+            }
+            MessageType::Error => {
+                let name = unsafe { ffi::dbus_message_get_error_name(m.msg) };
+                try!(writeln!(f, "error sender={} -> destination={} error_name={} reply_serial={}",
+                    sender, dest, opt(super::c_str_to_slice(&name), "(null error name)"), reply_serial));
+            }
+            MessageType::Invalid => try!(writeln!(f, "invalid message")),
+        }
+        let mut i = Iter::new(m);
+        while let Some(x) = i.get::<MessageItem>() {
+            try!(show_item(f, &x, 1));
+            i.advance();
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn pretty_message() {
+    use std::collections::HashMap;
+    use super::iter::Variant;
+
+    let mut m = Message::new_signal("/com/example", "com.example.Foo", "Bar").unwrap();
+    let mut h = HashMap::new();
+    h.insert("Count".to_string(), Variant(5u32));
+    m.append3("Hello", vec!(1u8, 2), h).unwrap();
+    m.append_items(&[MessageItem::Struct(vec!(MessageItem::Bool(true), MessageItem::ObjectPath(Path::new("/a").unwrap())))]);
+    assert_eq!(format!("{}", Pretty(&m)).as_slice(),
+"signal sender=(null sender) -> destination=(null destination) serial=0 path=/com/example; interface=com.example.Foo; member=Bar
+   string \"Hello\"
+   array [
+      byte 1
+      byte 2
+   ]
+   array [
+      dict entry(
+         string \"Count\"
+         variant       uint32 5
+      )
+   ]
+   struct {
+      boolean true
+      object path \"/a\"
+   }
+");
+    assert_eq!(format!("{}", Pretty(&MessageItem::Int32(-3))).as_slice(), "   int32 -3\n");
+}
@@ -21,14 +21,14 @@ impl<'a> Props<'a> {
     }
 
     pub fn get(&self, propname: &str) -> Result<MessageItem, Error> {
-        let mut m = Message::new_method_call(self.name.as_slice(), self.path.as_slice(),
-            "org.freedesktop.DBus.Properties", "Get").unwrap();
+        let mut m = try!(Message::new_method_call(self.name.as_slice(), self.path.as_slice(),
+            "org.freedesktop.DBus.Properties", "Get"));
         m.append_items(&[
             MessageItem::Str(self.interface.clone()),
             MessageItem::Str(propname.to_string())
         ]);
         let mut r = try!(self.conn.send_with_reply_and_block(m, self.timeout_ms));
-        let reply = try!(r.as_result()).get_items();
+        let reply = try!(try!(r.as_result()).get_items());
         if reply.len() == 1 {
             if let &MessageItem::Variant(ref v) = &reply[0] {
                 return Ok(v.deref().clone())
@@ -39,8 +39,8 @@ impl<'a> Props<'a> {
     }
 
     pub fn set(&self, propname: &str, value: MessageItem) -> Result<(), Error> {
-        let mut m = Message::new_method_call(self.name.as_slice(), self.path.as_slice(),
-            "org.freedesktop.DBus.Properties", "Set").unwrap();
+        let mut m = try!(Message::new_method_call(self.name.as_slice(), self.path.as_slice(),
+            "org.freedesktop.DBus.Properties", "Set"));
         m.append_items(&[
             MessageItem::Str(self.interface.clone()),
             MessageItem::Str(propname.to_string()),
@@ -52,20 +52,18 @@ impl<'a> Props<'a> {
     }
 
     pub fn get_all(&self) -> Result<BTreeMap<String, MessageItem>, Error> {
-        let mut m = Message::new_method_call(self.name.as_slice(), self.path.as_slice(),
-            "org.freedesktop.DBus.Properties", "GetAll").unwrap();
+        let mut m = try!(Message::new_method_call(self.name.as_slice(), self.path.as_slice(),
+            "org.freedesktop.DBus.Properties", "GetAll"));
         m.append_items(&[MessageItem::Str(self.interface.clone())]);
         let mut r = try!(self.conn.send_with_reply_and_block(m, self.timeout_ms));
-        let reply = try!(r.as_result()).get_items();
+        let reply = try!(try!(r.as_result()).get_items());
         if reply.len() == 1 {
-            if let &MessageItem::Array(ref a, _) = &reply[0] {
+            if let &MessageItem::Dict(ref a, _, _) = &reply[0] {
                 let mut t = BTreeMap::new();
                 let mut haserr = false;
-                for p in a.iter() {
-                    if let &MessageItem::DictEntry(ref k, ref v) = p {
-                        if let &MessageItem::Str(ref ks) = &**k {
-                            t.insert(ks.to_string(), v.deref().clone());
-                        } else { haserr = true; };
+                for &(ref k, ref v) in a.iter() {
+                    if let &MessageItem::Str(ref ks) = k {
+                        t.insert(ks.to_string(), v.clone());
                     } else { haserr = true; };
                 }
                 if !haserr { return Ok(t) };
@@ -110,8 +108,7 @@ impl<'a> PropHandler<'a> {
         Message::new_error(m, "org.freedesktop.DBus.Error.InvalidArgs", "Invalid arguments").unwrap()
     }
 
-    fn handle_get(&self, msg: &mut Message) -> Message {
-        let items = msg.get_items();
+    fn handle_get(&self, msg: &Message, items: &[MessageItem]) -> Message {
         let name = if let Some(s) = items.get(1) { s } else { return PropHandler::invalid_args(msg) };
         let name = if let &MessageItem::Str(ref s) = name { s } else { return PropHandler::invalid_args(msg) };
         let value = if let Some(s) = self.map.get(name) { s } else { return PropHandler::invalid_args(msg) };
@@ -139,7 +136,10 @@ impl<'a> PropHandler<'a> {
         if path.is_none() || path.unwrap() != self.p.path { return None; }
         if method.is_none() { return None; }
 
-        let items = msg.get_items();
+        let items = match msg.get_items() {
+            Ok(i) => i,
+            Err(_) => return Some(self.p.conn.send(PropHandler::invalid_args(msg))),
+        };
         if let Some(i) = items.get(0) {
             if let &MessageItem::Str(ref s) = i {
                 if *s != self.p.interface { return None; }
@@ -148,7 +148,7 @@ impl<'a> PropHandler<'a> {
 
         // Ok, we have a match
         let reply = match method.unwrap().as_slice() {
-            "Get" => self.handle_get(msg),
+            "Get" => self.handle_get(msg, items.as_slice()),
 //            "Set" => self.handle_set(msg),
             "GetAll" => self.handle_getall(msg),
             _ => PropHandler::invalid_args(msg)
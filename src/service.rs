@@ -0,0 +1,98 @@
+use super::{Connection, NameFlag, RequestNameReply, Error};
+use super::tree::{Factory, Tree, ObjectPath};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Serves a `Tree` under a well-known name, on a connection it borrows. Since the
+/// connection outlives the service, handlers and checks such as `Factory::authorize`
+/// and `Connection::defer_reply` can use it too.
+///
+/// Dropping the service unregisters its object paths and releases the name.
+///
+/// # Example
+///
+/// ```ignore
+/// let c = Connection::get_private(BusType::Session).unwrap();
+/// let mut s = Service::new(&c, "com.example.App").unwrap();
+/// s.add(Factory::new().object_path("/com/example/App").interface("com.example.App")
+///     .method("Quit", |&: _: &mut Message| Ok(vec!()))
+///     .authorize(&c, |&: cr: &Credentials| cr.unix_user_id == Some(0)).tree()).unwrap();
+/// s.run();
+/// ```
+pub struct Service<'a> {
+    conn: &'a Connection,
+    name: String,
+    tree: Tree<'a>,
+}
+
+impl<'a> Service<'a> {
+    /// Requests `name` on `c`, failing if someone else owns it.
+    pub fn new(c: &'a Connection, name: &str) -> Result<Service<'a>, Error> {
+        match try!(c.register_name(name, NameFlag::DoNotQueue as u32)) {
+            RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner => {},
+            _ => return Err(Error::new_custom("org.freedesktop.DBus.Error.Failed",
+                format!("The name {} is owned by another connection", name).as_slice())),
+        }
+        Ok(Service { conn: c, name: name.to_string(), tree: Factory::new().tree() })
+    }
+
+    pub fn connection(&self) -> &'a Connection { self.conn }
+
+    pub fn name(&self) -> &str { self.name.as_slice() }
+
+    pub fn tree(&self) -> &Tree<'a> { &self.tree }
+
+    /// Adds the object paths of `t` and registers them, see `Tree::insert_tree`.
+    pub fn add(&mut self, t: Tree<'a>) -> Result<(), Error> {
+        self.tree.insert_tree(t, Some(self.conn))
+    }
+
+    /// Unregisters and removes an object path, see `Tree::remove`.
+    pub fn remove(&mut self, path: &str) -> Option<ObjectPath<'a>> {
+        self.tree.remove(path, Some(self.conn))
+    }
+
+    /// Handles method calls until the connection is closed.
+    pub fn run(&self) {
+        self.run_until(&AtomicBool::new(false))
+    }
+
+    /// Handles method calls until `stop` is set, e g by a method handler, or the connection
+    /// is closed.
+    ///
+    /// This sleeps until something arrives, and checks `stop` after each message. Another
+    /// thread setting `stop` should therefore wake the service up after it, e g by calling
+    /// org.freedesktop.DBus.Peer.Ping on it.
+    pub fn run_until(&self, stop: &AtomicBool) {
+        for i in self.conn.iter(-1) {
+            if stop.load(Ordering::SeqCst) { break }
+            self.tree.dispatch(self.conn, i);
+            if stop.load(Ordering::SeqCst) { break }
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<'a> Drop for Service<'a> {
+    fn drop(&mut self) {
+        let _ = self.tree.set_registered(self.conn, false);
+        let _ = self.conn.release_name(self.name.as_slice());
+    }
+}
+
+#[test]
+fn service_run_until() {
+    use super::{BusType, Message};
+    let stop = AtomicBool::new(false);
+    let name = format!("com.example.service.test{}", ::std::rand::random::<u32>());
+    let sc = Connection::get_private(BusType::Session).unwrap();
+    let mut s = Service::new(&sc, name.as_slice()).unwrap();
+    s.add(Factory::new().object_path("/app").interface("com.example.App")
+        .method("Quit", |&: _: &mut Message| { stop.store(true, Ordering::SeqCst); Ok(vec!()) }).tree()).unwrap();
+
+    let c = Connection::get_private(BusType::Session).unwrap();
+    let m = Message::new_method_call(name.as_slice(), "/app", "com.example.App", "Quit").unwrap();
+    // The reply only comes once the service runs, so do not wait for it here.
+    c.send(m).unwrap();
+    s.run_until(&stop);
+    assert!(stop.load(Ordering::SeqCst));
+}
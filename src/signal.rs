@@ -0,0 +1,82 @@
+/// Defines a struct that maps to a D-Bus signal on a given interface.
+///
+/// Each field is declared together with the `MessageItem` variant it is sent as,
+/// so the generated `to_message`/`emit` and `from_message` functions always agree
+/// on the argument list.
+///
+/// # Example
+///
+/// ```ignore
+/// dbus_signal!(NameLost, "org.freedesktop.DBus", "NameLost", { name: Str(String) });
+///
+/// let s = NameLost { name: "com.example.test".to_string() };
+/// s.emit(&c, "/org/freedesktop/DBus").unwrap();
+///
+/// // ...and on the receiving side:
+/// if let Some(s) = NameLost::from_message(&mut msg) { println!("Lost {}", s.name); }
+/// ```
+#[macro_export]
+macro_rules! dbus_signal {
+    ($name:ident, $iface:expr, $member:expr, { $($field:ident: $variant:ident($t:ty)),* }) => {
+        pub struct $name {
+            $(pub $field: $t),*
+        }
+
+        impl $name {
+            pub fn interface() -> &'static str { $iface }
+            pub fn member() -> &'static str { $member }
+
+            /// Creates a signal message on the given path, with all fields appended as arguments.
+            /// Fails if the path is invalid or a field cannot be appended.
+            pub fn to_message(&self, path: &str) -> Result<$crate::Message, $crate::Error> {
+                let mut m = try!($crate::Message::new_signal(path, $iface, $member));
+                try!(m.try_append_items(&[$($crate::MessageItem::$variant(self.$field.clone())),*]));
+                Ok(m)
+            }
+
+            pub fn emit(&self, c: &$crate::Connection, path: &str) -> Result<(), $crate::Error> {
+                let m = try!(self.to_message(path));
+                c.send(m).map_err(|_| $crate::Error::new_custom("org.freedesktop.DBus.Error.Failed", "Sending the signal failed"))
+            }
+
+            /// Returns None if the message is not this signal, or if the arguments do not match.
+            pub fn from_message(m: &mut $crate::Message) -> Option<$name> {
+                let (t, _, i, mb) = m.headers();
+                if t != $crate::MessageType::Signal { return None; }
+                if i.as_ref().map(|s| s.as_slice()) != Some($iface) { return None; }
+                if mb.as_ref().map(|s| s.as_slice()) != Some($member) { return None; }
+
+                let mut items = match m.get_items() { Ok(i) => i.into_iter(), Err(_) => return None };
+                $(let $field = match items.next() {
+                    Some($crate::MessageItem::$variant(v)) => v,
+                    _ => return None,
+                };)*
+                if items.next().is_some() { return None; }
+                Some($name { $($field: $field),* })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+dbus_signal!(TestSignal, "com.example.signaltest", "ThisIsASignal", { name: Str(String), count: UInt32(u32) });
+
+#[test]
+fn signal_roundtrip() {
+    let s = TestSignal { name: "Hello".to_string(), count: 5 };
+    let mut m = s.to_message("/mysignal").unwrap();
+    let r = TestSignal::from_message(&mut m).unwrap();
+    assert_eq!(r.name.as_slice(), "Hello");
+    assert_eq!(r.count, 5);
+
+    let mut m2 = super::Message::new_signal("/mysignal", "com.example.signaltest", "Other").unwrap();
+    assert!(TestSignal::from_message(&mut m2).is_none());
+}
+
+#[test]
+fn signal_to_message_errors() {
+    let s = TestSignal { name: "a\0b".to_string(), count: 5 };
+    assert!(s.to_message("/mysignal").is_err());
+    let s = TestSignal { name: "Hello".to_string(), count: 5 };
+    assert!(s.to_message("not a path").is_err());
+}
@@ -0,0 +1,201 @@
+use super::{ffi, Error, MessageItem, to_c_str};
+use super::arg::Arg;
+use std::fmt;
+
+/// A D-Bus type signature, e g "a{sv}", which may contain several complete types.
+///
+/// # Example
+///
+/// ```
+/// use dbus::MessageItem;
+/// use dbus::signature::Signature;
+///
+/// let s = Signature::of::<Vec<String>>();
+/// assert_eq!(s.as_str(), "as");
+/// assert_eq!(MessageItem::new_array::<String>(vec!()).signature(), s);
+/// assert!(Signature::new("a{").is_err());
+/// ```
+#[deriving(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Signature(String);
+
+impl Signature {
+    /// Checks that `s` is a valid signature.
+    pub fn new(s: &str) -> Result<Signature, Error> {
+        let c = try!(to_c_str(s));
+        let mut e = Error::empty();
+        if unsafe { ffi::dbus_signature_validate(c.as_ptr(), e.get_mut()) } == 0 { return Err(e) }
+        Ok(Signature(s.to_string()))
+    }
+
+    /// The signature of a Rust type, e g "u" for `u32`.
+    pub fn of<T: Arg>() -> Signature { Signature(<T as Arg>::signature()) }
+
+    /// The signature of the items, one after the other, e g "su" for a string and a u32.
+    /// Not checked, see `MessageItem::signature`.
+    pub fn of_items(items: &[MessageItem]) -> Signature {
+        let mut s = String::new();
+        for i in items.iter() { s.push_str(i.type_sig().as_slice()) }
+        Signature(s)
+    }
+
+    pub fn as_str(&self) -> &str { self.0.as_slice() }
+
+    /// Parses the signature into its complete types, e g "sa{sv}" into a `Type::Str`
+    /// and a `Type::Dict`. Fails only for signatures made with `of_items` from items
+    /// that cannot be appended.
+    pub fn types(&self) -> Result<Vec<Type>, Error> {
+        let b = self.0.as_bytes();
+        let mut pos = 0;
+        let mut v = vec!();
+        while pos < b.len() { v.push(try!(parse_type(b, &mut pos, 0, 0).map_err(invalid))); }
+        Ok(v)
+    }
+
+    /// The signature of the types, one after the other.
+    pub fn from_types(t: &[Type]) -> Signature {
+        let mut s = String::new();
+        for x in t.iter() { s.push_str(x.signature().as_slice()) }
+        Signature(s)
+    }
+
+    pub fn into_string(self) -> String { self.0 }
+}
+
+impl fmt::Show for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{}", self.0) }
+}
+
+/// A single complete type, as parsed from a signature by `Signature::types`.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Type {
+    Byte,
+    Bool,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Int64,
+    UInt64,
+    Double,
+    Str,
+    ObjectPath,
+    Signature,
+    UnixFd,
+    Variant,
+    Array(Box<Type>),
+    /// "a{kv}", where the key is always a basic type.
+    Dict(Box<Type>, Box<Type>),
+    Struct(Vec<Type>),
+}
+
+// The limits from the D-Bus specification
+const MAX_SIGNATURE_LEN: uint = 255;
+const MAX_DEPTH: uint = 32;
+
+fn invalid(s: String) -> Error {
+    Error::new_custom("org.freedesktop.DBus.Error.InvalidSignature", s.as_slice())
+}
+
+fn parse_type(s: &[u8], pos: &mut uint, arrays: uint, structs: uint) -> Result<Type, String> {
+    if s.len() > MAX_SIGNATURE_LEN { return Err(format!("Signature is longer than {} bytes", MAX_SIGNATURE_LEN)) }
+    let c = match s.get(*pos) { Some(c) => *c, None => return Err("Signature ends in the middle of a type".to_string()) };
+    *pos += 1;
+    Ok(match c {
+        b'y' => Type::Byte,
+        b'b' => Type::Bool,
+        b'n' => Type::Int16,
+        b'q' => Type::UInt16,
+        b'i' => Type::Int32,
+        b'u' => Type::UInt32,
+        b'x' => Type::Int64,
+        b't' => Type::UInt64,
+        b'd' => Type::Double,
+        b's' => Type::Str,
+        b'o' => Type::ObjectPath,
+        b'g' => Type::Signature,
+        b'h' => Type::UnixFd,
+        b'v' => Type::Variant,
+        b'a' if arrays >= MAX_DEPTH => return Err("Arrays are nested too deeply".to_string()),
+        b'a' if s.get(*pos) == Some(&b'{') => {
+            *pos += 1;
+            let k = try!(parse_type(s, pos, arrays + 1, structs));
+            if !k.is_basic() { return Err(format!("Dictionary key {} is not a basic type", k.signature())) }
+            let v = try!(parse_type(s, pos, arrays + 1, structs));
+            if s.get(*pos) != Some(&b'}') { return Err("Dictionary entry does not end after the value".to_string()) }
+            *pos += 1;
+            Type::Dict(box k, box v)
+        }
+        b'a' => Type::Array(box try!(parse_type(s, pos, arrays + 1, structs))),
+        b'(' if structs >= MAX_DEPTH => return Err("Structs are nested too deeply".to_string()),
+        b'(' => {
+            let mut v = vec!();
+            while s.get(*pos) != Some(&b')') { v.push(try!(parse_type(s, pos, arrays, structs + 1))); }
+            *pos += 1;
+            if v.len() == 0 { return Err("Structs must have at least one field".to_string()) }
+            Type::Struct(v)
+        }
+        c => return Err(format!("Unexpected '{}' in signature", c as char)),
+    })
+}
+
+impl Type {
+    /// Parses a single complete type, e g "a(ii)".
+    pub fn parse(s: &str) -> Result<Type, Error> {
+        let mut pos = 0;
+        let t = try!(parse_type(s.as_bytes(), &mut pos, 0, 0).map_err(invalid));
+        if pos < s.len() { return Err(invalid(format!("'{}' is more than one complete type", s))) }
+        Ok(t)
+    }
+
+    /// Basic types are the ones that can be dictionary keys: everything but containers and variants.
+    pub fn is_basic(&self) -> bool {
+        match self {
+            &Type::Variant | &Type::Array(_) | &Type::Dict(_, _) | &Type::Struct(_) => false,
+            _ => true,
+        }
+    }
+
+    pub fn signature(&self) -> String {
+        let c = match self {
+            &Type::Array(ref t) => return format!("a{}", t.signature()),
+            &Type::Dict(ref k, ref v) => return format!("a{{{}{}}}", k.signature(), v.signature()),
+            &Type::Struct(ref v) => return format!("({})", Signature::from_types(v.as_slice())),
+            &Type::Byte => 'y',
+            &Type::Bool => 'b',
+            &Type::Int16 => 'n',
+            &Type::UInt16 => 'q',
+            &Type::Int32 => 'i',
+            &Type::UInt32 => 'u',
+            &Type::Int64 => 'x',
+            &Type::UInt64 => 't',
+            &Type::Double => 'd',
+            &Type::Str => 's',
+            &Type::ObjectPath => 'o',
+            &Type::Signature => 'g',
+            &Type::UnixFd => 'h',
+            &Type::Variant => 'v',
+        };
+        let mut s = String::new();
+        s.push(c);
+        s
+    }
+}
+
+#[test]
+fn parse() {
+    let s = Signature::new("sa{sv}a(iay)").unwrap();
+    let t = s.types().unwrap();
+    assert_eq!(t, vec!(Type::Str, Type::Dict(box Type::Str, box Type::Variant),
+        Type::Array(box Type::Struct(vec!(Type::Int32, Type::Array(box Type::Byte))))));
+    assert_eq!(Signature::from_types(t.as_slice()), s);
+    assert_eq!(Signature::new("").unwrap().types().unwrap(), vec!());
+
+    assert_eq!(Type::parse("a{ub}").unwrap().signature().as_slice(), "a{ub}");
+    for b in ["a", "a{vs}", "a{s}", "a{sss}", "()", "(s", "s)", "{sv}", "su", "z"].iter() {
+        assert!(Type::parse(*b).is_err(), "{} should not parse", b);
+    }
+    let mut deep = String::from_char(33, 'a');
+    deep.push('y');
+    assert!(Type::parse(deep.as_slice()).is_err());
+    assert!(Type::parse(deep.slice_from(1)).is_ok());
+}
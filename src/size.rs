@@ -0,0 +1,209 @@
+use super::{MessageItem, Error};
+
+/// The largest message the reference bus daemon accepts, by default (128 MiB).
+pub const MAX_MESSAGE_SIZE: uint = 134217728;
+
+/// The longest possible bus, interface or member name.
+pub const MAX_NAME_LENGTH: uint = 255;
+
+pub const MAX_SIGNATURE_LENGTH: uint = 255;
+
+/// The largest marshaled size of an array's contents (64 MiB).
+pub const MAX_ARRAY_SIZE: uint = 67108864;
+
+/// How deeply arrays may be nested.
+pub const MAX_ARRAY_DEPTH: uint = 32;
+
+/// How deeply containers of any kind may be nested, including variants.
+pub const MAX_TOTAL_DEPTH: uint = 64;
+
+/// A protocol limit that a message exceeds, see `Message::check_limits`.
+#[deriving(Show, PartialEq, Clone)]
+pub enum LimitViolation {
+    /// A header field (e g the interface name) is longer than `MAX_NAME_LENGTH`.
+    NameTooLong(String),
+    SignatureTooLong(uint),
+    /// An array's contents take up more than `MAX_ARRAY_SIZE` bytes.
+    ArrayTooLarge(uint),
+    /// Containers are nested this deep, which is more than allowed.
+    NestingTooDeep(uint),
+    MessageTooLarge(uint),
+    /// The arguments could not be read, so their size is unknown.
+    UnreadableBody(String),
+}
+
+impl LimitViolation {
+    /// A LimitsExceeded error describing the violation.
+    pub fn to_error(&self) -> Error {
+        let f = match self {
+            &LimitViolation::NameTooLong(ref n) => format!("Name '{}' is longer than {} bytes", n, MAX_NAME_LENGTH),
+            &LimitViolation::SignatureTooLong(n) => format!("Signature is {} bytes, more than {}", n, MAX_SIGNATURE_LENGTH),
+            &LimitViolation::ArrayTooLarge(n) => format!("Array is {} bytes, more than {}", n, MAX_ARRAY_SIZE),
+            &LimitViolation::NestingTooDeep(n) => format!("Containers are nested {} levels deep", n),
+            &LimitViolation::MessageTooLarge(n) => format!("Message is {} bytes, more than {}", n, MAX_MESSAGE_SIZE),
+            &LimitViolation::UnreadableBody(ref e) => format!("Arguments could not be read: {}", e),
+        };
+        Error::new_custom("org.freedesktop.DBus.Error.LimitsExceeded", f.as_slice())
+    }
+}
+
+fn align(pos: uint, n: uint) -> uint { (pos + n - 1) / n * n }
+
+fn type_alignment(t: u8) -> uint {
+    match t as char {
+        'y' | 'g' | 'v' => 1,
+        'n' | 'q' => 2,
+        'b' | 'i' | 'u' | 's' | 'o' | 'a' | 'h' => 4,
+        _ => 8,
+    }
+}
+
+fn item_signature(i: &MessageItem) -> String { i.type_sig() }
+
+// Untyped empty arrays cannot be appended anyway, so their alignment does not matter
+fn elem_alignment(i: &MessageItem) -> uint {
+    type_alignment(item_signature(i).as_bytes().get(1).map_or(b'y', |b| *b))
+}
+
+fn item_size(i: &MessageItem, pos: uint) -> uint {
+    let pos = align(pos, type_alignment(i.array_type() as u8));
+    match i {
+        &MessageItem::Byte(_) => pos + 1,
+        &MessageItem::Int16(_) | &MessageItem::UInt16(_) => pos + 2,
+        &MessageItem::Bool(_) | &MessageItem::Int32(_) | &MessageItem::UInt32(_) |
+            &MessageItem::UnixFd(_) => pos + 4,
+        &MessageItem::Int64(_) | &MessageItem::UInt64(_) | &MessageItem::Double(_) => pos + 8,
+        &MessageItem::Str(ref s) => pos + 4 + s.len() + 1,
+        &MessageItem::ObjectPath(ref s) => pos + 4 + s.as_str().len() + 1,
+        &MessageItem::Signature(ref s) => pos + 1 + s.len() + 1,
+        &MessageItem::Variant(ref v) => {
+            let sig = item_signature(&**v);
+            item_size(&**v, pos + 1 + sig.len() + 1)
+        }
+        &MessageItem::DictEntry(ref k, ref v) => item_size(&**v, item_size(&**k, pos)),
+        &MessageItem::Struct(ref a) => a.iter().fold(pos, |p, x| item_size(x, p)),
+        &MessageItem::Array(ref a, _) => {
+            let elem_align = elem_alignment(i);
+            // Padding up to the first element is there even if the array is empty
+            let start = align(pos + 4, elem_align);
+            a.iter().fold(start, |p, x| item_size(x, p))
+        }
+        &MessageItem::Dict(ref a, _, _) => {
+            // Dict entries are aligned to 8, like structs
+            let start = align(pos + 4, 8);
+            a.iter().fold(start, |p, &(ref k, ref v)| item_size(v, item_size(k, align(p, 8))))
+        }
+    }
+}
+
+fn check_item(i: &MessageItem, arrays: uint, total: uint) -> Result<(), LimitViolation> {
+    match i {
+        &MessageItem::Array(ref a, _) => {
+            if arrays + 1 > MAX_ARRAY_DEPTH { return Err(LimitViolation::NestingTooDeep(arrays + 1)) }
+            if total + 1 > MAX_TOTAL_DEPTH { return Err(LimitViolation::NestingTooDeep(total + 1)) }
+            // Arrays are aligned to 8 at most, so measuring from 0 is exact
+            let n = item_size(i, 0) - align(4, elem_alignment(i));
+            if n > MAX_ARRAY_SIZE { return Err(LimitViolation::ArrayTooLarge(n)) }
+            for x in a.iter() { try!(check_item(x, arrays + 1, total + 1)); }
+            Ok(())
+        }
+        &MessageItem::Dict(ref a, _, _) => {
+            // The array and its dict entries are two levels
+            if arrays + 1 > MAX_ARRAY_DEPTH { return Err(LimitViolation::NestingTooDeep(arrays + 1)) }
+            if total + 2 > MAX_TOTAL_DEPTH { return Err(LimitViolation::NestingTooDeep(total + 2)) }
+            let n = item_size(i, 0) - 8;
+            if n > MAX_ARRAY_SIZE { return Err(LimitViolation::ArrayTooLarge(n)) }
+            for &(ref k, ref v) in a.iter() {
+                try!(check_item(k, arrays + 1, total + 2));
+                try!(check_item(v, arrays + 1, total + 2));
+            }
+            Ok(())
+        }
+        &MessageItem::Variant(ref v) => {
+            if total + 1 > MAX_TOTAL_DEPTH { return Err(LimitViolation::NestingTooDeep(total + 1)) }
+            check_item(&**v, arrays, total + 1)
+        }
+        &MessageItem::DictEntry(ref k, ref v) => {
+            if total + 1 > MAX_TOTAL_DEPTH { return Err(LimitViolation::NestingTooDeep(total + 1)) }
+            try!(check_item(&**k, arrays, total + 1));
+            check_item(&**v, arrays, total + 1)
+        }
+        &MessageItem::Struct(ref a) => {
+            if total + 1 > MAX_TOTAL_DEPTH { return Err(LimitViolation::NestingTooDeep(total + 1)) }
+            for x in a.iter() { try!(check_item(x, arrays, total + 1)); }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks header fields and body against the limits of the D-Bus specification.
+pub fn check_limits(names: &[Option<&str>], items: &[MessageItem]) -> Result<(), LimitViolation> {
+    for n in names.iter().filter_map(|n| *n) {
+        if n.len() > MAX_NAME_LENGTH { return Err(LimitViolation::NameTooLong(n.to_string())) }
+    }
+    let siglen = items.iter().fold(0, |l, i| l + item_signature(i).len());
+    if siglen > MAX_SIGNATURE_LENGTH { return Err(LimitViolation::SignatureTooLong(siglen)) }
+    for i in items.iter() { try!(check_item(i, 0, 0)); }
+    Ok(())
+}
+
+/// The number of bytes the items take up when marshaled as a message body.
+pub fn body_size(items: &[MessageItem]) -> uint {
+    items.iter().fold(0, |p, x| item_size(x, p))
+}
+
+fn string_field_size(pos: uint, s: Option<&str>) -> uint {
+    match s {
+        // Field code and variant signature take four bytes, then the string itself
+        Some(s) => align(pos, 8) + 4 + 4 + s.len() + 1,
+        None => pos,
+    }
+}
+
+/// An upper bound of the size of a message with the given header fields and body.
+///
+/// The sender field is counted with the maximum length if it is not set, because the
+/// bus daemon fills it in when it relays the message.
+pub fn message_size_bound(path: Option<&str>, iface: Option<&str>, member: Option<&str>,
+    dest: Option<&str>, sender: Option<&str>, signature: &str, body: uint) -> uint {
+
+    let mut pos = 16;
+    pos = string_field_size(pos, path);
+    pos = string_field_size(pos, iface);
+    pos = string_field_size(pos, member);
+    pos = string_field_size(pos, dest);
+    pos = string_field_size(pos, Some(sender.unwrap_or(String::from_char(MAX_NAME_LENGTH, 'x').as_slice())));
+    // Error name and reply serial, which we do not know about
+    pos = align(pos, 8) + 4 + 4 + MAX_NAME_LENGTH + 1;
+    pos = align(pos, 8) + 4 + 4;
+    // Signature field
+    pos = align(pos, 8) + 4 + 1 + signature.len() + 1;
+    align(pos, 8) + body
+}
+
+#[test]
+fn sizes() {
+    assert_eq!(body_size(&[MessageItem::Byte(1), MessageItem::UInt32(5)]), 8);
+    assert_eq!(body_size(&[MessageItem::Str("abc".to_string())]), 8);
+    // Array length, padding to 8, then one 8-byte element
+    assert_eq!(body_size(&[MessageItem::Array(vec!(MessageItem::UInt64(1)), "".to_string())]), 16);
+    // Empty arrays still pad to the element alignment
+    assert_eq!(body_size(&[MessageItem::Byte(1), MessageItem::new_array::<u64>(vec!())]), 8);
+    assert_eq!(body_size(&[MessageItem::Variant(box MessageItem::Int32(3))]), 8);
+    assert_eq!(body_size(&[MessageItem::Byte(1), MessageItem::Double(0.5)]), 16);
+    // Structs start at 8 byte boundaries
+    assert_eq!(body_size(&[MessageItem::Byte(1), MessageItem::Struct(vec!(MessageItem::Byte(2)))]), 9);
+    // Signatures have a one byte length
+    assert_eq!(body_size(&[MessageItem::Signature("a{sv}".to_string())]), 7);
+}
+
+#[test]
+fn limits() {
+    let long = String::from_char(300, 'a');
+    assert_eq!(check_limits(&[Some(long.as_slice())], &[]), Err(LimitViolation::NameTooLong(long.clone())));
+    let mut deep = MessageItem::Byte(1);
+    for _ in range(0, 33u) { deep = MessageItem::Array(vec!(deep), "".to_string()); }
+    assert_eq!(check_limits(&[], &[deep]), Err(LimitViolation::NestingTooDeep(33)));
+    assert_eq!(check_limits(&[Some("com.example")], &[MessageItem::Str("x".to_string())]), Ok(()));
+}
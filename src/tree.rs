@@ -0,0 +1,1452 @@
+use super::{Connection, ConnectionItem, Credentials, Message, MessageItem, MessageType, Error, Path, ReplyToken,
+    is_valid_object_path};
+use super::{ffi, c_str_to_slice};
+use super::arg::{ArgList, ReadOptions};
+use libc;
+use std::ptr;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Show;
+
+/// An error reply from a method handler: a D-Bus error name and a description.
+#[deriving(Clone, Show, PartialEq)]
+pub struct MethodErr(String, String);
+
+impl MethodErr {
+    pub fn new(errorname: &str, description: &str) -> MethodErr {
+        MethodErr(errorname.to_string(), description.to_string())
+    }
+
+    pub fn invalid_arg<T: Show>(a: &T) -> MethodErr {
+        MethodErr::new("org.freedesktop.DBus.Error.InvalidArgs", format!("Invalid argument {}", a).as_slice())
+    }
+
+    pub fn no_arg() -> MethodErr {
+        MethodErr::new("org.freedesktop.DBus.Error.InvalidArgs", "Not enough arguments")
+    }
+
+    /// InvalidArgs for arguments that could not be read, e g by `Message::get_items`.
+    pub fn unreadable_args(e: Error) -> MethodErr {
+        MethodErr::new("org.freedesktop.DBus.Error.InvalidArgs", e.message().unwrap_or("Invalid arguments"))
+    }
+
+    pub fn failed<T: Show>(a: &T) -> MethodErr {
+        MethodErr::new("org.freedesktop.DBus.Error.Failed", format!("{}", a).as_slice())
+    }
+
+    pub fn errorname(&self) -> &str { self.0.as_slice() }
+
+    pub fn description(&self) -> &str { self.1.as_slice() }
+}
+
+pub type MethodResult = Result<Vec<MessageItem>, MethodErr>;
+
+/// What a handler added with `Factory::deferred_method` returns.
+pub enum Reply {
+    /// Reply with these items right away.
+    Now(Vec<MessageItem>),
+    /// The reply is sent later with `Connection::reply`.
+    Later(ReplyToken),
+}
+
+/// A method or signal argument, as shown in introspection data.
+#[deriving(Clone, Show, PartialEq)]
+pub struct Argument {
+    name: Option<String>,
+    sig: String,
+}
+
+impl Argument {
+    pub fn new(name: Option<&str>, sig: &str) -> Argument {
+        Argument { name: name.map(|n| n.to_string()), sig: sig.to_string() }
+    }
+
+    pub fn name(&self) -> Option<&str> { self.name.as_ref().map(|n| n.as_slice()) }
+
+    pub fn signature(&self) -> &str { self.sig.as_slice() }
+
+    fn introspect(&self, direction: Option<&str>) -> String {
+        let name = match self.name { Some(ref n) => format!(" name=\"{}\"", xml_escape(n.as_slice())), None => "".to_string() };
+        let dir = match direction { Some(d) => format!(" direction=\"{}\"", xml_escape(d)), None => "".to_string() };
+        format!("      <arg{} type=\"{}\"{}/>\n", name, xml_escape(self.sig.as_slice()), dir)
+    }
+}
+
+// Checks made before a method handler runs.
+type AuthCheck<'a> = Box<Fn(&Message) -> Result<(), MethodErr> + 'a>;
+
+pub struct Method<'a> {
+    name: String,
+    in_args: Vec<Argument>,
+    out_args: Vec<Argument>,
+    auth: Option<AuthCheck<'a>>,
+    annotations: Vec<(String, String)>,
+    cb: Box<Fn(&mut Message) -> Result<Reply, MethodErr> + 'a>,
+}
+
+impl<'a> Method<'a> {
+    pub fn name(&self) -> &str { self.name.as_slice() }
+
+    pub fn in_args(&self) -> &[Argument] { self.in_args.as_slice() }
+
+    pub fn out_args(&self) -> &[Argument] { self.out_args.as_slice() }
+
+    pub fn annotations(&self) -> &[(String, String)] { self.annotations.as_slice() }
+
+    fn is_no_reply(&self) -> bool {
+        self.annotations.iter().any(|&(ref n, ref v)| n.as_slice() == "org.freedesktop.DBus.Method.NoReply" && v.as_slice() == "true")
+    }
+}
+
+pub struct Signal {
+    name: String,
+    args: Vec<Argument>,
+}
+
+impl Signal {
+    pub fn name(&self) -> &str { self.name.as_slice() }
+
+    pub fn args(&self) -> &[Argument] { self.args.as_slice() }
+}
+
+/// When PropertiesChanged is emitted for a property that is set, as in the
+/// org.freedesktop.DBus.Property.EmitsChangedSignal annotation.
+#[deriving(Show, Copy, Clone, PartialEq)]
+pub enum EmitsChangedSignal {
+    /// With the new value.
+    True,
+    /// With the name of the property only.
+    Invalidates,
+    /// Never, because the property does not change.
+    Const,
+    /// Never.
+    False,
+}
+
+impl EmitsChangedSignal {
+    fn from_annotation(s: &str) -> Option<EmitsChangedSignal> {
+        match s {
+            "true" => Some(EmitsChangedSignal::True),
+            "invalidates" => Some(EmitsChangedSignal::Invalidates),
+            "const" => Some(EmitsChangedSignal::Const),
+            "false" => Some(EmitsChangedSignal::False),
+            _ => None,
+        }
+    }
+
+    fn as_annotation(&self) -> &'static str {
+        match *self {
+            EmitsChangedSignal::True => "true",
+            EmitsChangedSignal::Invalidates => "invalidates",
+            EmitsChangedSignal::Const => "const",
+            EmitsChangedSignal::False => "false",
+        }
+    }
+}
+
+static EMITS_CHANGED_SIGNAL: &'static str = "org.freedesktop.DBus.Property.EmitsChangedSignal";
+
+pub struct Property<'a> {
+    name: String,
+    sig: String,
+    emits: EmitsChangedSignal,
+    annotations: Vec<(String, String)>,
+    get: Option<Box<Fn() -> Result<MessageItem, MethodErr> + 'a>>,
+    set: Option<Box<Fn(MessageItem) -> Result<(), MethodErr> + 'a>>,
+}
+
+impl<'a> Property<'a> {
+    pub fn name(&self) -> &str { self.name.as_slice() }
+
+    pub fn signature(&self) -> &str { self.sig.as_slice() }
+
+    pub fn emits_changed(&self) -> EmitsChangedSignal { self.emits }
+
+    /// The annotations other than EmitsChangedSignal, which is `emits_changed`.
+    pub fn annotations(&self) -> &[(String, String)] { self.annotations.as_slice() }
+
+    pub fn get_value(&self) -> Result<MessageItem, MethodErr> {
+        match self.get {
+            Some(ref g) => (**g)(),
+            None => Err(MethodErr::failed(&format!("Property {} is write only", self.name))),
+        }
+    }
+
+    /// Sets the value, after checking that the property is writable and that the value
+    /// has the property's signature.
+    pub fn set_value(&self, v: MessageItem) -> Result<(), MethodErr> {
+        let set = match self.set {
+            Some(ref s) => s,
+            None => return Err(MethodErr::new("org.freedesktop.DBus.Error.PropertyReadOnly",
+                format!("Property {} is read only", self.name).as_slice())),
+        };
+        if v.signature().as_str() != self.sig.as_slice() {
+            return Err(MethodErr::new("org.freedesktop.DBus.Error.InvalidArgs",
+                format!("Property {} has signature '{}', not '{}'", self.name, self.sig, v.signature()).as_slice()));
+        }
+        (**set)(v)
+    }
+
+    fn access(&self) -> &'static str {
+        match (self.get.is_some(), self.set.is_some()) {
+            (true, true) => "readwrite",
+            (false, true) => "write",
+            _ => "read",
+        }
+    }
+}
+
+pub struct Interface<'a> {
+    name: String,
+    methods: BTreeMap<String, Method<'a>>,
+    signals: BTreeMap<String, Signal>,
+    props: BTreeMap<String, Property<'a>>,
+    auth: Option<AuthCheck<'a>>,
+    annotations: Vec<(String, String)>,
+}
+
+impl<'a> Interface<'a> {
+    pub fn name(&self) -> &str { self.name.as_slice() }
+
+    pub fn get_method(&self, name: &str) -> Option<&Method<'a>> { self.methods.get(name) }
+
+    pub fn get_signal(&self, name: &str) -> Option<&Signal> { self.signals.get(name) }
+
+    pub fn get_property(&self, name: &str) -> Option<&Property<'a>> { self.props.get(name) }
+
+    pub fn annotations(&self) -> &[(String, String)] { self.annotations.as_slice() }
+
+    // What the properties do if they do not say otherwise.
+    fn emits_default(&self) -> EmitsChangedSignal {
+        self.annotations.iter().filter(|&&(ref n, _)| n.as_slice() == EMITS_CHANGED_SIGNAL)
+            .filter_map(|&(_, ref v)| EmitsChangedSignal::from_annotation(v.as_slice())).last()
+            .unwrap_or(EmitsChangedSignal::True)
+    }
+
+    /// The values of all readable properties, as an a{sv} dictionary.
+    pub fn get_all(&self) -> Result<MessageItem, MethodErr> {
+        let mut d = vec!();
+        for p in self.props.values() {
+            if p.get.is_none() { continue }
+            d.push((MessageItem::Str(p.name.clone()), MessageItem::Variant(box try!(p.get_value()))));
+        }
+        Ok(MessageItem::Dict(d, "s".to_string(), "v".to_string()))
+    }
+
+    fn introspect(&self) -> String {
+        let mut s = format!("  <interface name=\"{}\">\n", xml_escape(self.name.as_slice()));
+        for a in self.annotations.iter() { s.push_str(introspect_annotation("    ", a).as_slice()) }
+        for m in self.methods.values() {
+            s.push_str(introspect_member("method", m.name(), m.in_args.iter().map(|a| a.introspect(Some("in")))
+                .chain(m.out_args.iter().map(|a| a.introspect(Some("out"))))
+                .chain(m.annotations.iter().map(|a| introspect_annotation("      ", a))).collect()).as_slice());
+        }
+        for g in self.signals.values() {
+            s.push_str(introspect_member("signal", g.name(), g.args.iter().map(|a| a.introspect(None)).collect()).as_slice());
+        }
+        for p in self.props.values() {
+            let mut a: Vec<String> = p.annotations.iter().map(|a| introspect_annotation("      ", a)).collect();
+            if p.emits != self.emits_default() {
+                a.push(introspect_annotation("      ", &(EMITS_CHANGED_SIGNAL.to_string(), p.emits.as_annotation().to_string())));
+            }
+            let attrs = format!("name=\"{}\" type=\"{}\" access=\"{}\"", xml_escape(p.name.as_slice()),
+                xml_escape(p.sig.as_slice()), p.access());
+            s.push_str(introspect_element("property", attrs.as_slice(), a).as_slice());
+        }
+        s.push_str("  </interface>\n");
+        s
+    }
+}
+
+/* For attribute values, which are quoted with '"'. */
+fn xml_escape(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => r.push_str("&amp;"),
+            '<' => r.push_str("&lt;"),
+            '>' => r.push_str("&gt;"),
+            '"' => r.push_str("&quot;"),
+            '\'' => r.push_str("&apos;"),
+            c => r.push(c),
+        }
+    }
+    r
+}
+
+fn introspect_annotation(indent: &str, a: &(String, String)) -> String {
+    format!("{}<annotation name=\"{}\" value=\"{}\"/>\n", indent, xml_escape(a.0.as_slice()), xml_escape(a.1.as_slice()))
+}
+
+fn introspect_member(kind: &str, name: &str, children: Vec<String>) -> String {
+    introspect_element(kind, format!("name=\"{}\"", xml_escape(name)).as_slice(), children)
+}
+
+fn introspect_element(kind: &str, attrs: &str, children: Vec<String>) -> String {
+    if children.is_empty() { return format!("    <{} {}/>\n", kind, attrs) }
+    let mut s = format!("    <{} {}>\n", kind, attrs);
+    for c in children.iter() { s.push_str(c.as_slice()) }
+    s.push_str(format!("    </{}>\n", kind).as_slice());
+    s
+}
+
+static INTROSPECTABLE: &'static str = r##"  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="xml_data" type="s" direction="out"/>
+    </method>
+  </interface>
+"##;
+
+static PEER: &'static str = r##"  <interface name="org.freedesktop.DBus.Peer">
+    <method name="GetMachineId">
+      <arg name="machine_uuid" type="s" direction="out"/>
+    </method>
+    <method name="Ping"/>
+  </interface>
+"##;
+
+static PROPERTIES: &'static str = r##"  <interface name="org.freedesktop.DBus.Properties">
+    <method name="Get">
+      <arg name="interface_name" type="s" direction="in"/>
+      <arg name="property_name" type="s" direction="in"/>
+      <arg name="value" type="v" direction="out"/>
+    </method>
+    <method name="GetAll">
+      <arg name="interface_name" type="s" direction="in"/>
+      <arg name="props" type="a{sv}" direction="out"/>
+    </method>
+    <method name="Set">
+      <arg name="interface_name" type="s" direction="in"/>
+      <arg name="property_name" type="s" direction="in"/>
+      <arg name="value" type="v" direction="in"/>
+    </method>
+    <signal name="PropertiesChanged">
+      <arg name="interface_name" type="s"/>
+      <arg name="changed_properties" type="a{sv}"/>
+      <arg name="invalidated_properties" type="as"/>
+    </signal>
+  </interface>
+"##;
+
+static OBJECT_MANAGER: &'static str = r##"  <interface name="org.freedesktop.DBus.ObjectManager">
+    <method name="GetManagedObjects">
+      <arg name="objpath_interfaces_and_properties" type="a{oa{sa{sv}}}" direction="out"/>
+    </method>
+    <signal name="InterfacesAdded">
+      <arg name="object_path" type="o"/>
+      <arg name="interfaces_and_properties" type="a{sa{sv}}"/>
+    </signal>
+    <signal name="InterfacesRemoved">
+      <arg name="object_path" type="o"/>
+      <arg name="interfaces" type="as"/>
+    </signal>
+  </interface>
+"##;
+
+pub struct ObjectPath<'a> {
+    path: String,
+    object_manager: bool,
+    fallback: bool,
+    ifaces: BTreeMap<String, Interface<'a>>,
+}
+
+impl<'a> ObjectPath<'a> {
+    pub fn path(&self) -> &str { self.path.as_slice() }
+
+    pub fn get_interface(&self, name: &str) -> Option<&Interface<'a>> { self.ifaces.get(name) }
+
+    /// Whether the object implements org.freedesktop.DBus.ObjectManager for the objects below it.
+    pub fn is_object_manager(&self) -> bool { self.object_manager }
+
+    /// Whether the object also handles calls to the paths below it that are not in the tree.
+    pub fn is_fallback(&self) -> bool { self.fallback }
+
+    // The a{sa{sv}} of interfaces and their properties, as used by the ObjectManager interface.
+    fn interfaces_item(&self) -> MessageItem {
+        let i = self.ifaces.values().map(|i| (MessageItem::Str(i.name.clone()),
+            i.get_all().unwrap_or(MessageItem::Dict(vec!(), "s".to_string(), "v".to_string())))).collect();
+        MessageItem::Dict(i, "s".to_string(), "a{sv}".to_string())
+    }
+
+    fn has_properties(&self) -> bool { self.ifaces.values().any(|i| !i.props.is_empty()) }
+
+    /// Returns introspection XML for the object, which includes the standard interfaces
+    /// added by the tree. Use `Tree::introspect` to also list the objects below it.
+    pub fn introspect(&self) -> String {
+        self.introspect_node(self.path.as_slice(), &[])
+    }
+
+    fn introspect_node(&self, name: &str, children: &[&str]) -> String {
+        let mut s = format!(r##"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN" "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node name="{}">
+"##, name);
+        for i in self.ifaces.values() { s.push_str(i.introspect().as_slice()) }
+        if !self.ifaces.contains_key("org.freedesktop.DBus.Introspectable") { s.push_str(INTROSPECTABLE) }
+        if !self.ifaces.contains_key("org.freedesktop.DBus.Peer") { s.push_str(PEER) }
+        if self.has_properties() && !self.ifaces.contains_key("org.freedesktop.DBus.Properties") {
+            s.push_str(PROPERTIES)
+        }
+        if self.object_manager && !self.ifaces.contains_key("org.freedesktop.DBus.ObjectManager") {
+            s.push_str(OBJECT_MANAGER)
+        }
+        for c in children.iter() { s.push_str(format!("  <node name=\"{}\"/>\n", c).as_slice()) }
+        s.push_str("</node>");
+        s
+    }
+
+    /// Sends the standard org.freedesktop.DBus.Properties.PropertiesChanged signal from this
+    /// object, with the new values of `changed` and the names of `invalidated` properties.
+    pub fn emit_properties_changed(&self, c: &Connection, iface: &str, changed: HashMap<&str, MessageItem>,
+        invalidated: &[&str]) -> Result<(), ()> {
+        c.send(try!(properties_changed(self.path.as_slice(), iface, changed, invalidated).map_err(|_| ())))
+    }
+
+    /// Sets a property, and emits PropertiesChanged for it as its `EmitsChangedSignal` says.
+    pub fn set_property(&self, c: &Connection, iface: &str, name: &str, value: MessageItem) -> Result<(), MethodErr> {
+        match try!(self.set_property_signal(self.path.as_slice(), iface, name, value)) {
+            Some(s) => c.send(s).map_err(|_| MethodErr::failed(&"Sending PropertiesChanged failed")),
+            None => Ok(()),
+        }
+    }
+
+    fn find_property(&self, iface: &str, name: &str) -> Result<(&Interface<'a>, &Property<'a>), MethodErr> {
+        let i = try!(self.ifaces.get(iface).ok_or(unknown_interface(iface)));
+        let p = try!(i.props.get(name).ok_or(MethodErr::new("org.freedesktop.DBus.Error.UnknownProperty",
+            format!("Unknown property {}", name).as_slice())));
+        Ok((i, p))
+    }
+
+    // Sets the property, and returns the PropertiesChanged signal to send from `path`, if any.
+    // The value is set even if the signal cannot be built, so then there is no signal.
+    fn set_property_signal(&self, path: &str, iface: &str, name: &str, value: MessageItem) -> Result<Option<Message>, MethodErr> {
+        let (i, p) = try!(self.find_property(iface, name));
+        try!(p.set_value(value.clone()));
+        Ok(match p.emits {
+            EmitsChangedSignal::True => {
+                let mut h = HashMap::new();
+                h.insert(p.name(), p.get_value().unwrap_or(value));
+                properties_changed(path, i.name(), h, &[]).ok()
+            }
+            EmitsChangedSignal::Invalidates => properties_changed(path, i.name(), HashMap::new(), &[p.name()]).ok(),
+            EmitsChangedSignal::Const | EmitsChangedSignal::False => None,
+        })
+    }
+
+    fn handle_properties(&self, m: &mut Message, member: &str) -> Vec<Message> {
+        let items = match m.get_items() {
+            Ok(i) => i,
+            Err(e) => return method_reply(m, Err(MethodErr::unreadable_args(e))),
+        };
+        // The properties belong to the interface, so its checks apply to them too
+        if let Some(i) = items.get(0).and_then(|i| i.as_str()).and_then(|i| self.ifaces.get(i)) {
+            for a in i.auth.iter() {
+                if let Err(e) = (**a)(m) { return method_reply(m, Err(e)) }
+            }
+        }
+        let path = m.path().unwrap_or(self.path.as_slice()).to_string();
+        match self.properties_call(path.as_slice(), member, items.as_slice()) {
+            Ok((items, signal)) => {
+                let mut r = method_reply(m, Ok(items));
+                r.extend(signal.into_iter());
+                r
+            }
+            Err(e) => method_reply(m, Err(e)),
+        }
+    }
+
+    // Returns the reply items, and the PropertiesChanged signal to send after the reply.
+    fn properties_call(&self, path: &str, member: &str, items: &[MessageItem]) -> Result<(Vec<MessageItem>, Option<Message>), MethodErr> {
+        let iface = try!(items.get(0).and_then(|i| i.as_str()).ok_or(MethodErr::no_arg()));
+        match member {
+            "Get" => {
+                let name = try!(items.get(1).and_then(|i| i.as_str()).ok_or(MethodErr::no_arg()));
+                let (_, p) = try!(self.find_property(iface, name));
+                Ok((vec!(MessageItem::Variant(box try!(p.get_value()))), None))
+            }
+            "GetAll" => {
+                let i = try!(self.ifaces.get(iface).ok_or(unknown_interface(iface)));
+                Ok((vec!(try!(i.get_all())), None))
+            }
+            "Set" => {
+                let name = try!(items.get(1).and_then(|i| i.as_str()).ok_or(MethodErr::no_arg()));
+                let v = match items.get(2) {
+                    Some(&MessageItem::Variant(ref v)) => (**v).clone(),
+                    Some(a) => return Err(MethodErr::invalid_arg(a)),
+                    None => return Err(MethodErr::no_arg()),
+                };
+                Ok((vec!(), try!(self.set_property_signal(path, iface, name, v))))
+            }
+            _ => Err(MethodErr::new("org.freedesktop.DBus.Error.UnknownMethod", format!("Unknown method {}", member).as_slice())),
+        }
+    }
+
+    fn handle(&self, m: &mut Message) -> Option<Vec<Message>> {
+        if let Some(r) = self.handle_builtin(m) { return Some(r) }
+        let member = match m.member() { Some(s) => s.to_string(), None => return None };
+        // The interface header is optional for method calls; without it, the first
+        // interface that has a method with the right name is used.
+        let (iface, method) = match m.interface() {
+            Some(i) => match self.ifaces.get(i) {
+                Some(i) => match i.methods.get(member.as_slice()) {
+                    Some(method) => (i, method),
+                    None => return Some(error_reply(m, "org.freedesktop.DBus.Error.UnknownMethod",
+                        format!("Unknown method {}", member).as_slice())),
+                },
+                None => return Some(error_reply(m, "org.freedesktop.DBus.Error.UnknownInterface",
+                    format!("Unknown interface {}", i).as_slice())),
+            },
+            None => match self.ifaces.values().filter_map(|i| i.methods.get(member.as_slice()).map(|m| (i, m))).next() {
+                Some(im) => im,
+                None => return Some(error_reply(m, "org.freedesktop.DBus.Error.UnknownMethod",
+                    format!("Unknown method {}", member).as_slice())),
+            },
+        };
+
+        for a in iface.auth.iter().chain(method.auth.iter()) {
+            if let Err(e) = (**a)(m) { return Some(if method.is_no_reply() { vec!() } else { method_reply(m, Err(e)) }) }
+        }
+        let r = match (*method.cb)(m) {
+            _ if method.is_no_reply() => return Some(vec!()),
+            Ok(Reply::Later(_)) => return Some(vec!()),
+            Ok(Reply::Now(items)) => Ok(items),
+            Err(e) => Err(e),
+        };
+        Some(method_reply(m, r))
+    }
+
+    // Interfaces every object has, unless the object has its own interface with the same name.
+    fn handle_builtin(&self, m: &mut Message) -> Option<Vec<Message>> {
+        let (iface, member) = match (m.interface(), m.member()) {
+            (Some(i), Some(n)) => (i.to_string(), n.to_string()),
+            _ => return None,
+        };
+        if self.ifaces.contains_key(iface.as_slice()) { return None }
+        let r = match (iface.as_slice(), member.as_slice()) {
+            ("org.freedesktop.DBus.Peer", "Ping") => Ok(vec!()),
+            ("org.freedesktop.DBus.Peer", "GetMachineId") => match local_machine_id() {
+                Some(id) => Ok(vec!(MessageItem::Str(id))),
+                None => Err(MethodErr::failed(&"Could not get the machine id")),
+            },
+            ("org.freedesktop.DBus.Properties", _) if self.has_properties() =>
+                return Some(self.handle_properties(m, member.as_slice())),
+            _ => return None,
+        };
+        Some(method_reply(m, r))
+    }
+}
+
+fn unknown_interface(name: &str) -> MethodErr {
+    MethodErr::new("org.freedesktop.DBus.Error.UnknownInterface", format!("Unknown interface {}", name).as_slice())
+}
+
+fn local_machine_id() -> Option<String> {
+    let s = unsafe { ffi::dbus_get_local_machine_id() };
+    if s == ptr::null_mut() { return None }
+    let r = c_str_to_slice(&(s as *const libc::c_char)).map(|s| s.to_string());
+    unsafe { ffi::dbus_free(s as *mut libc::c_void) };
+    r
+}
+
+fn method_reply(m: &Message, r: MethodResult) -> Vec<Message> {
+    if m.get_no_reply() { return vec!() }
+    let r = match r { Ok(ref items) => Ok(items.as_slice()), Err(ref e) => Err((e.errorname(), e.description())) };
+    super::method_result_reply(m, r).ok().into_iter().collect()
+}
+
+// Typed methods already have the signatures, so only the names are set for them.
+fn set_arg_names(v: &mut Vec<Argument>, args: &[(&str, &str)]) {
+    let named: Vec<Argument> = args.iter().map(|&(n, s)| Argument::new(Some(n), s)).collect();
+    if !v.is_empty() {
+        assert!(v.iter().map(|a| a.signature()).collect::<Vec<&str>>() == named.iter().map(|a| a.signature()).collect::<Vec<&str>>(),
+            "Arguments {} do not match the signatures {}", args, v);
+    }
+    *v = named;
+}
+
+// Registers all paths (as fallbacks if the flag is set), or none of them.
+fn register_paths(c: &Connection, paths: Vec<(&str, bool)>) -> Result<(), Error> {
+    for (n, &(p, fallback)) in paths.iter().enumerate() {
+        let r = if fallback { c.register_fallback(p) } else { c.register_object_path(p) };
+        if let Err(e) = r {
+            for &(d, _) in paths.slice_to(n).iter() { c.unregister_object_path(d) }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+fn properties_changed(path: &str, iface: &str, changed: HashMap<&str, MessageItem>, invalidated: &[&str]) -> Result<Message, Error> {
+    let mut m = try!(Message::new_signal(path, "org.freedesktop.DBus.Properties", "PropertiesChanged"));
+    let changed = changed.into_iter().map(|(k, v)|
+        (MessageItem::Str(k.to_string()), MessageItem::Variant(box v))).collect();
+    try!(m.try_append_items(&[
+        MessageItem::Str(iface.to_string()),
+        MessageItem::Dict(changed, "s".to_string(), "v".to_string()),
+        MessageItem::new_array::<String>(invalidated.iter().map(|s| MessageItem::Str(s.to_string())).collect()),
+    ]));
+    Ok(m)
+}
+
+fn error_reply(m: &Message, name: &str, description: &str) -> Vec<Message> {
+    if m.get_no_reply() { vec!() } else { vec!(Message::new_error(m, name, description).unwrap()) }
+}
+
+/// A set of object paths with interfaces and methods, built with a `Factory`.
+pub struct Tree<'a> {
+    paths: BTreeMap<String, ObjectPath<'a>>,
+}
+
+impl<'a> Tree<'a> {
+    pub fn get(&self, path: &str) -> Option<&ObjectPath<'a>> { self.paths.get(path) }
+
+    /// Registers (or unregisters) all object paths of the tree with the connection,
+    /// so that method calls to them come out of `Connection::iter`.
+    pub fn set_registered(&self, c: &Connection, register: bool) -> Result<(), Error> {
+        if !register {
+            for p in self.paths.keys() { c.unregister_object_path(p.as_slice()) }
+            return Ok(());
+        }
+        register_paths(c, self.paths.values().map(|o| (o.path(), o.fallback)).collect())
+    }
+
+    /// Adds the object paths of `t` to the tree, replacing objects with the same path.
+    ///
+    /// If `c` is given, the new paths are registered with it, and their object managers
+    /// emit InterfacesAdded for them.
+    pub fn insert_tree(&mut self, t: Tree<'a>, c: Option<&Connection>) -> Result<(), Error> {
+        let paths: Vec<String> = t.paths.keys().map(|p| p.clone()).collect();
+        if let Some(c) = c {
+            try!(register_paths(c, t.paths.values().filter(|o| !self.paths.contains_key(o.path()))
+                .map(|o| (o.path(), o.fallback)).collect()));
+        }
+        for (p, o) in t.paths.into_iter() { self.paths.insert(p, o); }
+        if let Some(c) = c {
+            for p in paths.iter() {
+                if let Some(s) = self.interfaces_added(p.as_slice()) { let _ = c.send(s); }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes an object path from the tree.
+    ///
+    /// If `c` is given, the path is unregistered from it, and its object manager
+    /// emits InterfacesRemoved for it.
+    pub fn remove(&mut self, path: &str, c: Option<&Connection>) -> Option<ObjectPath<'a>> {
+        if !self.paths.contains_key(path) { return None }
+        if let Some(c) = c {
+            if let Some(s) = self.interfaces_removed(path) { let _ = c.send(s); }
+            c.unregister_object_path(path);
+        }
+        self.paths.remove(path)
+    }
+
+    // The nearest object above the path for which f returns true, if any.
+    fn find_above<F: Fn(&ObjectPath<'a>) -> bool>(&self, path: &str, f: F) -> Option<&ObjectPath<'a>> {
+        let mut p = path;
+        loop {
+            p = match p.rfind('/') { Some(0) if p.len() > 1 => "/", Some(i) if i > 0 => p.slice_to(i), _ => return None };
+            if let Some(o) = self.paths.get(p) { if f(o) { return Some(o) } }
+        }
+    }
+
+    fn manager_of(&self, path: &str) -> Option<&ObjectPath<'a>> {
+        self.find_above(path, |&: o: &ObjectPath<'a>| o.object_manager)
+    }
+
+    fn managed_objects(&self, manager: &str) -> MessageItem {
+        let prefix = if manager == "/" { "/".to_string() } else { format!("{}/", manager) };
+        let objs = self.paths.values().filter(|o| o.path.as_slice() != manager && o.path.starts_with(prefix.as_slice()))
+            .map(|o| (MessageItem::ObjectPath(Path::new(o.path()).unwrap()), o.interfaces_item())).collect();
+        MessageItem::Dict(objs, "o".to_string(), "a{sa{sv}}".to_string())
+    }
+
+    fn interfaces_added(&self, path: &str) -> Option<Message> {
+        let (mgr, o) = match (self.manager_of(path), self.paths.get(path)) { (Some(m), Some(o)) => (m, o), _ => return None };
+        // Without the signal, clients see the change on their next GetManagedObjects
+        let mut s = match Message::new_signal(mgr.path(), "org.freedesktop.DBus.ObjectManager", "InterfacesAdded") { Ok(s) => s, Err(_) => return None };
+        if s.try_append_items(&[MessageItem::ObjectPath(Path::new(path).unwrap()), o.interfaces_item()]).is_err() { return None }
+        Some(s)
+    }
+
+    fn interfaces_removed(&self, path: &str) -> Option<Message> {
+        let (mgr, o) = match (self.manager_of(path), self.paths.get(path)) { (Some(m), Some(o)) => (m, o), _ => return None };
+        let mut s = match Message::new_signal(mgr.path(), "org.freedesktop.DBus.ObjectManager", "InterfacesRemoved") { Ok(s) => s, Err(_) => return None };
+        let items = [MessageItem::ObjectPath(Path::new(path).unwrap()),
+            MessageItem::new_array::<String>(o.ifaces.keys().map(|k| MessageItem::Str(k.clone())).collect())];
+        if s.try_append_items(&items).is_err() { return None }
+        Some(s)
+    }
+
+    /// Returns the replies to send for a method call, or None if the call is not for an
+    /// object in this tree.
+    ///
+    /// The replies are empty if the caller set the no-reply flag.
+    pub fn handle(&self, m: &mut Message) -> Option<Vec<Message>> {
+        if m.msg_type() != MessageType::MethodCall { return None }
+        let path = match m.path() { Some(p) => p.to_string(), None => return None };
+        let o = match self.find(path.as_slice()) { Some(o) => o, None => return None };
+        // The standard interfaces that need to know about the rest of the tree.
+        let r = match (m.interface(), m.member()) {
+            (Some(i), Some(n)) if !o.ifaces.contains_key(i) => match (i, n) {
+                ("org.freedesktop.DBus.Introspectable", "Introspect") =>
+                    Some(MessageItem::Str(self.introspect(path.as_slice()).unwrap())),
+                ("org.freedesktop.DBus.ObjectManager", "GetManagedObjects") if o.object_manager =>
+                    Some(self.managed_objects(o.path())),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(r) = r { return Some(method_reply(m, Ok(vec!(r)))) }
+        o.handle(m)
+    }
+
+    /// Returns introspection XML for the object handling `path`, with a node for each
+    /// path directly below it that has objects in the tree at or below it.
+    pub fn introspect(&self, path: &str) -> Option<String> {
+        let o = match self.find(path) { Some(o) => o, None => return None };
+        let prefix = if path == "/" { "/".to_string() } else { format!("{}/", path) };
+        let mut children: Vec<&str> = self.paths.keys().filter(|p| p.starts_with(prefix.as_slice()) && p.len() > prefix.len())
+            .map(|p| { let c = p.as_slice().slice_from(prefix.len()); c.slice_to(c.find('/').unwrap_or(c.len())) }).collect();
+        children.dedup();
+        Some(o.introspect_node(path, children.as_slice()))
+    }
+
+    // The object handling calls to the path, possibly as a fallback.
+    fn find(&self, path: &str) -> Option<&ObjectPath<'a>> {
+        self.paths.get(path).or_else(|| self.find_above(path, |&: o: &ObjectPath<'a>| o.fallback))
+    }
+
+    /// Handles method calls with `dispatch`, until the connection is closed.
+    pub fn run(&self, c: &Connection, timeout_ms: int) {
+        for i in c.iter(timeout_ms) { self.dispatch(c, i) }
+    }
+
+    /// Handles an item from `Connection::iter`: method calls are handled with `handle` and
+    /// the replies sent, everything else is ignored.
+    ///
+    /// Calls for object paths that are not in the tree are replied to with UnknownObject.
+    pub fn dispatch(&self, c: &Connection, i: ConnectionItem) {
+        let mut m = match i { ConnectionItem::MethodCall(m) => m, _ => return };
+        let replies = match self.handle(&mut m) {
+            Some(r) => r,
+            None => error_reply(&m, "org.freedesktop.DBus.Error.UnknownObject",
+                format!("Unknown object path {}", m.path().unwrap_or("")).as_slice()),
+        };
+        for r in replies.into_iter() { let _ = c.send(r); }
+    }
+}
+
+/// Builds a `Tree`, adding methods to the interface and object path added last.
+///
+/// # Example
+///
+/// ```ignore
+/// let tree = Factory::new()
+///     .object_path("/com/example/Foo")
+///     .interface("com.example.Foo")
+///     .method("Frob", |&: m: &mut Message| m.get_items().map_err(MethodErr::unreadable_args))
+///     .tree();
+/// tree.set_registered(&c, true).unwrap();
+/// tree.run(&c, 1000);
+/// ```
+pub struct Factory<'a> {
+    tree: Tree<'a>,
+    path: Option<String>,
+    iface: Option<String>,
+    method: Option<String>,
+    prop: Option<String>,
+}
+
+impl<'a> Factory<'a> {
+    pub fn new() -> Factory<'a> {
+        Factory { tree: Tree { paths: BTreeMap::new() }, path: None, iface: None, method: None, prop: None }
+    }
+
+    /// Adds an object path, or goes back to one added earlier.
+    ///
+    /// Panics if `path` is not a valid object path.
+    pub fn object_path(mut self, path: &str) -> Factory<'a> {
+        assert!(is_valid_object_path(path), "'{}' is not a valid object path", path);
+        if !self.tree.paths.contains_key(path) {
+            self.tree.paths.insert(path.to_string(), ObjectPath { path: path.to_string(),
+                object_manager: false, fallback: false, ifaces: BTreeMap::new() });
+        }
+        self.path = Some(path.to_string());
+        self.iface = None;
+        self.method = None;
+        self.prop = None;
+        self
+    }
+
+    /// Makes the current object path an org.freedesktop.DBus.ObjectManager for the objects below it.
+    ///
+    /// Panics if no object path has been added.
+    pub fn object_manager(mut self) -> Factory<'a> {
+        self.current_path().object_manager = true;
+        self
+    }
+
+    /// Makes the current object path handle calls to all paths below it that are not in
+    /// the tree, e g "/org/example/items/42" for "/org/example/items". The handlers can
+    /// tell which path was called from the message.
+    ///
+    /// Panics if no object path has been added.
+    pub fn fallback(mut self) -> Factory<'a> {
+        self.current_path().fallback = true;
+        self
+    }
+
+    /// Adds an interface to the current object path, or goes back to one added earlier.
+    ///
+    /// Panics if no object path has been added.
+    pub fn interface(mut self, name: &str) -> Factory<'a> {
+        {
+            let o = self.current_path();
+            if !o.ifaces.contains_key(name) {
+                o.ifaces.insert(name.to_string(), Interface { name: name.to_string(),
+                    methods: BTreeMap::new(), signals: BTreeMap::new(), props: BTreeMap::new(), auth: None,
+                    annotations: vec!() });
+            }
+        }
+        self.iface = Some(name.to_string());
+        self.method = None;
+        self.prop = None;
+        self
+    }
+
+    /// Adds a method to the current interface, replacing any earlier method with the same name.
+    /// The handler's items are sent back in the method return.
+    ///
+    /// Panics if no interface has been added.
+    pub fn method<F>(self, name: &str, f: F) -> Factory<'a>
+        where F: Fn(&mut Message) -> MethodResult + 'a
+    {
+        self.deferred_method(name, move |&: m: &mut Message| f(m).map(|items| Reply::Now(items)))
+    }
+
+    /// Like `method`, but the handler can keep the call with `Connection::defer_reply`
+    /// and return `Reply::Later`, to send the reply after it has returned.
+    ///
+    /// Panics if no interface has been added.
+    pub fn deferred_method<F>(mut self, name: &str, f: F) -> Factory<'a>
+        where F: Fn(&mut Message) -> Result<Reply, MethodErr> + 'a
+    {
+        self.current_iface().methods.insert(name.to_string(), Method { name: name.to_string(),
+            in_args: vec!(), out_args: vec!(), auth: None, annotations: vec!(), cb: box f });
+        self.method = Some(name.to_string());
+        self.prop = None;
+        self
+    }
+
+    /// Adds a method taking and returning tuples of Rust types to the current interface.
+    ///
+    /// The in and out signatures are inferred from the tuple types, and shown in introspection
+    /// data. Calls with other arguments are replied to with InvalidArgs without calling `f`.
+    ///
+    /// Panics if no interface has been added.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let f = Factory::new().object_path("/counter").interface("com.example.Counter")
+    ///     .typed_method("Add", |&: (name, n): (String, u32)| Ok((n + 1,)));
+    /// ```
+    pub fn typed_method<I, O, F>(mut self, name: &str, f: F) -> Factory<'a>
+        where I: ArgList, O: ArgList, F: Fn(I) -> Result<O, MethodErr> + 'a
+    {
+        let in_sigs = <I as ArgList>::signatures();
+        let out_sigs = <O as ArgList>::signatures();
+        let in_sig = in_sigs.iter().fold(String::new(), |mut s, a| { s.push_str(a.as_slice()); s });
+        let h = move |&: m: &mut Message| -> MethodResult {
+            if m.signature_str() != in_sig.as_slice() {
+                let e = format!("Invalid arguments: expected signature '{}', got '{}'", in_sig, m.signature_str());
+                return Err(MethodErr::new("org.freedesktop.DBus.Error.InvalidArgs", e.as_slice()));
+            }
+            let items = try!(m.get_items().map_err(MethodErr::unreadable_args));
+            match ArgList::read(items.as_slice(), &ReadOptions::strict()) {
+                Some(i) => f(i).map(|o: O| o.to_items()),
+                None => Err(MethodErr::invalid_arg(&items)),
+            }
+        };
+        self.current_iface().methods.insert(name.to_string(), Method { name: name.to_string(),
+            in_args: in_sigs.iter().map(|s| Argument::new(None, s.as_slice())).collect(),
+            out_args: out_sigs.iter().map(|s| Argument::new(None, s.as_slice())).collect(), auth: None,
+            annotations: vec!(), cb: box move |&: m: &mut Message| h(m).map(|items| Reply::Now(items)) });
+        self.method = Some(name.to_string());
+        self.prop = None;
+        self
+    }
+
+    /// Names the in arguments of the method added last, for introspection data, given as
+    /// (name, signature) pairs. For a typed method, the signatures must be the inferred ones.
+    ///
+    /// Panics if the member added last to the current interface is not a method, or if the
+    /// signatures do not match those of a typed method.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let f = Factory::new().object_path("/counter").interface("com.example.Counter")
+    ///     .typed_method("Add", |&: (name, n): (String, u32)| Ok((n + 1,)))
+    ///     .in_args(&[("name", "s"), ("n", "u")]).out_args(&[("sum", "u")]);
+    /// ```
+    pub fn in_args(mut self, args: &[(&str, &str)]) -> Factory<'a> {
+        set_arg_names(&mut self.current_method().in_args, args);
+        self
+    }
+
+    /// Names the out arguments of the method added last, see `in_args`.
+    pub fn out_args(mut self, args: &[(&str, &str)]) -> Factory<'a> {
+        set_arg_names(&mut self.current_method().out_args, args);
+        self
+    }
+
+    /// Only lets callers for which `f` returns true call the method added last, or all
+    /// methods of the current interface if no method has been added to it yet. Other
+    /// callers get AccessDenied before the handler runs. Checks on an interface also
+    /// apply to getting and setting its properties.
+    ///
+    /// The callers' credentials are asked from the bus through `c`, see
+    /// `Connection::get_connection_credentials`.
+    ///
+    /// Panics if no interface has been added.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let f = Factory::new().object_path("/admin").interface("com.example.Admin")
+    ///     .method("Reboot", reboot)
+    ///     .authorize(&c, |&: cr: &Credentials| cr.unix_user_id == Some(0));
+    /// ```
+    pub fn authorize<F>(mut self, c: &'a Connection, f: F) -> Factory<'a>
+        where F: Fn(&Credentials) -> bool + 'a
+    {
+        let check: AuthCheck<'a> = box move |&: m: &Message| -> Result<(), MethodErr> {
+            let denied = |&: why: &str| MethodErr::new("org.freedesktop.DBus.Error.AccessDenied", why);
+            let sender = match m.sender() { Some(s) => s, None => return Err(denied("The caller is not known")) };
+            match c.get_connection_credentials(sender.as_slice()) {
+                Ok(ref cr) if f(cr) => Ok(()),
+                Ok(_) => Err(denied("The caller is not allowed to call this method")),
+                Err(_) => Err(denied("The caller's credentials could not be checked")),
+            }
+        };
+        match self.method.clone() {
+            Some(m) => self.current_iface().methods.get_mut(m.as_slice()).unwrap().auth = Some(check),
+            None => self.current_iface().auth = Some(check),
+        }
+        self
+    }
+
+    /// Adds a read-only property to the current interface, with a getter returning values
+    /// of signature `sig`.
+    ///
+    /// Panics if no interface has been added.
+    pub fn property<G>(self, name: &str, sig: &str, get: G) -> Factory<'a>
+        where G: Fn() -> Result<MessageItem, MethodErr> + 'a
+    {
+        self.add_property(name, sig, Some(box get as Box<Fn() -> Result<MessageItem, MethodErr> + 'a>), None)
+    }
+
+    /// Adds a read-write property to the current interface. `set` is only called with
+    /// values of signature `sig`.
+    ///
+    /// Panics if no interface has been added.
+    pub fn property_rw<G, S>(self, name: &str, sig: &str, get: G, set: S) -> Factory<'a>
+        where G: Fn() -> Result<MessageItem, MethodErr> + 'a, S: Fn(MessageItem) -> Result<(), MethodErr> + 'a
+    {
+        self.add_property(name, sig, Some(box get as Box<Fn() -> Result<MessageItem, MethodErr> + 'a>),
+            Some(box set as Box<Fn(MessageItem) -> Result<(), MethodErr> + 'a>))
+    }
+
+    /// Adds a read-write property to the current interface, which keeps its value itself.
+    /// The signature is the one of `value`.
+    ///
+    /// Panics if no interface has been added.
+    pub fn property_value(self, name: &str, value: MessageItem) -> Factory<'a> {
+        let sig = value.signature().into_string();
+        let v = Rc::new(RefCell::new(value));
+        let v2 = v.clone();
+        self.property_rw(name, sig.as_slice(), move |&:| Ok(v.borrow().clone()),
+            move |&: n: MessageItem| { *v2.borrow_mut() = n; Ok(()) })
+    }
+
+    /// Sets when PropertiesChanged is emitted for the property added last. The default
+    /// is `EmitsChangedSignal::True`, unless the interface is annotated otherwise.
+    ///
+    /// Panics if the member added last to the current interface is not a property.
+    pub fn emits_changed(mut self, e: EmitsChangedSignal) -> Factory<'a> {
+        let p = self.prop.clone().expect("No property added");
+        self.current_iface().props.get_mut(p.as_slice()).unwrap().emits = e;
+        self
+    }
+
+    /// Adds an annotation to the method or property added last, or to the current interface
+    /// if nothing has been added to it yet. Annotations are shown in introspection data.
+    ///
+    /// org.freedesktop.DBus.Method.NoReply with "true" makes the method never reply, even
+    /// with an error. org.freedesktop.DBus.Property.EmitsChangedSignal works like `emits_changed`,
+    /// and on an interface, sets the default for the properties added after it.
+    ///
+    /// Panics if no interface has been added, or if the EmitsChangedSignal value is invalid.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let f = Factory::new().object_path("/foo").interface("com.example.Foo")
+    ///     .method("Frob", frob).annotate("org.freedesktop.DBus.Deprecated", "true");
+    /// ```
+    pub fn annotate(mut self, name: &str, value: &str) -> Factory<'a> {
+        let a = (name.to_string(), value.to_string());
+        if name == EMITS_CHANGED_SIGNAL {
+            let e = EmitsChangedSignal::from_annotation(value).expect("Invalid EmitsChangedSignal annotation");
+            if self.prop.is_some() { return self.emits_changed(e) }
+        }
+        match (self.prop.clone(), self.method.clone()) {
+            (Some(p), _) => self.current_iface().props.get_mut(p.as_slice()).unwrap().annotations.push(a),
+            (None, Some(m)) => self.current_iface().methods.get_mut(m.as_slice()).unwrap().annotations.push(a),
+            (None, None) => self.current_iface().annotations.push(a),
+        }
+        self
+    }
+
+    fn add_property(mut self, name: &str, sig: &str, get: Option<Box<Fn() -> Result<MessageItem, MethodErr> + 'a>>,
+        set: Option<Box<Fn(MessageItem) -> Result<(), MethodErr> + 'a>>) -> Factory<'a> {
+        let emits = self.current_iface().emits_default();
+        self.current_iface().props.insert(name.to_string(), Property { name: name.to_string(), sig: sig.to_string(),
+            emits: emits, annotations: vec!(), get: get, set: set });
+        self.prop = Some(name.to_string());
+        self
+    }
+
+    /// Declares a signal of the current interface, with the signatures of its arguments,
+    /// so that it is included in introspection data.
+    ///
+    /// Panics if no interface has been added.
+    pub fn signal(mut self, name: &str, args: &[&str]) -> Factory<'a> {
+        let args = args.iter().map(|s| Argument::new(None, *s)).collect();
+        self.current_iface().signals.insert(name.to_string(), Signal { name: name.to_string(), args: args });
+        self
+    }
+
+    pub fn tree(self) -> Tree<'a> { self.tree }
+
+    fn current_path(&mut self) -> &mut ObjectPath<'a> {
+        let p = self.path.as_ref().expect("No object path added");
+        self.tree.paths.get_mut(p.as_slice()).unwrap()
+    }
+
+    fn current_method(&mut self) -> &mut Method<'a> {
+        let m = match (self.method.clone(), &self.prop) { (Some(m), &None) => m, _ => panic!("No method added") };
+        self.current_iface().methods.get_mut(m.as_slice()).unwrap()
+    }
+
+    fn current_iface(&mut self) -> &mut Interface<'a> {
+        let i = self.iface.clone().expect("No interface added");
+        self.current_path().ifaces.get_mut(i.as_slice()).unwrap()
+    }
+}
+
+#[cfg(test)]
+fn echo_tree<'a>() -> Tree<'a> {
+    Factory::new()
+        .object_path("/echo")
+        .interface("com.example.Echo")
+        .method("Echo", |&: m: &mut Message| m.get_items().map_err(MethodErr::unreadable_args))
+        .method("Fail", |&: _: &mut Message| Err(MethodErr::failed(&"on purpose")))
+        .signal("Echoed", &["s"])
+        .typed_method("Add", |&: (a, b): (u32, u32)| Ok((a + b,)))
+        .tree()
+}
+
+#[test]
+fn tree_dispatch() {
+    let t = echo_tree();
+
+    let mut m = Message::new_method_call("com.example.Echo", "/echo", "com.example.Echo", "Echo").unwrap();
+    m.append_items(&[MessageItem::Str("Hello".to_string())]);
+    let mut r = t.handle(&mut m).unwrap();
+    assert_eq!(r.len(), 1);
+    assert_eq!(r[0].msg_type(), MessageType::MethodReturn);
+    assert_eq!(r[0].get_items().unwrap(), vec!(MessageItem::Str("Hello".to_string())));
+
+    let mut m = Message::new_method_call("com.example.Echo", "/echo", "com.example.Echo", "Fail").unwrap();
+    let r = t.handle(&mut m).unwrap();
+    assert_eq!(r[0].msg_type(), MessageType::Error);
+
+    let mut m = Message::new_method_call("com.example.Echo", "/echo", "com.example.Nope", "Echo").unwrap();
+    assert_eq!(t.handle(&mut m).unwrap()[0].msg_type(), MessageType::Error);
+
+    let mut m = Message::new_method_call("com.example.Echo", "/other", "com.example.Echo", "Echo").unwrap();
+    assert!(t.handle(&mut m).is_none());
+}
+
+#[test]
+fn tree_introspect() {
+    let t = echo_tree();
+    let mut m = Message::new_method_call("com.example.Echo", "/echo", "org.freedesktop.DBus.Introspectable", "Introspect").unwrap();
+    let mut r = t.handle(&mut m).unwrap();
+    let xml = r[0].get_items().unwrap();
+    assert_eq!(xml, vec!(MessageItem::Str(r##"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN" "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node name="/echo">
+  <interface name="com.example.Echo">
+    <method name="Add">
+      <arg type="u" direction="in"/>
+      <arg type="u" direction="in"/>
+      <arg type="u" direction="out"/>
+    </method>
+    <method name="Echo"/>
+    <method name="Fail"/>
+    <signal name="Echoed">
+      <arg type="s"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="xml_data" type="s" direction="out"/>
+    </method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Peer">
+    <method name="GetMachineId">
+      <arg name="machine_uuid" type="s" direction="out"/>
+    </method>
+    <method name="Ping"/>
+  </interface>
+</node>"##.to_string())));
+}
+
+#[test]
+fn tree_peer() {
+    let t = echo_tree();
+    let mut m = Message::new_method_call("com.example.Echo", "/echo", "org.freedesktop.DBus.Peer", "Ping").unwrap();
+    let mut r = t.handle(&mut m).unwrap();
+    assert_eq!(r[0].msg_type(), MessageType::MethodReturn);
+    assert_eq!(r[0].get_items().unwrap(), vec!());
+
+    let mut m = Message::new_method_call("com.example.Echo", "/echo", "org.freedesktop.DBus.Peer", "GetMachineId").unwrap();
+    let mut r = t.handle(&mut m).unwrap();
+    let id = r[0].get_items().unwrap();
+    assert_eq!(id[0].as_str().unwrap().len(), 32);
+}
+
+#[test]
+fn tree_bad_reply() {
+    let m = Message::new_method_call("com.example.Echo", "/echo", "com.example.Echo", "Echo").unwrap();
+    let r = method_reply(&m, Ok(vec!(MessageItem::Str("a\0b".to_string()))));
+    assert_eq!(r[0].msg_type(), MessageType::Error);
+    let r = method_reply(&m, Err(MethodErr::new("no dots", "bad name")));
+    assert_eq!(r[0].msg_type(), MessageType::Error);
+}
+
+#[test]
+fn tree_properties_changed() {
+    let mut h = HashMap::new();
+    h.insert("Count", MessageItem::UInt32(3));
+    let mut m = properties_changed("/echo", "com.example.Echo", h, &["Name"]).unwrap();
+    assert_eq!(m.signature_str(), "sa{sv}as");
+    assert_eq!(m.get_items().unwrap(), vec!(
+        MessageItem::Str("com.example.Echo".to_string()),
+        MessageItem::Dict(vec!((MessageItem::Str("Count".to_string()), MessageItem::Variant(box MessageItem::UInt32(3)))),
+            "s".to_string(), "v".to_string()),
+        MessageItem::new_array::<String>(vec!(MessageItem::Str("Name".to_string())))));
+}
+
+#[test]
+fn tree_object_manager() {
+    let mut t = Factory::new().object_path("/").object_manager().tree();
+    t.insert_tree(echo_tree(), None).unwrap();
+    assert_eq!(t.manager_of("/echo").map(|o| o.path()), Some("/"));
+    assert!(t.manager_of("/").is_none());
+
+    let mut m = Message::new_method_call("com.example.Echo", "/", "org.freedesktop.DBus.ObjectManager", "GetManagedObjects").unwrap();
+    let mut r = t.handle(&mut m).unwrap();
+    assert_eq!(r[0].signature_str(), "a{oa{sa{sv}}}");
+    let items = r[0].get_items().unwrap();
+    let objs = items[0].as_dict().unwrap();
+    assert_eq!(objs.len(), 1);
+    assert_eq!(objs[0].0.as_object_path(), Some("/echo"));
+
+    let mut s = t.interfaces_removed("/echo").unwrap();
+    assert_eq!(s.path(), Some("/"));
+    assert_eq!(s.get_items().unwrap()[1], MessageItem::new_array::<String>(vec!(MessageItem::Str("com.example.Echo".to_string()))));
+    assert!(t.remove("/echo", None).is_some());
+    assert!(t.interfaces_added("/echo").is_none());
+}
+
+#[test]
+fn tree_typed_method() {
+    let t = echo_tree();
+    let mut m = Message::new_method_call("com.example.Echo", "/echo", "com.example.Echo", "Add").unwrap();
+    m.append_items(&[MessageItem::UInt32(2), MessageItem::UInt32(3)]);
+    let mut r = t.handle(&mut m).unwrap();
+    assert_eq!(r[0].get_items().unwrap(), vec!(MessageItem::UInt32(5)));
+
+    let mut m = Message::new_method_call("com.example.Echo", "/echo", "com.example.Echo", "Add").unwrap();
+    m.append_items(&[MessageItem::UInt32(2), MessageItem::Str("3".to_string())]);
+    let r = t.handle(&mut m).unwrap();
+    assert_eq!(r[0].msg_type(), MessageType::Error);
+}
+
+#[test]
+fn tree_parse_limits() {
+    use super::ParseLimits;
+    let t = echo_tree();
+    let mut m = Message::new_method_call("com.example.Echo", "/echo", "com.example.Echo", "Echo").unwrap();
+    m.append_items(&[MessageItem::new_array::<u32>(vec!(MessageItem::UInt32(1), MessageItem::UInt32(2), MessageItem::UInt32(3)))]);
+    m.set_parse_limits(ParseLimits { max_array_len: 2, ..::std::default::Default::default() });
+    let e = m.get_items().err().unwrap();
+    assert_eq!(e.name(), Some("org.freedesktop.DBus.Error.LimitsExceeded"));
+
+    let mut r = t.handle(&mut m).unwrap();
+    let e = r[0].as_result().err().unwrap();
+    assert_eq!(e.name(), Some("org.freedesktop.DBus.Error.InvalidArgs"));
+}
+
+#[test]
+fn tree_deferred_method() {
+    use std::cell::Cell;
+    use std::sync::mpsc::channel;
+    let c = Connection::get_private(super::BusType::Session).unwrap();
+    c.register_object_path("/slow").unwrap();
+    let token = Cell::new(None);
+    let t = Factory::new().object_path("/slow").interface("com.example.Slow")
+        .deferred_method("Wait", |&: m: &mut Message| {
+            let r = c.defer_reply(m);
+            token.set(Some(r));
+            Ok(Reply::Later(r))
+        }).tree();
+
+    let (tx, rx) = channel();
+    let name = c.unique_name();
+    ::std::thread::Thread::spawn(move || {
+        let c2 = Connection::get_private(super::BusType::Session).unwrap();
+        let m = Message::new_method_call(name.as_slice(), "/slow", "com.example.Slow", "Wait").unwrap();
+        let mut r = c2.send_with_reply_and_block(m, 5000).unwrap();
+        tx.send(r.get_items().unwrap()).unwrap();
+    }).detach();
+
+    let mut call = None;
+    for n in c.iter(1000) {
+        if let ConnectionItem::MethodCall(m) = n { call = Some(m); break }
+    }
+    let mut m = call.unwrap();
+    assert_eq!(t.handle(&mut m).unwrap().len(), 0);
+    let r = token.get().unwrap();
+    assert_eq!(c.reply(r, Ok(vec!(MessageItem::Str("Done".to_string())))), Ok(()));
+    assert_eq!(c.reply(r, Ok(vec!())), Err(()));
+    assert_eq!(rx.recv().unwrap(), vec!(MessageItem::Str("Done".to_string())));
+
+    // Calls kept over a reconnect are dropped
+    let mut m = Message::new_method_call("com.example.Slow", "/slow", "com.example.Slow", "Wait").unwrap();
+    assert_eq!(t.handle(&mut m).unwrap().len(), 0);
+    c.reconnect().unwrap();
+    assert_eq!(c.reply(token.get().unwrap(), Ok(vec!())), Err(()));
+}
+
+#[test]
+fn tree_fallback() {
+    let t = Factory::new()
+        .object_path("/items").fallback().interface("com.example.Item")
+        .method("Path", |&: m: &mut Message| Ok(vec!(MessageItem::Str(m.path().unwrap().to_string()))))
+        .object_path("/items/special").interface("com.example.Item")
+        .method("Path", |&: _: &mut Message| Ok(vec!(MessageItem::Str("special".to_string()))))
+        .tree();
+
+    let mut m = Message::new_method_call("com.example.Item", "/items/42/a", "com.example.Item", "Path").unwrap();
+    let mut r = t.handle(&mut m).unwrap();
+    assert_eq!(r[0].get_items().unwrap(), vec!(MessageItem::Str("/items/42/a".to_string())));
+    let mut m = Message::new_method_call("com.example.Item", "/items/special", "com.example.Item", "Path").unwrap();
+    let mut r = t.handle(&mut m).unwrap();
+    assert_eq!(r[0].get_items().unwrap(), vec!(MessageItem::Str("special".to_string())));
+    let mut m = Message::new_method_call("com.example.Item", "/other", "com.example.Item", "Path").unwrap();
+    assert!(t.handle(&mut m).is_none());
+}
+
+#[test]
+fn tree_authorize() {
+    let c = Connection::get_private(super::BusType::Session).unwrap();
+    let t = Factory::new().object_path("/admin").interface("com.example.Admin")
+        .method("Open", |&: _: &mut Message| Ok(vec!()))
+        .method("Reboot", |&: _: &mut Message| Ok(vec!()))
+        .authorize(&c, |&: _: &Credentials| true)
+        .tree();
+
+    // A message that did not come from the bus has no sender, so it is always denied.
+    let mut m = Message::new_method_call("com.example.Admin", "/admin", "com.example.Admin", "Reboot").unwrap();
+    let mut r = t.handle(&mut m).unwrap();
+    match r[0].as_result() {
+        Err(e) => assert_eq!(e.name(), Some("org.freedesktop.DBus.Error.AccessDenied")),
+        Ok(_) => panic!("Reboot was not denied"),
+    }
+    let mut m = Message::new_method_call("com.example.Admin", "/admin", "com.example.Admin", "Open").unwrap();
+    assert_eq!(t.handle(&mut m).unwrap()[0].msg_type(), MessageType::MethodReturn);
+}
+
+#[test]
+fn tree_authorize_credentials() {
+    let c = Connection::get_private(super::BusType::Session).unwrap();
+    c.register_object_path("/admin").unwrap();
+    let t = Factory::new().object_path("/admin").interface("com.example.Admin")
+        .method("Open", |&: _: &mut Message| Ok(vec!()))
+        .authorize(&c, |&: cr: &Credentials| cr.unix_user_id.is_some())
+        .method("Reboot", |&: _: &mut Message| Ok(vec!()))
+        .authorize(&c, |&: _: &Credentials| false)
+        .tree();
+
+    let c2 = Connection::get_private(super::BusType::Session).unwrap();
+    for &(member, reply) in [("Open", MessageType::MethodReturn), ("Reboot", MessageType::Error), ("Open", MessageType::MethodReturn)].iter() {
+        let m = Message::new_method_call(c.unique_name().as_slice(), "/admin", "com.example.Admin", member).unwrap();
+        c2.send(m).unwrap();
+        let mut call = None;
+        for n in c.iter(1000) {
+            if let ConnectionItem::MethodCall(m) = n { call = Some(m); break }
+        }
+        let mut m = call.unwrap();
+        assert_eq!(t.handle(&mut m).unwrap()[0].msg_type(), reply);
+    }
+}
+
+#[test]
+fn tree_authorize_properties() {
+    let c = Connection::get_private(super::BusType::Session).unwrap();
+    let t = Factory::new().object_path("/admin").interface("com.example.Admin")
+        .authorize(&c, |&: _: &Credentials| true)
+        .property_value("Mode", MessageItem::Str("safe".to_string()))
+        .tree();
+
+    // Without a sender, the interface's check denies the call.
+    let mut m = Message::new_method_call("com.example.Admin", "/admin", "org.freedesktop.DBus.Properties", "Set").unwrap();
+    m.append_items(&[MessageItem::Str("com.example.Admin".to_string()), MessageItem::Str("Mode".to_string()),
+        MessageItem::Variant(box MessageItem::Str("unsafe".to_string()))]);
+    let mut r = t.handle(&mut m).unwrap();
+    assert_eq!(r.len(), 1);
+    let e = r[0].as_result().err().unwrap();
+    assert_eq!(e.name(), Some("org.freedesktop.DBus.Error.AccessDenied"));
+    let p = t.get("/admin").unwrap().get_interface("com.example.Admin").unwrap().get_property("Mode").unwrap();
+    assert_eq!(p.get_value().unwrap(), MessageItem::Str("safe".to_string()));
+}
+
+#[test]
+fn tree_properties() {
+    let t = Factory::new().object_path("/props").interface("com.example.Props")
+        .property_value("Name", MessageItem::Str("Foo".to_string()))
+        .property_value("Size", MessageItem::UInt32(1)).emits_changed(EmitsChangedSignal::Invalidates)
+        .property("Answer", "i", |&:| Ok(MessageItem::Int32(42)))
+        .tree();
+    let call = |&: member: &str, args: &[MessageItem]| {
+        let mut m = Message::new_method_call("com.example.Props", "/props", "org.freedesktop.DBus.Properties", member).unwrap();
+        m.append_items(args);
+        t.handle(&mut m).unwrap()
+    };
+    let iface = MessageItem::Str("com.example.Props".to_string());
+
+    let mut r = call("Get", &[iface.clone(), MessageItem::Str("Answer".to_string())]);
+    assert_eq!(r[0].get_items().unwrap(), vec!(MessageItem::Variant(box MessageItem::Int32(42))));
+
+    let mut r = call("Set", &[iface.clone(), MessageItem::Str("Name".to_string()), MessageItem::Variant(box MessageItem::Str("Bar".to_string()))]);
+    assert_eq!(r.len(), 2);
+    assert_eq!(r[0].msg_type(), MessageType::MethodReturn);
+    assert_eq!(r[1].member(), Some("PropertiesChanged"));
+    assert_eq!(r[1].get_items().unwrap()[1], MessageItem::Dict(vec!((MessageItem::Str("Name".to_string()),
+        MessageItem::Variant(box MessageItem::Str("Bar".to_string())))), "s".to_string(), "v".to_string()));
+
+    let mut r = call("Set", &[iface.clone(), MessageItem::Str("Size".to_string()), MessageItem::Variant(box MessageItem::UInt32(2))]);
+    assert_eq!(r[1].get_items().unwrap()[2], MessageItem::new_array::<String>(vec!(MessageItem::Str("Size".to_string()))));
+
+    let r = call("Set", &[iface.clone(), MessageItem::Str("Size".to_string()), MessageItem::Variant(box MessageItem::Int32(2))]);
+    assert_eq!(r.len(), 1);
+    assert_eq!(r[0].msg_type(), MessageType::Error);
+    let r = call("Set", &[iface.clone(), MessageItem::Str("Answer".to_string()), MessageItem::Variant(box MessageItem::Int32(2))]);
+    assert_eq!(r[0].msg_type(), MessageType::Error);
+
+    let mut r = call("GetAll", &[iface.clone()]);
+    let all = r[0].get_items().unwrap();
+    assert_eq!(all[0].as_dict().unwrap().len(), 3);
+    assert!(all[0].as_dict().unwrap().contains(&(MessageItem::Str("Name".to_string()),
+        MessageItem::Variant(box MessageItem::Str("Bar".to_string())))));
+}
+
+#[test]
+fn tree_annotations() {
+    let t = Factory::new().object_path("/notes").interface("com.example.Notes")
+        .annotate("org.freedesktop.DBus.Property.EmitsChangedSignal", "const")
+        .method("Poke", |&: _: &mut Message| Ok(vec!())).annotate("org.freedesktop.DBus.Method.NoReply", "true")
+        .property_value("Count", MessageItem::UInt32(0)).annotate("org.freedesktop.DBus.Property.EmitsChangedSignal", "false")
+        .annotate("org.freedesktop.DBus.Deprecated", "true")
+        .property("Version", "u", |&:| Ok(MessageItem::UInt32(1)))
+        .tree();
+
+    let o = t.get("/notes").unwrap();
+    let i = o.get_interface("com.example.Notes").unwrap();
+    assert_eq!(i.get_property("Version").unwrap().emits_changed(), EmitsChangedSignal::Const);
+    assert_eq!(i.get_property("Count").unwrap().emits_changed(), EmitsChangedSignal::False);
+    assert!(o.introspect().as_slice().contains(r##"  <interface name="com.example.Notes">
+    <annotation name="org.freedesktop.DBus.Property.EmitsChangedSignal" value="const"/>
+    <method name="Poke">
+      <annotation name="org.freedesktop.DBus.Method.NoReply" value="true"/>
+    </method>
+    <property name="Count" type="u" access="readwrite">
+      <annotation name="org.freedesktop.DBus.Deprecated" value="true"/>
+      <annotation name="org.freedesktop.DBus.Property.EmitsChangedSignal" value="false"/>
+    </property>
+    <property name="Version" type="u" access="read"/>
+  </interface>
+"##));
+
+    let mut m = Message::new_method_call("com.example.Notes", "/notes", "com.example.Notes", "Poke").unwrap();
+    assert_eq!(t.handle(&mut m).unwrap().len(), 0);
+}
+
+#[test]
+fn tree_introspect_escaping() {
+    let t = Factory::new().object_path("/notes").interface("com.example.Notes")
+        .method("Poke", |&: _: &mut Message| Ok(vec!())).in_args(&[("it's", "s")])
+        .annotate("com.example.Doc", "Pokes <b>\"it\"</b> & returns")
+        .tree();
+    assert!(t.get("/notes").unwrap().introspect().as_slice().contains(r##"    <method name="Poke">
+      <arg name="it&apos;s" type="s" direction="in"/>
+      <annotation name="com.example.Doc" value="Pokes &lt;b&gt;&quot;it&quot;&lt;/b&gt; &amp; returns"/>
+    </method>
+"##));
+}
+
+#[test]
+fn tree_arg_names() {
+    let t = Factory::new().object_path("/calc").interface("com.example.Calc")
+        .typed_method("Add", |&: (a, b): (u32, u32)| Ok((a + b,)))
+        .in_args(&[("a", "u"), ("b", "u")]).out_args(&[("sum", "u")])
+        .method("Hello", |&: m: &mut Message| Ok(vec!(MessageItem::Str(format!("Hello {}", m.get_items().unwrap())))))
+        .in_args(&[("name", "s")]).out_args(&[("greeting", "s")])
+        .tree();
+    assert!(t.get("/calc").unwrap().introspect().as_slice().contains(r##"    <method name="Add">
+      <arg name="a" type="u" direction="in"/>
+      <arg name="b" type="u" direction="in"/>
+      <arg name="sum" type="u" direction="out"/>
+    </method>
+    <method name="Hello">
+      <arg name="name" type="s" direction="in"/>
+      <arg name="greeting" type="s" direction="out"/>
+    </method>
+"##));
+}
+
+#[test]
+fn tree_child_nodes() {
+    let t = Factory::new()
+        .object_path("/com/example").interface("com.example.Root")
+        .object_path("/com/example/foo").interface("com.example.Foo")
+        .object_path("/com/example/foo/bar").interface("com.example.Bar")
+        .object_path("/com/example/baz/deep").interface("com.example.Deep")
+        .tree();
+    let xml = t.introspect("/com/example").unwrap();
+    assert!(xml.as_slice().ends_with(r##"  <node name="baz"/>
+  <node name="foo"/>
+</node>"##));
+    assert!(t.introspect("/com/example/foo/bar").unwrap().as_slice().ends_with("  </interface>\n</node>"));
+    assert!(t.introspect("/com").is_none());
+
+    let mut m = Message::new_method_call("com.example", "/com/example/foo", "org.freedesktop.DBus.Introspectable", "Introspect").unwrap();
+    let mut r = t.handle(&mut m).unwrap();
+    assert!(r[0].get_items().unwrap()[0].as_str().unwrap().contains("  <node name=\"bar\"/>\n"));
+}
@@ -0,0 +1,78 @@
+use std::cmp;
+use std::io;
+use std::io::timer;
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::thread::Thread;
+use libc;
+
+/// What a `Watchdog` does when a handler runs for longer than its budget.
+#[deriving(Show, PartialEq, Copy, Clone)]
+pub enum WatchdogAction {
+    /// Write a message to stderr, once per handler call.
+    Log,
+    /// Abort the process, e g to get a core dump of the stuck handler.
+    Abort,
+}
+
+struct WatchdogState {
+    // The running handler, and the number of polls since it started
+    current: Option<(u64, String)>,
+    reported: bool,
+    stop: bool,
+}
+
+/// Watches synchronous handlers from a separate thread, and logs (or aborts) when one of
+/// them takes longer than its time budget. While a handler is stuck, the connection
+/// looks hung to all other peers, so this helps finding out which handler is to blame.
+pub struct Watchdog {
+    state: Arc<Mutex<WatchdogState>>,
+}
+
+impl Watchdog {
+    pub fn new(budget_ms: u64, action: WatchdogAction) -> Watchdog {
+        let state = Arc::new(Mutex::new(WatchdogState { current: None, reported: false, stop: false }));
+        let s2 = state.clone();
+        Thread::spawn(move || {
+            let poll_ms = cmp::max(budget_ms / 4, 1);
+            loop {
+                timer::sleep(Duration::milliseconds(poll_ms as i64));
+                let mut s = s2.lock().unwrap();
+                if s.stop { break; }
+                let over = match s.current {
+                    Some((ref mut polls, ref name)) => {
+                        *polls += 1;
+                        // Counting polls is accurate to one poll interval, which is good enough here.
+                        if *polls * poll_ms > budget_ms { Some(name.clone()) } else { None }
+                    }
+                    None => None,
+                };
+                let over = if s.reported { None } else { over };
+                if let Some(name) = over {
+                    s.reported = true;
+                    let _ = writeln!(&mut io::stderr(), "D-Bus handler {} has been running for more than {} ms",
+                        name, budget_ms);
+                    if action == WatchdogAction::Abort { unsafe { libc::abort() } }
+                }
+            }
+        }).detach();
+        Watchdog { state: state }
+    }
+
+    /// Marks the start of a handler call; `name` is used in the log message.
+    pub fn begin(&self, name: String) {
+        let mut s = self.state.lock().unwrap();
+        s.current = Some((0, name));
+        s.reported = false;
+    }
+
+    pub fn end(&self) {
+        self.state.lock().unwrap().current = None;
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().stop = true;
+    }
+}
@@ -0,0 +1,125 @@
+use super::{Connection, Message, MessageItem};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread::Thread;
+
+/// A method call, decoded so that it can be handled on another thread.
+#[deriving(Show, Clone)]
+pub struct ThreadedCall {
+    pub path: String,
+    pub interface: String,
+    pub member: String,
+    pub sender: Option<String>,
+    pub args: Vec<MessageItem>,
+}
+
+/// Return values, or an error name and message.
+pub type ThreadedResult = Result<Vec<MessageItem>, (String, String)>;
+
+type ThreadedHandler = Arc<Box<Fn(&ThreadedCall) -> ThreadedResult + Send + Sync>>;
+
+/// Runs method handlers on a pool of worker threads.
+///
+/// The connection stays on the thread that owns the pool: incoming calls are handed
+/// over with `dispatch`, and `send_replies` sends the replies of finished calls, so one
+/// slow method does not hold up the others.
+///
+/// # Example
+///
+/// ```ignore
+/// let mut w = WorkerPool::new(4);
+/// w.add_method("com.example.Slow", "Compute", |&: c: &ThreadedCall| Ok(c.args.clone()));
+/// for n in c.iter(100) {
+///     if let ConnectionItem::MethodCall(m) = n {
+///         if let Err(m) = w.dispatch(m) { /* no threaded handler, handle it here */ }
+///     }
+///     w.send_replies(&c);
+/// }
+/// ```
+pub struct WorkerPool {
+    jobs: Sender<(u32, ThreadedHandler, ThreadedCall)>,
+    results: Receiver<(u32, ThreadedResult)>,
+    // For replying to calls that never reach a worker
+    results_tx: Sender<(u32, ThreadedResult)>,
+    handlers: BTreeMap<(String, String), ThreadedHandler>,
+    pending: BTreeMap<u32, Message>,
+    next_token: u32,
+}
+
+impl WorkerPool {
+    pub fn new(threads: uint) -> WorkerPool {
+        assert!(threads > 0);
+        let (jobs_tx, jobs_rx) = channel::<(u32, ThreadedHandler, ThreadedCall)>();
+        let (results_tx, results_rx) = channel();
+        let jobs_rx = Arc::new(Mutex::new(jobs_rx));
+        for _ in range(0, threads) {
+            let jobs_rx = jobs_rx.clone();
+            let results_tx = results_tx.clone();
+            Thread::spawn(move || {
+                loop {
+                    let job = jobs_rx.lock().unwrap().recv();
+                    let (token, h, call) = match job { Ok(j) => j, Err(_) => break };
+                    if results_tx.send((token, (**h)(&call))).is_err() { break; }
+                }
+            }).detach();
+        }
+        WorkerPool { jobs: jobs_tx, results: results_rx, results_tx: results_tx, handlers: BTreeMap::new(),
+            pending: BTreeMap::new(), next_token: 0 }
+    }
+
+    /// Adds a thread-safe handler for a method.
+    pub fn add_method<F>(&mut self, iface: &str, member: &str, f: F)
+        where F: Fn(&ThreadedCall) -> ThreadedResult + Send + Sync
+    {
+        self.handlers.insert((iface.to_string(), member.to_string()), Arc::new(box f as Box<Fn(&ThreadedCall) -> ThreadedResult + Send + Sync>));
+    }
+
+    /// Hands a method call over to a worker thread.
+    ///
+    /// If there is no handler for the method, the message is given back.
+    pub fn dispatch(&mut self, mut m: Message) -> Result<(), Message> {
+        let (_, path, iface, member) = m.headers();
+        let (path, iface, member) = match (path, iface, member) {
+            (Some(p), Some(i), Some(mb)) => (p, i, mb),
+            _ => return Err(m),
+        };
+        let h = match self.handlers.get(&(iface.clone(), member.clone())) {
+            Some(h) => h.clone(),
+            None => return Err(m),
+        };
+        let token = self.next_token;
+        self.next_token += 1;
+        match m.get_items() {
+            Ok(args) => {
+                let call = ThreadedCall { path: path, interface: iface, member: member, sender: m.sender(), args: args };
+                self.jobs.send((token, h, call)).ok().expect("worker threads have stopped");
+            }
+            // Replied to by the next send_replies, like any other call
+            Err(e) => {
+                let r = Err(("org.freedesktop.DBus.Error.InvalidArgs".to_string(), e.message().unwrap_or("").to_string()));
+                self.results_tx.send((token, r)).unwrap();
+            }
+        }
+        self.pending.insert(token, m);
+        Ok(())
+    }
+
+    /// The number of calls handed to workers that have not been replied to yet.
+    pub fn pending(&self) -> uint { self.pending.len() }
+
+    /// Sends replies for all calls that have finished. Returns the number of replies sent.
+    /// A result whose items cannot be appended is replied to with a Failed error.
+    pub fn send_replies(&mut self, c: &Connection) -> uint {
+        let mut count = 0;
+        while let Ok((token, r)) = self.results.try_recv() {
+            let m = self.pending.remove(&token).unwrap();
+            if m.get_no_reply() { continue; }
+            let r = match r { Ok(ref items) => Ok(items.as_slice()), Err((ref n, ref t)) => Err((n.as_slice(), t.as_slice())) };
+            let reply = match super::method_result_reply(&m, r) { Ok(reply) => reply, Err(_) => continue };
+            let _ = c.send(reply);
+            count += 1;
+        }
+        count
+    }
+}